@@ -2,8 +2,8 @@
 use anyhow::Result;
 use perbase_lib::{
     par_granges::{self, RegionProcessor},
-    position::pileup_position::PileupPosition,
-    read_filter::ReadFilter
+    position::pileup_position::{OptionalColumns, PileupPosition},
+    read_filter::{ReadFilter, SoftClipFraction},
 };
 use rust_htslib::bam::{self, record::Record, Read};
 use std::path::PathBuf;
@@ -34,9 +34,9 @@ impl ReadFilter for BasicReadFilter {
     #[inline]
     fn filter_read(&self, read: &Record) -> bool {
         let flags = read.flags();
-        (!flags) & &self.include_flags == 0
-            && flags & &self.exclude_flags == 0
-            && &read.mapq() >= &self.min_mapq
+        (!flags) & self.include_flags == 0
+            && flags & self.exclude_flags == 0
+            && read.mapq() >= self.min_mapq
     }
 }
 
@@ -45,41 +45,47 @@ impl<F: ReadFilter> RegionProcessor for BasicProcessor<F> {
     type P = PileupPosition;
 
     // This function receives an interval to examine.
-    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<Self::P> {
-        let mut reader = bam::IndexedReader::from_path(&self.bamfile).expect("Indexed reader");
+    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>> {
+        // Opening a reader per region is simplest, see `par_granges::with_reader` to
+        // reuse one reader per worker thread instead
+        let mut reader = bam::IndexedReader::from_path(&self.bamfile)?;
         let header = reader.header().to_owned();
         // fetch the region
-        reader.fetch(tid, start, stop).expect("Fetched ROI");
+        reader.fetch(tid, start, stop)?;
         // Walk over pileups
-        let result: Vec<PileupPosition> = reader
-            .pileup()
-            .flat_map(|p| {
-                let pileup = p.expect("Extracted a pileup");
-                // Verify that we are within the bounds of the chunk we are iterating on
-                // Since pileup will pull reads that overhang edges.
-                if (pileup.pos() as u64) >= start && (pileup.pos() as u64) < stop {
-                    Some(PileupPosition::from_pileup(pileup, &header, &self.read_filter))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        result
+        let mut result = vec![];
+        for p in reader.pileup() {
+            let pileup = p?;
+            // Verify that we are within the bounds of the chunk we are iterating on
+            // Since pileup will pull reads that overhang edges.
+            if (pileup.pos() as u64) >= start && (pileup.pos() as u64) < stop {
+                result.push(PileupPosition::from_pileup(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    None,
+                    OptionalColumns::default(),
+                ));
+            }
+        }
+        // Any error returned here stops the run, and is passed on to the receiver
+        Ok(result)
     }
 }
 
 fn main() -> Result<()> {
-    // Create the read filter
+    // Create the read filter, combined with one of the ready-made filters in `read_filter`
     let read_filter = BasicReadFilter {
         include_flags: 0,
         exclude_flags: 3848,
         min_mapq: 20,
-    };
+    }
+    .and(SoftClipFraction::new(0.5));
 
     // Create the region processor
     let basic_processor = BasicProcessor {
         bamfile: PathBuf::from("test/test.bam"),
-        read_filter: read_filter,
+        read_filter,
     };
 
     // Create a par_granges runner
@@ -94,12 +100,13 @@ fn main() -> Result<()> {
 
     // Run the processor
     let receiver = par_granges_runner.process()?;
-    // Pull the in-order results from the receiver channel
-    receiver.into_iter().for_each(|p: PileupPosition| {
+    // Pull the in-order results from the receiver channel, stopping at the first error
+    for p in receiver.into_iter() {
+        let p: PileupPosition = p?;
         // Note that the returned values are required to be `serde::Serialize`, so more fancy things
         // than just debug printing are doable.
         println!("{:?}", p);
-    });
+    }
 
     Ok(())
 }
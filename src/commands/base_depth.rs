@@ -3,23 +3,35 @@
 //! Base single pass over bam file to calculate depth at each position
 //! as well as depth per nucleotide. Additionally counts the number of
 //! insertions / deletions at each position.
-use anyhow::Result;
+use anyhow::{Context, Error, Result};
 use bio::io::fasta::IndexedReader;
 use csv;
 use grep_cli::stdout;
 use log::*;
 use perbase_lib::{
+    bgzf::{build_tabix_index, BgzfWriter, Output, TabixColumns},
+    filter_expr::ExprReadFilter,
     par_granges::{self, RegionProcessor},
-    position::pileup_position::PileupPosition,
-    read_filter::{DefaultReadFilter, ReadFilter},
-    reference, utils,
+    position::pileup_position::{OptionalColumns, PileupPosition},
+    read_filter::{DefaultReadFilter, FailReason, ReadFilter, ReadList},
+    read_groups::ReadGroups,
+    reference,
+    regions::MissingContig,
+    utils,
 };
 use rust_htslib::{bam, bam::Read};
+use rust_lapper::Lapper;
+use smartstring::alias::String;
 use std::{
+    collections::{BTreeMap, HashMap},
     convert::TryInto,
     fs::File,
     io::{BufWriter, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
 };
 use structopt::StructOpt;
 use termcolor::ColorChoice;
@@ -28,8 +40,9 @@ use termcolor::ColorChoice;
 #[derive(StructOpt)]
 #[structopt(author)]
 pub struct BaseDepth {
-    /// Input indexed BAM/CRAM to analyze.
-    reads: PathBuf,
+    /// Input indexed BAM/CRAM(s) to analyze. If more than one is given, each is reported as a separate sample.
+    #[structopt(required = true)]
+    reads: Vec<PathBuf>,
 
     /// Indexed reference fasta, set if using CRAM.
     #[structopt(long, short = "r")]
@@ -39,6 +52,18 @@ pub struct BaseDepth {
     #[structopt(long, short = "b")]
     bed_file: Option<PathBuf>,
 
+    /// A file of single positions to report on, either a BED file (`.bed`) or a TSV of `<chrom>\t<pos>` with 1-based
+    /// positions. An optional ID column is reported in an ID column. Exactly the listed positions are reported, in the
+    /// order of the file, with zero counts for positions without coverage.
+    #[structopt(long, short = "s", conflicts_with_all = &["bed-file", "split-by-read-group"])]
+    sites: Option<PathBuf>,
+
+    /// What to do with regions or sites on contigs that are not in the BAM/CRAM header: fail, listing all of them (error), skip
+    /// them with a warning listing all of them (warn), or skip them silently (skip). Contig names like `chr1` and `1`, or
+    /// `chrM` and `MT`, are matched to each other before a contig is considered missing.
+    #[structopt(long, default_value = "error", possible_values = &["error", "warn", "skip"])]
+    missing_contig: MissingContig,
+
     /// Output path, defaults to stdout.
     #[structopt(long, short = "o")]
     output: Option<PathBuf>,
@@ -47,10 +72,22 @@ pub struct BaseDepth {
     #[structopt(long, short = "t", default_value = utils::NUM_CPU.as_str())]
     threads: usize,
 
-    /// The ideal number of basepairs each worker receives. Total bp in memory at one time is (threads - 2) * chunksize.
+    /// The ideal number of basepairs each worker receives. Each worker holds the results of its region in memory on top
+    /// of --buffer-mb.
     #[structopt(long, short = "c")]
     chunksize: Option<usize>, // default set by par_granges at 1_000_000
 
+    /// Approximate memory, in MB, used to hold results that are waiting to be written. Lower it to cap memory use on
+    /// deeply covered regions.
+    #[structopt(long, default_value = "256")]
+    buffer_mb: usize,
+
+    /// Size regions by the read density estimated from the BAI index, splitting dense regions into smaller pieces so
+    /// that each worker gets a similar amount of work. Regions are never longer than the chunksize. Without a BAI index,
+    /// regions are split by chunksize only.
+    #[structopt(long)]
+    adaptive_chunks: bool,
+
     /// SAM flags to include.
     #[structopt(long, short = "f", default_value = "0")]
     include_flags: u16,
@@ -63,17 +100,105 @@ pub struct BaseDepth {
     #[structopt(long, short = "m")]
     mate_fix: bool,
 
+    /// Count each molecule once, grouping reads into molecules by the UMI in this aux tag, such as RX, and the start and
+    /// strand of their fragment. Adds a RAW_DEPTH column with the depth before collapsing, see docs for full details.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_tag),
+        conflicts_with_all = &["mate-fix", "split-by-read-group", "split-by-tag"]
+    )]
+    umi_tag: Option<[u8; 2]>,
+
     /// Minimum MAPQ for a read to count toward depth.
     #[structopt(long, short = "q", default_value = "0")]
     min_mapq: u8,
 
+    /// Only count reads that pass a filter expression, such as `mapq >= 20 && !duplicate && tag(NM) <= 4`, see docs
+    /// for the full language. Applied on top of the flag and MAPQ filters.
+    #[structopt(long)]
+    filter: Option<ExprReadFilter>,
+
+    /// Only count reads whose name, or --list-tag value, is listed in this file, one per line. May be gzipped.
+    #[structopt(long)]
+    whitelist: Option<PathBuf>,
+
+    /// Don't count reads whose name, or --list-tag value, is listed in this file, one per line. May be gzipped.
+    #[structopt(long)]
+    blacklist: Option<PathBuf>,
+
+    /// Match --whitelist and --blacklist against the value of this aux tag, such as CB or UB, instead of read names.
+    #[structopt(long, parse(try_from_str = parse_tag))]
+    list_tag: Option<[u8; 2]>,
+
+    /// Minimum base quality for a base to count toward depth. Bases below it are counted in LOW_QUAL.
+    #[structopt(long, short = "Q")]
+    min_base_quality: Option<u8>,
+
+    /// Report the mean base quality of each nucleotide at each position.
+    #[structopt(long)]
+    mean_base_quality: bool,
+
+    /// Report nucleotide, insertion, and deletion counts split by forward and reverse strand.
+    #[structopt(long)]
+    strand_aware: bool,
+
+    /// Break FAIL down by the rule each read failed in FAIL_{DUP,QC,FLAG,MAPQ,OTHER} columns.
+    #[structopt(long)]
+    fail_reasons: bool,
+
+    /// Write the number of reads each filter rule rejected to this file, as a TSV with REASON and READS columns. Each
+    /// read is counted once, however many positions it covers.
+    #[structopt(long)]
+    fail_summary: Option<PathBuf>,
+
     /// Output positions as 0-based instead of 1-based.
     #[structopt(long, short = "z")]
     zero_base: bool,
 
+    /// With multiple inputs, output one group of columns per sample instead of one row per sample with a SAMPLE column.
+    #[structopt(long, short = "w", conflicts_with = "split-by-read-group")]
+    wide: bool,
+
+    /// Split the counts at each position by the read group of the reads, adding a READ_GROUP column.
+    #[structopt(long, short = "G")]
+    split_by_read_group: bool,
+
+    /// When splitting by read group, group read groups by the SM tag of their @RG header line instead of their ID.
+    #[structopt(long, requires = "split-by-read-group")]
+    read_group_sample: bool,
+
+    /// Split the counts at each position by the value of an aux tag, such as CB, adding a TAG_VALUE column. Only the
+    /// values with reads at a position get a row.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_tag),
+        conflicts_with_all = &["split-by-read-group", "wide", "keep-zeros", "sites"]
+    )]
+    split_by_tag: Option<[u8; 2]>,
+
+    /// Also report positions without any coverage, with all counts zero.
+    #[structopt(long, short = "k", conflicts_with = "split-by-read-group")]
+    keep_zeros: bool,
+
     /// Number of Reference Sequences to hold in memory at one time. Smaller will decrease mem usage.
     #[structopt(long, default_value = "10")]
     ref_cache_size: usize,
+
+    /// Write BGZF compressed output. If the output is a file, a tabix index is built alongside it.
+    #[structopt(long, short = "Z")]
+    bgzip: bool,
+
+    /// The BGZF compression level, 0-9, defaults to 6.
+    #[structopt(long, requires = "bgzip")]
+    compression_level: Option<u32>,
+
+    /// The number of threads to use for BGZF compression and indexing, defaults to 1.
+    #[structopt(long, requires = "bgzip")]
+    compression_threads: Option<usize>,
+
+    /// Build a CSI index instead of a TBI index, needed for contigs longer than 2^29 bases.
+    #[structopt(long, requires = "bgzip")]
+    csi: bool,
 }
 
 impl BaseDepth {
@@ -83,42 +208,160 @@ impl BaseDepth {
 
         let mut writer = self.get_writer()?;
 
+        let samples = if self.reads.len() > 1 {
+            Some(sample_names(&self.reads)?)
+        } else {
+            None
+        };
+
+        let whitelist = match &self.whitelist {
+            Some(path) => Some(ReadList::from_path(self.list_tag, path)?),
+            None => None,
+        };
+        let blacklist = match &self.blacklist {
+            Some(path) => Some(ReadList::from_path(self.list_tag, path)?.exclude()),
+            None => None,
+        };
         let read_filter =
-            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq);
+            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq)
+                .with_expression(self.filter.clone())
+                .with_read_list(whitelist)
+                .with_read_list(blacklist);
         let base_processor = BaseProcessor::new(
             self.reads.clone(),
             self.ref_fasta.clone(),
-            self.mate_fix,
             if self.zero_base { 0 } else { 1 },
             read_filter,
             self.ref_cache_size,
-        );
+        )?
+        .with_samples(samples.clone())
+        .with_mate_fix(self.mate_fix)
+        .with_umi_tag(self.umi_tag)
+        .with_min_base_quality(self.min_base_quality)
+        .with_columns(OptionalColumns {
+            strand: self.strand_aware,
+            mean_quals: self.mean_base_quality,
+            fail_reasons: self.fail_reasons,
+        })
+        .with_split_by_read_group(self.split_by_read_group, self.read_group_sample)
+        .with_split_by_tag(self.split_by_tag)
+        .with_keep_zeros(self.keep_zeros)
+        .with_fail_tally(self.fail_summary.is_some());
+        let fail_tally = base_processor.fail_tally.clone();
 
-        let par_granges_runner = par_granges::ParGranges::new(
+        let par_granges_runner = par_granges::ParGranges::with_multiple_reads(
             self.reads.clone(),
             self.ref_fasta.clone(),
             self.bed_file.clone(),
             Some(cpus),
-            self.chunksize.clone(),
+            self.chunksize,
             base_processor,
-        );
+        )
+        .with_missing_contig(self.missing_contig)
+        .with_buffer_size(self.buffer_mb * 1024 * 1024)
+        .with_adaptive_chunks(self.adaptive_chunks);
+        if let (Some(tally), None) = (&fail_tally, &self.sites) {
+            tally.set_intervals(par_granges_runner.intervals()?);
+        }
 
-        let receiver = par_granges_runner.process()?;
+        let positions: Box<dyn Iterator<Item = Result<PileupPosition>>> = match &self.sites {
+            Some(sites) => Box::new(
+                par_granges_runner
+                    .process_sites(sites.clone())?
+                    .into_iter()
+                    .flat_map(|result| match result {
+                        Ok((site, positions)) => {
+                            let id = site.id.unwrap_or_else(|| String::from("."));
+                            positions
+                                .into_iter()
+                                .map(|mut pos| {
+                                    pos.labels_mut().id = Some(id.clone());
+                                    Ok(pos)
+                                })
+                                .collect()
+                        }
+                        Err(err) => vec![Err(err)],
+                    }),
+            ),
+            None => Box::new(par_granges_runner.process()?.into_iter()),
+        };
 
-        receiver
-            .into_iter()
-            .for_each(|pos| writer.serialize(pos).unwrap());
-        writer.flush()?;
+        match samples {
+            Some(samples) if self.wide => {
+                // Each position comes through as one row per sample, in sample order
+                let mut group = Vec::with_capacity(samples.len());
+                let mut write_header = true;
+                for pos in positions {
+                    group.push(pos?);
+                    if group.len() == samples.len() {
+                        write_wide(&mut writer, &samples, &group, write_header)?;
+                        write_header = false;
+                        group.clear();
+                    }
+                }
+            }
+            _ => {
+                for pos in positions {
+                    writer.serialize(pos?)?;
+                }
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|err| Error::msg(format!("Failed to flush output: {}", err.error())))?
+            .finish()?;
+
+        if let (Some(path), Some(tally)) = (&self.fail_summary, fail_tally) {
+            info!("Writing filter failure summary to {:?}", path);
+            tally
+                .write(path)
+                .with_context(|| format!("Failed to write filter failure summary to {:?}", path))?;
+        }
+
+        if let Some(path) = self.output_file() {
+            if self.bgzip {
+                info!("Building index for {:?}", path);
+                let columns = TabixColumns {
+                    seq: 1,
+                    begin: 2,
+                    end: None,
+                    zero_based: self.zero_base,
+                    skip_lines: 1,
+                };
+                build_tabix_index(
+                    path,
+                    columns,
+                    self.csi,
+                    self.compression_threads.unwrap_or(1),
+                )?;
+            }
+        }
         Ok(())
     }
 
-    /// Open a CSV Writer to a file or stdout
-    fn get_writer(&self) -> Result<csv::Writer<Box<dyn Write>>> {
-        let raw_writer: Box<dyn Write> = match &self.output {
-            Some(path) if path.to_str().unwrap() != "-" => {
-                Box::new(BufWriter::new(File::open(path)?))
-            }
-            _ => Box::new(stdout(ColorChoice::Never)),
+    /// The output path, if output is going to a file rather than stdout
+    fn output_file(&self) -> Option<&PathBuf> {
+        self.output
+            .as_ref()
+            .filter(|path| path.to_str().unwrap() != "-")
+    }
+
+    /// Open a CSV Writer to a file or stdout, BGZF compressed if requested
+    fn get_writer(&self) -> Result<csv::Writer<Output>> {
+        let raw_writer = if self.bgzip {
+            let path = self
+                .output_file()
+                .map_or(Path::new("-"), |path| path.as_path());
+            Output::Bgzf(BufWriter::new(BgzfWriter::from_path(
+                path,
+                self.compression_level.unwrap_or(6),
+                self.compression_threads.unwrap_or(1),
+            )?))
+        } else {
+            Output::Plain(match self.output_file() {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(stdout(ColorChoice::Never)),
+            })
         };
         Ok(csv::WriterBuilder::new()
             .delimiter(b'\t')
@@ -127,47 +370,449 @@ impl BaseDepth {
 }
 
 /// Holds the info needed for [par_io::RegionProcessor] implementation
-struct BaseProcessor<F: ReadFilter> {
-    /// path to indexed BAM/CRAM
-    reads: PathBuf,
+pub(crate) struct BaseProcessor<F: ReadFilter> {
+    /// paths to indexed BAM/CRAMs, one per sample
+    reads: Vec<PathBuf>,
+    /// names of the samples in `reads`, set when counting several samples at once
+    samples: Option<Vec<String>>,
     /// path to indexed ref file
     ref_fasta: Option<PathBuf>,
     /// Cached access to the ref_fasta
     ref_buffer: Option<reference::Buffer>,
     /// Indicate whether or not to account for overlapping mates.
     mate_fix: bool,
+    /// The aux tag holding UMIs to collapse reads from the same molecule by, if any
+    umi_tag: Option<[u8; 2]>,
     /// 0-based or 1-based coordiante output
     coord_base: usize,
     /// implementation of [position::ReadFilter] that will be used
     read_filter: F,
+    /// Minimum base quality for a base to count toward depth
+    min_base_quality: Option<u8>,
+    /// The optional groups of columns to report, such as the counts on each strand
+    columns: OptionalColumns,
+    /// Indicate whether or not to split counts by read group
+    split_by_read_group: bool,
+    /// Indicate whether or not to group read groups by their sample when splitting by read group
+    read_group_sample: bool,
+    /// The aux tag to split counts by, if any
+    split_by_tag: Option<[u8; 2]>,
+    /// Indicate whether or not to report positions without coverage
+    keep_zeros: bool,
+    /// Tally of the reads each filter rule rejected, if one is kept
+    fail_tally: Option<Arc<FailTally>>,
+}
+
+/// The number of reads each filter rule rejected over a run, counting each read once.
+///
+/// A failing read is counted at the first queried position it covers. A read that starts before
+/// the region it is seen in is left to an earlier region if it covers any of the intervals given
+/// to [`FailTally::set_intervals`] before this region. Without intervals, as for sites, such a
+/// read is counted by each call to [`BaseProcessor::process_sites`] whose sites it covers.
+#[derive(Debug, Default)]
+pub(crate) struct FailTally {
+    /// Number of reads rejected, by [`FailReason::index`]
+    counts: [AtomicUsize; 5],
+    /// The intervals being queried, by tid
+    intervals: OnceLock<Vec<Lapper<u64, ()>>>,
+}
+
+impl FailTally {
+    /// Set the intervals being queried, by tid, see [`ParGranges::intervals`].
+    ///
+    /// [`ParGranges::intervals`]: par_granges::ParGranges::intervals
+    pub(crate) fn set_intervals(&self, intervals: Vec<Lapper<u64, ()>>) {
+        let _ = self.intervals.set(intervals);
+    }
+
+    /// Count the failing reads of the pileup column at queried position `pos`.
+    ///
+    /// `prev` is the queried position before `pos` in the same call, if any.
+    fn count<F: ReadFilter>(
+        &self,
+        pileup: &bam::pileup::Pileup,
+        read_filter: &F,
+        prev: Option<u64>,
+    ) {
+        let pos = u64::from(pileup.pos());
+        let earlier = self
+            .intervals
+            .get()
+            .and_then(|intervals| intervals.get(pileup.tid() as usize));
+        for alignment in pileup.alignments() {
+            let record = alignment.record();
+            let reason = match read_filter.fail_reason(&record) {
+                Some(reason) => reason,
+                None => continue,
+            };
+            let start = record.pos() as u64;
+            match prev {
+                // Reads covering the previous queried position were counted there
+                Some(prev) if start <= prev => continue,
+                // Reads covering a queried position before this call were counted by that call
+                None if start < pos
+                    && earlier
+                        .is_some_and(|intervals| intervals.find(start, pos).next().is_some()) =>
+                {
+                    continue
+                }
+                _ => (),
+            }
+            self.counts[reason.index()].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of reads rejected, by [`FailReason::index`].
+    pub(crate) fn counts(&self) -> [usize; 5] {
+        let mut counts = [0; 5];
+        for (count, total) in counts.iter_mut().zip(self.counts.iter()) {
+            *count = total.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// Write the number of reads rejected by each rule as a TSV.
+    fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "REASON\tREADS")?;
+        let counts = self.counts();
+        for reason in FailReason::ALL.iter() {
+            writeln!(
+                writer,
+                "{}\t{}",
+                reason.name().to_uppercase(),
+                counts[reason.index()]
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
 }
 
 impl<F: ReadFilter> BaseProcessor<F> {
     /// Create a new BaseProcessor
-    fn new(
-        reads: PathBuf,
+    pub(crate) fn new(
+        reads: Vec<PathBuf>,
         ref_fasta: Option<PathBuf>,
-        mate_fix: bool,
         coord_base: usize,
         read_filter: F,
         ref_buffer_capacity: usize,
-    ) -> Self {
-        let ref_buffer = if let Some(ref_fasta) = &ref_fasta {
-            Some(reference::Buffer::new(
-                IndexedReader::from_file(ref_fasta).expect("Reading Indexed FASTA"),
+    ) -> Result<Self> {
+        let ref_buffer = match &ref_fasta {
+            Some(ref_fasta) => Some(reference::Buffer::new(
+                IndexedReader::from_file(ref_fasta)
+                    .with_context(|| format!("Failed to open indexed FASTA {:?}", ref_fasta))?,
                 ref_buffer_capacity,
-            ))
-        } else {
-            None
+            )),
+            None => None,
         };
-        Self {
+        Ok(Self {
             reads,
+            samples: None,
             ref_fasta,
             ref_buffer,
-            mate_fix,
+            mate_fix: false,
+            umi_tag: None,
             coord_base,
             read_filter,
+            min_base_quality: None,
+            columns: OptionalColumns::default(),
+            split_by_read_group: false,
+            read_group_sample: false,
+            split_by_tag: None,
+            keep_zeros: false,
+            fail_tally: None,
+        })
+    }
+
+    /// Name the samples of the reads, to count several samples at once
+    pub(crate) fn with_samples(mut self, samples: Option<Vec<String>>) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Only count one read of each pair of overlapping mates
+    pub(crate) fn with_mate_fix(mut self, mate_fix: bool) -> Self {
+        self.mate_fix = mate_fix;
+        self
+    }
+
+    /// Count each molecule once, grouping reads by the UMI in `umi_tag`
+    pub(crate) fn with_umi_tag(mut self, umi_tag: Option<[u8; 2]>) -> Self {
+        self.umi_tag = umi_tag;
+        self
+    }
+
+    /// Count bases below `min_base_quality` as low quality instead of toward depth
+    pub(crate) fn with_min_base_quality(mut self, min_base_quality: Option<u8>) -> Self {
+        self.min_base_quality = min_base_quality;
+        self
+    }
+
+    /// Report the given optional groups of columns
+    pub(crate) fn with_columns(mut self, columns: OptionalColumns) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    /// Split counts by read group, grouping read groups by their sample if `read_group_sample` is set
+    pub(crate) fn with_split_by_read_group(
+        mut self,
+        split_by_read_group: bool,
+        read_group_sample: bool,
+    ) -> Self {
+        self.split_by_read_group = split_by_read_group;
+        self.read_group_sample = read_group_sample;
+        self
+    }
+
+    /// Split counts by the value of the aux tag `split_by_tag`
+    pub(crate) fn with_split_by_tag(mut self, split_by_tag: Option<[u8; 2]>) -> Self {
+        self.split_by_tag = split_by_tag;
+        self
+    }
+
+    /// Report positions without coverage, with all counts zero
+    pub(crate) fn with_keep_zeros(mut self, keep_zeros: bool) -> Self {
+        self.keep_zeros = keep_zeros;
+        self
+    }
+
+    /// Keep a tally of the reads each filter rule rejected, see [`FailTally`].
+    pub(crate) fn with_fail_tally(mut self, fail_tally: bool) -> Self {
+        self.fail_tally = if fail_tally {
+            Some(Arc::new(FailTally::default()))
+        } else {
+            None
+        };
+        self
+    }
+}
+
+impl<F: ReadFilter> BaseProcessor<F> {
+    /// Count the bases in a region of the `sample`th BAM/CRAM, reusing this thread's reader on it.
+    ///
+    /// If `sites` are given, only those positions of the region are queried.
+    fn process_reads(
+        &self,
+        sample: usize,
+        tid: u32,
+        start: u64,
+        stop: u64,
+        sites: Option<&[u64]>,
+    ) -> Result<Vec<PileupPosition>> {
+        let reads = &self.reads[sample];
+        par_granges::with_reader(reads, self.ref_fasta.as_deref(), |reader| {
+            self.process_reader(reader, sample, tid, start, stop, sites)
+        })
+    }
+
+    /// Count the bases in a region of the open `sample`th BAM/CRAM.
+    fn process_reader(
+        &self,
+        reader: &mut bam::IndexedReader,
+        sample: usize,
+        tid: u32,
+        start: u64,
+        stop: u64,
+        sites: Option<&[u64]>,
+    ) -> Result<Vec<PileupPosition>> {
+        let reads = &self.reads[sample];
+        let header = reader.header().to_owned();
+        // Inputs may be missing contigs at the end of the shared header
+        if tid >= header.target_count() {
+            return Ok(vec![]);
+        }
+        // fetch the region of interest
+        reader
+            .fetch(tid, start, stop)
+            .with_context(|| format!("Failed to fetch from {:?}", reads))?;
+        // Walk over pileups
+        let mut pileup = reader.pileup();
+        pileup.set_max_depth(i32::MAX.try_into().unwrap());
+        let read_groups = if self.split_by_read_group {
+            Some(ReadGroups::from_header(&header, self.read_group_sample))
+        } else {
+            None
+        };
+        let mut result: Vec<PileupPosition> = vec![];
+        for p in pileup {
+            let pileup = p.with_context(|| format!("Failed to read pileup from {:?}", reads))?;
+            // Verify that we are within the bounds of the chunk we are iterating on
+            if (pileup.pos() as u64) < start || (pileup.pos() as u64) >= stop {
+                continue;
+            }
+            if let Some(fail_tally) = &self.fail_tally {
+                let pos = pileup.pos() as u64;
+                match sites {
+                    Some(sites) => {
+                        if let Ok(i) = sites.binary_search(&pos) {
+                            let prev = i.checked_sub(1).map(|i| sites[i]);
+                            fail_tally.count(&pileup, &self.read_filter, prev);
+                        }
+                    }
+                    None => {
+                        let prev = if pos > start { Some(pos - 1) } else { None };
+                        fail_tally.count(&pileup, &self.read_filter, prev);
+                    }
+                }
+            }
+            let mut positions = if let Some(read_groups) = &read_groups {
+                PileupPosition::from_pileup_by_read_group(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    self.min_base_quality,
+                    self.columns,
+                    self.mate_fix,
+                    read_groups,
+                )
+            } else if let Some(tag) = &self.split_by_tag {
+                PileupPosition::from_pileup_by_tag(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    self.min_base_quality,
+                    self.columns,
+                    self.mate_fix,
+                    tag,
+                )
+            } else if let Some(umi_tag) = &self.umi_tag {
+                vec![PileupPosition::from_pileup_umi_aware(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    self.min_base_quality,
+                    self.columns,
+                    umi_tag,
+                )]
+            } else if self.mate_fix {
+                vec![PileupPosition::from_pileup_mate_aware(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    self.min_base_quality,
+                    self.columns,
+                )]
+            } else {
+                vec![PileupPosition::from_pileup(
+                    pileup,
+                    &header,
+                    &self.read_filter,
+                    self.min_base_quality,
+                    self.columns,
+                )]
+            };
+            for pos in positions.iter_mut() {
+                // Add the ref base if reference is available
+                pos.ref_base = self.ref_base(&pos.ref_seq, pos.pos)?;
+                pos.pos += self.coord_base;
+            }
+            result.extend(positions);
         }
+
+        if self.keep_zeros {
+            // Fill the positions the pileup skipped, staying within the contig
+            let ref_seq = std::str::from_utf8(header.tid2name(tid)).unwrap();
+            let stop = std::cmp::min(stop, header.target_len(tid).unwrap_or(stop));
+            let mut result = result.into_iter().peekable();
+            let mut filled = Vec::with_capacity(stop.saturating_sub(start) as usize);
+            for pos in start as usize..stop as usize {
+                let before = filled.len();
+                while let Some(covered) = result.next_if(|p| p.pos == pos + self.coord_base) {
+                    filled.push(covered);
+                }
+                if filled.len() == before {
+                    let ref_base = self.ref_base(ref_seq, pos)?;
+                    filled.push(self.empty_position(ref_seq, pos + self.coord_base, ref_base));
+                }
+            }
+            return Ok(filled);
+        }
+        Ok(result)
+    }
+
+    /// Count the bases in a region of every sample, see [`BaseProcessor::process_region`].
+    ///
+    /// If `sites` are given, only those positions of the region are queried.
+    fn process_samples(
+        &self,
+        tid: u32,
+        start: u64,
+        stop: u64,
+        sites: Option<&[u64]>,
+    ) -> Result<Vec<PileupPosition>> {
+        let samples = match &self.samples {
+            Some(samples) => samples,
+            None => return self.process_reads(0, tid, start, stop, sites),
+        };
+
+        // Collect each sample's counts by position
+        let mut by_pos: BTreeMap<usize, Vec<Vec<PileupPosition>>> = BTreeMap::new();
+        for i in 0..self.reads.len() {
+            for pos in self.process_reads(i, tid, start, stop, sites)? {
+                let group = by_pos
+                    .entry(pos.pos)
+                    .or_insert_with(|| samples.iter().map(|_| vec![]).collect());
+                group[i].push(pos);
+            }
+        }
+
+        let mut result = Vec::with_capacity(by_pos.len() * samples.len());
+        for (_, group) in by_pos.into_iter() {
+            let covered = group.iter().flatten().next().unwrap();
+            let (ref_seq, pos, ref_base) = (covered.ref_seq.clone(), covered.pos, covered.ref_base);
+            for (mut sample_positions, sample) in group.into_iter().zip(samples.iter()) {
+                // When split by read group or tag only the groups with reads are reported
+                if sample_positions.is_empty() && !self.is_split() {
+                    sample_positions.push(self.empty_position(&ref_seq, pos, ref_base));
+                }
+                for mut sample_pos in sample_positions {
+                    sample_pos.labels_mut().sample = Some(sample.clone());
+                    result.push(sample_pos);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Whether or not counts are split into one row per read group or tag value with reads.
+    fn is_split(&self) -> bool {
+        self.split_by_read_group || self.split_by_tag.is_some()
+    }
+
+    /// The reference base at a 0-based position, if a reference is available.
+    fn ref_base(&self, ref_seq: &str, pos: usize) -> Result<Option<char>> {
+        let buffer = match &self.ref_buffer {
+            Some(buffer) => buffer,
+            None => return Ok(None),
+        };
+        let seq = buffer.seq(ref_seq).with_context(|| {
+            format!(
+                "Failed to fetch {} from reference {:?}",
+                ref_seq, self.ref_fasta
+            )
+        })?;
+        let base = seq.get(pos).with_context(|| {
+            format!(
+                "Position {}:{} is past the end of reference {:?}, the input does not match the reference",
+                ref_seq, pos, self.ref_fasta
+            )
+        })?;
+        Ok(Some(char::from(*base)))
+    }
+
+    /// Create a position with all counts zeroed for a sample that had no coverage.
+    fn empty_position(&self, ref_seq: &str, pos: usize, ref_base: Option<char>) -> PileupPosition {
+        let mut pos = PileupPosition::with_columns(String::from(ref_seq), pos, self.columns);
+        pos.ref_base = ref_base;
+        if self.umi_tag.is_some() {
+            pos.raw_depth = Some(0);
+        }
+        if self.min_base_quality.is_some() {
+            pos.low_qual = Some(0);
+        }
+        pos
     }
 }
 
@@ -178,64 +823,178 @@ impl<F: ReadFilter> RegionProcessor for BaseProcessor<F> {
 
     /// Process a region by fetching it from a BAM/CRAM, getting a pileup, and then
     /// walking the pileup (checking bounds) to create Position objects according to
-    /// the defined filters
-    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<PileupPosition> {
+    /// the defined filters.
+    ///
+    /// When counting several samples, every position covered by any sample gets one
+    /// row per sample, in sample order.
+    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<PileupPosition>> {
         info!("Processing region {}:{}-{}", tid, start, stop);
-        // Create a reader
-        let mut reader =
-            bam::IndexedReader::from_path(&self.reads).expect("Indexed Reader for region");
+        self.process_samples(tid, start, stop, None)
+    }
+
+    /// Process the region spanning the positions with a single fetch, and pick out the rows at
+    /// each position. Positions without coverage get a zero-filled row, one per sample when
+    /// counting several samples.
+    fn process_sites(
+        &self,
+        tid: u32,
+        ref_seq: &str,
+        positions: &[u64],
+    ) -> Result<Vec<Vec<PileupPosition>>> {
+        let (start, stop) = match (positions.first(), positions.last()) {
+            (Some(first), Some(last)) => (*first, *last + 1),
+            _ => return Ok(vec![]),
+        };
+        let mut by_pos: HashMap<usize, Vec<PileupPosition>> = HashMap::new();
+        for pos in self.process_samples(tid, start, stop, Some(positions))? {
+            by_pos
+                .entry(pos.pos - self.coord_base)
+                .or_default()
+                .push(pos);
+        }
+        let mut result = Vec::with_capacity(positions.len());
+        for &pos in positions {
+            let pos = pos as usize;
+            if let Some(covered) = by_pos.remove(&pos) {
+                result.push(covered);
+                continue;
+            }
+            // When split by read group or tag only the groups with reads are reported
+            if self.is_split() {
+                result.push(vec![]);
+                continue;
+            }
+            let ref_base = self.ref_base(ref_seq, pos)?;
+            let empty = || self.empty_position(ref_seq, pos + self.coord_base, ref_base);
+            result.push(match &self.samples {
+                Some(samples) => samples
+                    .iter()
+                    .map(|sample| {
+                        let mut empty = empty();
+                        empty.labels_mut().sample = Some(sample.clone());
+                        empty
+                    })
+                    .collect(),
+                None => vec![empty()],
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// Parse a two character aux tag name, such as `CB`.
+fn parse_tag(tag: &str) -> Result<[u8; 2]> {
+    match tag.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([*a, *b]),
+        _ => Err(Error::msg(format!(
+            "Invalid tag {:?}, tags are a letter followed by a letter or digit",
+            tag
+        ))),
+    }
+}
 
-        // If passed add ref_fasta
-        if let Some(ref_fasta) = &self.ref_fasta {
-            reader.set_reference(ref_fasta).expect("Set ref");
+/// Get the sample name of each input, see [`sample_name`], checking that they are all different.
+pub(crate) fn sample_names(reads: &[PathBuf]) -> Result<Vec<String>> {
+    let samples = reads.iter().map(sample_name).collect::<Result<Vec<_>>>()?;
+    for (i, sample) in samples.iter().enumerate() {
+        if samples[..i].contains(sample) {
+            return Err(Error::msg(format!(
+                "Sample name {} is used by more than one input",
+                sample
+            )));
         }
+    }
+    info!("Using sample names: {}", samples.join(", "));
+    Ok(samples)
+}
 
-        let header = reader.header().to_owned();
-        // fetch the region of interest
-        reader.fetch(tid, start, stop).expect("Fetched a region");
-        // Walk over pileups
-        let mut pileup = reader.pileup();
-        pileup.set_max_depth(i32::max_value().try_into().unwrap());
-        let result: Vec<PileupPosition> = pileup
-            .flat_map(|p| {
-                let pileup = p.expect("Extracted a pileup");
-                // Verify that we are within the bounds of the chunk we are iterating on
-                if (pileup.pos() as u64) >= start && (pileup.pos() as u64) < stop {
-                    let mut pos = if self.mate_fix {
-                        PileupPosition::from_pileup_mate_aware(pileup, &header, &self.read_filter)
-                    } else {
-                        PileupPosition::from_pileup(pileup, &header, &self.read_filter)
-                    };
-                    // Add the ref base if reference is available
-                    if let Some(buffer) = &self.ref_buffer {
-                        let seq = buffer
-                            .seq(&pos.ref_seq)
-                            .expect("Fetched reference sequence");
-                        pos.ref_base = Some(char::from(
-                            *seq.get(pos.pos)
-                                .expect("Input SAM does not match reference"),
-                        ));
-                    }
-                    pos.pos += self.coord_base;
-                    Some(pos)
-                } else {
-                    None
+/// Get the name of a sample from the `SM` tag of its read groups, falling back to the file stem
+/// if there are no read groups or they disagree.
+fn sample_name(reads: &PathBuf) -> Result<String> {
+    let read_groups = ReadGroups::from_path(reads, true)?;
+    match read_groups.header_names() {
+        [name] => Ok(name.clone()),
+        _ => {
+            let stem = reads
+                .file_stem()
+                .with_context(|| format!("No file name for {:?}", reads))?;
+            Ok(String::from(stem.to_string_lossy().as_ref()))
+        }
+    }
+}
+
+/// The columns that identify a position, which are written once for all samples in the wide output.
+const POSITION_COLUMNS: [&str; 4] = ["REF", "POS", "ID", "REF_BASE"];
+
+/// Write the rows for one position across all samples as a single wide row, with one group of
+/// columns per sample. If `write_header` is true the header is written first.
+///
+/// Each row is serialized as it would be in the long output, and every column other than the
+/// [`POSITION_COLUMNS`] and `SAMPLE` is prefixed with the sample name.
+fn write_wide<W: Write>(
+    writer: &mut csv::Writer<W>,
+    samples: &[String],
+    group: &[PileupPosition],
+    write_header: bool,
+) -> Result<()> {
+    let mut header = csv::StringRecord::new();
+    let mut record = csv::StringRecord::new();
+    for (i, (sample, pos)) in samples.iter().zip(group).enumerate() {
+        let (long_header, long_record) = long_row(pos)?;
+        for (column, value) in long_header.iter().zip(long_record.iter()) {
+            if POSITION_COLUMNS.contains(&column) {
+                if i == 0 {
+                    header.push_field(column);
+                    record.push_field(value);
                 }
-            })
-            .collect();
-        result
+            } else if column != "SAMPLE" {
+                header.push_field(&format!("{}_{}", sample, column));
+                record.push_field(value);
+            }
+        }
+    }
+    if write_header {
+        writer.write_record(&header)?;
     }
+    writer.write_record(&record)?;
+    Ok(())
+}
+
+/// Serialize a position as it is written in the long output, returning its header and row.
+fn long_row(pos: &PileupPosition) -> Result<(csv::StringRecord, csv::StringRecord)> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(vec![]);
+    writer.serialize(pos)?;
+    let output = writer
+        .into_inner()
+        .map_err(|err| Error::msg(err.to_string()))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(output.as_slice());
+    let header = reader.headers()?.clone();
+    let record = reader
+        .records()
+        .next()
+        .context("Position was serialized without a row")??;
+    Ok((header, record))
 }
 
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
     use super::*;
+    use crate::commands::test_pairs::{arb_pairs, expected_depths, write_pairs_bam, CONTIG_LEN};
     use perbase_lib::position::{pileup_position::PileupPosition, Position};
+    use proptest::prelude::*;
     use rstest::*;
     use rust_htslib::{bam, bam::record::Record};
+    use rust_lapper::Interval;
     use smartstring::alias::*;
-    use std::{collections::HashMap, path::PathBuf};
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
     use tempfile::{tempdir, TempDir};
 
     #[fixture]
@@ -310,16 +1069,18 @@ mod tests {
         (path, tempdir)
     }
 
-    #[fixture]
-    fn non_mate_aware_positions(
+    /// Count the positions of the test bam with a [`BaseProcessor`] set up by `configure`, by contig.
+    fn positions_with(
         bamfile: (PathBuf, TempDir),
         read_filter: DefaultReadFilter,
+        configure: impl FnOnce(BaseProcessor<DefaultReadFilter>) -> BaseProcessor<DefaultReadFilter>,
     ) -> HashMap<String, Vec<PileupPosition>> {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
         // Use the number of cpus available as a proxy for how may ref seqs to hold in memory at one time.
-        let base_processor =
-            BaseProcessor::new(bamfile.0.clone(), None, false, 1, read_filter, cpus);
+        let base_processor = configure(
+            BaseProcessor::new(vec![bamfile.0.clone()], None, 1, read_filter, cpus).unwrap(),
+        );
 
         let par_granges_runner =
             par_granges::ParGranges::new(bamfile.0, None, None, Some(cpus), None, base_processor);
@@ -328,6 +1089,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -336,34 +1098,21 @@ mod tests {
     }
 
     #[fixture]
-    fn mate_aware_positions(
+    fn non_mate_aware_positions(
         bamfile: (PathBuf, TempDir),
         read_filter: DefaultReadFilter,
     ) -> HashMap<String, Vec<PileupPosition>> {
-        let cpus = utils::determine_allowed_cpus(8).unwrap();
-
-        // Use the number of cpus available as a proxy for how may ref seqs to hold in memory at one time.
-        let base_processor = BaseProcessor::new(
-            bamfile.0.clone(),
-            None,
-            true, // mate aware
-            1,
-            read_filter,
-            cpus,
-        );
+        positions_with(bamfile, read_filter, |processor| processor)
+    }
 
-        let par_granges_runner =
-            par_granges::ParGranges::new(bamfile.0, None, None, Some(cpus), None, base_processor);
-        let mut positions = HashMap::new();
-        par_granges_runner
-            .process()
-            .unwrap()
-            .into_iter()
-            .for_each(|p| {
-                let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
-                pos.push(p)
-            });
-        positions
+    #[fixture]
+    fn mate_aware_positions(
+        bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+    ) -> HashMap<String, Vec<PileupPosition>> {
+        positions_with(bamfile, read_filter, |processor| {
+            processor.with_mate_fix(true)
+        })
     }
 
     #[rstest(
@@ -586,4 +1335,925 @@ mod tests {
         assert_eq!(positions.get("chr2").unwrap()[43].a, 2 - awareness_modifier); // mate overlap
         assert_eq!(positions.get("chr2").unwrap()[44].a, 1);
     }
+
+    #[rstest]
+    fn check_fail_reasons(bamfile: (PathBuf, TempDir)) {
+        let read_filter = DefaultReadFilter::new(0, 512, 41);
+        let positions = positions_with(bamfile, read_filter, |processor| {
+            processor.with_columns(OptionalColumns {
+                fail_reasons: true,
+                ..OptionalColumns::default()
+            })
+        });
+        // The QC failing read is failed for being a QC failure rather than for its MAPQ
+        let pos = &positions.get("chr2").unwrap()[64];
+        assert_eq!(pos.fail, 4);
+        assert_eq!(pos.fail_reasons(), Some(&[0, 1, 0, 3, 0]));
+
+        for pos in positions.values().flatten() {
+            assert_eq!(pos.fail_reasons().unwrap().iter().sum::<usize>(), pos.fail);
+        }
+    }
+
+    fn fail_tally_processor(bamfile: &Path) -> BaseProcessor<DefaultReadFilter> {
+        BaseProcessor::new(
+            vec![bamfile.to_path_buf()],
+            None,
+            1,
+            DefaultReadFilter::new(0, 512, 41),
+            1,
+        )
+        .unwrap()
+        .with_fail_tally(true)
+    }
+
+    #[rstest]
+    fn check_fail_tally(bamfile: (PathBuf, TempDir)) {
+        // Every read is below the minimum MAPQ, except the QC failing read
+        let expected = [0, 1, 0, 19, 0];
+        for chunksize in [1_000, 7, 1].iter() {
+            let processor = fail_tally_processor(&bamfile.0);
+            let tally = processor.fail_tally.clone().unwrap();
+            let runner = par_granges::ParGranges::new(
+                bamfile.0.clone(),
+                None,
+                None,
+                Some(2),
+                Some(*chunksize),
+                processor,
+            );
+            tally.set_intervals(runner.intervals().unwrap());
+            runner.process().unwrap().into_iter().for_each(|p| {
+                p.unwrap();
+            });
+            assert_eq!(tally.counts(), expected, "chunksize {}", chunksize);
+        }
+    }
+
+    #[rstest]
+    fn check_fail_tally_reads_before_region(bamfile: (PathBuf, TempDir)) {
+        let processor = fail_tally_processor(&bamfile.0);
+        let tally = processor.fail_tally.clone().unwrap();
+        tally.set_intervals(vec![
+            Lapper::new(vec![]),
+            Lapper::new(vec![Interval {
+                start: 70,
+                stop: 90,
+                val: (),
+            }]),
+        ]);
+        // chr2:70-80 is covered by 4 reads, all starting before it
+        processor.process_region(1, 70, 80).unwrap();
+        assert_eq!(tally.counts(), [0, 1, 0, 3, 0]);
+        // The two of them that go on past 80 are not counted again
+        processor.process_region(1, 80, 90).unwrap();
+        assert_eq!(tally.counts(), [0, 1, 0, 3, 0]);
+
+        // With a gap between the regions, reads are counted by the first region they cover
+        let processor = fail_tally_processor(&bamfile.0);
+        let tally = processor.fail_tally.clone().unwrap();
+        tally.set_intervals(vec![
+            Lapper::new(vec![]),
+            Lapper::new(vec![
+                Interval {
+                    start: 55,
+                    stop: 60,
+                    val: (),
+                },
+                Interval {
+                    start: 80,
+                    stop: 90,
+                    val: (),
+                },
+            ]),
+        ]);
+        // Of the two reads covering chr2:80-90, one also covers chr2:55-60, the other starts in the gap
+        processor.process_region(1, 80, 90).unwrap();
+        assert_eq!(tally.counts(), [0, 1, 0, 0, 0]);
+        // Four reads cover chr2:55-60, among them the one left out at chr2:80-90
+        processor.process_region(1, 55, 60).unwrap();
+        assert_eq!(tally.counts(), [0, 1, 0, 4, 0]);
+
+        // Sites count the reads covering them, once each
+        let processor = fail_tally_processor(&bamfile.0);
+        let tally = processor.fail_tally.clone().unwrap();
+        processor.process_sites(0, "chr1", &[10, 20, 60]).unwrap();
+        assert_eq!(tally.counts(), [0, 0, 0, 8, 0]);
+    }
+
+    #[rstest]
+    fn check_base_quality(bamfile: (PathBuf, TempDir), read_filter: DefaultReadFilter) {
+        // All bases in the test bam have a quality of 2 ('#')
+        let positions = positions_with(bamfile, read_filter, |processor| {
+            processor
+                .with_min_base_quality(Some(2))
+                .with_columns(OptionalColumns {
+                    mean_quals: true,
+                    ..OptionalColumns::default()
+                })
+        });
+        assert_eq!(positions.get("chr1").unwrap()[0].depth, 1);
+        assert_eq!(positions.get("chr1").unwrap()[0].a, 1);
+        assert_eq!(positions.get("chr1").unwrap()[0].low_qual, Some(0));
+        let mean_quals = positions.get("chr1").unwrap()[0].mean_quals().unwrap();
+        assert_eq!(mean_quals[0], 2.0);
+        assert_eq!(mean_quals[3], 0.0);
+    }
+
+    #[rstest]
+    fn check_low_base_quality(bamfile: (PathBuf, TempDir), read_filter: DefaultReadFilter) {
+        let positions = positions_with(bamfile, read_filter, |processor| {
+            processor
+                .with_min_base_quality(Some(3))
+                .with_columns(OptionalColumns {
+                    mean_quals: true,
+                    ..OptionalColumns::default()
+                })
+        });
+        assert_eq!(positions.get("chr1").unwrap()[0].depth, 0);
+        assert_eq!(positions.get("chr1").unwrap()[0].a, 0);
+        assert_eq!(positions.get("chr1").unwrap()[0].low_qual, Some(1));
+        assert_eq!(positions.get("chr1").unwrap()[19].depth, 0);
+        assert_eq!(positions.get("chr1").unwrap()[19].low_qual, Some(5));
+        // Insertions are still counted after a low quality base
+        assert_eq!(positions.get("chr2").unwrap()[1].low_qual, Some(1));
+        assert_eq!(positions.get("chr2").unwrap()[1].ins, 1);
+        // Deletions have no base quality and still count toward depth
+        assert_eq!(positions.get("chr2").unwrap()[6].del, 1);
+        assert_eq!(positions.get("chr2").unwrap()[6].depth, 1);
+        // Filtered reads are counted as failures before base quality is checked
+        assert_eq!(positions.get("chr2").unwrap()[84].fail, 1);
+        assert_eq!(positions.get("chr2").unwrap()[84].low_qual, Some(0));
+    }
+
+    #[rstest(mate_fix => [true, false])]
+    fn check_strand_counts(
+        bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+        mate_fix: bool,
+    ) {
+        let positions = positions_with(bamfile, read_filter, |processor| {
+            processor
+                .with_mate_fix(mate_fix)
+                .with_columns(OptionalColumns {
+                    strand: true,
+                    ..OptionalColumns::default()
+                })
+        });
+        let strand = |contig: &str, i: usize| positions.get(contig).unwrap()[i].strand().unwrap();
+        // Forward strand first in pair
+        assert_eq!(strand("chr1", 0).a_fwd, 1);
+        assert_eq!(strand("chr1", 0).a_rev, 0);
+        // Reverse strand second in pair at position 50, NB: -6 bc there are 6 positions with no coverage from 44-50
+        assert_eq!(strand("chr1", 50 - 6).t_fwd, 0);
+        assert_eq!(strand("chr1", 50 - 6).t_rev, 1);
+        // Insertions and deletions
+        assert_eq!(strand("chr2", 1).ins_fwd, 1);
+        assert_eq!(strand("chr2", 1).ins_rev, 0);
+        assert_eq!(strand("chr2", 6).del_fwd, 1);
+        assert_eq!(strand("chr2", 6).del_rev, 0);
+        // Strand counts always add up to the total counts, mate overlap or not
+        for pos in positions.values().flatten() {
+            let strand = pos.strand().unwrap();
+            assert_eq!(strand.a_fwd + strand.a_rev, pos.a);
+            assert_eq!(strand.c_fwd + strand.c_rev, pos.c);
+            assert_eq!(strand.g_fwd + strand.g_rev, pos.g);
+            assert_eq!(strand.t_fwd + strand.t_rev, pos.t);
+            assert_eq!(strand.n_fwd + strand.n_rev, pos.n);
+            assert_eq!(strand.ins_fwd + strand.ins_rev, pos.ins);
+            assert_eq!(strand.del_fwd + strand.del_rev, pos.del);
+        }
+        // Only one of the overlapping mates is counted with mate fix
+        let overlap = strand("chr2", 34);
+        if mate_fix {
+            assert_eq!(overlap.a_fwd + overlap.a_rev, 3);
+        } else {
+            assert_eq!(overlap.a_fwd, 3);
+            assert_eq!(overlap.a_rev, 1);
+        }
+    }
+
+    #[rstest]
+    fn check_no_strand_counts(non_mate_aware_positions: HashMap<String, Vec<PileupPosition>>) {
+        assert!(non_mate_aware_positions.get("chr1").unwrap()[0]
+            .strand()
+            .is_none());
+    }
+
+    /// Write a BAM with the given contigs, read group IDs and samples, and SAM records and index it.
+    fn write_bam(
+        path: &std::path::Path,
+        contigs: &[(&str, usize)],
+        read_groups: &[(&str, &str)],
+        records: &[&[u8]],
+    ) {
+        let mut header = bam::header::Header::new();
+        for (name, len) in contigs {
+            let mut contig = bam::header::HeaderRecord::new(b"SQ");
+            contig.push_tag(b"SN", name);
+            contig.push_tag(b"LN", len);
+            header.push_record(&contig);
+        }
+        for (id, sample) in read_groups {
+            let mut rg = bam::header::HeaderRecord::new(b"RG");
+            rg.push_tag(b"ID", id);
+            rg.push_tag(b"SM", sample);
+            header.push_record(&rg);
+        }
+        let view = bam::HeaderView::from_header(&header);
+        let mut writer =
+            bam::Writer::from_path(path, &header, bam::Format::BAM).expect("Created writer");
+        for record in records {
+            writer
+                .write(&Record::from_sam(&view, record).unwrap())
+                .expect("Wrote record");
+        }
+        drop(writer);
+        bam::index::build(path, None, bam::index::Type::BAI, 1).unwrap();
+    }
+
+    #[fixture]
+    fn normal_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("normal.bam");
+        // Only shares chr1 with `bamfile`, and extends past the end of its coverage
+        write_bam(
+            &path,
+            &[("chr1", 100)],
+            &[("rg1", "normal")],
+            &[b"N1\t0\tchr1\t90\t40\t10M\t*\t0\t0\tCCCCCCCCCC\t##########"],
+        );
+        (path, tempdir)
+    }
+
+    #[fixture]
+    fn multi_sample_positions(
+        bamfile: (PathBuf, TempDir),
+        normal_bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+    ) -> HashMap<String, Vec<PileupPosition>> {
+        let cpus = utils::determine_allowed_cpus(8).unwrap();
+        let reads = vec![bamfile.0.clone(), normal_bamfile.0.clone()];
+        let base_processor = BaseProcessor::new(reads.clone(), None, 1, read_filter, cpus)
+            .unwrap()
+            .with_samples(Some(vec![String::from("tumor"), String::from("normal")]));
+
+        let par_granges_runner = par_granges::ParGranges::with_multiple_reads(
+            reads,
+            None,
+            None,
+            Some(cpus),
+            None,
+            base_processor,
+        );
+        let mut positions = HashMap::new();
+        par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .for_each(|p| {
+                let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
+                pos.push(p)
+            });
+        positions
+    }
+
+    #[rstest]
+    fn check_multi_sample(
+        multi_sample_positions: HashMap<String, Vec<PileupPosition>>,
+        non_mate_aware_positions: HashMap<String, Vec<PileupPosition>>,
+    ) {
+        // Every position has a row for each sample, in sample order
+        for positions in multi_sample_positions.values() {
+            for pair in positions.chunks(2) {
+                assert_eq!(pair[0].labels().sample, Some(String::from("tumor")));
+                assert_eq!(pair[1].labels().sample, Some(String::from("normal")));
+                assert_eq!(pair[0].pos, pair[1].pos);
+            }
+        }
+
+        // The tumor counts match running it alone
+        let chr1 = multi_sample_positions.get("chr1").unwrap();
+        let tumor: Vec<&PileupPosition> = chr1.iter().step_by(2).collect();
+        for expected in non_mate_aware_positions.get("chr1").unwrap() {
+            let found = tumor.iter().find(|p| p.pos == expected.pos).unwrap();
+            assert_eq!(found.depth, expected.depth);
+            assert_eq!(found.a, expected.a);
+            assert_eq!(found.t, expected.t);
+        }
+
+        // Both samples cover pos 92, only the normal covers pos 99
+        let pos_92: Vec<&PileupPosition> = chr1.iter().filter(|p| p.pos == 92).collect();
+        assert_eq!(pos_92[0].depth, 1);
+        assert_eq!(pos_92[1].depth, 1);
+        assert_eq!(pos_92[1].c, 1);
+        let pos_99: Vec<&PileupPosition> = chr1.iter().filter(|p| p.pos == 99).collect();
+        assert_eq!(pos_99[0].depth, 0);
+        assert_eq!(pos_99[1].depth, 1);
+
+        // The normal is missing chr2, so has zero counts there
+        let chr2 = multi_sample_positions.get("chr2").unwrap();
+        assert_eq!(
+            chr2.len(),
+            non_mate_aware_positions.get("chr2").unwrap().len() * 2
+        );
+        assert!(chr2.iter().skip(1).step_by(2).all(|p| p.depth == 0));
+    }
+
+    #[rstest]
+    fn check_sample_names(bamfile: (PathBuf, TempDir), normal_bamfile: (PathBuf, TempDir)) {
+        assert_eq!(sample_name(&normal_bamfile.0).unwrap().as_str(), "normal");
+        // No read groups, so use the file name
+        assert_eq!(sample_name(&bamfile.0).unwrap().as_str(), "test");
+    }
+
+    #[rstest]
+    fn check_mismatched_contigs(bamfile: (PathBuf, TempDir), read_filter: DefaultReadFilter) {
+        let tempdir = tempdir().unwrap();
+        let swapped = tempdir.path().join("swapped.bam");
+        write_bam(&swapped, &[("chr2", 100), ("chr1", 100)], &[], &[]);
+
+        let reads = vec![bamfile.0.clone(), swapped];
+        let base_processor = BaseProcessor::new(reads.clone(), None, 1, read_filter, 1)
+            .unwrap()
+            .with_samples(Some(vec![String::from("test"), String::from("swapped")]));
+        let par_granges_runner = par_granges::ParGranges::with_multiple_reads(
+            reads,
+            None,
+            None,
+            Some(1),
+            None,
+            base_processor,
+        );
+        assert!(par_granges_runner.process().is_err());
+    }
+
+    #[rstest]
+    fn check_wide_output(multi_sample_positions: HashMap<String, Vec<PileupPosition>>) {
+        let samples = vec![String::from("tumor"), String::from("normal")];
+        let chr1 = multi_sample_positions.get("chr1").unwrap();
+        let pos_99 = chr1.iter().position(|p| p.pos == 99).unwrap();
+
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+        write_wide(&mut writer, &samples, &chr1[pos_99..pos_99 + 2], true).unwrap();
+        let output = std::string::String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            "REF\tPOS\ttumor_DEPTH\ttumor_A\ttumor_C\ttumor_G\ttumor_T\ttumor_N\ttumor_INS\ttumor_DEL\ttumor_REF_SKIP\ttumor_FAIL\tnormal_DEPTH\tnormal_A\tnormal_C\tnormal_G\tnormal_T\tnormal_N\tnormal_INS\tnormal_DEL\tnormal_REF_SKIP\tnormal_FAIL"
+        );
+        assert_eq!(
+            lines[1],
+            "chr1\t99\t0\t0\t0\t0\t0\t0\t0\t0\t0\t0\t1\t0\t1\t0\t0\t0\t0\t0\t0\t0"
+        );
+    }
+
+    #[rstest]
+    fn check_wide_columns_match_long() {
+        let samples = vec![String::from("tumor"), String::from("normal")];
+        let columns = OptionalColumns {
+            strand: true,
+            mean_quals: true,
+            fail_reasons: true,
+        };
+        let group: Vec<PileupPosition> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let mut pos = PileupPosition::with_columns(String::from("chr1"), 5, columns);
+                pos.ref_base = Some('A');
+                pos.raw_depth = Some(i + 2);
+                pos.low_qual = Some(0);
+                pos.depth = i + 1;
+                pos.labels_mut().sample = Some(sample.clone());
+                pos
+            })
+            .collect();
+
+        let mut wide = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+        write_wide(&mut wide, &samples, &group, true).unwrap();
+        let wide = wide.into_inner().unwrap();
+        let mut wide = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(wide.as_slice());
+        let wide_header = wide.headers().unwrap().clone();
+        let wide_record = wide.records().next().unwrap().unwrap();
+        let wide: HashMap<&str, &str> = wide_header.iter().zip(wide_record.iter()).collect();
+
+        // Every long column is in the wide output, once per sample unless it identifies the position
+        let mut expected_columns = 0;
+        for (sample, pos) in samples.iter().zip(&group) {
+            let (header, record) = long_row(pos).unwrap();
+            for (column, value) in header.iter().zip(record.iter()) {
+                if POSITION_COLUMNS.contains(&column) {
+                    assert_eq!(wide[column], value);
+                } else if column != "SAMPLE" {
+                    assert_eq!(wide[format!("{}_{}", sample, column).as_str()], value);
+                    expected_columns += 1;
+                }
+            }
+        }
+        assert!(wide.contains_key("tumor_RAW_DEPTH"));
+        assert!(wide.contains_key("tumor_LOW_QUAL"));
+        assert!(wide.contains_key("normal_FAIL_OTHER"));
+        assert_eq!(wide.len(), expected_columns + 3);
+    }
+
+    #[fixture]
+    fn read_group_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("read_groups.bam");
+        write_bam(
+            &path,
+            &[("chr1", 100)],
+            &[("rg1", "alice"), ("rg2", "bob"), ("rg3", "alice")],
+            &[
+                b"R1\t0\tchr1\t1\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tRG:Z:rg1",
+                b"R2\t0\tchr1\t5\t40\t10M\t*\t0\t0\tCCCCCCCCCC\t##########\tRG:Z:rg2",
+                b"R3\t0\tchr1\t5\t40\t10M\t*\t0\t0\tGGGGGGGGGG\t##########",
+                b"R4\t16\tchr1\t5\t40\t10M\t*\t0\t0\tTTTTTTTTTT\t##########\tRG:Z:rg3",
+            ],
+        );
+        (path, tempdir)
+    }
+
+    fn read_group_positions(
+        bamfile: &std::path::Path,
+        read_filter: DefaultReadFilter,
+        read_group_sample: bool,
+    ) -> Vec<PileupPosition> {
+        let base_processor =
+            BaseProcessor::new(vec![bamfile.to_path_buf()], None, 1, read_filter, 1)
+                .unwrap()
+                .with_split_by_read_group(true, read_group_sample);
+        let par_granges_runner = par_granges::ParGranges::new(
+            bamfile.to_path_buf(),
+            None,
+            None,
+            Some(1),
+            None,
+            base_processor,
+        );
+        par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[rstest]
+    fn check_split_by_read_group(
+        read_group_bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+    ) {
+        let positions = read_group_positions(&read_group_bamfile.0, read_filter, false);
+        let at = |pos: usize| -> Vec<&PileupPosition> {
+            positions.iter().filter(|p| p.pos == pos).collect()
+        };
+
+        // Only rg1 covers pos 2
+        let pos_2 = at(2);
+        assert_eq!(pos_2.len(), 1);
+        assert_eq!(pos_2[0].labels().read_group, Some(String::from("rg1")));
+        assert_eq!(pos_2[0].depth, 1);
+
+        // All groups cover pos 6, in header order with unknown last
+        let pos_6 = at(6);
+        let groups: Vec<&str> = pos_6
+            .iter()
+            .map(|p| p.labels().read_group.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(groups, vec!["rg1", "rg2", "rg3", "UNKNOWN"]);
+        assert_eq!((pos_6[0].depth, pos_6[0].a), (1, 1));
+        assert_eq!((pos_6[1].depth, pos_6[1].c), (1, 1));
+        assert_eq!((pos_6[2].depth, pos_6[2].t), (1, 1));
+        assert_eq!((pos_6[3].depth, pos_6[3].g), (1, 1));
+    }
+
+    #[rstest]
+    fn check_split_by_read_group_sample(
+        read_group_bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+    ) {
+        let positions = read_group_positions(&read_group_bamfile.0, read_filter, true);
+        let pos_6: Vec<&PileupPosition> = positions.iter().filter(|p| p.pos == 6).collect();
+        let groups: Vec<&str> = pos_6
+            .iter()
+            .map(|p| p.labels().read_group.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(groups, vec!["alice", "bob", "UNKNOWN"]);
+        // rg1 and rg3 are both alice
+        assert_eq!((pos_6[0].depth, pos_6[0].a, pos_6[0].t), (2, 1, 1));
+        assert_eq!((pos_6[1].depth, pos_6[1].c), (1, 1));
+    }
+
+    #[fixture]
+    fn barcode_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("barcodes.bam");
+        write_bam(
+            &path,
+            &[("chr1", 100)],
+            &[],
+            &[
+                b"R1\t0\tchr1\t1\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tCB:Z:TTTT-1",
+                b"R2\t0\tchr1\t5\t40\t10M\t*\t0\t0\tCCCCCCCCCC\t##########\tCB:Z:AAAA-1",
+                b"R3\t0\tchr1\t5\t40\t10M\t*\t0\t0\tGGGGGGGGGG\t##########",
+                b"R4\t16\tchr1\t5\t40\t10M\t*\t0\t0\tTTTTTTTTTT\t##########\tCB:Z:TTTT-1",
+                b"R5\t0\tchr1\t5\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tCB:Z:GGGG-1",
+            ],
+        );
+        (path, tempdir)
+    }
+
+    fn barcode_positions(
+        bamfile: &std::path::Path,
+        read_filter: DefaultReadFilter,
+    ) -> Vec<PileupPosition> {
+        let base_processor =
+            BaseProcessor::new(vec![bamfile.to_path_buf()], None, 1, read_filter, 1)
+                .unwrap()
+                .with_split_by_tag(Some(*b"CB"));
+        let par_granges_runner = par_granges::ParGranges::new(
+            bamfile.to_path_buf(),
+            None,
+            None,
+            Some(1),
+            None,
+            base_processor,
+        );
+        par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[rstest]
+    fn check_split_by_tag(barcode_bamfile: (PathBuf, TempDir), read_filter: DefaultReadFilter) {
+        let positions = barcode_positions(&barcode_bamfile.0, read_filter);
+        let at = |pos: usize| -> Vec<&PileupPosition> {
+            positions.iter().filter(|p| p.pos == pos).collect()
+        };
+
+        // Only the barcodes with reads at a position get a row
+        let pos_2 = at(2);
+        assert_eq!(pos_2.len(), 1);
+        assert_eq!(pos_2[0].labels().tag_value, Some(String::from("TTTT-1")));
+        assert_eq!(pos_2[0].depth, 1);
+
+        // Sorted by barcode, with untagged reads last
+        let pos_6 = at(6);
+        let barcodes: Vec<&str> = pos_6
+            .iter()
+            .map(|p| p.labels().tag_value.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(barcodes, vec!["AAAA-1", "GGGG-1", "TTTT-1", "UNKNOWN"]);
+        assert_eq!((pos_6[0].depth, pos_6[0].c), (1, 1));
+        assert_eq!((pos_6[1].depth, pos_6[1].a), (1, 1));
+        assert_eq!((pos_6[2].depth, pos_6[2].a, pos_6[2].t), (2, 1, 1));
+        assert_eq!((pos_6[3].depth, pos_6[3].g), (1, 1));
+        assert!(positions.iter().all(|p| p.labels().read_group.is_none()));
+    }
+
+    #[rstest]
+    fn check_split_by_tag_whitelist(barcode_bamfile: (PathBuf, TempDir)) {
+        let whitelist = ReadList::new(Some(*b"CB"), vec![b"TTTT-1".to_vec(), b"GGGG-1".to_vec()]);
+        let read_filter = DefaultReadFilter::new(0, 512, 0).with_read_list(Some(whitelist));
+        let positions = barcode_positions(&barcode_bamfile.0, read_filter);
+        let pos_6: Vec<&PileupPosition> = positions.iter().filter(|p| p.pos == 6).collect();
+        let counts: Vec<(&str, usize, usize)> = pos_6
+            .iter()
+            .map(|p| {
+                (
+                    p.labels().tag_value.as_ref().unwrap().as_str(),
+                    p.depth,
+                    p.fail,
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("AAAA-1", 0, 1),
+                ("GGGG-1", 1, 0),
+                ("TTTT-1", 2, 0),
+                ("UNKNOWN", 0, 1)
+            ]
+        );
+    }
+
+    #[fixture]
+    fn umi_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("umis.bam");
+        write_bam(
+            &path,
+            &[("chr1", 100)],
+            &[],
+            &[
+                // U1 and its PCR duplicate D1, with their mates
+                b"U1\t99\tchr1\t1\t40\t10M\t=\t21\t30\tAAAAAAAAAA\t##########\tRX:Z:AAAA",
+                b"D1\t99\tchr1\t1\t40\t10M\t=\t21\t30\tAAAAAAAAAA\t##########\tRX:Z:AAAA",
+                // The same start with another UMI
+                b"U2\t99\tchr1\t1\t40\t10M\t=\t31\t40\tCCCCCCCCCC\t##########\tRX:Z:CCCC",
+                // The same UMI on the other strand
+                b"U3\t16\tchr1\t1\t40\t10M\t*\t0\t0\tGGGGGGGGGG\t##########\tRX:Z:AAAA",
+                // No UMI
+                b"U4\t0\tchr1\t1\t40\t10M\t*\t0\t0\tTTTTTTTTTT\t##########",
+                b"U1\t147\tchr1\t21\t40\t10M\t=\t1\t-30\tAAAAAAAAAA\t##########\tRX:Z:AAAA",
+                b"D1\t147\tchr1\t21\t40\t10M\t=\t1\t-30\tAAAAAAAAAA\t##########\tRX:Z:AAAA",
+                b"U2\t147\tchr1\t31\t40\t10M\t=\t1\t-40\tCCCCCCCCCC\t##########\tRX:Z:CCCC",
+                // Overlapping mates of one molecule
+                b"U5\t99\tchr1\t41\t40\t10M\t=\t46\t15\tAAAAAAAAAA\t##########\tRX:Z:GGGG",
+                b"U5\t147\tchr1\t46\t40\t10M\t=\t41\t-15\tAAAAAAAAAA\t##########\tRX:Z:GGGG",
+            ],
+        );
+        (path, tempdir)
+    }
+
+    #[rstest]
+    fn check_umi_collapse(umi_bamfile: (PathBuf, TempDir), read_filter: DefaultReadFilter) {
+        let base_processor =
+            BaseProcessor::new(vec![umi_bamfile.0.clone()], None, 1, read_filter, 1)
+                .unwrap()
+                .with_umi_tag(Some(*b"RX"));
+        let positions: Vec<PileupPosition> =
+            par_granges::ParGranges::new(umi_bamfile.0, None, None, Some(1), None, base_processor)
+                .process()
+                .unwrap()
+                .into_iter()
+                .collect::<Result<_>>()
+                .unwrap();
+        let at = |pos: usize| positions.iter().find(|p| p.pos == pos).unwrap();
+
+        // U1 and D1 are one molecule, U2, U3, and U4 are each their own
+        assert_eq!((at(1).depth, at(1).raw_depth), (4, Some(5)));
+        assert_eq!((at(1).a, at(1).c, at(1).g, at(1).t), (1, 1, 1, 1));
+        // The mates of U1 and D1 are still one molecule
+        assert_eq!((at(21).depth, at(21).raw_depth), (1, Some(2)));
+        assert_eq!((at(31).depth, at(31).raw_depth), (1, Some(1)));
+        // Overlapping mates are one molecule
+        assert_eq!((at(45).depth, at(45).raw_depth), (1, Some(1)));
+        assert_eq!((at(46).depth, at(46).raw_depth), (1, Some(2)));
+    }
+
+    /// Run base-depth over a sites file, returning the sites and their rows in output order.
+    fn sites_positions(
+        reads: Vec<PathBuf>,
+        samples: Option<Vec<String>>,
+        sites: &str,
+    ) -> Vec<(perbase_lib::sites::Site, Vec<PileupPosition>)> {
+        let tempdir = tempdir().unwrap();
+        let sites_file = tempdir.path().join("sites.tsv");
+        std::fs::write(&sites_file, sites).unwrap();
+        let base_processor =
+            BaseProcessor::new(reads.clone(), None, 1, DefaultReadFilter::new(0, 512, 0), 1)
+                .unwrap()
+                .with_samples(samples);
+        par_granges::ParGranges::with_multiple_reads(
+            reads,
+            None,
+            None,
+            Some(1),
+            // Small enough to need several batches
+            Some(2),
+            base_processor,
+        )
+        .process_sites(sites_file)
+        .unwrap()
+        .into_iter()
+        .collect::<Result<_>>()
+        .unwrap()
+    }
+
+    #[rstest]
+    fn check_sites(
+        bamfile: (PathBuf, TempDir),
+        normal_bamfile: (PathBuf, TempDir),
+        non_mate_aware_positions: HashMap<String, Vec<PileupPosition>>,
+    ) {
+        let sites = "chrom\tpos\tid\nchr2\t10\ts1\nchr1\t1\ts2\nchr1\t99\nchr2\t10\ts3\nchr1\t3\n";
+
+        let positions = sites_positions(vec![bamfile.0.clone()], None, sites);
+        let found: Vec<(&str, u64, Option<&str>)> = positions
+            .iter()
+            .map(|(site, _)| (site.ref_seq.as_str(), site.pos, site.id.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("chr2", 9, Some("s1")),
+                ("chr1", 0, Some("s2")),
+                ("chr1", 98, None),
+                ("chr2", 9, Some("s3")),
+                ("chr1", 2, None),
+            ]
+        );
+        // Each site has exactly the row of a full run at that position
+        for (site, rows) in positions.iter() {
+            assert_eq!(rows.len(), 1);
+            let row = &rows[0];
+            assert_eq!(row.pos as u64, site.pos + 1);
+            match non_mate_aware_positions[&site.ref_seq]
+                .iter()
+                .find(|p| p.pos == row.pos)
+            {
+                Some(expected) => {
+                    assert_eq!(
+                        (row.depth, row.a, row.t),
+                        (expected.depth, expected.a, expected.t)
+                    )
+                }
+                None => assert_eq!(row.depth, 0),
+            }
+        }
+        // chr1:99 has no coverage
+        assert_eq!(positions[2].1[0].depth, 0);
+        assert_eq!(positions[2].1[0].ref_seq.as_str(), "chr1");
+
+        // With several samples every site has a row per sample
+        let positions = sites_positions(
+            vec![bamfile.0.clone(), normal_bamfile.0.clone()],
+            Some(vec![String::from("tumor"), String::from("normal")]),
+            sites,
+        );
+        assert_eq!(positions.len(), 5);
+        for (_, rows) in positions.iter() {
+            let samples: Vec<&str> = rows
+                .iter()
+                .map(|r| r.labels().sample.as_deref().unwrap())
+                .collect();
+            assert_eq!(samples, vec!["tumor", "normal"]);
+        }
+        // Only the normal covers chr1:99
+        assert_eq!((positions[2].1[0].depth, positions[2].1[1].depth), (0, 1));
+    }
+
+    #[rstest]
+    fn check_sites_past_contig_end(bamfile: (PathBuf, TempDir)) {
+        let tempdir = tempdir().unwrap();
+        let sites_file = tempdir.path().join("sites.tsv");
+        // chr1 is 100bp long
+        std::fs::write(&sites_file, "chr1\t100\nchr1\t101\n").unwrap();
+        let base_processor = BaseProcessor::new(
+            vec![bamfile.0.clone()],
+            None,
+            1,
+            DefaultReadFilter::new(0, 512, 0),
+            1,
+        )
+        .unwrap();
+        let err =
+            par_granges::ParGranges::new(bamfile.0, None, None, Some(1), None, base_processor)
+                .process_sites(sites_file)
+                .unwrap()
+                .into_iter()
+                .collect::<Result<Vec<_>>>()
+                .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("chr1:101 on line 2"), "{}", message);
+        assert!(message.contains("100bp long"), "{}", message);
+    }
+
+    #[rstest]
+    fn check_keep_zeros(
+        bamfile: (PathBuf, TempDir),
+        non_mate_aware_positions: HashMap<String, Vec<PileupPosition>>,
+    ) {
+        // A reference of all `G`s, with an index
+        let tempdir = tempdir().unwrap();
+        let ref_fasta = tempdir.path().join("ref.fa");
+        let seq = "G".repeat(100);
+        std::fs::write(&ref_fasta, format!(">chr1\n{}\n>chr2\n{}\n", seq, seq)).unwrap();
+        std::fs::write(
+            tempdir.path().join("ref.fa.fai"),
+            "chr1\t100\t6\t100\t101\nchr2\t100\t113\t100\t101\n",
+        )
+        .unwrap();
+
+        let base_processor = BaseProcessor::new(
+            vec![bamfile.0.clone()],
+            Some(ref_fasta),
+            1,
+            DefaultReadFilter::new(0, 512, 0),
+            1,
+        )
+        .unwrap()
+        .with_keep_zeros(true);
+        // Chunks that end part way through the coverage
+        let par_granges_runner =
+            par_granges::ParGranges::new(bamfile.0, None, None, Some(1), Some(30), base_processor);
+        let positions: Vec<PileupPosition> = par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        // Every position of both contigs, in order
+        assert_eq!(positions.len(), 200);
+        for (i, pos) in positions.iter().enumerate() {
+            let ref_seq = if i < 100 { "chr1" } else { "chr2" };
+            assert_eq!(pos.ref_seq.as_str(), ref_seq);
+            assert_eq!(pos.pos, i % 100 + 1);
+            assert_eq!(pos.ref_base, Some('G'));
+            match non_mate_aware_positions[ref_seq]
+                .iter()
+                .find(|p| p.pos == pos.pos)
+            {
+                Some(expected) => assert_eq!((pos.depth, pos.a), (expected.depth, expected.a)),
+                None => assert_eq!((pos.depth, pos.fail, pos.ref_skip), (0, 0, 0)),
+            }
+        }
+        // chr1 is covered up to 94
+        assert_eq!(positions[93].depth, 1);
+        assert_eq!(positions[94].depth, 0);
+    }
+
+    #[rstest]
+    fn check_adaptive_chunks(bamfile: (PathBuf, TempDir)) {
+        let positions = |adaptive_chunks: bool, chunksize: usize| -> Vec<std::string::String> {
+            let base_processor = BaseProcessor::new(
+                vec![bamfile.0.clone()],
+                None,
+                1,
+                DefaultReadFilter::new(0, 512, 0),
+                10,
+            )
+            .unwrap()
+            .with_mate_fix(true);
+            par_granges::ParGranges::new(
+                bamfile.0.clone(),
+                None,
+                None,
+                Some(1),
+                Some(chunksize),
+                base_processor,
+            )
+            .with_adaptive_chunks(adaptive_chunks)
+            .process()
+            .unwrap()
+            .into_iter()
+            .map(|p| format!("{:?}", p.unwrap()))
+            .collect()
+        };
+        // Splitting regions by read density doesn't change the counts
+        let expected = positions(false, 1_000_000);
+        assert!(!expected.is_empty());
+        for chunksize in [7, 1_000_000] {
+            assert_eq!(positions(true, chunksize), expected);
+        }
+    }
+
+    /// The depth at each base of the test contig from running base-depth with mate fix on.
+    fn mate_fix_depths(bam: &Path, chunksize: usize, cpus: usize) -> Vec<usize> {
+        let base_processor = BaseProcessor::new(
+            vec![bam.to_path_buf()],
+            None,
+            0,
+            DefaultReadFilter::new(0, 0, 0),
+            cpus,
+        )
+        .unwrap()
+        .with_mate_fix(true);
+        let runner = par_granges::ParGranges::new(
+            bam.to_path_buf(),
+            None,
+            None,
+            Some(cpus),
+            Some(chunksize),
+            base_processor,
+        );
+        let mut depths = vec![0; CONTIG_LEN as usize];
+        for position in runner.process().unwrap() {
+            let position = position.unwrap();
+            depths[position.pos] = position.depth;
+        }
+        depths
+    }
+
+    proptest! {
+        #[test]
+        // Mate fix depths are the same whatever the chunksize and threads. Since the mate kept at a
+        // position may have a REF_SKIP there, they can be lower than counting each pair once.
+        fn mate_fix_is_chunk_invariant(pairs in arb_pairs(40), chunksize in 1usize..2_500, cpus in 1..=num_cpus::get()) {
+            let tempdir = tempdir().unwrap();
+            let bam = tempdir.path().join("pairs.bam");
+            write_pairs_bam(&bam, &pairs);
+
+            let single = mate_fix_depths(&bam, CONTIG_LEN as usize, 1);
+            let max_depths = expected_depths(&pairs, false);
+            prop_assert!(single.iter().zip(max_depths.iter()).all(|(depth, max)| depth <= max));
+            let chunked = mate_fix_depths(&bam, chunksize, cpus);
+            prop_assert_eq!(&chunked, &single);
+        }
+    }
 }
@@ -52,7 +52,7 @@ impl MergeAdjacent {
             };
             let mut curr_depth = first.depth;
 
-            while let Some(bedlike) = iter.next() {
+            for bedlike in iter {
                 if bedlike.depth == curr_depth
                     && curr_end >= bedlike.pos
                     && bedlike.ref_seq == curr_seq
@@ -112,7 +112,7 @@ impl MergeAdjacent {
     fn get_writer(&self) -> Result<csv::Writer<Box<dyn Write>>> {
         let raw_writer: Box<dyn Write> = match &self.output {
             Some(path) if path.to_str().unwrap() != "-" => {
-                Box::new(BufWriter::new(File::open(path)?))
+                Box::new(BufWriter::new(File::create(path)?))
             }
             _ => Box::new(stdout(ColorChoice::Never)),
         };
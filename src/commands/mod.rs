@@ -1,3 +1,7 @@
 pub mod base_depth;
 pub mod merge_adjacent;
 pub mod only_depth;
+pub mod region_stats;
+#[cfg(test)]
+mod test_pairs;
+pub mod vcf_counts;
@@ -3,26 +3,38 @@
 //! Calculates the depth only at each position. This uses the same algorithm described in
 //! the [`mosdepth`](https://academic.oup.com/bioinformatics/article/doi/10.1093/bioinformatics/btx699/4583630?guestAccessKey=35b55064-4566-4ab3-a769-32916fa1c6e6)
 //! paper.
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use csv;
 use grep_cli::stdout;
 use log::*;
 use perbase_lib::{
+    bgzf::{build_tabix_index, BgzfWriter, Output, TabixColumns},
+    bigwig::BigWigWriter,
+    filter_expr::ExprReadFilter,
     par_granges::{self, RegionProcessor},
     position::{range_positions::RangePositions, Position},
     read_filter::{DefaultReadFilter, ReadFilter},
+    read_groups::ReadGroups,
+    regions::MissingContig,
     utils,
 };
-use rust_htslib::{bam, bam::ext::BamRecordExtensions, bam::record::Cigar, bam::Read};
+use rust_htslib::{
+    bam,
+    bam::ext::BamRecordExtensions,
+    bam::record::{Cigar, CigarString},
+    bam::Read,
+};
 use rust_lapper::{Interval, Lapper};
+use serde::Serialize;
 use smartstring::alias::String;
-use std::convert::TryFrom;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::File,
     io::{BufWriter, Write},
-    path::PathBuf,
+    marker::PhantomData,
+    path::{Path, PathBuf},
 };
+use std::{convert::TryFrom, str::FromStr};
 use structopt::StructOpt;
 use termcolor::ColorChoice;
 
@@ -41,6 +53,12 @@ pub struct OnlyDepth {
     #[structopt(long, short = "b")]
     bed_file: Option<PathBuf>,
 
+    /// What to do with regions on contigs that are not in the BAM/CRAM header: fail, listing all of them (error), skip
+    /// them with a warning listing all of them (warn), or skip them silently (skip). Contig names like `chr1` and `1`, or
+    /// `chrM` and `MT`, are matched to each other before a contig is considered missing.
+    #[structopt(long, default_value = "error", possible_values = &["error", "warn", "skip"])]
+    missing_contig: MissingContig,
+
     /// Output path, defaults to stdout.
     #[structopt(long, short = "o")]
     output: Option<PathBuf>,
@@ -49,10 +67,22 @@ pub struct OnlyDepth {
     #[structopt(long, short = "t", default_value = utils::NUM_CPU.as_str())]
     threads: usize,
 
-    /// The ideal number of basepairs each worker receives. Total bp in memory at one time is (threads - 2) * chunksize.
+    /// The ideal number of basepairs each worker receives. Each worker holds the results of its region in memory on top
+    /// of --buffer-mb.
     #[structopt(long, short = "c")]
     chunksize: Option<usize>, // default set by par_granges at 1_000_000
 
+    /// Approximate memory, in MB, used to hold results that are waiting to be written. Lower it to cap memory use on
+    /// deeply covered regions.
+    #[structopt(long, default_value = "256")]
+    buffer_mb: usize,
+
+    /// Size regions by the read density estimated from the BAI index, splitting dense regions into smaller pieces so
+    /// that each worker gets a similar amount of work. Regions are never longer than the chunksize. Without a BAI index,
+    /// regions are split by chunksize only.
+    #[structopt(long)]
+    adaptive_chunks: bool,
+
     /// SAM flags to include.
     #[structopt(long, short = "f", default_value = "0")]
     include_flags: u16,
@@ -69,6 +99,17 @@ pub struct OnlyDepth {
     #[structopt(long, short = "x")]
     fast_mode: bool,
 
+    /// Calculate depth over whole fragments of properly paired reads, from the start of the leftmost read to the end
+    /// of its mate, counting each fragment once. Reads that aren't properly paired, and secondary and supplementary
+    /// alignments, are skipped.
+    #[structopt(long, conflicts_with_all = &["fast-mode", "mate-fix"])]
+    fragment_mode: bool,
+
+    /// With --fragment-mode, the longest fragment to count. Reads up to this far before each region are read, so that
+    /// fragments that span a region without either read being in it are still counted.
+    #[structopt(long, default_value = "1000")]
+    max_fragment_length: u64,
+
     /// Skip merging adjacent bases that have the same depth.
     #[structopt(long, short = "n")]
     no_merge: bool,
@@ -77,9 +118,95 @@ pub struct OnlyDepth {
     #[structopt(long, short = "q", default_value = "0")]
     min_mapq: u8,
 
+    /// Only count reads that pass a filter expression, such as `mapq >= 20 && !duplicate && tag(NM) <= 4`, see docs
+    /// for the full language. Applied on top of the flag and MAPQ filters.
+    #[structopt(long)]
+    filter: Option<ExprReadFilter>,
+
     /// Output positions as 0-based instead of 1-based.
     #[structopt(long, short = "z")]
     zero_base: bool,
+
+    /// Write the per-base output of mosdepth: headerless `chrom start end depth` lines with 0-based half-open
+    /// coordinates, where adjacent ranges with the same depth are merged even across chunk boundaries.
+    #[structopt(long, conflicts_with_all = &["no-merge", "split-by-read-group"])]
+    mosdepth: bool,
+
+    /// Write the quantized output of mosdepth instead of depths: adjacent bases whose depths fall in the same bin are
+    /// merged into one `chrom start end label` line. Bins are given as `<lower>:<upper>:...`, e.g. `0:1:5:150:`, each
+    /// including its lower bound and excluding its upper bound. An empty first bound means 0 and an empty last bound
+    /// means no upper limit. Bases whose depth is in no bin are left out.
+    #[structopt(long, conflicts_with_all = &["no-merge", "split-by-read-group"])]
+    quantize: Option<QuantizeBins>,
+
+    /// Comma separated labels for the quantize bins, one per bin. Defaults to `<lower>:<upper>`, as mosdepth does.
+    #[structopt(long, requires = "quantize", use_delimiter = true)]
+    quantize_labels: Option<Vec<std::string::String>>,
+
+    /// Write a bedGraph track: a `track type=bedGraph` line followed by `chrom start end depth` lines with 0-based
+    /// half-open coordinates, where adjacent ranges with the same depth are merged even across chunk boundaries.
+    #[structopt(
+        long,
+        conflicts_with_all = &["no-merge", "split-by-read-group", "mosdepth", "quantize"]
+    )]
+    bedgraph: bool,
+
+    /// Write a bigWig file of the depths to the output path, with the same ranges as --bedgraph. The contigs and
+    /// their lengths are taken from the BAM/CRAM header.
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &["no-merge", "split-by-read-group", "mosdepth", "quantize", "bedgraph", "bgzip"]
+    )]
+    bigwig: bool,
+
+    /// Scale the depths written by --bedgraph or --bigwig: by one million over the number of mapped reads (cpm), or
+    /// by one billion over the number of mapped reads, giving reads per kilobase per million mapped reads at each base
+    /// (rpkm). Mapped reads are counted from the BAM index, so this needs a BAM input, and are not filtered.
+    #[structopt(long, default_value = "none", possible_values = &["none", "cpm", "rpkm"])]
+    normalize: Normalize,
+
+    /// Report depth over fixed-width windows of this many bases instead of ranges of positions: the number of bases
+    /// counted (BASES), the number with a depth above zero (COVERED), and the MEAN and MEDIAN depth. Windows start at
+    /// the beginning of each contig. With --bed-file only bases in the regions are counted, and windows without any are
+    /// left out.
+    #[structopt(
+        long,
+        conflicts_with_all = &["no-merge", "histogram", "mosdepth", "quantize", "bedgraph", "bigwig"]
+    )]
+    window: Option<u64>,
+
+    /// Also write the number of bases at each depth, per contig and in total, to <output>.hist.tsv.
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &["no-merge", "split-by-read-group"]
+    )]
+    histogram: bool,
+
+    /// Split the depths by the read group of the reads, adding a READ_GROUP column.
+    #[structopt(long, short = "G")]
+    split_by_read_group: bool,
+
+    /// When splitting by read group, group read groups by the SM tag of their @RG header line instead of their ID.
+    #[structopt(long, requires = "split-by-read-group")]
+    read_group_sample: bool,
+
+    /// Write BGZF compressed output. If the output is a file, a tabix index is built alongside it.
+    #[structopt(long, short = "Z")]
+    bgzip: bool,
+
+    /// The BGZF compression level, 0-9, defaults to 6.
+    #[structopt(long, requires = "bgzip")]
+    compression_level: Option<u32>,
+
+    /// The number of threads to use for BGZF compression and indexing, defaults to 1.
+    #[structopt(long, requires = "bgzip")]
+    compression_threads: Option<usize>,
+
+    /// Build a CSI index instead of a TBI index, needed for contigs longer than 2^29 bases.
+    #[structopt(long, requires = "bgzip")]
+    csi: bool,
 }
 
 impl OnlyDepth {
@@ -87,69 +214,346 @@ impl OnlyDepth {
         info!("Running only-depth on: {:?}", self.reads);
         let cpus = utils::determine_allowed_cpus(self.threads)?;
 
-        let mut writer = self.get_writer()?;
-
         let read_filter =
-            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq);
+            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq)
+                .with_expression(self.filter.clone());
         let processor = OnlyDepthProcessor::new(
             self.reads.clone(),
             self.ref_fasta.clone(),
-            self.mate_fix,
-            self.fast_mode,
-            self.no_merge,
-            if self.zero_base { 0 } else { 1 },
+            if self.zero_base || self.bed_layout() {
+                0
+            } else {
+                1
+            },
             read_filter,
-        );
+        )
+        .with_mate_fix(self.mate_fix)
+        .with_fast_mode(self.fast_mode)
+        .with_no_merge(self.no_merge)
+        .with_split_by_read_group(self.split_by_read_group, self.read_group_sample);
+        let processor = if self.fragment_mode {
+            processor.with_fragment_mode(self.max_fragment_length)
+        } else {
+            processor
+        };
+        if let Some(window) = self.window {
+            if window == 0 {
+                return Err(anyhow!("--window must be at least 1"));
+            }
+            return self.run_windows(processor.with_windows(window), cpus);
+        }
 
-        let par_granges_runner = par_granges::ParGranges::new(
+        let labels = self.quantize_labels()?;
+        let scale = self.scale()?;
+
+        let mut output = if self.bigwig {
+            let path = self
+                .output_file()
+                .context("--bigwig needs an output file, not stdout")?;
+            DepthOutput::BigWig(BigWigWriter::from_path(path, self.contigs()?)?)
+        } else {
+            DepthOutput::Text(self.get_writer()?)
+        };
+
+        let receiver = self.par_granges(processor, cpus).process()?;
+
+        let mut histogram = if self.histogram {
+            Some(DepthHistogram::new())
+        } else {
+            None
+        };
+        let mut merger = if self.bed_layout() {
+            Some(RangeMerger::default())
+        } else {
+            None
+        };
+        for pos in receiver.into_iter() {
+            let pos = pos?;
+            if let Some(histogram) = histogram.as_mut() {
+                histogram.add(&pos);
+            }
+            if let Some(merger) = merger.as_mut() {
+                let value = match &self.quantize {
+                    Some(bins) => bins.bin(pos.depth),
+                    None => Some(pos.depth),
+                };
+                if let Some(range) = merger.push(&pos, value) {
+                    Self::write_range(&mut output, range, labels.as_deref(), scale)?;
+                }
+            } else if let DepthOutput::Text(writer) = &mut output {
+                writer.serialize(pos)?;
+            }
+        }
+        if let Some(range) = merger.and_then(RangeMerger::finish) {
+            Self::write_range(&mut output, range, labels.as_deref(), scale)?;
+        }
+        match output {
+            DepthOutput::Text(writer) => writer
+                .into_inner()
+                .map_err(|err| anyhow!("Failed to flush output: {}", err.error()))?
+                .finish()?,
+            DepthOutput::BigWig(writer) => writer.finish()?,
+        }
+
+        self.index_output(self.bed_layout() && !self.bedgraph)?;
+
+        if let Some(histogram) = histogram {
+            let mut path = self.output.clone().unwrap().into_os_string();
+            path.push(".hist.tsv");
+            info!("Writing depth histogram to {:?}", path);
+            let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+            for row in histogram.rows() {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Report the depth over windows, merging the parts of windows that were split between regions
+    fn run_windows(
+        &self,
+        processor: OnlyDepthProcessor<DefaultReadFilter, WindowDepth>,
+        cpus: usize,
+    ) -> Result<()> {
+        let mut writer = self.get_writer()?;
+        let receiver = self.par_granges(processor, cpus).process()?;
+
+        // The parts of the current window, one per read group
+        let mut pending: Vec<WindowDepth> = vec![];
+        for window in receiver.into_iter() {
+            let window = window?;
+            if pending.first().is_some_and(|w| !w.same_window(&window)) {
+                for mut done in pending.drain(..) {
+                    done.finish();
+                    writer.serialize(done)?;
+                }
+            }
+            match pending
+                .iter_mut()
+                .find(|w| w.read_group == window.read_group)
+            {
+                Some(part) => part.merge(window),
+                None => pending.push(window),
+            }
+        }
+        for mut done in pending.drain(..) {
+            done.finish();
+            writer.serialize(done)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| anyhow!("Failed to flush output: {}", err.error()))?
+            .finish()?;
+        self.index_output(false)
+    }
+
+    /// Build the tabix index for BGZF compressed output written to a file
+    fn index_output(&self, headerless: bool) -> Result<()> {
+        if let Some(path) = self.output_file() {
+            if self.bgzip {
+                info!("Building index for {:?}", path);
+                // With 1-based output END is still exclusive, so tabix treats each range as one
+                // base longer than it is. Queries may return one extra range, but never miss one.
+                let columns = TabixColumns {
+                    seq: 1,
+                    begin: 2,
+                    end: Some(3),
+                    zero_based: self.zero_base || self.bed_layout(),
+                    skip_lines: if headerless { 0 } else { 1 },
+                };
+                build_tabix_index(
+                    path,
+                    columns,
+                    self.csi,
+                    self.compression_threads.unwrap_or(1),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set up the ParGranges runner for a processor
+    fn par_granges<R: RegionProcessor + Send + Sync>(
+        &self,
+        processor: R,
+        cpus: usize,
+    ) -> par_granges::ParGranges<R> {
+        par_granges::ParGranges::new(
             self.reads.clone(),
             self.ref_fasta.clone(),
             self.bed_file.clone(),
             Some(cpus),
-            self.chunksize.clone(),
+            self.chunksize,
             processor,
-        );
+        )
+        .with_missing_contig(self.missing_contig)
+        .with_buffer_size(self.buffer_mb * 1024 * 1024)
+        .with_adaptive_chunks(self.adaptive_chunks)
+    }
 
-        let receiver = par_granges_runner.process()?;
+    /// True if writing 0-based BED style ranges, merged across chunk boundaries
+    fn bed_layout(&self) -> bool {
+        self.mosdepth || self.quantize.is_some() || self.bedgraph || self.bigwig
+    }
 
-        receiver
-            .into_iter()
-            .for_each(|pos| writer.serialize(pos).unwrap());
-        writer.flush()?;
-        Ok(())
+    /// The factor to scale depths by, or None if not normalizing
+    fn scale(&self) -> Result<Option<f64>> {
+        let factor = match self.normalize {
+            Normalize::None => return Ok(None),
+            Normalize::Cpm => 1e6,
+            Normalize::Rpkm => 1e9,
+        };
+        if !(self.bedgraph || self.bigwig) {
+            return Err(anyhow!("--normalize needs --bedgraph or --bigwig"));
+        }
+        let mapped = utils::mapped_reads(&self.reads)?;
+        if mapped == 0 {
+            return Err(anyhow!(
+                "Can't normalize, {:?} has no mapped reads",
+                self.reads
+            ));
+        }
+        info!("Normalizing by {} mapped reads", mapped);
+        Ok(Some(factor / mapped as f64))
+    }
+
+    /// The name and length of each contig in the BAM/CRAM header
+    fn contigs(&self) -> Result<Vec<(std::string::String, u32)>> {
+        let reader = bam::Reader::from_path(&self.reads)
+            .with_context(|| format!("Failed to open {:?}", self.reads))?;
+        let header = reader.header();
+        (0..header.target_count())
+            .map(|tid| {
+                let name = std::str::from_utf8(header.tid2name(tid))?.to_owned();
+                let length = header.target_len(tid).context("Missing contig length")?;
+                Ok((name, u32::try_from(length)?))
+            })
+            .collect()
+    }
+
+    /// The label written for each quantize bin, or None if not quantizing
+    fn quantize_labels(&self) -> Result<Option<Vec<std::string::String>>> {
+        let bins = match &self.quantize {
+            Some(bins) => bins,
+            None => return Ok(None),
+        };
+        match &self.quantize_labels {
+            Some(labels) if labels.len() != bins.len() => Err(anyhow!(
+                "Got {} quantize labels for {} quantize bins",
+                labels.len(),
+                bins.len()
+            )),
+            Some(labels) => Ok(Some(labels.clone())),
+            None => Ok(Some(bins.labels())),
+        }
     }
 
-    /// Open a CSV Writer to a file or stdout
-    fn get_writer(&self) -> Result<csv::Writer<Box<dyn Write>>> {
-        let raw_writer: Box<dyn Write> = match &self.output {
-            Some(path) if path.to_str().unwrap() != "-" => {
-                Box::new(BufWriter::new(File::open(path)?))
+    /// Write a merged range as a BED line or bigWig record, labelled by its quantize bin if there are labels
+    /// and scaled if normalizing
+    fn write_range(
+        output: &mut DepthOutput,
+        range: MergedRange,
+        labels: Option<&[std::string::String]>,
+        scale: Option<f64>,
+    ) -> Result<()> {
+        let writer = match output {
+            DepthOutput::Text(writer) => writer,
+            DepthOutput::BigWig(writer) => {
+                let value = range.value as f64 * scale.unwrap_or(1.0);
+                return writer.push(
+                    &range.ref_seq,
+                    u32::try_from(range.start)?,
+                    u32::try_from(range.end)?,
+                    value as f32,
+                );
             }
-            _ => Box::new(stdout(ColorChoice::Never)),
         };
+        let value = match (labels, scale) {
+            (Some(labels), _) => labels[range.value].clone(),
+            (None, Some(scale)) => ((range.value as f64 * scale) as f32).to_string(),
+            (None, None) => range.value.to_string(),
+        };
+        writer.write_record([
+            range.ref_seq.as_str(),
+            &range.start.to_string(),
+            &range.end.to_string(),
+            &value,
+        ])?;
+        Ok(())
+    }
+
+    /// The output path, if output is going to a file rather than stdout
+    fn output_file(&self) -> Option<&PathBuf> {
+        self.output
+            .as_ref()
+            .filter(|path| path.to_str().unwrap() != "-")
+    }
+
+    /// Open a CSV Writer to a file or stdout, BGZF compressed if requested, with the bedGraph track line written
+    fn get_writer(&self) -> Result<csv::Writer<Output>> {
+        let mut raw_writer = if self.bgzip {
+            let path = self
+                .output_file()
+                .map_or(Path::new("-"), |path| path.as_path());
+            Output::Bgzf(BufWriter::new(BgzfWriter::from_path(
+                path,
+                self.compression_level.unwrap_or(6),
+                self.compression_threads.unwrap_or(1),
+            )?))
+        } else {
+            Output::Plain(match self.output_file() {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(stdout(ColorChoice::Never)),
+            })
+        };
+        if self.bedgraph {
+            raw_writer.write_all(b"track type=bedGraph\n")?;
+        }
         Ok(csv::WriterBuilder::new()
             .delimiter(b'\t')
             .from_writer(raw_writer))
     }
 
-    /// Detect an overlap of mates
-    /// TODO: Add more tests for this
+    /// Detect a possible overlap of mates.
+    ///
+    /// This must never have a false negative, since a read that is not checked against its mate is
+    /// counted on its own. A read whose mate starts at or after it overlaps its mate exactly when the
+    /// mate starts before the read ends. A read whose mate starts before it can only tell where its
+    /// mate ends from the `MC` tag, and is otherwise always considered.
     #[inline]
     fn maybe_overlaps_mate(record: &bam::Record) -> bool {
         if !record.is_paired() || record.tid() != record.mtid() {
             return false;
         }
-        let tlen = record.insert_size().abs();
-        let read_len = record.reference_end() - record.reference_start();
-        // if the half the tlen is less than the readlen, or the mate start is less than one readlen away, concider for mate detection
-        // (is there any scenario where this is not true?)
-        // This is a heuristic, so it just have to never have a false negative and not have false positives > N% of the time
-        (tlen / 2) < read_len || (record.mpos() - record.pos()).abs() < read_len
+        let (pos, mpos, end) = (
+            record.reference_start(),
+            record.mpos(),
+            record.reference_end(),
+        );
+        if mpos >= pos {
+            return mpos < end;
+        }
+        if let Some(bam::record::Aux::String(mate_cigar)) = record.aux(b"MC") {
+            if let Ok(mate_cigar) = CigarString::try_from(mate_cigar) {
+                let mate_end = mate_cigar.iter().fold(mpos, |mate_end, op| match op {
+                    Cigar::Match(len)
+                    | Cigar::Equal(len)
+                    | Cigar::Diff(len)
+                    | Cigar::Del(len)
+                    | Cigar::RefSkip(len) => mate_end + i64::from(*len),
+                    _ => mate_end,
+                });
+                return pos < mate_end;
+            }
+        }
+        true
     }
 }
 
-/// Holds the info needed for [par_io::RegionProcessor] implementation
-struct OnlyDepthProcessor<F: ReadFilter> {
+/// Holds the info needed for [par_io::RegionProcessor] implementation. The depths of each region are
+/// reduced to rows of type `R`, ranges of positions with the same depth by default.
+pub(crate) struct OnlyDepthProcessor<F: ReadFilter, R = RangePositions> {
     /// path to indexed BAM/CRAM
     reads: PathBuf,
     /// path to indexed ref file
@@ -158,36 +562,155 @@ struct OnlyDepthProcessor<F: ReadFilter> {
     mate_fix: bool,
     /// Indicate whether or not to run in fastmode, only using read starts and stops
     fast_mode: bool,
+    /// The longest fragment to count, when counting whole fragments instead of reads
+    fragment_length: Option<u64>,
     /// Indicate whether or not to merge adjacent positions that have the same depth
     no_merge: bool,
     /// implementation of [position::ReadFilter] that will be used
     read_filter: F,
     /// 0-based or 1-based coordinate output
     coord_base: usize,
+    /// Indicate whether or not to split depths by read group
+    split_by_read_group: bool,
+    /// Indicate whether or not to group read groups by their sample when splitting by read group
+    read_group_sample: bool,
+    /// The width of the windows to report, when reporting windows
+    window: u64,
+    /// The type of row each region is reduced to
+    rows: PhantomData<R>,
 }
 
 impl<F: ReadFilter> OnlyDepthProcessor<F> {
     /// Create a new OnlyDepthProcessor
-    fn new(
+    pub(crate) fn new(
         reads: PathBuf,
         ref_fasta: Option<PathBuf>,
-        mate_fix: bool,
-        fast_mode: bool,
-        no_merge: bool,
         coord_base: usize,
         read_filter: F,
     ) -> Self {
         Self {
             reads,
             ref_fasta,
-            fast_mode,
-            mate_fix,
-            no_merge,
+            mate_fix: false,
+            fast_mode: false,
+            fragment_length: None,
+            no_merge: false,
             coord_base,
             read_filter,
+            split_by_read_group: false,
+            read_group_sample: false,
+            window: 0,
+            rows: PhantomData,
         }
     }
 
+    /// Only count one read of each pair of overlapping mates, not checked in fast mode
+    pub(crate) fn with_mate_fix(mut self, mate_fix: bool) -> Self {
+        self.mate_fix = mate_fix;
+        self
+    }
+
+    /// Only use read starts and stops, without looking at deletions or reference skips
+    pub(crate) fn with_fast_mode(mut self, fast_mode: bool) -> Self {
+        self.fast_mode = fast_mode;
+        self
+    }
+
+    /// Report every position on its own instead of merging adjacent positions with the same depth
+    pub(crate) fn with_no_merge(mut self, no_merge: bool) -> Self {
+        self.no_merge = no_merge;
+        self
+    }
+
+    /// Split depths by read group, grouping read groups by their sample if `read_group_sample` is set
+    pub(crate) fn with_split_by_read_group(
+        mut self,
+        split_by_read_group: bool,
+        read_group_sample: bool,
+    ) -> Self {
+        self.split_by_read_group = split_by_read_group;
+        self.read_group_sample = read_group_sample;
+        self
+    }
+
+    /// Count the depth over whole fragments of properly paired reads, up to `max_length` long, instead of reads
+    pub(crate) fn with_fragment_mode(mut self, max_length: u64) -> Self {
+        self.fragment_length = Some(max_length);
+        self
+    }
+
+    /// Report the depth over fixed-width windows, aligned to the start of each contig, instead of ranges
+    pub(crate) fn with_windows(self, window: u64) -> OnlyDepthProcessor<F, WindowDepth> {
+        OnlyDepthProcessor {
+            reads: self.reads,
+            ref_fasta: self.ref_fasta,
+            mate_fix: self.mate_fix,
+            fast_mode: self.fast_mode,
+            fragment_length: self.fragment_length,
+            no_merge: self.no_merge,
+            read_filter: self.read_filter,
+            coord_base: self.coord_base,
+            split_by_read_group: self.split_by_read_group,
+            read_group_sample: self.read_group_sample,
+            window,
+            rows: PhantomData,
+        }
+    }
+}
+
+impl<F: ReadFilter, R: DepthRows> OnlyDepthProcessor<F, R> {
+    /// The read groups to split counts by, if splitting by read group
+    fn read_groups(&self, header: &bam::HeaderView) -> Option<ReadGroups> {
+        if self.split_by_read_group {
+            Some(ReadGroups::from_header(header, self.read_group_sample))
+        } else {
+            None
+        }
+    }
+
+    /// Sum the counts of each read group, keeping the resulting rows sorted by position
+    fn sum_counters(
+        &self,
+        counters: Counters,
+        read_groups: Option<&ReadGroups>,
+        contig: &str,
+        contig_len: u64,
+        region_start: u64,
+        region_stop: u64,
+    ) -> Vec<R> {
+        let read_groups = match read_groups {
+            Some(read_groups) => read_groups,
+            None => {
+                let counter = counters.counters.into_iter().next().unwrap().unwrap();
+                return R::from_counter(
+                    self,
+                    counter,
+                    contig,
+                    contig_len,
+                    region_start,
+                    region_stop,
+                );
+            }
+        };
+        let mut results = vec![];
+        for (group, counter) in counters.counters.into_iter().enumerate() {
+            if let Some(counter) = counter {
+                let name = read_groups.name(group);
+                results.extend(
+                    R::from_counter(self, counter, contig, contig_len, region_start, region_stop)
+                        .into_iter()
+                        .map(|mut row| {
+                            row.set_read_group(name.clone());
+                            row
+                        }),
+                );
+            }
+        }
+        // Stable, so rows starting at the same position stay in read group order
+        results.sort_by_key(|row| row.pos());
+        results
+    }
+
     /// Sum the counts within the region to get the depths at each RangePosition
     fn sum_counter(
         &self,
@@ -245,85 +768,136 @@ impl<F: ReadFilter> OnlyDepthProcessor<F> {
         }
     }
 
-    /// Process a region, taking into account REF_SKIPs and mates
-    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<RangePositions> {
-        // Create a reader
-        let mut reader =
-            bam::IndexedReader::from_path(&self.reads).expect("Indexed Reader for region");
-
-        // If passed add ref_fasta
-        if let Some(ref_fasta) = &self.ref_fasta {
-            reader.set_reference(ref_fasta).expect("Set ref");
+    /// Sum the counts within the region into the windows it overlaps. Runs of bases with the same depth
+    /// are added to a window at once, so no per-base rows are made. Windows that are only partly in the
+    /// region only count the bases in the region, and are merged with the rest of the window by the writer.
+    fn sum_counter_windows(
+        &self,
+        counter: Vec<i32>,
+        contig: &str,
+        contig_len: u64,
+        region_start: u64,
+        region_stop: u64,
+    ) -> Vec<WindowDepth> {
+        let mut windows: Vec<WindowDepth> = vec![];
+        let mut add_run = |start: u64, stop: u64, depth: i32| {
+            let window_start = start - start % self.window;
+            if windows.last().map(|w| w.start) != Some(window_start) {
+                let window_stop = (window_start + self.window).min(contig_len);
+                windows.push(WindowDepth::new(
+                    contig,
+                    window_start,
+                    window_stop,
+                    self.coord_base,
+                ));
+            }
+            let depth = usize::try_from(depth).expect("All depths are positive");
+            windows.last_mut().unwrap().add(depth, stop - start);
+        };
+
+        let mut sum: i32 = 0;
+        let mut run_start = region_start;
+        let mut run_depth = counter[0];
+        let mut window_end = region_start - region_start % self.window + self.window;
+        for (i, count) in counter.iter().enumerate() {
+            sum += count;
+            let pos = region_start + i as u64;
+            // Runs are split at window boundaries so that each falls in a single window
+            let at_window_end = pos == window_end;
+            if at_window_end {
+                window_end += self.window;
+            }
+            if sum != run_depth || (at_window_end && pos != run_start) {
+                add_run(run_start, pos, run_depth);
+                run_start = pos;
+                run_depth = sum;
+            }
         }
+        add_run(run_start, region_stop, run_depth);
+        windows
+    }
 
+    /// Fetch a region from a reader on the BAM/CRAM
+    fn fetch(
+        &self,
+        reader: &mut bam::IndexedReader,
+        tid: u32,
+        start: u64,
+        stop: u64,
+    ) -> Result<()> {
+        reader
+            .fetch(tid, start, stop)
+            .with_context(|| format!("Failed to fetch from {:?}", self.reads))
+    }
+
+    /// Process a region, taking into account REF_SKIPs and mates
+    fn process_region(
+        &self,
+        reader: &mut bam::IndexedReader,
+        tid: u32,
+        start: u64,
+        stop: u64,
+    ) -> Result<Vec<R>> {
+        self.fetch(reader, tid, start, stop)?;
         let header = reader.header().to_owned();
-        // fetch the region of interest
-        reader.fetch(tid, start, stop).expect("Fetched a region");
 
-        let mut counter: Vec<i32> = vec![0; (stop - start) as usize];
+        let read_groups = self.read_groups(&header);
+        let mut counters = Counters::new(read_groups.as_ref(), (stop - start) as usize);
+        let group_of = |record: &bam::Record| read_groups.as_ref().map_or(0, |rg| rg.index(record));
         let mut maties = HashMap::new();
 
         // Walk over each read, counting the starts and ends
-        for record in reader
-            .records()
-            .map(|r| r.expect("Read record"))
-            .filter(|read| self.read_filter.filter_read(&read))
-            .flat_map(|record| IterAlignedBlocks::new(record, self.mate_fix))
-        {
-            let rec_start = u64::try_from(record.0).expect("check overflow");
-            let rec_stop = u64::try_from(record.1).expect("check overflow");
-
-            // NB: since we are splitting the region, it's possible the region we are looking at
-            // may occur before the ROI, or after the ROI
-            if rec_start > stop || start > rec_stop {
+        for read in reader.records() {
+            let read =
+                read.with_context(|| format!("Failed to read a record from {:?}", self.reads))?;
+            if !self.read_filter.filter_read(&read) {
                 continue;
             }
+            let group = group_of(&read);
+            for record in IterAlignedBlocks::new(read, self.mate_fix) {
+                let counter = counters.get_mut(group);
+                let rec_start = u64::try_from(record.0).expect("check overflow");
+                let rec_stop = u64::try_from(record.1).expect("check overflow");
+
+                // NB: since we are splitting the region, it's possible the region we are looking at
+                // may occur before the ROI, or after the ROI
+                if rec_start >= stop || start >= rec_stop {
+                    continue;
+                }
 
-            // rectify start / stop with region boundaries
-            // increment the start of the region
-            let adjusted_start = if rec_start < start {
-                0
-            } else {
-                (rec_start - start) as usize
-            };
-
-            let mut dont_count_stop = false; // if this interval extends past the end of the region, don't count an end for it
-            let adjusted_stop = if rec_stop >= stop {
-                dont_count_stop = true;
-                counter.len() - 1
-            } else {
-                (rec_stop - start) as usize
-            };
-
-            // check if this read has a mate that will be seen within this region
-            // that this works for both mates in pair
-            if self.mate_fix && record.2 {
-                // TODO: figure out better way of passing qname around, get rid of Lazy
-                // let qname = String::from(std::str::from_utf8(record.3).expect("Convert qname"));
-                let intervals = maties.entry(record.3).or_insert(vec![]);
-                intervals.push(Interval {
-                    start: adjusted_start,
-                    stop: adjusted_stop,
-                    val: dont_count_stop,
-                });
-            } else {
-                counter[adjusted_start] += 1;
-                if !dont_count_stop {
-                    // check if the end of interval extended past region end
-                    counter[adjusted_stop] -= 1;
+                // rectify start / stop with region boundaries, an interval that extends past the
+                // end of the region stops at the counter length and doesn't count an end
+                let adjusted_start = rec_start.saturating_sub(start) as usize;
+                let adjusted_stop = (rec_stop.min(stop) - start) as usize;
+
+                // check if this read has a mate that will be seen within this region
+                // that this works for both mates in pair
+                if self.mate_fix && record.2 {
+                    let intervals = maties.entry((group, record.3)).or_insert(vec![]);
+                    intervals.push(Interval {
+                        start: adjusted_start,
+                        stop: adjusted_stop,
+                        val: (),
+                    });
+                } else {
+                    counter[adjusted_start] += 1;
+                    if adjusted_stop < counter.len() {
+                        counter[adjusted_stop] -= 1;
+                    }
                 }
             }
         }
 
         if self.mate_fix {
             // check maties
-            for (_qname, ivs) in maties.drain() {
+            for ((group, _qname), ivs) in maties.drain() {
+                let counter = counters.get_mut(group);
                 let mut lapper = Lapper::new(ivs);
                 lapper.merge_overlaps();
                 for iv in lapper.intervals {
                     counter[iv.start] += 1;
-                    if !iv.val {
-                        // check if the end of interval extended past region end
+                    // check if the end of interval extended past region end
+                    if iv.stop < counter.len() {
                         counter[iv.stop] -= 1;
                     }
                 }
@@ -332,65 +906,64 @@ impl<F: ReadFilter> OnlyDepthProcessor<F> {
 
         // Sum the counter and merge same-depth ranges of positions
         let contig = std::str::from_utf8(header.tid2name(tid)).unwrap();
-        self.sum_counter(counter, contig, start, stop)
+        let contig_len = header.target_len(tid).unwrap();
+        Ok(self.sum_counters(
+            counters,
+            read_groups.as_ref(),
+            contig,
+            contig_len,
+            start,
+            stop,
+        ))
     }
 
-    fn process_region_fast(&self, tid: u32, start: u64, stop: u64) -> Vec<RangePositions> {
-        // Create a reader
-        let mut reader =
-            bam::IndexedReader::from_path(&self.reads).expect("Indexed Reader for region");
-
-        // If passed add ref_fasta
-        if let Some(ref_fasta) = &self.ref_fasta {
-            reader.set_reference(ref_fasta).expect("Set ref");
-        }
-
+    fn process_region_fast(
+        &self,
+        reader: &mut bam::IndexedReader,
+        tid: u32,
+        start: u64,
+        stop: u64,
+    ) -> Result<Vec<R>> {
+        self.fetch(reader, tid, start, stop)?;
         let header = reader.header().to_owned();
-        // fetch the region of interest
-        reader.fetch(tid, start, stop).expect("Fetched a region");
 
-        let mut counter: Vec<i32> = vec![0; (stop - start) as usize];
+        let read_groups = self.read_groups(&header);
+        let mut counters = Counters::new(read_groups.as_ref(), (stop - start) as usize);
+        let group_of = |record: &bam::Record| read_groups.as_ref().map_or(0, |rg| rg.index(record));
         let mut maties = HashMap::new();
 
         // Walk over each read, counting the starts and ends
-        for record in reader
-            .records()
-            .map(|r| r.expect("Read record"))
-            .filter(|read| self.read_filter.filter_read(&read))
-        {
+        for record in reader.records() {
+            let record =
+                record.with_context(|| format!("Failed to read a record from {:?}", self.reads))?;
+            if !self.read_filter.filter_read(&record) {
+                continue;
+            }
+            let group = group_of(&record);
+            let counter = counters.get_mut(group);
             let rec_start = u64::try_from(record.reference_start()).expect("check overflow");
             let rec_stop = u64::try_from(record.reference_end()).expect("check overflow");
 
-            // rectify start / stop with region boundaries
+            // rectify start / stop with region boundaries, an interval that extends past the end of
+            // the region stops at the counter length and doesn't count an end
             // NB: impossible for rec_start > start since this is from fetch and we aren't splitting bam
-            let adjusted_start = if rec_start < start {
-                0
-            } else {
-                (rec_start - start) as usize
-            };
-
-            let mut dont_count_stop = false; // set this flag if this interval extends past the end of our region
-            let adjusted_stop = if rec_stop >= stop {
-                dont_count_stop = true;
-                counter.len() - 1
-            } else {
-                (rec_stop - start) as usize
-            };
+            let adjusted_start = rec_start.saturating_sub(start) as usize;
+            let adjusted_stop = (rec_stop.min(stop) - start) as usize;
 
             // check if this read has a mate that will be seen within this region
             if self.mate_fix && OnlyDepth::maybe_overlaps_mate(&record) {
                 let qname =
                     String::from(std::str::from_utf8(record.qname()).expect("Convert qname"));
-                let intervals = maties.entry(qname).or_insert(vec![]);
+                let intervals = maties.entry((group, qname)).or_insert(vec![]);
                 intervals.push(Interval {
                     start: adjusted_start,
                     stop: adjusted_stop,
-                    val: dont_count_stop,
+                    val: (),
                 });
             } else {
                 counter[adjusted_start] += 1;
                 // Check if end of interval extended past region end
-                if !dont_count_stop {
+                if adjusted_stop < counter.len() {
                     counter[adjusted_stop] -= 1;
                 }
             }
@@ -398,13 +971,14 @@ impl<F: ReadFilter> OnlyDepthProcessor<F> {
 
         if self.mate_fix {
             // check maties
-            for (_qname, ivs) in maties.drain() {
+            for ((group, _qname), ivs) in maties.drain() {
+                let counter = counters.get_mut(group);
                 let mut lapper = Lapper::new(ivs);
                 lapper.merge_overlaps();
                 for iv in lapper.intervals {
                     counter[iv.start] += 1;
                     // Check if end of interval extended past region end
-                    if !iv.val {
+                    if iv.stop < counter.len() {
                         counter[iv.stop] -= 1;
                     }
                 }
@@ -413,26 +987,507 @@ impl<F: ReadFilter> OnlyDepthProcessor<F> {
 
         // Sum the counter and merge same-depth ranges of positions
         let contig = std::str::from_utf8(header.tid2name(tid)).unwrap();
-        self.sum_counter(counter, contig, start, stop)
+        let contig_len = header.target_len(tid).unwrap();
+        Ok(self.sum_counters(
+            counters,
+            read_groups.as_ref(),
+            contig,
+            contig_len,
+            start,
+            stop,
+        ))
+    }
+
+    /// Process a region counting each fragment of a properly paired read over its whole span. Each
+    /// fragment is only counted from its leftmost read, and reads are fetched from up to the longest
+    /// fragment length before the region so that fragments starting before the region are counted.
+    fn process_region_fragments(
+        &self,
+        reader: &mut bam::IndexedReader,
+        tid: u32,
+        start: u64,
+        stop: u64,
+        max_length: u64,
+    ) -> Result<Vec<R>> {
+        self.fetch(reader, tid, start.saturating_sub(max_length), stop)?;
+        let header = reader.header().to_owned();
+
+        let read_groups = self.read_groups(&header);
+        let mut counters = Counters::new(read_groups.as_ref(), (stop - start) as usize);
+        let group_of = |record: &bam::Record| read_groups.as_ref().map_or(0, |rg| rg.index(record));
+
+        for record in reader.records() {
+            let record =
+                record.with_context(|| format!("Failed to read a record from {:?}", self.reads))?;
+            if !record.is_proper_pair()
+                || record.is_secondary()
+                || record.is_supplementary()
+                || !self.read_filter.filter_read(&record)
+            {
+                continue;
+            }
+            // Only the leftmost read counts the fragment, or the first read if both start together
+            let leftmost = record.pos() < record.mpos()
+                || (record.pos() == record.mpos() && record.is_first_in_template());
+            let length = record.insert_size().unsigned_abs();
+            if !leftmost || length == 0 || length > max_length {
+                continue;
+            }
+            let rec_start = u64::try_from(record.pos()).expect("check overflow");
+            let rec_stop = rec_start + length;
+            if rec_stop <= start || rec_start >= stop {
+                continue;
+            }
+
+            let counter = counters.get_mut(group_of(&record));
+            let adjusted_start = rec_start.saturating_sub(start) as usize;
+            counter[adjusted_start] += 1;
+            // Fragments that extend past the end of the region don't end within it
+            if rec_stop < stop {
+                counter[(rec_stop - start) as usize] -= 1;
+            }
+        }
+
+        let contig = std::str::from_utf8(header.tid2name(tid)).unwrap();
+        let contig_len = header.target_len(tid).unwrap();
+        Ok(self.sum_counters(
+            counters,
+            read_groups.as_ref(),
+            contig,
+            contig_len,
+            start,
+            stop,
+        ))
     }
 }
 
 /// Implement [par_io::RegionProcessor] for [SimpleProcessor]
-impl<F: ReadFilter> RegionProcessor for OnlyDepthProcessor<F> {
+impl<F: ReadFilter, R: DepthRows> RegionProcessor for OnlyDepthProcessor<F, R> {
     /// Objects of [position::Position] will be returned by each call to [SimpleProcessor::process_region]
-    type P = RangePositions;
+    type P = R;
 
     /// Process a region by fetching it from a BAM/CRAM, getting a pileup, and then
     /// walking the pileup (checking bounds) to create Position objects according to
     /// the defined filters
-    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<RangePositions> {
+    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<R>> {
         info!("Processing region {}:{}-{}", tid, start, stop);
-        if self.fast_mode {
-            self.process_region_fast(tid, start, stop)
+        // Reuse this thread's reader rather than opening the file for every region
+        par_granges::with_reader(&self.reads, self.ref_fasta.as_deref(), |reader| {
+            if let Some(max_length) = self.fragment_length {
+                self.process_region_fragments(reader, tid, start, stop, max_length)
+            } else if self.fast_mode {
+                self.process_region_fast(reader, tid, start, stop)
+            } else {
+                self.process_region(reader, tid, start, stop)
+            }
+        })
+    }
+}
+
+/// The rows that [OnlyDepthProcessor] reduces the depths of a region to.
+pub(crate) trait DepthRows: Serialize + Send + Sync + Sized + 'static {
+    /// Sum a counter of depth changes over a region into rows
+    fn from_counter<F: ReadFilter>(
+        processor: &OnlyDepthProcessor<F, Self>,
+        counter: Vec<i32>,
+        contig: &str,
+        contig_len: u64,
+        region_start: u64,
+        region_stop: u64,
+    ) -> Vec<Self>;
+
+    /// Set the read group the row was counted for
+    fn set_read_group(&mut self, read_group: String);
+
+    /// The position the row starts at
+    fn pos(&self) -> usize;
+}
+
+impl DepthRows for RangePositions {
+    fn from_counter<F: ReadFilter>(
+        processor: &OnlyDepthProcessor<F, Self>,
+        counter: Vec<i32>,
+        contig: &str,
+        _contig_len: u64,
+        region_start: u64,
+        region_stop: u64,
+    ) -> Vec<Self> {
+        processor.sum_counter(counter, contig, region_start, region_stop)
+    }
+
+    fn set_read_group(&mut self, read_group: String) {
+        self.read_group = Some(read_group);
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
+    }
+}
+
+/// The depth over a fixed-width window of a contig.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) struct WindowDepth {
+    /// Reference sequence name.
+    #[serde(rename = "REF")]
+    ref_seq: String,
+    /// The start of the window.
+    pos: usize,
+    /// The end of the window, non-inclusive.
+    end: usize,
+    /// The read group of this window, only set when splitting depths by read group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_group: Option<String>,
+    /// The number of bases counted, less than the window length if regions only cover part of it.
+    bases: u64,
+    /// The number of bases with a depth above zero.
+    covered: u64,
+    /// The mean depth, set by [WindowDepth::finish].
+    mean: f64,
+    /// The median depth, set by [WindowDepth::finish].
+    median: f64,
+    /// 0-based start of the window, to find the window a base falls in
+    #[serde(skip)]
+    start: u64,
+    /// The number of bases at each depth
+    #[serde(skip)]
+    depths: BTreeMap<usize, u64>,
+}
+
+impl WindowDepth {
+    fn new(contig: &str, start: u64, stop: u64, coord_base: usize) -> Self {
+        Self {
+            ref_seq: String::from(contig),
+            pos: start as usize + coord_base,
+            end: stop as usize + coord_base,
+            read_group: None,
+            bases: 0,
+            covered: 0,
+            mean: 0.0,
+            median: 0.0,
+            start,
+            depths: BTreeMap::new(),
+        }
+    }
+
+    /// Count a run of bases at the same depth
+    #[inline]
+    fn add(&mut self, depth: usize, bases: u64) {
+        self.bases += bases;
+        if depth > 0 {
+            self.covered += bases;
+        }
+        *self.depths.entry(depth).or_insert(0) += bases;
+    }
+
+    /// True if `other` is part of the same window
+    fn same_window(&self, other: &Self) -> bool {
+        self.pos == other.pos && self.ref_seq == other.ref_seq
+    }
+
+    /// Add the counts of another part of the same window
+    fn merge(&mut self, other: Self) {
+        for (depth, bases) in other.depths {
+            self.add(depth, bases);
+        }
+    }
+
+    /// Compute the mean and median once every part of the window has been added
+    fn finish(&mut self) {
+        let total: u64 = self.depths.iter().map(|(d, b)| *d as u64 * b).sum();
+        self.mean = total as f64 / self.bases as f64;
+        // The median is the mean of the two middle depths when there is an even number of bases
+        let middle = [(self.bases - 1) / 2, self.bases / 2];
+        let mut medians = [0; 2];
+        let mut seen = 0;
+        for (depth, bases) in self.depths.iter() {
+            for (median, rank) in medians.iter_mut().zip(middle.iter()) {
+                if seen <= *rank && *rank < seen + bases {
+                    *median = *depth;
+                }
+            }
+            seen += bases;
+        }
+        self.median = (medians[0] + medians[1]) as f64 / 2.0;
+    }
+}
+
+impl DepthRows for WindowDepth {
+    fn from_counter<F: ReadFilter>(
+        processor: &OnlyDepthProcessor<F, Self>,
+        counter: Vec<i32>,
+        contig: &str,
+        contig_len: u64,
+        region_start: u64,
+        region_stop: u64,
+    ) -> Vec<Self> {
+        processor.sum_counter_windows(counter, contig, contig_len, region_start, region_stop)
+    }
+
+    fn set_read_group(&mut self, read_group: String) {
+        self.read_group = Some(read_group);
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
+    }
+}
+
+/// The depth counters for a region, with one counter per read group when splitting by read group.
+struct Counters {
+    /// The counter of each group, only created once a read from the group is seen
+    counters: Vec<Option<Vec<i32>>>,
+    /// The length of the region being counted
+    len: usize,
+}
+
+impl Counters {
+    /// Create the counters for a region of `len` bases. If not splitting by read group a single counter
+    /// is created up front, so that regions without reads are still reported.
+    fn new(read_groups: Option<&ReadGroups>, len: usize) -> Self {
+        let counters = match read_groups {
+            Some(read_groups) => (0..read_groups.len()).map(|_| None).collect(),
+            None => vec![Some(vec![0; len])],
+        };
+        Self { counters, len }
+    }
+
+    /// Get the counter for a group, creating it if needed
+    #[inline]
+    fn get_mut(&mut self, group: usize) -> &mut Vec<i32> {
+        let len = self.len;
+        self.counters[group].get_or_insert_with(|| vec![0; len])
+    }
+}
+
+/// The number of bases seen at each depth, per contig, built up from the stream of [RangePositions].
+struct DepthHistogram {
+    /// Histogram for each contig in the order they were seen
+    contigs: Vec<(String, BTreeMap<usize, u64>)>,
+}
+
+/// A row of the depth histogram output.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+struct DepthHistogramRow {
+    /// Reference sequence name, or `total` for the genome wide histogram.
+    #[serde(rename = "REF")]
+    ref_seq: String,
+    /// The depth.
+    depth: usize,
+    /// The number of bases at this depth.
+    bases: u64,
+    /// The fraction of bases with at least this depth.
+    cumulative_fraction: f64,
+}
+
+impl DepthHistogram {
+    fn new() -> Self {
+        Self { contigs: vec![] }
+    }
+
+    /// Count all the bases in a range toward its depth.
+    #[inline]
+    fn add(&mut self, range: &RangePositions) {
+        // Ranges come in sorted order, so a new contig is always at the end
+        if self.contigs.last().map(|(name, _)| name) != Some(&range.ref_seq) {
+            self.contigs.push((range.ref_seq.clone(), BTreeMap::new()));
+        }
+        let (_, histogram) = self.contigs.last_mut().unwrap();
+        *histogram.entry(range.depth).or_insert(0) += (range.end - range.pos) as u64;
+    }
+
+    /// Convert a single histogram into rows, with the cumulative fraction of bases at or above each depth.
+    fn histogram_rows(ref_seq: &str, histogram: &BTreeMap<usize, u64>) -> Vec<DepthHistogramRow> {
+        let total: u64 = histogram.values().sum();
+        let mut at_or_above = total;
+        histogram
+            .iter()
+            .map(|(depth, bases)| {
+                let row = DepthHistogramRow {
+                    ref_seq: String::from(ref_seq),
+                    depth: *depth,
+                    bases: *bases,
+                    cumulative_fraction: at_or_above as f64 / total as f64,
+                };
+                at_or_above -= bases;
+                row
+            })
+            .collect()
+    }
+
+    /// Get the rows for each contig followed by the rows for the genome wide total.
+    fn rows(&self) -> Vec<DepthHistogramRow> {
+        let mut total = BTreeMap::new();
+        let mut rows = vec![];
+        for (ref_seq, histogram) in self.contigs.iter() {
+            for (depth, bases) in histogram.iter() {
+                *total.entry(*depth).or_insert(0) += bases;
+            }
+            rows.extend(Self::histogram_rows(ref_seq, histogram));
+        }
+        rows.extend(Self::histogram_rows("total", &total));
+        rows
+    }
+}
+
+/// Where depths are written.
+enum DepthOutput {
+    /// Tab separated text, BGZF compressed or not
+    Text(csv::Writer<Output>),
+    /// A bigWig file
+    BigWig(BigWigWriter),
+}
+
+/// How `--normalize` scales depths.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Normalize {
+    /// Write depths as they are
+    None,
+    /// Counts per million mapped reads
+    Cpm,
+    /// Reads per kilobase per million mapped reads
+    Rpkm,
+}
+
+impl FromStr for Normalize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "cpm" => Ok(Self::Cpm),
+            "rpkm" => Ok(Self::Rpkm),
+            _ => Err(anyhow!(
+                "Invalid normalization {:?}, expected none, cpm, or rpkm",
+                s
+            )),
+        }
+    }
+}
+
+/// The depth bins of `--quantize`, parsed from mosdepth's `<lower>:<upper>:...` syntax.
+#[derive(Debug, Clone, PartialEq)]
+struct QuantizeBins {
+    /// The lower bound of each bin, and the upper bound of the last bin if it has one
+    bounds: Vec<usize>,
+    /// True if the last bin has no upper bound
+    open_ended: bool,
+}
+
+impl QuantizeBins {
+    /// The number of bins.
+    fn len(&self) -> usize {
+        if self.open_ended {
+            self.bounds.len()
         } else {
-            self.process_region(tid, start, stop)
+            self.bounds.len() - 1
         }
     }
+
+    /// The index of the bin containing `depth`, if any.
+    #[inline]
+    fn bin(&self, depth: usize) -> Option<usize> {
+        if depth < self.bounds[0] {
+            return None;
+        }
+        // The number of bounds at or below depth, less one, is the bin depth falls in
+        let bin = self.bounds.partition_point(|bound| *bound <= depth) - 1;
+        if bin < self.len() {
+            Some(bin)
+        } else {
+            None
+        }
+    }
+
+    /// The default labels for each bin, `<lower>:<upper>` or `<lower>:inf` for an open ended bin.
+    fn labels(&self) -> Vec<std::string::String> {
+        (0..self.len())
+            .map(|i| match self.bounds.get(i + 1) {
+                Some(upper) => format!("{}:{}", self.bounds[i], upper),
+                None => format!("{}:inf", self.bounds[i]),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for QuantizeBins {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let open_ended = fields.len() > 1 && fields[fields.len() - 1].is_empty();
+        let fields = if open_ended {
+            &fields[..fields.len() - 1]
+        } else {
+            &fields[..]
+        };
+        let bounds = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match field {
+                f if f.is_empty() && i == 0 => Ok(0),
+                f => f
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid quantize bound {:?} in {:?}", f, s)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if bounds.len() < 2 && !open_ended {
+            return Err(anyhow!(
+                "Quantize bins {:?} need at least two bounds, e.g. 0:1:5:150:",
+                s
+            ));
+        }
+        if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("Quantize bounds in {:?} must be increasing", s));
+        }
+        Ok(Self { bounds, open_ended })
+    }
+}
+
+/// A run of adjacent positions sharing a value, written as one line of BED output.
+#[derive(Debug, PartialEq)]
+struct MergedRange {
+    ref_seq: String,
+    start: usize,
+    end: usize,
+    value: usize,
+}
+
+/// Merges adjacent [RangePositions] with the same value, including across region boundaries, which
+/// the processor can't see.
+#[derive(Default)]
+struct RangeMerger {
+    current: Option<MergedRange>,
+}
+
+impl RangeMerger {
+    /// Add the next range, returning the previous merged range if this one does not extend it. A range
+    /// without a value is left out, and ends the current range.
+    #[inline]
+    fn push(&mut self, range: &RangePositions, value: Option<usize>) -> Option<MergedRange> {
+        if let (Some(current), Some(value)) = (self.current.as_mut(), value) {
+            if current.value == value
+                && current.end == range.pos
+                && current.ref_seq == range.ref_seq
+            {
+                current.end = range.end;
+                return None;
+            }
+        }
+        let next = value.map(|value| MergedRange {
+            ref_seq: range.ref_seq.clone(),
+            start: range.pos,
+            end: range.end,
+            value,
+        });
+        std::mem::replace(&mut self.current, next)
+    }
+
+    /// Take the last merged range.
+    fn finish(self) -> Option<MergedRange> {
+        self.current
+    }
 }
 
 // A tweaked impl of IterAlignedBlocks from [here](https://github.com/rust-bio/rust-htslib/blob/9175d3ca186baef4f84a7d7ccb27869b43471e36/src/bam/ext.rs#L51)
@@ -494,14 +1549,24 @@ impl Iterator for IterAlignedBlocks {
 #[allow(unused)]
 mod tests {
     use super::*;
+    use crate::commands::test_pairs::{arb_pairs, expected_depths, write_pairs_bam, CONTIG_LEN};
     use perbase_lib::{
         position::{range_positions::RangePositions, Position},
         read_filter::DefaultReadFilter,
     };
+    use proptest::prelude::*;
     use rstest::*;
-    use rust_htslib::{bam, bam::record::Record};
+    use rust_htslib::{
+        bam,
+        bam::record::Record,
+        tbx::{self, Read as TbxRead},
+    };
     use smartstring::alias::*;
-    use std::{collections::HashMap, path::PathBuf};
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::TryInto,
+        path::{Path, PathBuf},
+    };
     use tempfile::{tempdir, TempDir};
 
     #[fixture]
@@ -619,15 +1684,9 @@ mod tests {
     ) {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
-        let onlydepth_processor = OnlyDepthProcessor::new(
-            bamfile.0.clone(),
-            None,
-            mate_fix,
-            fast_mode,
-            false,
-            0,
-            read_filter,
-        );
+        let onlydepth_processor = OnlyDepthProcessor::new(bamfile.0.clone(), None, 0, read_filter)
+            .with_mate_fix(mate_fix)
+            .with_fast_mode(fast_mode);
 
         let par_granges_runner = par_granges::ParGranges::new(
             bamfile.0,
@@ -642,6 +1701,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -656,8 +1716,7 @@ mod tests {
     ) -> HashMap<String, Vec<RangePositions>> {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
-        let onlydepth_processor =
-            OnlyDepthProcessor::new(bamfile.0.clone(), None, false, false, false, 0, read_filter);
+        let onlydepth_processor = OnlyDepthProcessor::new(bamfile.0.clone(), None, 0, read_filter);
 
         let par_granges_runner = par_granges::ParGranges::new(
             bamfile.0,
@@ -672,6 +1731,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -687,7 +1747,7 @@ mod tests {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
         let onlydepth_processor =
-            OnlyDepthProcessor::new(bamfile.0.clone(), None, true, false, false, 0, read_filter);
+            OnlyDepthProcessor::new(bamfile.0.clone(), None, 0, read_filter).with_mate_fix(true);
 
         let par_granges_runner = par_granges::ParGranges::new(
             bamfile.0,
@@ -702,6 +1762,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -717,7 +1778,7 @@ mod tests {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
         let onlydepth_processor =
-            OnlyDepthProcessor::new(bamfile.0.clone(), None, false, true, false, 0, read_filter);
+            OnlyDepthProcessor::new(bamfile.0.clone(), None, 0, read_filter).with_fast_mode(true);
 
         let par_granges_runner = par_granges::ParGranges::new(
             bamfile.0,
@@ -732,6 +1793,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -746,8 +1808,9 @@ mod tests {
     ) -> HashMap<String, Vec<RangePositions>> {
         let cpus = utils::determine_allowed_cpus(8).unwrap();
 
-        let onlydepth_processor =
-            OnlyDepthProcessor::new(bamfile.0.clone(), None, true, true, false, 0, read_filter);
+        let onlydepth_processor = OnlyDepthProcessor::new(bamfile.0.clone(), None, 0, read_filter)
+            .with_mate_fix(true)
+            .with_fast_mode(true);
 
         let par_granges_runner = par_granges::ParGranges::new(
             bamfile.0,
@@ -762,6 +1825,7 @@ mod tests {
             .process()
             .unwrap()
             .into_iter()
+            .map(Result::unwrap)
             .for_each(|p| {
                 let pos = positions.entry(p.ref_seq.clone()).or_insert(vec![]);
                 pos.push(p)
@@ -1037,7 +2101,7 @@ mod tests {
         case::vanilla(vanilla_positions(bamfile(), read_filter()), 0),
         case::fast_mode(fast_mode_positions(bamfile(), read_filter()), 2),
         case::vanilla_mate_fix(vanilla_positions_mate_fix(bamfile(), read_filter()), 0),
-        case::fast_mode_mate_fix(fast_mode_positions_mate_fix(bamfile(), read_filter()), 2)
+        case::fast_mode_mate_fix(fast_mode_positions_mate_fix(bamfile(), read_filter()), 1)
     )]
     fn check_chunk_ends(
         positions: HashMap<String, Vec<RangePositions>>,
@@ -1067,22 +2131,541 @@ mod tests {
             assert_eq!(positions.get("chr3").unwrap()[25].end, 3_000_000);
             assert_eq!(positions.get("chr3").unwrap()[25].depth, 0);
         } else {
-            // only counting read start / ends
-            assert_eq!(positions.get("chr3").unwrap()[17].pos, 94);
-            assert_eq!(positions.get("chr3").unwrap()[17].end, 1_000_000);
-            assert_eq!(positions.get("chr3").unwrap()[17].depth, 2);
-            assert_eq!(positions.get("chr3").unwrap()[18].pos, 1_000_000);
-            assert_eq!(positions.get("chr3").unwrap()[18].end, 2_000_000);
-            assert_eq!(positions.get("chr3").unwrap()[18].depth, 2);
-            assert_eq!(positions.get("chr3").unwrap()[19].pos, 2_000_000);
-            assert_eq!(positions.get("chr3").unwrap()[19].end, 2_000_025);
-            assert_eq!(positions.get("chr3").unwrap()[19].depth, 2);
-            assert_eq!(positions.get("chr3").unwrap()[20].pos, 2_000_025);
-            assert_eq!(positions.get("chr3").unwrap()[20].end, 2_000_034);
-            assert_eq!(positions.get("chr3").unwrap()[20].depth, 1);
-            assert_eq!(positions.get("chr3").unwrap()[21].pos, 2_000_034);
-            assert_eq!(positions.get("chr3").unwrap()[21].end, 3_000_000);
-            assert_eq!(positions.get("chr3").unwrap()[21].depth, 0);
+            // only counting read start / ends, with mate fix the mates of ONE and THREE fall within
+            // the spans of their first reads and don't add ranges of their own
+            let first = if awareness_modifier == 2 { 17 } else { 13 };
+            assert_eq!(positions.get("chr3").unwrap()[first].pos, 94);
+            assert_eq!(positions.get("chr3").unwrap()[first].end, 1_000_000);
+            assert_eq!(positions.get("chr3").unwrap()[first].depth, 2);
+            assert_eq!(positions.get("chr3").unwrap()[first + 1].pos, 1_000_000);
+            assert_eq!(positions.get("chr3").unwrap()[first + 1].end, 2_000_000);
+            assert_eq!(positions.get("chr3").unwrap()[first + 1].depth, 2);
+            assert_eq!(positions.get("chr3").unwrap()[first + 2].pos, 2_000_000);
+            assert_eq!(positions.get("chr3").unwrap()[first + 2].end, 2_000_025);
+            assert_eq!(positions.get("chr3").unwrap()[first + 2].depth, 2);
+            assert_eq!(positions.get("chr3").unwrap()[first + 3].pos, 2_000_025);
+            assert_eq!(positions.get("chr3").unwrap()[first + 3].end, 2_000_034);
+            assert_eq!(positions.get("chr3").unwrap()[first + 3].depth, 1);
+            assert_eq!(positions.get("chr3").unwrap()[first + 4].pos, 2_000_034);
+            assert_eq!(positions.get("chr3").unwrap()[first + 4].end, 3_000_000);
+            assert_eq!(positions.get("chr3").unwrap()[first + 4].depth, 0);
+        }
+    }
+
+    #[rstest]
+    fn check_histogram(vanilla_positions: HashMap<String, Vec<RangePositions>>) {
+        let mut contigs: Vec<&String> = vanilla_positions.keys().collect();
+        contigs.sort();
+        let mut histogram = DepthHistogram::new();
+        for contig in contigs {
+            for pos in vanilla_positions.get(contig).unwrap() {
+                histogram.add(pos);
+            }
+        }
+        let rows = histogram.rows();
+
+        let chr1: Vec<&DepthHistogramRow> = rows
+            .iter()
+            .filter(|r| r.ref_seq.as_str() == "chr1")
+            .collect();
+        // Every base is counted once
+        assert_eq!(chr1.iter().map(|r| r.bases).sum::<u64>(), 3_000_000);
+        assert_eq!(chr1[0].depth, 0);
+        assert!((chr1[0].cumulative_fraction - 1.0).abs() < f64::EPSILON);
+        let max = chr1.last().unwrap();
+        assert_eq!(max.depth, 5);
+        // 19-25 and 69-74
+        assert_eq!(max.bases, 11);
+        assert!((max.cumulative_fraction - 11.0 / 3_000_000.0).abs() < f64::EPSILON);
+
+        let total: Vec<&DepthHistogramRow> = rows
+            .iter()
+            .filter(|r| r.ref_seq.as_str() == "total")
+            .collect();
+        assert_eq!(total.iter().map(|r| r.bases).sum::<u64>(), 9_000_000);
+        assert_eq!(rows.last().unwrap().ref_seq.as_str(), "total");
+    }
+
+    #[rstest(
+        bins,
+        depths,
+        labels,
+        case("0:1:5:150:", vec![Some(0), Some(1), Some(2), Some(2), Some(3)], vec!["0:1", "1:5", "5:150", "150:inf"]),
+        case(":1:5:", vec![Some(0), Some(1), Some(2), Some(2), Some(2)], vec!["0:1", "1:5", "5:inf"]),
+        case("1:5", vec![None, Some(0), None, None, None], vec!["1:5"]),
+        case("5:", vec![None, None, Some(0), Some(0), Some(0)], vec!["5:inf"])
+    )]
+    fn check_quantize_bins(bins: &str, depths: Vec<Option<usize>>, labels: Vec<&str>) {
+        let bins = QuantizeBins::from_str(bins).unwrap();
+        let got: Vec<Option<usize>> = [0, 1, 5, 149, 150].iter().map(|d| bins.bin(*d)).collect();
+        assert_eq!(got, depths);
+        assert_eq!(bins.labels(), labels);
+    }
+
+    #[rstest(bins, case("5"), case("5:1"), case("1::5"), case("a:5"), case(""))]
+    fn check_quantize_bins_invalid(bins: &str) {
+        assert!(QuantizeBins::from_str(bins).is_err());
+    }
+
+    /// Run only-depth with the given options on a bam, returning the output lines.
+    fn run_only_depth(bamfile: &(PathBuf, TempDir), args: &[&str]) -> Vec<std::string::String> {
+        let output = bamfile.1.path().join("out.bed");
+        let mut argv = vec![
+            "only-depth",
+            bamfile.0.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        argv.extend(args);
+        OnlyDepth::from_iter(argv).run().unwrap();
+        std::fs::read_to_string(output)
+            .unwrap()
+            .lines()
+            .map(std::string::String::from)
+            .collect()
+    }
+
+    #[rstest]
+    fn check_mosdepth(bamfile: (PathBuf, TempDir)) {
+        let lines = run_only_depth(&bamfile, &["--mosdepth", "-c", "1000000"]);
+        // Headerless and 0-based, starting at the first base of the first contig
+        assert!(lines[0].starts_with("chr1\t0\t"));
+        // The zero depth ranges either side of the chunk boundaries are merged
+        assert!(lines.contains(&std::string::String::from("chr3\t94\t2000002\t0")));
+        let ranges: Vec<Vec<&str>> = lines.iter().map(|l| l.split('\t').collect()).collect();
+        for pair in ranges.windows(2) {
+            if pair[0][0] == pair[1][0] {
+                assert_eq!(pair[0][2], pair[1][1]);
+                assert_ne!(pair[0][3], pair[1][3]);
+            }
+        }
+    }
+
+    #[rstest]
+    fn check_quantize(bamfile: (PathBuf, TempDir)) {
+        let lines = run_only_depth(
+            &bamfile,
+            &["--quantize", "1:5:", "--quantize-labels", "LOW,HIGH"],
+        );
+        // Zero depth bases are in no bin so are left out
+        assert!(lines.iter().all(|l| !l.ends_with("\t0")));
+        // chr1 reaches depth 5 at 19-25 and 69-74
+        let high: Vec<&std::string::String> = lines
+            .iter()
+            .filter(|l| l.starts_with("chr1\t") && l.ends_with("\tHIGH"))
+            .collect();
+        assert_eq!(high, vec!["chr1\t19\t25\tHIGH", "chr1\t69\t74\tHIGH"]);
+        let ranges: Vec<Vec<&str>> = lines.iter().map(|l| l.split('\t').collect()).collect();
+        for pair in ranges.windows(2) {
+            if pair[0][0] == pair[1][0] && pair[0][2] == pair[1][1] {
+                assert_ne!(pair[0][3], pair[1][3]);
+            }
+        }
+    }
+
+    #[rstest]
+    fn check_bedgraph(bamfile: (PathBuf, TempDir)) {
+        let lines = run_only_depth(&bamfile, &["--bedgraph", "-c", "1000000"]);
+        assert_eq!(lines[0], "track type=bedGraph");
+        assert!(lines[1].starts_with("chr1\t0\t"));
+        assert!(lines.contains(&std::string::String::from("chr3\t94\t2000002\t0")));
+
+        let mapped = utils::mapped_reads(&bamfile.0).unwrap();
+        let lines = run_only_depth(&bamfile, &["--bedgraph", "--normalize", "cpm"]);
+        // chr1 reaches depth 5 at 19-25
+        let expected = ((5.0 * 1e6 / mapped as f64) as f32).to_string();
+        assert!(lines.contains(&format!("chr1\t19\t25\t{}", expected)));
+    }
+
+    #[rstest]
+    fn check_bigwig(bamfile: (PathBuf, TempDir)) {
+        let output = bamfile.1.path().join("out.bw");
+        OnlyDepth::from_iter(vec![
+            "only-depth",
+            bamfile.0.to_str().unwrap(),
+            "--bigwig",
+            "--normalize",
+            "rpkm",
+            "-o",
+            output.to_str().unwrap(),
+        ])
+        .run()
+        .unwrap();
+        let bytes = std::fs::read(output).unwrap();
+        assert_eq!(bytes[..4], [0x26, 0xFC, 0x8F, 0x88]);
+        // Every base of the three contigs is covered, and the largest value is the max depth of 5
+        let summary = u64::from_le_bytes(bytes[44..52].try_into().unwrap()) as usize;
+        let covered = u64::from_le_bytes(bytes[summary..summary + 8].try_into().unwrap());
+        assert_eq!(covered, 9_000_000);
+        let max = f64::from_le_bytes(bytes[summary + 16..summary + 24].try_into().unwrap());
+        let mapped = utils::mapped_reads(&bamfile.0).unwrap();
+        assert!((max - 5.0 * 1e9 / mapped as f64).abs() / max < 1e-6);
+    }
+
+    #[rstest]
+    fn check_bgzip(bamfile: (PathBuf, TempDir)) {
+        let output = bamfile.1.path().join("out.bed.gz");
+        let args = |extra: &[&'static str]| {
+            let mut args = vec![
+                "only-depth",
+                bamfile.0.to_str().unwrap(),
+                "-o",
+                output.to_str().unwrap(),
+            ];
+            args.extend(extra);
+            args
+        };
+        // The compression options only apply to BGZF output
+        assert!(OnlyDepth::from_iter_safe(args(&["--compression-level", "1"])).is_err());
+        assert!(OnlyDepth::from_iter_safe(args(&["--compression-threads", "2"])).is_err());
+
+        // Headerless, so that htslib reads it back as BED
+        OnlyDepth::from_iter(args(&[
+            "--mosdepth",
+            "--bgzip",
+            "--compression-level",
+            "1",
+            "--compression-threads",
+            "2",
+        ]))
+        .run()
+        .unwrap();
+        let mut reader = tbx::Reader::from_path(&output).unwrap();
+        let tid = reader.tid("chr3").unwrap();
+        reader.fetch(tid, 1_999_999, 2_000_001).unwrap();
+        let lines: Vec<Vec<u8>> = reader.records().map(|r| r.unwrap()).collect();
+        assert!(!lines.is_empty());
+        assert!(lines.iter().all(|line| line.starts_with(b"chr3\t")));
+    }
+
+    #[rstest]
+    fn check_normalize_needs_track_output(bamfile: (PathBuf, TempDir)) {
+        let output = bamfile.1.path().join("out.tsv");
+        let result = OnlyDepth::from_iter(vec![
+            "only-depth",
+            bamfile.0.to_str().unwrap(),
+            "--normalize",
+            "cpm",
+            "-o",
+            output.to_str().unwrap(),
+        ])
+        .run();
+        assert!(result.is_err());
+    }
+
+    #[fixture]
+    fn read_group_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("read_groups.bam");
+
+        let mut header = bam::header::Header::new();
+        let mut chr1 = bam::header::HeaderRecord::new(b"SQ");
+        chr1.push_tag(b"SN", &"chr1".to_owned());
+        chr1.push_tag(b"LN", &"100".to_owned());
+        header.push_record(&chr1);
+        for (id, sample) in &[("rg1", "alice"), ("rg2", "bob"), ("rg3", "alice")] {
+            let mut rg = bam::header::HeaderRecord::new(b"RG");
+            rg.push_tag(b"ID", id);
+            rg.push_tag(b"SM", sample);
+            header.push_record(&rg);
+        }
+        let view = bam::HeaderView::from_header(&header);
+
+        let records = [
+            Record::from_sam(
+                &view,
+                b"R1\t0\tchr1\t1\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tRG:Z:rg1",
+            )
+            .unwrap(),
+            Record::from_sam(
+                &view,
+                b"R2\t0\tchr1\t5\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tRG:Z:rg2",
+            )
+            .unwrap(),
+            Record::from_sam(
+                &view,
+                b"R3\t0\tchr1\t5\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########",
+            )
+            .unwrap(),
+            Record::from_sam(
+                &view,
+                b"R4\t0\tchr1\t8\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########\tRG:Z:rg3",
+            )
+            .unwrap(),
+        ];
+        let mut writer =
+            bam::Writer::from_path(&path, &header, bam::Format::BAM).expect("Created writer");
+        for record in records.iter() {
+            writer.write(record).expect("Wrote record");
+        }
+        drop(writer);
+        bam::index::build(&path, None, bam::index::Type::BAI, 1).unwrap();
+        (path, tempdir)
+    }
+
+    #[rstest(
+        fast_mode => [true, false],
+        read_group_sample => [true, false]
+    )]
+    fn check_split_by_read_group(
+        read_group_bamfile: (PathBuf, TempDir),
+        read_filter: DefaultReadFilter,
+        fast_mode: bool,
+        read_group_sample: bool,
+    ) {
+        let onlydepth_processor =
+            OnlyDepthProcessor::new(read_group_bamfile.0.clone(), None, 0, read_filter)
+                .with_fast_mode(fast_mode)
+                .with_split_by_read_group(true, read_group_sample);
+        let par_granges_runner = par_granges::ParGranges::new(
+            read_group_bamfile.0,
+            None,
+            None,
+            Some(1),
+            None,
+            onlydepth_processor,
+        );
+        let positions: Vec<RangePositions> = par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        // Sorted by position so the output can still be indexed
+        assert!(positions.windows(2).all(|w| w[0].pos <= w[1].pos));
+
+        let ranges = |group: &str| -> Vec<(usize, usize, usize)> {
+            positions
+                .iter()
+                .filter(|p| p.read_group.as_ref().unwrap().as_str() == group)
+                .map(|p| (p.pos, p.end, p.depth))
+                .collect()
+        };
+        assert_eq!(ranges("UNKNOWN"), vec![(0, 4, 0), (4, 14, 1), (14, 100, 0)]);
+        if read_group_sample {
+            assert_eq!(
+                ranges("alice"),
+                vec![(0, 7, 1), (7, 10, 2), (10, 17, 1), (17, 100, 0)]
+            );
+            assert_eq!(ranges("bob"), vec![(0, 4, 0), (4, 14, 1), (14, 100, 0)]);
+        } else {
+            assert_eq!(ranges("rg1"), vec![(0, 10, 1), (10, 100, 0)]);
+            assert_eq!(ranges("rg3"), vec![(0, 7, 0), (7, 17, 1), (17, 100, 0)]);
+        }
+    }
+
+    #[rstest]
+    fn check_windows(
+        bamfile: (PathBuf, TempDir),
+        vanilla_positions: HashMap<String, Vec<RangePositions>>,
+    ) {
+        // Windows are the same however the contigs are chunked
+        let lines = run_only_depth(&bamfile, &["--window", "100", "-z", "-c", "1000000"]);
+        let chunked = run_only_depth(&bamfile, &["--window", "100", "-z", "-c", "150"]);
+        assert_eq!(lines, chunked);
+        assert_eq!(lines[0], "REF\tPOS\tEND\tBASES\tCOVERED\tMEAN\tMEDIAN");
+        // Every base is in exactly one window
+        assert_eq!(lines.len() - 1, 3 * 30_000);
+
+        // Check the first windows of chr1 against the depth of each base
+        let mut depths = vec![];
+        for range in vanilla_positions.get("chr1").unwrap() {
+            if range.pos >= 1000 {
+                break;
+            }
+            depths.extend(vec![range.depth; range.end - range.pos]);
+        }
+        for (i, line) in lines[1..11].iter().enumerate() {
+            let mut window: Vec<usize> = depths[i * 100..(i + 1) * 100].to_vec();
+            let mean = window.iter().sum::<usize>() as f64 / 100.0;
+            let covered = window.iter().filter(|d| **d > 0).count();
+            window.sort_unstable();
+            let median = (window[49] + window[50]) as f64 / 2.0;
+            let expected = format!(
+                "chr1\t{}\t{}\t100\t{}\t{:?}\t{:?}",
+                i * 100,
+                (i + 1) * 100,
+                covered,
+                mean,
+                median
+            );
+            assert_eq!(line, &expected);
+        }
+    }
+
+    #[rstest]
+    fn check_windows_by_read_group(read_group_bamfile: (PathBuf, TempDir)) {
+        let lines = run_only_depth(&read_group_bamfile, &["--window", "30", "-G", "-z"]);
+        assert_eq!(
+            lines[0],
+            "REF\tPOS\tEND\tREAD_GROUP\tBASES\tCOVERED\tMEAN\tMEDIAN"
+        );
+        // A row for each of the four read groups in each window of the 100 base contig
+        let rows: Vec<Vec<&str>> = lines[1..].iter().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(rows.len(), 16);
+        for window in rows.chunks(4) {
+            let groups: HashSet<&str> = window.iter().map(|row| row[3]).collect();
+            assert_eq!(groups.len(), 4);
+            let bases = if window[0][1] == "90" { "10" } else { "30" };
+            assert!(window.iter().all(|row| row[4] == bases));
+        }
+        assert!(lines.contains(&std::string::String::from(
+            "chr1\t0\t30\trg1\t30\t10\t0.3333333333333333\t0.0"
+        )));
+
+        // A read group is only counted in the chunks it has reads in, but the parts of a window
+        // from different chunks are still merged
+        let lines = run_only_depth(
+            &read_group_bamfile,
+            &["--window", "30", "-G", "-z", "-c", "10"],
+        );
+        assert!(lines.contains(&std::string::String::from(
+            "chr1\t0\t30\trg2\t20\t10\t0.5\t0.5"
+        )));
+    }
+
+    #[fixture]
+    fn fragment_bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("fragments.bam");
+
+        let mut header = bam::header::Header::new();
+        let mut chr1 = bam::header::HeaderRecord::new(b"SQ");
+        chr1.push_tag(b"SN", &"chr1".to_owned());
+        chr1.push_tag(b"LN", &"1000".to_owned());
+        header.push_record(&chr1);
+        let view = bam::HeaderView::from_header(&header);
+
+        let sam: [&[u8]; 8] = [
+            // A fragment over 10-60
+            b"A\t99\tchr1\t11\t40\t10M\t=\t51\t50\tAAAAAAAAAA\t##########",
+            // Not properly paired, so not counted
+            b"B\t97\tchr1\t31\t40\t10M\t=\t71\t50\tAAAAAAAAAA\t##########",
+            // Both reads start at 100, a fragment over 100-110
+            b"C\t99\tchr1\t101\t40\t10M\t=\t101\t10\tAAAAAAAAAA\t##########",
+            b"C\t147\tchr1\t101\t40\t10M\t=\t101\t-10\tAAAAAAAAAA\t##########",
+            // A fragment over 200-410, with no reads between 210 and 400
+            b"D\t99\tchr1\t201\t40\t10M\t=\t401\t210\tAAAAAAAAAA\t##########",
+            b"A\t147\tchr1\t51\t40\t10M\t=\t11\t-50\tAAAAAAAAAA\t##########",
+            b"B\t145\tchr1\t71\t40\t10M\t=\t31\t-50\tAAAAAAAAAA\t##########",
+            b"D\t147\tchr1\t401\t40\t10M\t=\t201\t-210\tAAAAAAAAAA\t##########",
+        ];
+        let mut records: Vec<Record> = sam
+            .iter()
+            .map(|line| Record::from_sam(&view, line).unwrap())
+            .collect();
+        records.sort_by_key(|record| record.pos());
+        let mut writer =
+            bam::Writer::from_path(&path, &header, bam::Format::BAM).expect("Created writer");
+        for record in records.iter() {
+            writer.write(record).expect("Wrote record");
+        }
+        drop(writer);
+        bam::index::build(&path, None, bam::index::Type::BAI, 1).unwrap();
+        (path, tempdir)
+    }
+
+    #[rstest(chunksize, case("1000"), case("50"), case("7"))]
+    fn check_fragment_mode(fragment_bamfile: (PathBuf, TempDir), chunksize: &str) {
+        let lines = run_only_depth(
+            &fragment_bamfile,
+            &["--fragment-mode", "--mosdepth", "-c", chunksize],
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "chr1\t0\t10\t0",
+                "chr1\t10\t60\t1",
+                "chr1\t60\t100\t0",
+                "chr1\t100\t110\t1",
+                "chr1\t110\t200\t0",
+                "chr1\t200\t410\t1",
+                "chr1\t410\t1000\t0",
+            ]
+        );
+
+        // Fragments longer than the maximum are skipped
+        let lines = run_only_depth(
+            &fragment_bamfile,
+            &[
+                "--fragment-mode",
+                "--max-fragment-length",
+                "100",
+                "--mosdepth",
+                "-c",
+                chunksize,
+            ],
+        );
+        assert_eq!(lines[lines.len() - 1], "chr1\t110\t1000\t0");
+    }
+
+    #[rstest]
+    fn check_filter_expression() {
+        // An expression that drops the same reads as the flag filter gives the same depths
+        let expression = DefaultReadFilter::new(0, 0, 0)
+            .with_expression(Some("!qcfail && mapq >= 40".parse().unwrap()));
+        let filtered = vanilla_positions(bamfile(), expression);
+        let expected = vanilla_positions(bamfile(), read_filter());
+        assert_eq!(
+            format!("{:?}", filtered.get("chr2")),
+            format!("{:?}", expected.get("chr2"))
+        );
+        // Dropping FOUR's first read, while the flag filter already drops its mate, leaves no
+        // trace of FOUR at all
+        let expression = DefaultReadFilter::new(0, 512, 0)
+            .with_expression(Some("qname != \"FOUR\"".parse().unwrap()));
+        let filtered = vanilla_positions(bamfile(), expression);
+        let unfiltered = vanilla_positions(bamfile(), read_filter());
+        let depth_at = |positions: &HashMap<String, Vec<RangePositions>>, pos: usize| {
+            positions
+                .get("chr2")
+                .unwrap()
+                .iter()
+                .find(|p| p.pos <= pos && pos < p.end)
+                .unwrap()
+                .depth
+        };
+        assert_eq!(depth_at(&filtered, 15), depth_at(&unfiltered, 15) - 1);
+    }
+
+    /// The depth at each base of the test contig from running only-depth with mate fix on.
+    fn mate_fix_depths(bam: &Path, fast_mode: bool, chunksize: usize, cpus: usize) -> Vec<usize> {
+        let processor =
+            OnlyDepthProcessor::new(bam.to_path_buf(), None, 0, DefaultReadFilter::new(0, 0, 0))
+                .with_mate_fix(true)
+                .with_fast_mode(fast_mode);
+        let runner = par_granges::ParGranges::new(
+            bam.to_path_buf(),
+            None,
+            None,
+            Some(cpus),
+            Some(chunksize),
+            processor,
+        );
+        let mut depths = vec![];
+        for range in runner.process().unwrap() {
+            let range = range.unwrap();
+            assert_eq!(range.pos, depths.len());
+            depths.extend(vec![range.depth; range.end - range.pos]);
+        }
+        depths
+    }
+
+    proptest! {
+        #[test]
+        // Mate fix depths are the same whatever the chunksize and threads, and count each base
+        // covered by a pair once
+        fn mate_fix_is_chunk_invariant(pairs in arb_pairs(40), chunksize in 1usize..2_500, cpus in 1..=num_cpus::get(), fast_mode in any::<bool>()) {
+            let tempdir = tempdir().unwrap();
+            let bam = tempdir.path().join("pairs.bam");
+            write_pairs_bam(&bam, &pairs);
+
+            let expected = expected_depths(&pairs, fast_mode);
+            let single = mate_fix_depths(&bam, fast_mode, CONTIG_LEN as usize, 1);
+            prop_assert_eq!(&single, &expected);
+            let chunked = mate_fix_depths(&bam, fast_mode, chunksize, cpus);
+            prop_assert_eq!(&chunked, &expected);
         }
     }
 }
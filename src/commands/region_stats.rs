@@ -0,0 +1,555 @@
+//! # Region Stats
+//!
+//! Summarizes the depth over each region in a BED file. Depths are calculated with the
+//! same counting logic as `only-depth`, and then reduced per region to the mean, median,
+//! min, and max depth, as well as the fraction of bases at or above each requested threshold.
+use crate::commands::only_depth::OnlyDepthProcessor;
+use anyhow::{Context, Result};
+use csv;
+use grep_cli::stdout;
+use log::*;
+use perbase_lib::{
+    filter_expr::ExprReadFilter,
+    par_granges,
+    position::range_positions::RangePositions,
+    read_filter::DefaultReadFilter,
+    regions::{self, ContigMatcher, MissingContig},
+    utils,
+};
+use rust_htslib::{bam, bam::Read};
+use smartstring::alias::String;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+use termcolor::ColorChoice;
+
+/// Calculate depth summary statistics for each region in a BED file.
+#[derive(StructOpt)]
+#[structopt(author)]
+pub struct RegionStats {
+    /// Input indexed BAM/CRAM to analyze.
+    reads: PathBuf,
+
+    /// A BED file containing the regions to summarize.
+    #[structopt(long, short = "b")]
+    bed_file: PathBuf,
+
+    /// What to do with regions on contigs that are not in the BAM/CRAM header: fail, listing all of them (error), skip
+    /// them with a warning listing all of them (warn), or skip them silently (skip). Contig names like `chr1` and `1`, or
+    /// `chrM` and `MT`, are matched to each other before a contig is considered missing.
+    #[structopt(long, default_value = "error", possible_values = &["error", "warn", "skip"])]
+    missing_contig: MissingContig,
+
+    /// Indexed reference fasta, set if using CRAM.
+    #[structopt(long, short = "r")]
+    ref_fasta: Option<PathBuf>,
+
+    /// Output path, defaults to stdout.
+    #[structopt(long, short = "o")]
+    output: Option<PathBuf>,
+
+    /// The number of threads to use.
+    #[structopt(long, short = "t", default_value = utils::NUM_CPU.as_str())]
+    threads: usize,
+
+    /// The ideal number of basepairs each worker receives.
+    #[structopt(long, short = "c")]
+    chunksize: Option<usize>, // default set by par_granges at 1_000_000
+
+    /// SAM flags to include.
+    #[structopt(long, short = "f", default_value = "0")]
+    include_flags: u16,
+
+    /// SAM flags to exclude, recommended 3848.
+    #[structopt(long, short = "F", default_value = "0")]
+    exclude_flags: u16,
+
+    /// Fix overlapping mates counts, see docs for full details.
+    #[structopt(long, short = "m", conflicts_with = "fast_mode")]
+    mate_fix: bool,
+
+    /// Calculate depth based only on read starts/stops, see docs for full details.
+    #[structopt(long, short = "x")]
+    fast_mode: bool,
+
+    /// Minimum MAPQ for a read to count toward depth.
+    #[structopt(long, short = "q", default_value = "0")]
+    min_mapq: u8,
+
+    /// Only count reads that pass a filter expression, such as `mapq >= 20 && !duplicate && tag(NM) <= 4`, see docs
+    /// for the full language. Applied on top of the flag and MAPQ filters.
+    #[structopt(long)]
+    filter: Option<ExprReadFilter>,
+
+    /// Output positions as 0-based instead of 1-based.
+    #[structopt(long, short = "z")]
+    zero_base: bool,
+
+    /// Comma separated depths to report the fraction of bases at or above, ex: 1,10,20,30.
+    #[structopt(long, short = "T", use_delimiter = true)]
+    thresholds: Vec<usize>,
+}
+
+impl RegionStats {
+    pub fn run(self) -> Result<()> {
+        info!("Running region-stats on: {:?}", self.reads);
+        let mut writer = self.get_writer()?;
+
+        let mut header = vec![
+            "REF".to_owned(),
+            "POS".to_owned(),
+            "END".to_owned(),
+            "NAME".to_owned(),
+            "MEAN".to_owned(),
+            "MEDIAN".to_owned(),
+            "MIN".to_owned(),
+            "MAX".to_owned(),
+        ];
+        header.extend(self.thresholds.iter().map(|t| format!("FRAC_{}X", t)));
+        writer.write_record(&header)?;
+
+        for summary in self.summarize()? {
+            writer.write_record(summary.to_record())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Calculate the depths over the BED regions and summarize each region, in BED file order.
+    fn summarize(&self) -> Result<Vec<RegionSummary>> {
+        let cpus = utils::determine_allowed_cpus(self.threads)?;
+
+        let mut reader = bam::IndexedReader::from_path(&self.reads)
+            .with_context(|| format!("Failed to open {:?}", self.reads))?;
+        if let Some(ref_fasta) = &self.ref_fasta {
+            reader.set_reference(ref_fasta)?;
+        }
+        let header = reader.header().to_owned();
+        let regions = Region::from_bed(&self.bed_file, &header, self.missing_contig)?;
+
+        let read_filter =
+            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq)
+                .with_expression(self.filter.clone());
+        // Always merge and always work in 0-based coords so the ranges line up with the BED regions
+        let processor =
+            OnlyDepthProcessor::new(self.reads.clone(), self.ref_fasta.clone(), 0, read_filter)
+                .with_mate_fix(self.mate_fix)
+                .with_fast_mode(self.fast_mode);
+
+        let par_granges_runner = par_granges::ParGranges::new(
+            self.reads.clone(),
+            self.ref_fasta.clone(),
+            Some(self.bed_file.clone()),
+            Some(cpus),
+            self.chunksize,
+            processor,
+        )
+        // Missing contigs were already reported when reading the regions
+        .with_missing_contig(MissingContig::Skip);
+
+        let receiver = par_granges_runner.process()?;
+
+        summarize_regions(
+            regions,
+            receiver.into_iter(),
+            &header,
+            &self.thresholds,
+            if self.zero_base { 0 } else { 1 },
+        )
+    }
+
+    /// Open a CSV Writer to a file or stdout
+    fn get_writer(&self) -> Result<csv::Writer<Box<dyn Write>>> {
+        let raw_writer: Box<dyn Write> = match &self.output {
+            Some(path) if path.to_str().unwrap() != "-" => {
+                Box::new(BufWriter::new(File::create(path)?))
+            }
+            _ => Box::new(stdout(ColorChoice::Never)),
+        };
+        Ok(csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(raw_writer))
+    }
+}
+
+/// A region from the BED file along with the depths seen over it so far.
+struct Region {
+    /// The order this region was found in in the BED file
+    index: usize,
+    /// The tid of the contig this region is on
+    tid: u32,
+    /// 0-based start of the region
+    start: u64,
+    /// 0-based, non-inclusive, end of the region
+    stop: u64,
+    /// Optional name of the region
+    name: Option<String>,
+    /// The number of bases seen at each depth
+    histogram: BTreeMap<usize, u64>,
+}
+
+impl Region {
+    /// Read all the regions in a BED file, keeping the order they are listed in. Regions on contigs
+    /// that are not in the header are handled according to `missing_contig`.
+    fn from_bed(
+        bed_file: &Path,
+        header: &bam::HeaderView,
+        missing_contig: MissingContig,
+    ) -> Result<Vec<Self>> {
+        let mut matcher = ContigMatcher::new(header, "line");
+        let records = regions::read_bed(bed_file, &mut matcher)?;
+        matcher.check(missing_contig, bed_file)?;
+        Ok(records
+            .into_iter()
+            .enumerate()
+            .map(|(index, record)| Region {
+                index,
+                tid: record.tid,
+                start: record.start,
+                stop: record.stop,
+                name: record.name.map(String::from),
+                histogram: BTreeMap::new(),
+            })
+            .collect())
+    }
+
+    /// Add the depth of a range to the histogram for the part of the range that overlaps this region.
+    #[inline]
+    fn add(&mut self, start: u64, stop: u64, depth: usize) {
+        let overlap =
+            std::cmp::min(stop, self.stop).saturating_sub(std::cmp::max(start, self.start));
+        if overlap > 0 {
+            *self.histogram.entry(depth).or_insert(0) += overlap;
+        }
+    }
+
+    /// Get the depth of the nth (0-based) base when the bases are sorted by depth.
+    fn nth_depth(&self, n: u64) -> usize {
+        let mut seen = 0;
+        for (depth, count) in self.histogram.iter() {
+            seen += count;
+            if seen > n {
+                return *depth;
+            }
+        }
+        0
+    }
+
+    /// Reduce the histogram for this region down to its summary statistics.
+    fn summarize(
+        mut self,
+        header: &bam::HeaderView,
+        thresholds: &[usize],
+        coord_base: u64,
+    ) -> RegionSummary {
+        let len = self.stop.saturating_sub(self.start);
+        // Any bases that were not seen had no coverage
+        let seen: u64 = self.histogram.values().sum();
+        if len > seen {
+            *self.histogram.entry(0).or_insert(0) += len - seen;
+        }
+
+        let (mean, median, fractions) = if len == 0 {
+            (0.0, 0.0, vec![0.0; thresholds.len()])
+        } else {
+            let total: u64 = self
+                .histogram
+                .iter()
+                .map(|(depth, count)| *depth as u64 * count)
+                .sum();
+            let median = if len % 2 == 1 {
+                self.nth_depth(len / 2) as f64
+            } else {
+                (self.nth_depth(len / 2 - 1) + self.nth_depth(len / 2)) as f64 / 2.0
+            };
+            let fractions = thresholds
+                .iter()
+                .map(|t| {
+                    let at_or_above: u64 = self.histogram.range(t..).map(|(_, count)| count).sum();
+                    at_or_above as f64 / len as f64
+                })
+                .collect();
+            (total as f64 / len as f64, median, fractions)
+        };
+
+        RegionSummary {
+            index: self.index,
+            ref_seq: String::from(std::str::from_utf8(header.tid2name(self.tid)).unwrap()),
+            pos: self.start + coord_base,
+            end: self.stop + coord_base,
+            name: self.name,
+            mean,
+            median,
+            min: self.histogram.keys().next().copied().unwrap_or(0),
+            max: self.histogram.keys().next_back().copied().unwrap_or(0),
+            fractions,
+        }
+    }
+}
+
+/// The depth summary statistics for a single region.
+#[derive(Debug)]
+struct RegionSummary {
+    /// The order the region was found in in the BED file
+    index: usize,
+    /// Reference sequence name.
+    ref_seq: String,
+    /// Start of the region.
+    pos: u64,
+    /// End of the region, non-inclusive.
+    end: u64,
+    /// Name of the region, if the BED file had one.
+    name: Option<String>,
+    /// Mean depth over the region.
+    mean: f64,
+    /// Median depth over the region.
+    median: f64,
+    /// Min depth over the region.
+    min: usize,
+    /// Max depth over the region.
+    max: usize,
+    /// Fraction of bases in the region at or above each threshold.
+    fractions: Vec<f64>,
+}
+
+impl RegionSummary {
+    /// Convert into the fields of an output row.
+    fn to_record(&self) -> Vec<std::string::String> {
+        let mut record = vec![
+            self.ref_seq.to_string(),
+            self.pos.to_string(),
+            self.end.to_string(),
+            self.name.as_deref().unwrap_or(".").to_owned(),
+            format!("{:.2}", self.mean),
+            format!("{:.1}", self.median),
+            self.min.to_string(),
+            self.max.to_string(),
+        ];
+        record.extend(self.fractions.iter().map(|f| format!("{:.4}", f)));
+        record
+    }
+}
+
+/// Sweep over the in-order ranges of depths, adding each range to all of the regions it overlaps.
+///
+/// `ranges` must be 0-based and sorted by tid and position, as returned by [par_granges::ParGranges].
+/// The regions may overlap each other and be in any order. The summaries are returned in the
+/// original order of `regions`. The first error in `ranges` is returned.
+fn summarize_regions<I: Iterator<Item = Result<RangePositions>>>(
+    mut regions: Vec<Region>,
+    ranges: I,
+    header: &bam::HeaderView,
+    thresholds: &[usize],
+    coord_base: u64,
+) -> Result<Vec<RegionSummary>> {
+    let mut summaries = Vec::with_capacity(regions.len());
+    regions.sort_by_key(|r| (r.tid, r.start));
+    let mut pending = regions.into_iter().peekable();
+    let mut active: Vec<Region> = vec![];
+
+    for range in ranges {
+        let range = range?;
+        let tid = header.tid(range.ref_seq.as_bytes()).with_context(|| {
+            format!("Chromosome {} not found in BAM/CRAM header", range.ref_seq)
+        })?;
+        let (start, stop) = (range.pos as u64, range.end as u64);
+
+        // Pull in any regions that start before this range ends
+        while let Some(region) = pending.peek() {
+            if (region.tid, region.start) < (tid, stop) {
+                active.push(pending.next().unwrap());
+            } else {
+                break;
+            }
+        }
+
+        // Finish any regions that end before this range starts, count this range toward the rest
+        let mut i = 0;
+        while i < active.len() {
+            if active[i].tid < tid || active[i].stop <= start {
+                summaries.push(
+                    active
+                        .swap_remove(i)
+                        .summarize(header, thresholds, coord_base),
+                );
+            } else {
+                active[i].add(start, stop, range.depth);
+                i += 1;
+            }
+        }
+    }
+
+    summaries.extend(
+        active
+            .into_iter()
+            .chain(pending)
+            .map(|region| region.summarize(header, thresholds, coord_base)),
+    );
+    summaries.sort_by_key(|s| s.index);
+    Ok(summaries)
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use bio::io::bed;
+    use rstest::*;
+    use rust_htslib::{bam, bam::record::Record};
+    use tempfile::{tempdir, TempDir};
+
+    #[fixture]
+    fn bamfile() -> (PathBuf, TempDir) {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.bam");
+
+        // Build a header
+        let mut header = bam::header::Header::new();
+        let mut chr1 = bam::header::HeaderRecord::new(b"SQ");
+        chr1.push_tag(b"SN", &"chr1".to_owned());
+        chr1.push_tag(b"LN", &"100".to_owned());
+        let mut chr2 = bam::header::HeaderRecord::new(b"SQ");
+        chr2.push_tag(b"SN", &"chr2".to_owned());
+        chr2.push_tag(b"LN", &"100".to_owned());
+        header.push_record(&chr1);
+        header.push_record(&chr2);
+        let view = bam::HeaderView::from_header(&header);
+
+        // Add records
+        let records = [
+            Record::from_sam(&view, b"ONE\t67\tchr1\t1\t40\t25M\tchr1\t50\t75\tAAAAAAAAAAAAAAAAAAAAAAAAA\t#########################").unwrap(),
+            Record::from_sam(&view, b"TWO\t67\tchr1\t5\t40\t25M\tchr1\t55\t75\tAAAAAAAAAAAAAAAAAAAAAAAAA\t#########################").unwrap(),
+            Record::from_sam(&view, b"THREE\t67\tchr1\t10\t40\t25M\tchr1\t60\t75\tAAAAAAAAAAAAAAAAAAAAAAAAA\t#########################").unwrap(),
+            Record::from_sam(&view, b"FOUR\t67\tchr1\t15\t40\t25M\tchr1\t65\t75\tAAAAAAAAAAAAAAAAAAAAAAAAA\t#########################").unwrap(),
+            Record::from_sam(&view, b"FIVE\t67\tchr1\t20\t40\t25M\tchr1\t70\t75\tAAAAAAAAAAAAAAAAAAAAAAAAA\t#########################").unwrap(),
+        ];
+
+        let mut writer =
+            bam::Writer::from_path(&path, &header, bam::Format::BAM).expect("Created writer");
+        for record in records.iter() {
+            writer.write(record).expect("Wrote record");
+        }
+        drop(writer); // force it to flush so indexing can happen
+        bam::index::build(&path, None, bam::index::Type::BAI, 1).unwrap();
+        (path, tempdir)
+    }
+
+    #[fixture]
+    fn bedfile(bamfile: (PathBuf, TempDir)) -> (PathBuf, PathBuf, TempDir) {
+        let path = bamfile.1.path().join("test.bed");
+        let mut writer = bed::Writer::to_file(&path).expect("Opened test.bed for writing");
+        // Out of order and overlapping
+        for (chrom, start, end, name) in &[
+            ("chr1", 40, 60, "b"),
+            ("chr1", 0, 10, "a"),
+            ("chr1", 5, 25, "c"),
+            ("chr2", 10, 20, "d"),
+        ] {
+            let mut record = bed::Record::new();
+            record.set_chrom(chrom);
+            record.set_start(*start);
+            record.set_end(*end);
+            record.set_name(name);
+            writer.write(&record).expect("Wrote to test.bed");
+        }
+        drop(writer);
+        (bamfile.0, path, bamfile.1)
+    }
+
+    #[rstest(
+        chunksize => [7, 1_000_000],
+        fast_mode => [true, false]
+    )]
+    fn check_region_stats(bedfile: (PathBuf, PathBuf, TempDir), chunksize: usize, fast_mode: bool) {
+        let region_stats = RegionStats {
+            reads: bedfile.0,
+            bed_file: bedfile.1,
+            missing_contig: MissingContig::Error,
+            ref_fasta: None,
+            output: None,
+            threads: utils::determine_allowed_cpus(8).unwrap(),
+            chunksize: Some(chunksize),
+            include_flags: 0,
+            exclude_flags: 512,
+            mate_fix: false,
+            fast_mode,
+            min_mapq: 0,
+            filter: None,
+            zero_base: true,
+            thresholds: vec![1, 2, 3],
+        };
+        let summaries = region_stats.summarize().unwrap();
+        assert_eq!(summaries.len(), 4);
+
+        // Kept in BED order
+        let b = &summaries[0];
+        assert_eq!(b.name.as_deref(), Some("b"));
+        assert_eq!((b.pos, b.end), (40, 60));
+        assert!((b.mean - 0.2).abs() < f64::EPSILON);
+        assert!((b.median - 0.0).abs() < f64::EPSILON);
+        assert_eq!((b.min, b.max), (0, 1));
+        assert_eq!(b.fractions, vec![0.2, 0.0, 0.0]);
+
+        let a = &summaries[1];
+        assert_eq!(a.name.as_deref(), Some("a"));
+        assert!((a.mean - 1.7).abs() < f64::EPSILON);
+        assert!((a.median - 2.0).abs() < f64::EPSILON);
+        assert_eq!((a.min, a.max), (1, 3));
+        assert_eq!(a.fractions, vec![1.0, 0.6, 0.1]);
+
+        // Overlaps a
+        let c = &summaries[2];
+        assert_eq!(c.name.as_deref(), Some("c"));
+        assert!((c.mean - 3.65).abs() < f64::EPSILON);
+        assert!((c.median - 4.0).abs() < f64::EPSILON);
+        assert_eq!((c.min, c.max), (2, 5));
+        assert_eq!(c.fractions, vec![1.0, 1.0, 0.8]);
+
+        // No coverage
+        let d = &summaries[3];
+        assert_eq!(d.ref_seq, String::from("chr2"));
+        assert!((d.mean - 0.0).abs() < f64::EPSILON);
+        assert_eq!((d.min, d.max), (0, 0));
+        assert_eq!(d.fractions, vec![0.0, 0.0, 0.0]);
+    }
+
+    #[rstest]
+    fn check_missing_contig(bamfile: (PathBuf, TempDir)) {
+        let bed_file = bamfile.1.path().join("aliased.bed");
+        // `1` is an alias of chr1, chrZ is not in the header
+        std::fs::write(&bed_file, "1\t0\t10\ta\nchrZ\t0\t5\tz\nchr2\t10\t20\td\n").unwrap();
+        let region_stats = |missing_contig| RegionStats {
+            reads: bamfile.0.clone(),
+            bed_file: bed_file.clone(),
+            missing_contig,
+            ref_fasta: None,
+            output: None,
+            threads: 1,
+            chunksize: None,
+            include_flags: 0,
+            exclude_flags: 512,
+            mate_fix: false,
+            fast_mode: false,
+            min_mapq: 0,
+            filter: None,
+            zero_base: true,
+            thresholds: vec![],
+        };
+
+        let err = region_stats(MissingContig::Error)
+            .summarize()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("chrZ (line 2)"), "{}", err);
+
+        let summaries = region_stats(MissingContig::Skip).summarize().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].ref_seq, String::from("chr1"));
+        assert!((summaries[0].mean - 1.7).abs() < f64::EPSILON);
+        assert_eq!(summaries[1].name.as_deref(), Some("d"));
+    }
+}
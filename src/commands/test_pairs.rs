@@ -0,0 +1,151 @@
+//! Random read pairs for property tests of the mate fixes.
+use proptest::prelude::*;
+use rust_htslib::bam::{
+    self,
+    record::{Cigar, CigarString, Record},
+};
+use std::path::Path;
+
+/// The length of the single contig, `chr1`, that pairs are placed on.
+pub(crate) const CONTIG_LEN: u64 = 2_000;
+
+/// A pair of reads: the start of the first read, how far after it its mate starts, and the CIGARs
+/// of both reads.
+pub(crate) type ArbPair = (i64, i64, Vec<Cigar>, Vec<Cigar>);
+
+/// A CIGAR of match blocks separated by insertions, deletions and skips.
+fn arb_cigar() -> impl Strategy<Value = Vec<Cigar>> {
+    (
+        1u32..40,
+        prop::collection::vec((0u8..3, 1u32..20, 1u32..40), 0..3),
+    )
+        .prop_map(|(first, rest)| {
+            let mut cigar = vec![Cigar::Match(first)];
+            for (op, len, next) in rest {
+                cigar.push(match op {
+                    0 => Cigar::Ins(len),
+                    1 => Cigar::Del(len),
+                    _ => Cigar::RefSkip(len),
+                });
+                cigar.push(Cigar::Match(next));
+            }
+            cigar
+        })
+}
+
+/// Up to `max_pairs` pairs that fit on the contig, with mates often overlapping each other.
+pub(crate) fn arb_pairs(max_pairs: usize) -> impl Strategy<Value = Vec<ArbPair>> {
+    prop::collection::vec(
+        (0i64..1_600, 0i64..150, arb_cigar(), arb_cigar()),
+        0..max_pairs,
+    )
+}
+
+/// The number of reference bases a CIGAR covers.
+fn ref_len(cigar: &[Cigar]) -> i64 {
+    cigar
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(len) | Cigar::Del(len) | Cigar::RefSkip(len) => *len as i64,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The reference blocks of a read that count toward depth, everything but skips.
+fn blocks(pos: i64, cigar: &[Cigar]) -> Vec<(i64, i64)> {
+    let mut blocks = vec![];
+    let mut pos = pos;
+    for op in cigar {
+        match op {
+            Cigar::Match(len) | Cigar::Del(len) => {
+                blocks.push((pos, pos + *len as i64));
+                pos += *len as i64;
+            }
+            Cigar::RefSkip(len) => pos += *len as i64,
+            _ => (),
+        }
+    }
+    blocks
+}
+
+/// The depth at each base of the contig when each pair is counted once wherever either read covers
+/// it, over the whole span of each read in fast mode.
+pub(crate) fn expected_depths(pairs: &[ArbPair], fast_mode: bool) -> Vec<usize> {
+    let mut depths = vec![0; CONTIG_LEN as usize];
+    for (pos, offset, cigar1, cigar2) in pairs.iter() {
+        let mut covered = vec![false; CONTIG_LEN as usize];
+        for read in [blocks(*pos, cigar1), blocks(pos + offset, cigar2)].iter() {
+            let spans = if fast_mode {
+                vec![(read[0].0, read[read.len() - 1].1)]
+            } else {
+                read.clone()
+            };
+            for (start, stop) in spans {
+                for base in start..stop {
+                    covered[base as usize] = true;
+                }
+            }
+        }
+        for (depth, covered) in depths.iter_mut().zip(covered) {
+            *depth += covered as usize;
+        }
+    }
+    depths
+}
+
+/// Write the pairs to a sorted and indexed BAM, as properly paired reads with an MC tag only on
+/// every other pair.
+pub(crate) fn write_pairs_bam(path: &Path, pairs: &[ArbPair]) {
+    let mut header = bam::header::Header::new();
+    let mut chr1 = bam::header::HeaderRecord::new(b"SQ");
+    chr1.push_tag(b"SN", &"chr1".to_owned());
+    chr1.push_tag(b"LN", &CONTIG_LEN.to_string());
+    header.push_record(&chr1);
+
+    let mut records = vec![];
+    for (i, (pos, offset, cigar1, cigar2)) in pairs.iter().enumerate() {
+        let mate_pos = pos + offset;
+        let end = (pos + ref_len(cigar1)).max(mate_pos + ref_len(cigar2));
+        let qname = format!("pair{}", i);
+        let reads = [
+            (*pos, mate_pos, cigar1, cigar2, end - pos, 99),
+            (mate_pos, *pos, cigar2, cigar1, pos - end, 147),
+        ];
+        for (pos, mate_pos, cigar, mate_cigar, tlen, flags) in reads.iter() {
+            let cigar = CigarString(cigar.to_vec());
+            let len = cigar.iter().fold(0, |len, op| match op {
+                Cigar::Match(l) | Cigar::Ins(l) => len + *l as usize,
+                _ => len,
+            });
+            let mut record = Record::new();
+            record.set(
+                qname.as_bytes(),
+                Some(&cigar),
+                &vec![b'A'; len],
+                &vec![30; len],
+            );
+            record.set_tid(0);
+            record.set_pos(*pos);
+            record.set_mtid(0);
+            record.set_mpos(*mate_pos);
+            record.set_insert_size(*tlen);
+            record.set_flags(*flags);
+            record.set_mapq(60);
+            if i % 2 == 0 {
+                let mate_cigar = CigarString(mate_cigar.to_vec()).to_string();
+                record.push_aux(b"MC", &bam::record::Aux::String(mate_cigar.as_bytes()));
+            }
+            records.push(record);
+        }
+    }
+    records.sort_by_key(|record| record.pos());
+
+    let mut writer =
+        bam::Writer::from_path(path, &header, bam::Format::BAM).expect("Opened BAM for writing");
+    for record in records.iter() {
+        writer.write(record).expect("Wrote record");
+    }
+    drop(writer);
+    bam::index::build(path, None, bam::index::Type::BAI, 1).unwrap();
+}
@@ -0,0 +1,457 @@
+//! # VCF Counts
+//!
+//! Counts the alleles at each site of a VCF/BCF file. The input records are kept as they are,
+//! including REF, ALT, and INFO, and the depths calculated the same way as `base-depth` are
+//! added as FORMAT fields, with one sample per input BAM/CRAM.
+use crate::commands::base_depth::{sample_names, BaseProcessor};
+use anyhow::{Context, Error, Result};
+use log::*;
+use perbase_lib::{
+    filter_expr::ExprReadFilter,
+    par_granges::{self, SiteValues},
+    position::pileup_position::{OptionalColumns, PileupPosition},
+    read_filter::DefaultReadFilter,
+    regions::MissingContig,
+    utils,
+};
+use rust_htslib::bcf::{self, record::Numeric, Read};
+use smartstring::alias::String;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Count the alleles at each site of a VCF/BCF, adding AD, DP, ADF, and ADR FORMAT fields.
+#[derive(StructOpt)]
+#[structopt(author)]
+pub struct VcfCounts {
+    /// Input indexed BAM/CRAM(s) to analyze, each is output as a separate sample.
+    #[structopt(required = true)]
+    reads: Vec<PathBuf>,
+
+    /// A VCF/BCF file of the sites to count alleles at.
+    #[structopt(long, short = "v")]
+    vcf: PathBuf,
+
+    /// What to do with sites on contigs that are not in the BAM/CRAM header: fail, listing the ones in the batch of
+    /// records being counted (error), skip them with a warning listing all of them (warn), or skip them silently
+    /// (skip). Contig names like `chr1` and `1`, or `chrM` and `MT`, are matched to each other before a contig is
+    /// considered missing. Skipped sites are still output, with missing counts.
+    #[structopt(long, default_value = "error", possible_values = &["error", "warn", "skip"])]
+    missing_contig: MissingContig,
+
+    /// Indexed reference fasta, set if using CRAM.
+    #[structopt(long, short = "r")]
+    ref_fasta: Option<PathBuf>,
+
+    /// Output path, defaults to stdout.
+    #[structopt(long, short = "o")]
+    output: Option<PathBuf>,
+
+    /// Output type: compressed BCF (b), uncompressed BCF (u), compressed VCF (z), uncompressed VCF (v).
+    #[structopt(long, short = "O", default_value = "v", possible_values = &["b", "u", "z", "v"])]
+    output_type: std::string::String,
+
+    /// The number of threads to use.
+    #[structopt(long, short = "t", default_value = utils::NUM_CPU.as_str())]
+    threads: usize,
+
+    /// The number of records counted at a time. Nearby records of a batch are fetched together, in regions of at most
+    /// chunksize bp.
+    #[structopt(long, short = "c")]
+    chunksize: Option<usize>, // default set by par_granges at 1_000_000
+
+    /// SAM flags to include.
+    #[structopt(long, short = "f", default_value = "0")]
+    include_flags: u16,
+
+    /// SAM flags to exclude, recommended 3848.
+    #[structopt(long, short = "F", default_value = "0")]
+    exclude_flags: u16,
+
+    /// Fix overlapping mates counts, see docs for full details.
+    #[structopt(long, short = "m")]
+    mate_fix: bool,
+
+    /// Minimum MAPQ for a read to count toward depth.
+    #[structopt(long, short = "q", default_value = "0")]
+    min_mapq: u8,
+
+    /// Only count reads that pass a filter expression, such as `mapq >= 20 && !duplicate && tag(NM) <= 4`, see docs
+    /// for the full language. Applied on top of the flag and MAPQ filters.
+    #[structopt(long)]
+    filter: Option<ExprReadFilter>,
+
+    /// Minimum base quality for a base to count toward depth.
+    #[structopt(long, short = "Q")]
+    min_base_quality: Option<u8>,
+
+    /// Number of Reference Sequences to hold in memory at one time. Smaller will decrease mem usage.
+    #[structopt(long, default_value = "10")]
+    ref_cache_size: usize,
+}
+
+impl VcfCounts {
+    pub fn run(self) -> Result<()> {
+        info!("Running vcf-counts on: {:?}", self.reads);
+        let cpus = utils::determine_allowed_cpus(self.threads)?;
+        let samples = sample_names(&self.reads)?;
+
+        let read_filter =
+            DefaultReadFilter::new(self.include_flags, self.exclude_flags, self.min_mapq)
+                .with_expression(self.filter.clone());
+        // Always work in 0-based coords so the positions line up with the VCF records
+        let base_processor = BaseProcessor::new(
+            self.reads.clone(),
+            self.ref_fasta.clone(),
+            0,
+            read_filter,
+            self.ref_cache_size,
+        )?
+        .with_samples(Some(samples.clone()))
+        .with_mate_fix(self.mate_fix)
+        .with_min_base_quality(self.min_base_quality)
+        // Strand aware for ADF and ADR
+        .with_columns(OptionalColumns {
+            strand: true,
+            ..OptionalColumns::default()
+        });
+
+        // Only the positions of the VCF records are counted, and they come back in VCF order
+        let par_granges_runner = par_granges::ParGranges::with_multiple_reads(
+            self.reads.clone(),
+            self.ref_fasta.clone(),
+            None,
+            Some(cpus),
+            self.chunksize,
+            base_processor,
+        )
+        .with_missing_contig(self.missing_contig);
+        let counts = par_granges_runner.process_vcf_sites(self.vcf.clone())?;
+
+        self.write_counts(&samples, counts)
+    }
+
+    /// Copy each record of the input VCF/BCF to the output, adding the counts for each sample.
+    ///
+    /// `counts` has the values of each record in VCF order, except for records on skipped contigs.
+    fn write_counts<I>(&self, samples: &[String], counts: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<SiteValues<PileupPosition>>>,
+    {
+        let mut reader = bcf::Reader::from_path(&self.vcf)
+            .with_context(|| format!("Failed to open VCF/BCF {:?}", self.vcf))?;
+
+        // Drop any samples in the input and add one for each BAM/CRAM
+        let mut header = bcf::Header::from_template_subset(reader.header(), &[])?;
+        header.push_record(
+            br#"##FORMAT=<ID=DP,Number=1,Type=Integer,Description="Total depth at the site, as calculated by perbase base-depth">"#,
+        );
+        header.push_record(
+            br#"##FORMAT=<ID=AD,Number=R,Type=Integer,Description="Number of reads supporting each allele, missing for alleles that are not a single base">"#,
+        );
+        header.push_record(
+            br#"##FORMAT=<ID=ADF,Number=R,Type=Integer,Description="Number of forward strand reads supporting each allele, missing for alleles that are not a single base">"#,
+        );
+        header.push_record(
+            br#"##FORMAT=<ID=ADR,Number=R,Type=Integer,Description="Number of reverse strand reads supporting each allele, missing for alleles that are not a single base">"#,
+        );
+        for sample in samples {
+            header.push_sample(sample.as_bytes());
+        }
+        let mut writer = self.get_writer(&header)?;
+
+        let mut counts = counts.into_iter().peekable();
+        for (index, record) in reader.records().enumerate() {
+            let mut record = record?;
+            // The sites are numbered by record, so a record without counts was skipped
+            let positions = counts
+                .next_if(|item| match item {
+                    Ok((site, _)) => site.line == index + 1,
+                    Err(_) => true,
+                })
+                .transpose()?
+                .map(|(_, positions)| positions);
+            let alleles: Vec<Vec<u8>> = record.alleles().iter().map(|a| a.to_vec()).collect();
+
+            let mut dp = Vec::with_capacity(samples.len());
+            let mut ad = Vec::with_capacity(samples.len() * alleles.len());
+            let mut adf = Vec::with_capacity(samples.len() * alleles.len());
+            let mut adr = Vec::with_capacity(samples.len() * alleles.len());
+            for i in 0..samples.len() {
+                // Sites on skipped contigs were not counted at all
+                let positions = match &positions {
+                    Some(positions) => positions,
+                    None => {
+                        dp.push(i32::missing());
+                        for _ in &alleles {
+                            ad.push(i32::missing());
+                            adf.push(i32::missing());
+                            adr.push(i32::missing());
+                        }
+                        continue;
+                    }
+                };
+                let pos = &positions[i];
+                dp.push(pos.depth as i32);
+                for allele in &alleles {
+                    let [total, fwd, rev] = allele_counts(pos, allele);
+                    ad.push(total);
+                    adf.push(fwd);
+                    adr.push(rev);
+                }
+            }
+
+            writer.translate(&mut record);
+            writer.subset(&mut record);
+            record.push_format_integer(b"DP", &dp)?;
+            record.push_format_integer(b"AD", &ad)?;
+            record.push_format_integer(b"ADF", &adf)?;
+            record.push_format_integer(b"ADR", &adr)?;
+            writer.write(&record)?;
+        }
+        // Errors are sent after all of the counts
+        counts.next().transpose()?;
+        Ok(())
+    }
+
+    /// Open a VCF/BCF writer to a file or stdout
+    fn get_writer(&self, header: &bcf::Header) -> Result<bcf::Writer> {
+        let (uncompressed, format) = match self.output_type.as_str() {
+            "b" => (false, bcf::Format::BCF),
+            "u" => (true, bcf::Format::BCF),
+            "z" => (false, bcf::Format::VCF),
+            "v" => (true, bcf::Format::VCF),
+            other => return Err(Error::msg(format!("Unknown output type {}", other))),
+        };
+        let writer = match &self.output {
+            Some(path) if path.to_str().unwrap() != "-" => {
+                bcf::Writer::from_path(path, header, uncompressed, format)
+                    .with_context(|| format!("Failed to open {:?} for writing", path))?
+            }
+            _ => bcf::Writer::from_stdout(header, uncompressed, format)?,
+        };
+        Ok(writer)
+    }
+}
+
+/// The total, forward strand, and reverse strand counts of an allele at a position.
+///
+/// Only single base alleles can be counted, all others are missing.
+fn allele_counts(pos: &PileupPosition, allele: &[u8]) -> [i32; 3] {
+    if allele.len() != 1 {
+        return [i32::missing(); 3];
+    }
+    let strand = pos.strand();
+    let (total, fwd, rev) = match allele[0].to_ascii_uppercase() {
+        b'A' => (pos.a, strand.map(|s| s.a_fwd), strand.map(|s| s.a_rev)),
+        b'C' => (pos.c, strand.map(|s| s.c_fwd), strand.map(|s| s.c_rev)),
+        b'G' => (pos.g, strand.map(|s| s.g_fwd), strand.map(|s| s.g_rev)),
+        b'T' => (pos.t, strand.map(|s| s.t_fwd), strand.map(|s| s.t_rev)),
+        b'N' => (pos.n, strand.map(|s| s.n_fwd), strand.map(|s| s.n_rev)),
+        _ => return [i32::missing(); 3],
+    };
+    [
+        total as i32,
+        fwd.unwrap_or(0) as i32,
+        rev.unwrap_or(0) as i32,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam;
+    use tempfile::tempdir;
+
+    /// The DP, AD, ADF, and ADR of a sample
+    type SampleCounts = (i32, Vec<i32>, Vec<i32>, Vec<i32>);
+
+    /// Write and index a BAM with one sample and a single 100bp contig.
+    fn write_bam(path: &std::path::Path, sample: &str, records: &[&[u8]]) {
+        let mut header = bam::header::Header::new();
+        let mut contig = bam::header::HeaderRecord::new(b"SQ");
+        contig.push_tag(b"SN", &"chr1");
+        contig.push_tag(b"LN", &100);
+        header.push_record(&contig);
+        let mut rg = bam::header::HeaderRecord::new(b"RG");
+        rg.push_tag(b"ID", &"rg1");
+        rg.push_tag(b"SM", &sample);
+        header.push_record(&rg);
+        let view = bam::HeaderView::from_header(&header);
+        let mut writer =
+            bam::Writer::from_path(path, &header, bam::Format::BAM).expect("Created writer");
+        for record in records {
+            writer
+                .write(&bam::Record::from_sam(&view, record).unwrap())
+                .expect("Wrote record");
+        }
+        drop(writer);
+        bam::index::build(path, None, bam::index::Type::BAI, 1).unwrap();
+    }
+
+    /// Write a VCF of (1-based position, alleles) sites on chr1.
+    fn write_vcf(path: &std::path::Path, sites: &[(i64, &[&[u8]])]) {
+        let mut header = bcf::Header::new();
+        header.push_record(b"##contig=<ID=chr1,length=100>");
+        let mut writer =
+            bcf::Writer::from_path(path, &header, true, bcf::Format::VCF).expect("Created writer");
+        for (pos, alleles) in sites {
+            let mut record = writer.empty_record();
+            record.set_rid(Some(0));
+            record.set_pos(pos - 1);
+            record.set_alleles(alleles).unwrap();
+            writer.write(&record).unwrap();
+        }
+    }
+
+    #[test]
+    fn check_vcf_counts() {
+        let tempdir = tempdir().unwrap();
+        let tumor = tempdir.path().join("tumor.bam");
+        let normal = tempdir.path().join("normal.bam");
+        let vcf = tempdir.path().join("sites.vcf");
+        let output = tempdir.path().join("counts.vcf");
+        write_bam(
+            &tumor,
+            "tumor",
+            &[
+                b"T1\t0\tchr1\t10\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########",
+                b"T2\t0\tchr1\t10\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########",
+                b"T3\t16\tchr1\t10\t40\t10M\t*\t0\t0\tCCCCCCCCCC\t##########",
+            ],
+        );
+        write_bam(
+            &normal,
+            "normal",
+            &[b"N1\t0\tchr1\t50\t40\t4M\t*\t0\t0\tGGGG\t####"],
+        );
+        write_vcf(
+            &vcf,
+            &[
+                (12, &[b"A", b"C"]),
+                (15, &[b"A", b"AT"]),
+                (51, &[b"G", b"T"]),
+                (80, &[b"T", b"G"]),
+            ],
+        );
+
+        let vcf_counts = VcfCounts {
+            reads: vec![tumor, normal],
+            vcf,
+            missing_contig: MissingContig::Error,
+            ref_fasta: None,
+            output: Some(output.clone()),
+            output_type: "v".to_owned(),
+            threads: 1,
+            chunksize: None,
+            include_flags: 0,
+            exclude_flags: 0,
+            mate_fix: false,
+            min_mapq: 0,
+            filter: None,
+            min_base_quality: None,
+            ref_cache_size: 10,
+        };
+        vcf_counts.run().unwrap();
+
+        let mut reader = bcf::Reader::from_path(&output).unwrap();
+        let samples: Vec<&[u8]> = reader.header().samples();
+        assert_eq!(samples, vec![&b"tumor"[..], &b"normal"[..]]);
+        let missing = i32::missing();
+        // (DP, AD, ADF, ADR) of each sample at each site
+        let expected: Vec<Vec<SampleCounts>> = vec![
+            vec![
+                (3, vec![2, 1], vec![2, 0], vec![0, 1]),
+                (0, vec![0, 0], vec![0, 0], vec![0, 0]),
+            ],
+            vec![
+                (3, vec![2, missing], vec![2, missing], vec![0, missing]),
+                (0, vec![0, missing], vec![0, missing], vec![0, missing]),
+            ],
+            vec![
+                (0, vec![0, 0], vec![0, 0], vec![0, 0]),
+                (1, vec![1, 0], vec![1, 0], vec![0, 0]),
+            ],
+            vec![
+                (0, vec![0, 0], vec![0, 0], vec![0, 0]),
+                (0, vec![0, 0], vec![0, 0], vec![0, 0]),
+            ],
+        ];
+        let mut n = 0;
+        for (record, expected) in reader.records().zip(expected) {
+            let mut record = record.unwrap();
+            let mut values = |tag: &[u8]| -> Vec<Vec<i32>> {
+                let values = record.format(tag).integer().unwrap();
+                values.iter().map(|v| v.to_vec()).collect()
+            };
+            let (dp, ad, adf, adr) = (values(b"DP"), values(b"AD"), values(b"ADF"), values(b"ADR"));
+            for (i, (e_dp, e_ad, e_adf, e_adr)) in expected.into_iter().enumerate() {
+                assert_eq!(dp[i], vec![e_dp]);
+                assert_eq!(ad[i], e_ad);
+                assert_eq!(adf[i], e_adf);
+                assert_eq!(adr[i], e_adr);
+            }
+            n += 1;
+        }
+        assert_eq!(n, 4);
+    }
+
+    #[test]
+    fn check_missing_contig() {
+        let tempdir = tempdir().unwrap();
+        let tumor = tempdir.path().join("tumor.bam");
+        let vcf = tempdir.path().join("sites.vcf");
+        let output = tempdir.path().join("counts.vcf");
+        write_bam(
+            &tumor,
+            "tumor",
+            &[b"T1\t0\tchr1\t10\t40\t10M\t*\t0\t0\tAAAAAAAAAA\t##########"],
+        );
+        // `1` is an alias of chr1, chrZ is not in the BAM header
+        let mut header = bcf::Header::new();
+        header.push_record(b"##contig=<ID=1,length=100>");
+        header.push_record(b"##contig=<ID=chrZ,length=100>");
+        let mut writer =
+            bcf::Writer::from_path(&vcf, &header, true, bcf::Format::VCF).expect("Created writer");
+        for rid in 0..2 {
+            let mut record = writer.empty_record();
+            record.set_rid(Some(rid));
+            record.set_pos(11);
+            record.set_alleles(&[b"A", b"C"]).unwrap();
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+
+        let vcf_counts = |missing_contig| VcfCounts {
+            reads: vec![tumor.clone()],
+            vcf: vcf.clone(),
+            missing_contig,
+            ref_fasta: None,
+            output: Some(output.clone()),
+            output_type: "v".to_owned(),
+            threads: 1,
+            chunksize: None,
+            include_flags: 0,
+            exclude_flags: 0,
+            mate_fix: false,
+            min_mapq: 0,
+            filter: None,
+            min_base_quality: None,
+            ref_cache_size: 10,
+        };
+        let err = format!("{:#}", vcf_counts(MissingContig::Error).run().unwrap_err());
+        assert!(err.contains("chrZ (record 2)"), "{}", err);
+
+        vcf_counts(MissingContig::Skip).run().unwrap();
+        let mut reader = bcf::Reader::from_path(&output).unwrap();
+        let ad: Vec<Vec<i32>> = reader
+            .records()
+            .map(|record| {
+                let mut record = record.unwrap();
+                let ad = record.format(b"AD").integer().unwrap();
+                ad[0].to_vec()
+            })
+            .collect();
+        // Skipped sites are kept, with missing counts
+        assert_eq!(ad, vec![vec![1, 0], vec![i32::missing(), i32::missing()]]);
+    }
+}
@@ -0,0 +1,268 @@
+//! Writing BGZF compressed output and building tabix indexes for it via htslib.
+use anyhow::{Context, Error, Result};
+use rust_htslib::htslib;
+use std::{
+    ffi::CString,
+    io::{self, BufWriter, Write},
+    os::raw::{c_int, c_void},
+    path::Path,
+};
+
+/// A [`Write`] implementation that BGZF compresses everything written to it.
+///
+/// Call [`BgzfWriter::finish`] once everything is written to close the file and check that the
+/// last block and the EOF marker made it out. Dropping the writer closes it too, but any error is
+/// lost.
+#[derive(Debug)]
+pub struct BgzfWriter {
+    inner: *mut htslib::BGZF,
+}
+
+// The BGZF handle is only ever accessed through `&mut self`.
+unsafe impl Send for BgzfWriter {}
+
+impl BgzfWriter {
+    /// Open a BGZF writer to a file, or to stdout if the path is `-`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the file to write to
+    /// * `compression_level` - the compression level, 0-9
+    /// * `threads` - the number of threads to use for compression
+    pub fn from_path<P: AsRef<Path>>(
+        path: P,
+        compression_level: u32,
+        threads: usize,
+    ) -> Result<Self> {
+        if compression_level > 9 {
+            return Err(Error::msg(format!(
+                "Invalid compression level {}, must be 0-9",
+                compression_level
+            )));
+        }
+        let c_path = path_to_cstring(path.as_ref())?;
+        let mode = CString::new(format!("w{}", compression_level)).unwrap();
+        let inner = unsafe { htslib::bgzf_open(c_path.as_ptr(), mode.as_ptr()) };
+        if inner.is_null() {
+            return Err(Error::msg(format!(
+                "Failed to open {:?} for BGZF writing",
+                path.as_ref()
+            )));
+        }
+        let writer = Self { inner };
+        if threads > 1 && unsafe { htslib::bgzf_mt(writer.inner, threads as c_int, 256) } != 0 {
+            return Err(Error::msg("Failed to set BGZF compression threads"));
+        }
+        Ok(writer)
+    }
+
+    /// Flush any buffered data and close the file, failing if it could not be written.
+    pub fn finish(mut self) -> Result<()> {
+        let inner = std::mem::replace(&mut self.inner, std::ptr::null_mut());
+        if unsafe { htslib::bgzf_close(inner) } != 0 {
+            Err(Error::msg("Failed to close BGZF output"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Write for BgzfWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = unsafe {
+            htslib::bgzf_write(self.inner, buf.as_ptr() as *const c_void, buf.len() as _)
+        };
+        if written < 0 {
+            Err(io::Error::other("Failed to write BGZF data"))
+        } else {
+            Ok(written as usize)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { htslib::bgzf_flush(self.inner) } != 0 {
+            Err(io::Error::other("Failed to flush BGZF data"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for BgzfWriter {
+    fn drop(&mut self) {
+        // Best effort, `finish` has already closed the file if it was called
+        if !self.inner.is_null() {
+            unsafe {
+                htslib::bgzf_close(self.inner);
+            }
+        }
+    }
+}
+
+/// An output that is either written as is or BGZF compressed.
+pub enum Output {
+    /// Uncompressed output, such as a file or stdout
+    Plain(Box<dyn Write>),
+    /// BGZF compressed output
+    Bgzf(BufWriter<BgzfWriter>),
+}
+
+impl Output {
+    /// Flush the output, and close it if it is BGZF compressed, failing if it could not be written.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Output::Plain(mut writer) => writer.flush()?,
+            Output::Bgzf(writer) => writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .finish()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Bgzf(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Bgzf(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The columns tabix will use to index a file.
+#[derive(Debug, Clone, Copy)]
+pub struct TabixColumns {
+    /// 1-based column holding the sequence name.
+    pub seq: usize,
+    /// 1-based column holding the start position.
+    pub begin: usize,
+    /// Optional 1-based column holding the end position. If `None`, each line covers a single base.
+    pub end: Option<usize>,
+    /// Whether the start position is 0-based, as in a BED file, or 1-based.
+    pub zero_based: bool,
+    /// The number of header lines to skip.
+    pub skip_lines: usize,
+}
+
+/// Build a tabix index for a BGZF compressed file.
+///
+/// A `.tbi` index is created next to the file, or a `.csi` index if `csi` is true. CSI indexes are
+/// needed for contigs longer than 2^29 bases.
+pub fn build_tabix_index<P: AsRef<Path>>(
+    path: P,
+    columns: TabixColumns,
+    csi: bool,
+    threads: usize,
+) -> Result<()> {
+    let c_path = path_to_cstring(path.as_ref())?;
+    let conf = htslib::tbx_conf_t {
+        preset: if columns.zero_based {
+            htslib::TBX_UCSC as i32
+        } else {
+            htslib::TBX_GENERIC as i32
+        },
+        sc: columns.seq as i32,
+        bc: columns.begin as i32,
+        ec: columns.end.unwrap_or(0) as i32,
+        meta_char: '#' as i32,
+        line_skip: columns.skip_lines as i32,
+    };
+    // A min_shift of 0 builds a TBI index, 14 is the default for CSI
+    let min_shift = if csi { 14 } else { 0 };
+    let result = unsafe {
+        htslib::tbx_index_build3(
+            c_path.as_ptr(),
+            std::ptr::null(),
+            min_shift,
+            threads as c_int,
+            &conf,
+        )
+    };
+    if result != 0 {
+        Err(Error::msg(format!(
+            "Failed to build tabix index for {:?}",
+            path.as_ref()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Convert a path into a C string for htslib.
+fn path_to_cstring(path: &Path) -> Result<CString> {
+    path.to_str()
+        .and_then(|p| CString::new(p).ok())
+        .with_context(|| format!("Invalid path {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::tbx::{self, Read};
+    use tempfile::tempdir;
+
+    #[test]
+    fn write_and_index() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.tsv.gz");
+
+        // NB: no header line so that htslib detects this as a BED-like file for reading back
+        let mut writer = BgzfWriter::from_path(&path, 6, 2).unwrap();
+        writer.write_all(b"chr1\t0\t10\t1\n").unwrap();
+        writer.write_all(b"chr1\t10\t20\t2\n").unwrap();
+        writer.write_all(b"chr2\t0\t20\t3\n").unwrap();
+        writer.finish().unwrap();
+
+        let columns = TabixColumns {
+            seq: 1,
+            begin: 2,
+            end: Some(3),
+            zero_based: true,
+            skip_lines: 0,
+        };
+        build_tabix_index(&path, columns, false, 1).unwrap();
+        assert!(tempdir.path().join("test.tsv.gz.tbi").exists());
+
+        let mut reader = tbx::Reader::from_path(&path).unwrap();
+        let tid = reader.tid("chr1").unwrap();
+        reader.fetch(tid, 12, 15).unwrap();
+        let lines: Vec<Vec<u8>> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(lines, vec![b"chr1\t10\t20\t2".to_vec()]);
+    }
+
+    #[test]
+    fn csi_index() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.tsv.gz");
+
+        let mut writer = BgzfWriter::from_path(&path, 1, 1).unwrap();
+        writer.write_all(b"REF\tPOS\tDEPTH\n").unwrap();
+        writer.write_all(b"chr1\t1\t1\n").unwrap();
+        // Dropping the writer closes it as well
+        drop(writer);
+
+        let columns = TabixColumns {
+            seq: 1,
+            begin: 2,
+            end: None,
+            zero_based: false,
+            skip_lines: 1,
+        };
+        build_tabix_index(&path, columns, true, 1).unwrap();
+        assert!(tempdir.path().join("test.tsv.gz.csi").exists());
+    }
+
+    #[test]
+    fn bad_compression_level() {
+        let tempdir = tempdir().unwrap();
+        assert!(BgzfWriter::from_path(tempdir.path().join("test.gz"), 10, 1).is_err());
+    }
+}
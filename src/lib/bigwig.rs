@@ -0,0 +1,1007 @@
+//! Writing bigWig files from a sorted stream of bedGraph style ranges.
+//!
+//! The writer follows the bigWig format of the UCSC genome browser tools: a header, a B+ tree of
+//! contig names, zlib compressed sections of bedGraph records, and an R tree index over the
+//! sections. Zoom levels summarizing the records over increasingly large bins follow, each with
+//! its own R tree index, so that genome browsers can show zoomed out views without reading the
+//! full resolution data.
+use anyhow::{Context, Error, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const INDEX_MAGIC: u32 = 0x2468_ACE0;
+/// The bbi format version, 4 is the first version with a total summary.
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+/// The number of zoom level headers reserved after the header, as in the UCSC tools.
+const MAX_ZOOM_LEVELS: usize = 10;
+/// The total summary follows the zoom level headers.
+const SUMMARY_OFFSET: u64 = HEADER_SIZE + MAX_ZOOM_LEVELS as u64 * ZOOM_HEADER_SIZE;
+const SUMMARY_SIZE: u64 = 40;
+/// The bin size of the first zoom level, in multiples of the mean record length.
+const ZOOM_INITIAL_FACTOR: u32 = 10;
+/// The bin size of each zoom level, in multiples of the bin size of the level before it.
+const ZOOM_INCREMENT: u32 = 4;
+/// The maximum number of records in a compressed section.
+const ITEMS_PER_SLOT: usize = 1024;
+/// The maximum number of children of an R tree node.
+const INDEX_BLOCK_SIZE: usize = 256;
+/// The maximum number of children of a chromosome B+ tree node.
+const CHROM_BLOCK_SIZE: usize = 256;
+/// The section type for bedGraph records.
+const BEDGRAPH_TYPE: u8 = 1;
+
+/// The bounds of a section or index node: start contig and base, end contig and base.
+type Bounds = (u32, u32, u32, u32);
+
+/// A bedGraph record: start, end, and value.
+type Record = (u32, u32, f32);
+
+/// The summary of the records in one bin of a zoom level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ZoomRecord {
+    id: u32,
+    /// The start of the first base with a value
+    start: u32,
+    /// The end of the last base with a value
+    end: u32,
+    /// The number of bases with a value
+    bases: u32,
+    min: f32,
+    max: f32,
+    sum: f32,
+    sum_squares: f32,
+}
+
+impl ZoomRecord {
+    /// Serialize the record as it is stored in a zoom level block.
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        let fields = [
+            self.id.to_le_bytes(),
+            self.start.to_le_bytes(),
+            self.end.to_le_bytes(),
+            self.bases.to_le_bytes(),
+            self.min.to_le_bytes(),
+            self.max.to_le_bytes(),
+            self.sum.to_le_bytes(),
+            self.sum_squares.to_le_bytes(),
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(field);
+        }
+        bytes
+    }
+}
+
+/// Summarizes records into bins of `reduction` bases, starting at the start of each contig.
+#[derive(Debug)]
+struct ZoomLevel {
+    reduction: u32,
+    /// The summary of the bin being filled
+    current: Option<ZoomRecord>,
+}
+
+impl ZoomLevel {
+    fn new(reduction: u32) -> Self {
+        Self {
+            reduction,
+            current: None,
+        }
+    }
+
+    /// Add a record, passing the summary of each bin that is complete to `done`. Records must be
+    /// added in order.
+    fn push<F: FnMut(ZoomRecord) -> Result<()>>(
+        &mut self,
+        id: u32,
+        (start, end, value): Record,
+        done: &mut F,
+    ) -> Result<()> {
+        let reduction = u64::from(self.reduction);
+        let mut start = start;
+        while start < end {
+            let bin = u64::from(start) / reduction;
+            let piece_end = end.min(((bin + 1) * reduction).min(u64::from(u32::MAX)) as u32);
+            let bases = piece_end - start;
+            let same_bin = self
+                .current
+                .is_some_and(|zoom| zoom.id == id && u64::from(zoom.start) / reduction == bin);
+            if !same_bin {
+                if let Some(zoom) = self.current.take() {
+                    done(zoom)?;
+                }
+                self.current = Some(ZoomRecord {
+                    id,
+                    start,
+                    end: piece_end,
+                    bases: 0,
+                    min: value,
+                    max: value,
+                    sum: 0.0,
+                    sum_squares: 0.0,
+                });
+            }
+            let zoom = self.current.as_mut().unwrap();
+            zoom.end = piece_end;
+            zoom.bases += bases;
+            zoom.min = zoom.min.min(value);
+            zoom.max = zoom.max.max(value);
+            zoom.sum += value * bases as f32;
+            zoom.sum_squares += value * value * bases as f32;
+            start = piece_end;
+        }
+        Ok(())
+    }
+
+    /// Pass the summary of the last bin to `done`.
+    fn finish<F: FnMut(ZoomRecord) -> Result<()>>(&mut self, done: &mut F) -> Result<()> {
+        match self.current.take() {
+            Some(zoom) => done(zoom),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A compressed section of records that has been written to the file.
+#[derive(Debug)]
+struct Section {
+    bounds: Bounds,
+    offset: u64,
+    size: u64,
+}
+
+/// Summary statistics over every base with a value, stored in the header.
+#[derive(Debug, Default)]
+struct Summary {
+    bases: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+/// Writes a bigWig file from bedGraph style ranges.
+///
+/// Ranges must be added in the order of the contigs given to [`BigWigWriter::from_path`], sorted
+/// by start and not overlapping within a contig. The file is only valid once [`BigWigWriter::finish`]
+/// has been called.
+///
+/// # Example
+/// ```no_run
+/// use perbase_lib::bigwig::BigWigWriter;
+///
+/// let contigs = vec![(String::from("chr1"), 1000), (String::from("chr2"), 500)];
+/// let mut writer = BigWigWriter::from_path("depth.bw", contigs).unwrap();
+/// writer.push("chr1", 0, 10, 1.0).unwrap();
+/// writer.push("chr1", 10, 20, 2.0).unwrap();
+/// writer.push("chr2", 5, 100, 0.5).unwrap();
+/// writer.finish().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct BigWigWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// The current offset in the file
+    offset: u64,
+    contigs: Vec<(String, u32)>,
+    contig_ids: HashMap<String, u32>,
+    full_data_offset: u64,
+    /// The contig and records of the section being built
+    current: Option<(u32, Vec<Record>)>,
+    /// The end of the last record, to check that records are sorted
+    last_end: Option<(u32, u32)>,
+    sections: Vec<Section>,
+    /// The largest uncompressed size of a section or zoom level block
+    max_section_size: usize,
+    summary: Summary,
+    /// The number of records added
+    records: u64,
+}
+
+impl BigWigWriter {
+    /// Create a bigWig file for the given contigs, as (name, length) pairs in the order that ranges
+    /// will be added.
+    pub fn from_path<P: AsRef<Path>>(path: P, contigs: Vec<(String, u32)>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create bigWig file {:?}", path))?;
+        let contig_ids = contigs
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (name.clone(), id as u32))
+            .collect();
+        let mut writer = Self {
+            path,
+            file: BufWriter::new(file),
+            offset: 0,
+            contigs,
+            contig_ids,
+            full_data_offset: 0,
+            current: None,
+            last_end: None,
+            sections: vec![],
+            max_section_size: 0,
+            summary: Summary::default(),
+            records: 0,
+        };
+        // The header, zoom level headers, and summary are filled in by finish
+        writer.write(&[0; (SUMMARY_OFFSET + SUMMARY_SIZE) as usize])?;
+        let chrom_tree = chrom_tree(&writer.contigs, writer.offset);
+        writer.write(&chrom_tree)?;
+        writer.full_data_offset = writer.offset;
+        // The number of sections, filled in by finish
+        writer.write(&0u64.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    /// Add a range with a value, using 0-based half-open coordinates.
+    pub fn push(&mut self, contig: &str, start: u32, end: u32, value: f32) -> Result<()> {
+        let id = *self
+            .contig_ids
+            .get(contig)
+            .with_context(|| format!("Contig {} is not in the bigWig header", contig))?;
+        let length = self.contigs[id as usize].1;
+        if start >= end || end > length {
+            return Err(Error::msg(format!(
+                "Invalid bigWig range {}:{}-{} for contig of length {}",
+                contig, start, end, length
+            )));
+        }
+        if let Some((last_id, last_end)) = self.last_end {
+            if id < last_id || (id == last_id && start < last_end) {
+                return Err(Error::msg(format!(
+                    "bigWig ranges must be sorted, got {}:{}-{} after {}:{}",
+                    contig, start, end, self.contigs[last_id as usize].0, last_end
+                )));
+            }
+        }
+        self.last_end = Some((id, end));
+
+        let bases = u64::from(end - start);
+        let value_f64 = f64::from(value);
+        if self.summary.bases == 0 {
+            self.summary.min = value_f64;
+            self.summary.max = value_f64;
+        }
+        self.summary.bases += bases;
+        self.summary.min = self.summary.min.min(value_f64);
+        self.summary.max = self.summary.max.max(value_f64);
+        self.summary.sum += value_f64 * bases as f64;
+        self.summary.sum_squares += value_f64 * value_f64 * bases as f64;
+        self.records += 1;
+
+        let full = match &self.current {
+            Some((current_id, records)) => *current_id != id || records.len() == ITEMS_PER_SLOT,
+            None => false,
+        };
+        if full {
+            self.write_section()?;
+        }
+        self.current
+            .get_or_insert_with(|| (id, vec![]))
+            .1
+            .push((start, end, value));
+        Ok(())
+    }
+
+    /// Write the last section, the index, the zoom levels, and the header, completing the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.write_section()?;
+        let index_offset = self.offset;
+        let index = index(&self.sections, index_offset);
+        self.write(&index)?;
+        let zoom_levels = self.write_zoom_levels()?;
+
+        let mut header = Vec::with_capacity((SUMMARY_OFFSET + SUMMARY_SIZE) as usize);
+        header.extend_from_slice(&BIGWIG_MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(zoom_levels.len() as u16).to_le_bytes());
+        // The chromosome tree follows the summary
+        header.extend_from_slice(&(SUMMARY_OFFSET + SUMMARY_SIZE).to_le_bytes());
+        header.extend_from_slice(&self.full_data_offset.to_le_bytes());
+        header.extend_from_slice(&index_offset.to_le_bytes());
+        // Field count and defined field count, only used by bigBed
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        // AutoSql offset
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&SUMMARY_OFFSET.to_le_bytes());
+        header.extend_from_slice(&(self.max_section_size as u32).to_le_bytes());
+        // Extension offset
+        header.extend_from_slice(&0u64.to_le_bytes());
+        // Reduction level, reserved, data offset, and index offset of each zoom level, the unused
+        // headers are left as zeros
+        for (reduction, data_offset, index_offset) in zoom_levels.iter() {
+            header.extend_from_slice(&reduction.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&data_offset.to_le_bytes());
+            header.extend_from_slice(&index_offset.to_le_bytes());
+        }
+        header.resize(SUMMARY_OFFSET as usize, 0);
+        header.extend_from_slice(&self.summary.bases.to_le_bytes());
+        header.extend_from_slice(&self.summary.min.to_le_bytes());
+        header.extend_from_slice(&self.summary.max.to_le_bytes());
+        header.extend_from_slice(&self.summary.sum.to_le_bytes());
+        header.extend_from_slice(&self.summary.sum_squares.to_le_bytes());
+
+        self.write_at(0, &header)?;
+        let section_count = (self.sections.len() as u64).to_le_bytes();
+        self.write_at(self.full_data_offset, &section_count)?;
+        let path = &self.path;
+        self.file
+            .flush()
+            .with_context(|| format!("Failed to write bigWig file {:?}", path))?;
+        Ok(())
+    }
+
+    /// Write the zoom levels, returning the reduction level, data offset, and index offset of each.
+    ///
+    /// The first level has bins of [`ZOOM_INITIAL_FACTOR`] times the mean record length, and each
+    /// level after it bins [`ZOOM_INCREMENT`] times as many bases. Only levels with at most half as
+    /// many records as the level below them are written, up to [`MAX_ZOOM_LEVELS`]. The records
+    /// are read back from the sections already written, so no more than a block of zoom records
+    /// is held in memory.
+    fn write_zoom_levels(&mut self) -> Result<Vec<(u32, u64, u64)>> {
+        if self.records == 0 {
+            return Ok(vec![]);
+        }
+        let mean_len = (self.summary.bases / self.records).max(1);
+        let first = mean_len.saturating_mul(u64::from(ZOOM_INITIAL_FACTOR));
+        let reductions = std::iter::successors(Some(first), |reduction| {
+            reduction.checked_mul(u64::from(ZOOM_INCREMENT))
+        })
+        .take_while(|reduction| *reduction <= u64::from(u32::MAX))
+        .take(MAX_ZOOM_LEVELS);
+
+        // Count the records of every candidate level in one pass
+        let mut levels: Vec<ZoomLevel> = reductions
+            .map(|reduction| ZoomLevel::new(reduction as u32))
+            .collect();
+        let mut counts = vec![0u64; levels.len()];
+        self.read_records(|id, record| {
+            for (level, count) in levels.iter_mut().zip(counts.iter_mut()) {
+                level.push(id, record, &mut |_| {
+                    *count += 1;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+        for (level, count) in levels.iter_mut().zip(counts.iter_mut()) {
+            level.finish(&mut |_| {
+                *count += 1;
+                Ok(())
+            })?;
+        }
+        let mut below = self.records;
+        let kept: Vec<u32> = levels
+            .iter()
+            .zip(counts)
+            .take_while(|(_, count)| {
+                let keep = count * 2 <= below;
+                below = *count;
+                keep
+            })
+            .map(|(level, _)| level.reduction)
+            .collect();
+
+        let mut headers = vec![];
+        for reduction in kept {
+            let data_offset = self.offset;
+            // The number of zoom records, filled in once they are written
+            self.write(&0u32.to_le_bytes())?;
+            let mut level = ZoomLevel::new(reduction);
+            let mut blocks = vec![];
+            let mut block: Vec<ZoomRecord> = vec![];
+            let mut count = 0u32;
+            let mut done = |zoom: ZoomRecord, writer: &mut Self| -> Result<()> {
+                if block.first().is_some_and(|first| first.id != zoom.id)
+                    || block.len() == ITEMS_PER_SLOT
+                {
+                    blocks.push(writer.write_zoom_block(&block)?);
+                    block.clear();
+                }
+                block.push(zoom);
+                count += 1;
+                Ok(())
+            };
+            // The sections are read while zoom blocks are written after them
+            let (path, sections) = (self.path.clone(), std::mem::take(&mut self.sections));
+            let result = Self::read_sections(&path, &sections, |id, record| {
+                level.push(id, record, &mut |zoom| done(zoom, self))
+            })
+            .and_then(|_| level.finish(&mut |zoom| done(zoom, self)));
+            self.sections = sections;
+            result?;
+            if !block.is_empty() {
+                blocks.push(self.write_zoom_block(&block)?);
+            }
+            self.write_at(data_offset, &count.to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(self.offset))?;
+
+            let index_offset = self.offset;
+            let index = index(&blocks, index_offset);
+            self.write(&index)?;
+            headers.push((reduction, data_offset, index_offset));
+        }
+        Ok(headers)
+    }
+
+    /// Compress and write a block of zoom records, returning where it was written.
+    fn write_zoom_block(&mut self, block: &[ZoomRecord]) -> Result<Section> {
+        let mut data = Vec::with_capacity(block.len() * 32);
+        for zoom in block {
+            data.extend_from_slice(&zoom.to_bytes());
+        }
+        self.max_section_size = self.max_section_size.max(data.len());
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+        let (first, last) = (block[0], block[block.len() - 1]);
+        let section = Section {
+            bounds: (first.id, first.start, last.id, last.end),
+            offset: self.offset,
+            size: compressed.len() as u64,
+        };
+        self.write(&compressed)?;
+        Ok(section)
+    }
+
+    /// Read back every record that has been written, in order, passing each to `f` along with
+    /// the id of its contig.
+    fn read_records<F: FnMut(u32, Record) -> Result<()>>(&mut self, f: F) -> Result<()> {
+        let path = &self.path;
+        self.file
+            .flush()
+            .with_context(|| format!("Failed to write bigWig file {:?}", path))?;
+        Self::read_sections(&self.path, &self.sections, f)
+    }
+
+    /// Read the records of written sections back from the file at `path`, see
+    /// [`BigWigWriter::read_records`].
+    fn read_sections<F: FnMut(u32, Record) -> Result<()>>(
+        path: &Path,
+        sections: &[Section],
+        mut f: F,
+    ) -> Result<()> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to read back bigWig file {:?}", path))?;
+        let mut compressed = vec![];
+        let mut data = vec![];
+        for section in sections {
+            compressed.resize(section.size as usize, 0);
+            data.clear();
+            file.seek(SeekFrom::Start(section.offset))
+                .and_then(|_| file.read_exact(&mut compressed))
+                .and_then(|_| ZlibDecoder::new(&compressed[..]).read_to_end(&mut data))
+                .with_context(|| format!("Failed to read back bigWig file {:?}", path))?;
+            // Skip the section header, the records are 12 bytes each
+            for record in data[24..].chunks_exact(12) {
+                let field = |i: usize| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&record[i * 4..(i + 1) * 4]);
+                    bytes
+                };
+                let record = (
+                    u32::from_le_bytes(field(0)),
+                    u32::from_le_bytes(field(1)),
+                    f32::from_le_bytes(field(2)),
+                );
+                f(section.bounds.0, record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compress and write the current section, if it has any records.
+    fn write_section(&mut self) -> Result<()> {
+        let (id, records) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let start = records[0].0;
+        let end = records[records.len() - 1].1;
+        let mut data = Vec::with_capacity(24 + records.len() * 12);
+        data.extend_from_slice(&id.to_le_bytes());
+        data.extend_from_slice(&start.to_le_bytes());
+        data.extend_from_slice(&end.to_le_bytes());
+        // Item step and span, only used by fixed and variable step sections
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.push(BEDGRAPH_TYPE);
+        data.push(0);
+        data.extend_from_slice(&(records.len() as u16).to_le_bytes());
+        for (start, end, value) in records.iter() {
+            data.extend_from_slice(&start.to_le_bytes());
+            data.extend_from_slice(&end.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        self.max_section_size = self.max_section_size.max(data.len());
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+        self.sections.push(Section {
+            bounds: (id, start, id, end),
+            offset: self.offset,
+            size: compressed.len() as u64,
+        });
+        self.write(&compressed)
+    }
+
+    /// Overwrite bytes at an earlier offset.
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+        let path = &self.path;
+        let file = &mut self.file;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(bytes))
+            .with_context(|| format!("Failed to write bigWig file {:?}", path))
+    }
+
+    /// Write bytes at the current offset.
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file
+            .write_all(bytes)
+            .with_context(|| format!("Failed to write bigWig file {:?}", self.path))?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// Build the B+ tree mapping contig names to their ids and lengths, to be written at `offset`.
+fn chrom_tree(contigs: &[(String, u32)], offset: u64) -> Vec<u8> {
+    let mut items: Vec<(&[u8], u32, u32)> = contigs
+        .iter()
+        .enumerate()
+        .map(|(id, (name, length))| (name.as_bytes(), id as u32, *length))
+        .collect();
+    items.sort_unstable();
+    let key_size = items
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(1);
+    let block_size = items.len().clamp(1, CHROM_BLOCK_SIZE);
+    // Leaf items hold an id and length and index items hold an offset, both 8 bytes
+    let item_size = key_size + 8;
+    let node_size = (4 + block_size * item_size) as u64;
+
+    let mut tree = vec![];
+    tree.extend_from_slice(&CHROM_TREE_MAGIC.to_le_bytes());
+    tree.extend_from_slice(&(block_size as u32).to_le_bytes());
+    tree.extend_from_slice(&(key_size as u32).to_le_bytes());
+    tree.extend_from_slice(&8u32.to_le_bytes());
+    tree.extend_from_slice(&(items.len() as u64).to_le_bytes());
+    tree.extend_from_slice(&0u64.to_le_bytes());
+    let key = |tree: &mut Vec<u8>, name: &[u8]| {
+        tree.extend_from_slice(name);
+        tree.resize(tree.len() + key_size - name.len(), 0);
+    };
+
+    let mut levels = 1;
+    let mut nodes = items.len();
+    while nodes > block_size {
+        nodes = nodes.div_ceil(block_size);
+        levels += 1;
+    }
+    // Index levels from the root down, each child pointing into the level below
+    let mut level_offset = offset + tree.len() as u64;
+    for level in (1..levels).rev() {
+        let items_per_child = block_size.pow(level as u32);
+        let items_per_node = items_per_child * block_size;
+        let node_count = items.len().div_ceil(items_per_node);
+        let mut child_offset = level_offset + node_count as u64 * node_size;
+        for node in 0..node_count {
+            let children: Vec<usize> = (node * items_per_node
+                ..items.len().min((node + 1) * items_per_node))
+                .step_by(items_per_child)
+                .collect();
+            tree.push(0);
+            tree.push(0);
+            tree.extend_from_slice(&(children.len() as u16).to_le_bytes());
+            for child in children.iter() {
+                key(&mut tree, items[*child].0);
+                tree.extend_from_slice(&child_offset.to_le_bytes());
+                child_offset += node_size;
+            }
+            tree.resize(tree.len() + (block_size - children.len()) * item_size, 0);
+        }
+        level_offset += node_count as u64 * node_size;
+    }
+    // Leaves, always at least one even if empty
+    let leaves: Vec<&[(&[u8], u32, u32)]> = if items.is_empty() {
+        vec![&[]]
+    } else {
+        items.chunks(block_size).collect()
+    };
+    for leaf in leaves {
+        tree.push(1);
+        tree.push(0);
+        tree.extend_from_slice(&(leaf.len() as u16).to_le_bytes());
+        for (name, id, length) in leaf.iter() {
+            key(&mut tree, name);
+            tree.extend_from_slice(&id.to_le_bytes());
+            tree.extend_from_slice(&length.to_le_bytes());
+        }
+        tree.resize(tree.len() + (block_size - leaf.len()) * item_size, 0);
+    }
+    tree
+}
+
+/// Build the R tree index over the sections, to be written at `offset`, right after the data.
+fn index(sections: &[Section], offset: u64) -> Vec<u8> {
+    const LEAF_ITEM_SIZE: usize = 32;
+    const NODE_ITEM_SIZE: usize = 24;
+    let node_size = |level: usize| {
+        let item_size = if level == 0 {
+            LEAF_ITEM_SIZE
+        } else {
+            NODE_ITEM_SIZE
+        };
+        (4 + INDEX_BLOCK_SIZE * item_size) as u64
+    };
+    let span = |bounds: &[Bounds]| match (bounds.first(), bounds.last()) {
+        (Some(first), Some(last)) => (first.0, first.1, last.2, last.3),
+        _ => (0, 0, 0, 0),
+    };
+
+    // The bounds of the nodes at each level, from the leaves up to a single root
+    let section_bounds: Vec<Bounds> = sections.iter().map(|s| s.bounds).collect();
+    let mut levels: Vec<Vec<Bounds>> = vec![if sections.is_empty() {
+        vec![(0, 0, 0, 0)]
+    } else {
+        section_bounds.chunks(INDEX_BLOCK_SIZE).map(span).collect()
+    }];
+    while levels[levels.len() - 1].len() > 1 {
+        let above = levels[levels.len() - 1]
+            .chunks(INDEX_BLOCK_SIZE)
+            .map(span)
+            .collect();
+        levels.push(above);
+    }
+
+    let mut index = vec![];
+    let (start_id, start, end_id, end) = span(&section_bounds);
+    index.extend_from_slice(&INDEX_MAGIC.to_le_bytes());
+    index.extend_from_slice(&(INDEX_BLOCK_SIZE as u32).to_le_bytes());
+    index.extend_from_slice(&(sections.len() as u64).to_le_bytes());
+    for value in [start_id, start, end_id, end].iter() {
+        index.extend_from_slice(&value.to_le_bytes());
+    }
+    // The end of the data is the start of the index
+    index.extend_from_slice(&offset.to_le_bytes());
+    index.extend_from_slice(&(ITEMS_PER_SLOT as u32).to_le_bytes());
+    index.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut level_offsets = vec![0; levels.len()];
+    let mut level_offset = offset + index.len() as u64;
+    for level in (0..levels.len()).rev() {
+        level_offsets[level] = level_offset;
+        level_offset += levels[level].len() as u64 * node_size(level);
+    }
+    for level in (1..levels.len()).rev() {
+        for (node, children) in levels[level - 1].chunks(INDEX_BLOCK_SIZE).enumerate() {
+            index.push(0);
+            index.push(0);
+            index.extend_from_slice(&(children.len() as u16).to_le_bytes());
+            for (i, bounds) in children.iter().enumerate() {
+                let child = (node * INDEX_BLOCK_SIZE + i) as u64;
+                for value in [bounds.0, bounds.1, bounds.2, bounds.3].iter() {
+                    index.extend_from_slice(&value.to_le_bytes());
+                }
+                let child_offset = level_offsets[level - 1] + child * node_size(level - 1);
+                index.extend_from_slice(&child_offset.to_le_bytes());
+            }
+            index.resize(
+                index.len() + (INDEX_BLOCK_SIZE - children.len()) * NODE_ITEM_SIZE,
+                0,
+            );
+        }
+    }
+    let leaves: Vec<&[Section]> = if sections.is_empty() {
+        vec![&[]]
+    } else {
+        sections.chunks(INDEX_BLOCK_SIZE).collect()
+    };
+    for leaf in leaves {
+        index.push(1);
+        index.push(0);
+        index.extend_from_slice(&(leaf.len() as u16).to_le_bytes());
+        for section in leaf.iter() {
+            let (start_id, start, end_id, end) = section.bounds;
+            for value in [start_id, start, end_id, end].iter() {
+                index.extend_from_slice(&value.to_le_bytes());
+            }
+            index.extend_from_slice(&section.offset.to_le_bytes());
+            index.extend_from_slice(&section.size.to_le_bytes());
+        }
+        index.resize(
+            index.len() + (INDEX_BLOCK_SIZE - leaf.len()) * LEAF_ITEM_SIZE,
+            0,
+        );
+    }
+    index
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::{convert::TryInto, io::Read};
+    use tempfile::tempdir;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn f64_at(bytes: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Read all records back through the chromosome tree and the index, as a reader would.
+    fn read_bigwig(bytes: &[u8]) -> Vec<(String, u32, u32, f32)> {
+        assert_eq!(u32_at(bytes, 0), BIGWIG_MAGIC);
+        let chrom_tree = u64_at(bytes, 8) as usize;
+        let index = u64_at(bytes, 24) as usize;
+
+        // Walk the chromosome tree down its first child at each level, then along the leaves
+        assert_eq!(u32_at(bytes, chrom_tree), CHROM_TREE_MAGIC);
+        let block_size = u32_at(bytes, chrom_tree + 4) as usize;
+        let key_size = u32_at(bytes, chrom_tree + 8) as usize;
+        let item_count = u64_at(bytes, chrom_tree + 16) as usize;
+        let mut node = chrom_tree + 32;
+        while bytes[node] == 0 {
+            node = u64_at(bytes, node + 4 + key_size) as usize;
+        }
+        let mut names = HashMap::new();
+        while names.len() < item_count {
+            assert_eq!(bytes[node], 1);
+            for i in 0..u16_at(bytes, node + 2) as usize {
+                let item = node + 4 + i * (key_size + 8);
+                let name = String::from_utf8(bytes[item..item + key_size].to_vec()).unwrap();
+                names.insert(
+                    u32_at(bytes, item + key_size),
+                    name.trim_end_matches('\0').to_owned(),
+                );
+            }
+            node += 4 + block_size * (key_size + 8);
+        }
+
+        let mut records = vec![];
+        for data in read_index(bytes, index) {
+            let name = &names[&u32_at(&data, 0)];
+            assert_eq!(data[20], BEDGRAPH_TYPE);
+            for j in 0..u16_at(&data, 22) as usize {
+                let record = 24 + j * 12;
+                records.push((
+                    name.clone(),
+                    u32_at(&data, record),
+                    u32_at(&data, record + 4),
+                    f32_at(&data, record + 8),
+                ));
+            }
+        }
+        records
+    }
+
+    /// Read every zoom level back through its index, as its reduction level and records.
+    fn read_zoom_levels(bytes: &[u8]) -> Vec<(u32, Vec<ZoomRecord>)> {
+        (0..u16_at(bytes, 6) as usize)
+            .map(|level| {
+                let header = (HEADER_SIZE + level as u64 * ZOOM_HEADER_SIZE) as usize;
+                let data_offset = u64_at(bytes, header + 8) as usize;
+                let mut records = vec![];
+                for data in read_index(bytes, u64_at(bytes, header + 16) as usize) {
+                    assert_eq!(data.len() % 32, 0);
+                    records.extend(data.chunks_exact(32).map(|zoom| ZoomRecord {
+                        id: u32_at(zoom, 0),
+                        start: u32_at(zoom, 4),
+                        end: u32_at(zoom, 8),
+                        bases: u32_at(zoom, 12),
+                        min: f32_at(zoom, 16),
+                        max: f32_at(zoom, 20),
+                        sum: f32_at(zoom, 24),
+                        sum_squares: f32_at(zoom, 28),
+                    }));
+                }
+                assert_eq!(u32_at(bytes, data_offset) as usize, records.len());
+                (u32_at(bytes, header), records)
+            })
+            .collect()
+    }
+
+    /// Decompress every block below the root of an R tree index, in order.
+    fn read_index(bytes: &[u8], index: usize) -> Vec<Vec<u8>> {
+        assert_eq!(u32_at(bytes, index), INDEX_MAGIC);
+        let mut blocks = vec![];
+        read_index_node(bytes, index + 48, &mut blocks);
+        // Every block fits in the buffer readers allocate for decompressing
+        let max_size = u32_at(bytes, 52) as usize;
+        assert!(blocks.iter().all(|block| block.len() <= max_size));
+        blocks
+    }
+
+    /// Decompress the blocks below an index node, in order.
+    fn read_index_node(bytes: &[u8], node: usize, blocks: &mut Vec<Vec<u8>>) {
+        for i in 0..u16_at(bytes, node + 2) as usize {
+            if bytes[node] == 0 {
+                let child = u64_at(bytes, node + 4 + i * 24 + 16) as usize;
+                read_index_node(bytes, child, blocks);
+                continue;
+            }
+            let item = node + 4 + i * 32;
+            let offset = u64_at(bytes, item + 16) as usize;
+            let size = u64_at(bytes, item + 24) as usize;
+            let mut data = vec![];
+            ZlibDecoder::new(&bytes[offset..offset + size])
+                .read_to_end(&mut data)
+                .unwrap();
+            blocks.push(data);
+        }
+    }
+
+    /// Summarize records into the bins of a zoom level one base at a time.
+    fn expected_zoom(records: &[(u32, u32, u32, f32)], reduction: u32) -> Vec<ZoomRecord> {
+        let mut zooms: Vec<ZoomRecord> = vec![];
+        for &(id, start, end, value) in records {
+            for pos in start..end {
+                match zooms.last_mut() {
+                    Some(zoom) if zoom.id == id && zoom.start / reduction == pos / reduction => {
+                        zoom.end = pos + 1;
+                        zoom.bases += 1;
+                        zoom.min = zoom.min.min(value);
+                        zoom.max = zoom.max.max(value);
+                        zoom.sum += value;
+                        zoom.sum_squares += value * value;
+                    }
+                    _ => zooms.push(ZoomRecord {
+                        id,
+                        start: pos,
+                        end: pos + 1,
+                        bases: 1,
+                        min: value,
+                        max: value,
+                        sum: value,
+                        sum_squares: value * value,
+                    }),
+                }
+            }
+        }
+        zooms
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.bw");
+        let contigs = vec![
+            (String::from("chr2"), 1_000_000),
+            (String::from("chr1"), 1_000_000),
+            (String::from("chrM"), 100),
+        ];
+        let mut writer = BigWigWriter::from_path(&path, contigs).unwrap();
+        let mut expected = vec![];
+        // Enough records for several sections and a multi level index
+        for i in 0..300_000 {
+            expected.push((String::from("chr2"), i * 2, i * 2 + 1, (i % 7) as f32));
+        }
+        expected.push((String::from("chr1"), 10, 20, 0.5));
+        expected.push((String::from("chrM"), 0, 100, 3.0));
+        for (name, start, end, value) in expected.iter() {
+            writer.push(name, *start, *end, *value).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(read_bigwig(&bytes), expected);
+        // Bases covered and min / max in the total summary
+        let summary = SUMMARY_OFFSET as usize;
+        assert_eq!(u64_at(&bytes, 44), SUMMARY_OFFSET);
+        assert_eq!(u64_at(&bytes, summary), 300_000 + 10 + 100);
+        assert!((f64_at(&bytes, summary + 8) - 0.0).abs() < f64::EPSILON);
+        assert!((f64_at(&bytes, summary + 16) - 6.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn zoom_levels() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.bw");
+        let contigs = vec![
+            (String::from("chr1"), 200_000),
+            (String::from("chr2"), 200_000),
+        ];
+        let mut writer = BigWigWriter::from_path(&path, contigs).unwrap();
+        // Records of 1 to 13 bases with gaps of 0 to 2 bases between them
+        let mut records = vec![];
+        for id in 0..2 {
+            let mut start = 5;
+            for i in 0..7_800 {
+                let end = start + i % 13 + 1;
+                records.push((id, start, end, (i % 5) as f32 + 0.5));
+                start = end + i % 3;
+            }
+        }
+        let names = ["chr1", "chr2"];
+        for (id, start, end, value) in records.iter() {
+            writer
+                .push(names[*id as usize], *start, *end, *value)
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let levels = read_zoom_levels(&bytes);
+        assert!(levels.len() > 2, "{} zoom levels", levels.len());
+        // The mean record length is 7, so the first level has bins of 70 bases
+        assert_eq!(levels[0].0, 70);
+        let mut below = records.len();
+        for (reduction, zooms) in levels.iter() {
+            assert!(zooms.len() * 2 <= below);
+            below = zooms.len();
+            let expected = expected_zoom(&records, *reduction);
+            assert_eq!(zooms.len(), expected.len(), "reduction {}", reduction);
+            for (zoom, expected) in zooms.iter().zip(expected) {
+                let close = |a: f32, b: f32| (a - b).abs() <= 1e-4 * b.abs().max(1.0);
+                assert_eq!(
+                    (zoom.id, zoom.start, zoom.end, zoom.bases),
+                    (expected.id, expected.start, expected.end, expected.bases)
+                );
+                assert_eq!((zoom.min, zoom.max), (expected.min, expected.max));
+                assert!(close(zoom.sum, expected.sum), "{:?} {:?}", zoom, expected);
+                assert!(close(zoom.sum_squares, expected.sum_squares));
+            }
+        }
+        for pair in levels.windows(2) {
+            assert_eq!(pair[1].0, pair[0].0 * ZOOM_INCREMENT);
+        }
+        // The full resolution data is unchanged
+        assert_eq!(read_bigwig(&bytes).len(), records.len());
+    }
+
+    #[test]
+    fn no_zoom_levels_without_records() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.bw");
+        let writer = BigWigWriter::from_path(&path, vec![(String::from("chr1"), 100)]).unwrap();
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u16_at(&bytes, 6), 0);
+        assert!(read_bigwig(&bytes).is_empty());
+    }
+
+    #[test]
+    fn unsorted_ranges_are_errors() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("test.bw");
+        let contigs = vec![(String::from("chr1"), 100), (String::from("chr2"), 100)];
+        let mut writer = BigWigWriter::from_path(&path, contigs).unwrap();
+        writer.push("chr2", 10, 20, 1.0).unwrap();
+        assert!(writer.push("chr1", 10, 20, 1.0).is_err());
+        assert!(writer.push("chr2", 15, 30, 1.0).is_err());
+        assert!(writer.push("chr2", 30, 101, 1.0).is_err());
+        assert!(writer.push("chr3", 0, 10, 1.0).is_err());
+        writer.push("chr2", 20, 30, 1.0).unwrap();
+        writer.finish().unwrap();
+    }
+}
@@ -0,0 +1,676 @@
+//! A small expression language for filtering reads.
+//!
+//! An expression such as `mapq >= 20 && !duplicate && tag(NM) <= 4 && tlen.abs() < 1000 && rg == "L1"`
+//! is parsed and type checked once by [`ExprReadFilter::new`], resolving every name up front, and
+//! the resulting tree is evaluated against each read without allocating.
+//!
+//! The language has:
+//!
+//! - flags, which are conditions on their own: `paired`, `proper_pair`, `unmapped`, `mate_unmapped`,
+//!   `reverse`, `mate_reverse`, `read1`, `read2`, `secondary`, `qcfail`, `duplicate`, `supplementary`
+//! - numbers: `mapq`, `flag`, `tlen`, `pos` and `mpos` (0-based), `end` (0-based, exclusive), `len`
+//!   (the length of the read sequence), and number literals
+//! - strings: `rg` (the `RG` tag), `qname`, and double quoted literals
+//! - `tag(XX)`, the value of any aux tag, which on its own is a condition that the tag is present
+//! - `.abs()` and unary `-` on numbers
+//! - the comparisons `==`, `!=`, `<`, `<=`, `>`, `>=`, where strings only support `==` and `!=`
+//! - `!`, `&&` and `||`, from tightest to loosest, and parentheses
+//!
+//! Comparisons with a tag that is missing, or that holds the other type of value, are false.
+use crate::read_filter::ReadFilter;
+use anyhow::{Error, Result};
+use rust_htslib::bam::{
+    ext::BamRecordExtensions,
+    record::{Aux, Record},
+};
+use std::{fmt, str::FromStr};
+
+/// The flags that can be used as conditions, by name.
+const FLAGS: [(&str, u16); 12] = [
+    ("paired", 0x1),
+    ("proper_pair", 0x2),
+    ("unmapped", 0x4),
+    ("mate_unmapped", 0x8),
+    ("reverse", 0x10),
+    ("mate_reverse", 0x20),
+    ("read1", 0x40),
+    ("read2", 0x80),
+    ("secondary", 0x100),
+    ("qcfail", 0x200),
+    ("duplicate", 0x400),
+    ("supplementary", 0x800),
+];
+
+/// A [`ReadFilter`] that passes the reads an expression holds for.
+#[derive(Debug, Clone)]
+pub struct ExprReadFilter {
+    /// The expression as written
+    source: String,
+    /// The compiled expression
+    cond: Cond,
+}
+
+impl ExprReadFilter {
+    /// Parse and compile an expression, failing on syntax errors, unknown names, and comparisons
+    /// between numbers and strings.
+    pub fn new(source: &str) -> Result<Self> {
+        let cond = tokenize(source)
+            .and_then(|tokens| Parser { tokens, next: 0 }.parse())
+            .map_err(|e| Error::msg(format!("Invalid filter {:?}: {}", source, e)))?;
+        Ok(Self {
+            source: source.to_owned(),
+            cond,
+        })
+    }
+}
+
+impl FromStr for ExprReadFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for ExprReadFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl ReadFilter for ExprReadFilter {
+    /// Filter reads by evaluating the expression, true is pass
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        self.cond.eval(read)
+    }
+}
+
+/// A compiled condition.
+#[derive(Debug, Clone)]
+enum Cond {
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+    Not(Box<Cond>),
+    /// Any of these flag bits are set
+    Flag(u16),
+    /// The aux tag is present
+    HasTag([u8; 2]),
+    Compare(Value, CmpOp, Value),
+}
+
+impl Cond {
+    fn eval(&self, read: &Record) -> bool {
+        match self {
+            Cond::And(a, b) => a.eval(read) && b.eval(read),
+            Cond::Or(a, b) => a.eval(read) || b.eval(read),
+            Cond::Not(a) => !a.eval(read),
+            Cond::Flag(bits) => read.flags() & bits != 0,
+            Cond::HasTag(tag) => read.aux(tag).is_some(),
+            Cond::Compare(a, op, b) => match (a.eval(read), b.eval(read)) {
+                (Val::Num(a), Val::Num(b)) => op.holds(a.partial_cmp(&b)),
+                (a, b) => match (a.bytes(), b.bytes()) {
+                    (Some(a), Some(b)) => op.holds(Some(a.cmp(b))),
+                    _ => false,
+                },
+            },
+        }
+    }
+}
+
+/// A comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    /// Whether the comparison holds for an ordering, which is `None` for NaN
+    #[inline]
+    fn holds(self, ordering: Option<std::cmp::Ordering>) -> bool {
+        use std::cmp::Ordering::*;
+        match (self, ordering) {
+            (_, None) => self == CmpOp::Ne,
+            (CmpOp::Eq, Some(o)) => o == Equal,
+            (CmpOp::Ne, Some(o)) => o != Equal,
+            (CmpOp::Lt, Some(o)) => o == Less,
+            (CmpOp::Le, Some(o)) => o != Greater,
+            (CmpOp::Gt, Some(o)) => o == Greater,
+            (CmpOp::Ge, Some(o)) => o != Less,
+        }
+    }
+}
+
+/// A compiled value.
+#[derive(Debug, Clone)]
+enum Value {
+    Num(f64),
+    Str(Vec<u8>),
+    Field(Field),
+    Tag([u8; 2]),
+    Abs(Box<Value>),
+    Neg(Box<Value>),
+}
+
+/// The value of a value for a read.
+enum Val<'a> {
+    Num(f64),
+    Str(&'a [u8]),
+    /// A single character tag, compared as a string
+    Char(u8),
+    Missing,
+}
+
+impl Val<'_> {
+    /// The bytes of a string value
+    #[inline]
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Val::Str(s) => Some(s),
+            Val::Char(c) => Some(std::slice::from_ref(c)),
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    fn eval<'a>(&'a self, read: &'a Record) -> Val<'a> {
+        match self {
+            Value::Num(n) => Val::Num(*n),
+            Value::Str(s) => Val::Str(s),
+            Value::Field(field) => field.eval(read),
+            Value::Tag(tag) => match read.aux(tag) {
+                Some(Aux::Integer(n)) => Val::Num(n as f64),
+                Some(Aux::Float(n)) => Val::Num(n),
+                Some(Aux::String(s)) => Val::Str(s),
+                Some(Aux::Char(c)) => Val::Char(c),
+                None => Val::Missing,
+            },
+            Value::Abs(v) => match v.eval(read) {
+                Val::Num(n) => Val::Num(n.abs()),
+                _ => Val::Missing,
+            },
+            Value::Neg(v) => match v.eval(read) {
+                Val::Num(n) => Val::Num(-n),
+                _ => Val::Missing,
+            },
+        }
+    }
+}
+
+/// A field of a read.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Mapq,
+    Flag,
+    Tlen,
+    Pos,
+    Mpos,
+    End,
+    Len,
+    Rg,
+    Qname,
+}
+
+impl Field {
+    /// The fields by name, and whether they are strings
+    const ALL: [(&'static str, Field, Type); 9] = [
+        ("mapq", Field::Mapq, Type::Num),
+        ("flag", Field::Flag, Type::Num),
+        ("tlen", Field::Tlen, Type::Num),
+        ("pos", Field::Pos, Type::Num),
+        ("mpos", Field::Mpos, Type::Num),
+        ("end", Field::End, Type::Num),
+        ("len", Field::Len, Type::Num),
+        ("rg", Field::Rg, Type::Str),
+        ("qname", Field::Qname, Type::Str),
+    ];
+
+    #[inline]
+    fn eval(self, read: &Record) -> Val<'_> {
+        match self {
+            Field::Mapq => Val::Num(f64::from(read.mapq())),
+            Field::Flag => Val::Num(f64::from(read.flags())),
+            Field::Tlen => Val::Num(read.insert_size() as f64),
+            Field::Pos => Val::Num(read.pos() as f64),
+            Field::Mpos => Val::Num(read.mpos() as f64),
+            Field::End => Val::Num(read.reference_end() as f64),
+            Field::Len => Val::Num(read.seq_len() as f64),
+            Field::Rg => match read.aux(b"RG") {
+                Some(Aux::String(rg)) => Val::Str(rg),
+                _ => Val::Missing,
+            },
+            Field::Qname => Val::Str(read.qname()),
+        }
+    }
+}
+
+/// The type of a value, known when compiling unless it is a tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Num,
+    Str,
+    Any,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Num => "a number",
+            Type::Str => "a string",
+            Type::Any => "a tag",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A token of an expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    LParen,
+    RParen,
+    Dot,
+    Minus,
+    Not,
+    And,
+    Or,
+    Cmp(CmpOp),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Dot => write!(f, "."),
+            Token::Minus => write!(f, "-"),
+            Token::Not => write!(f, "!"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Cmp(op) => {
+                let op = match op {
+                    CmpOp::Eq => "==",
+                    CmpOp::Ne => "!=",
+                    CmpOp::Lt => "<",
+                    CmpOp::Le => "<=",
+                    CmpOp::Gt => ">",
+                    CmpOp::Ge => ">=",
+                };
+                write!(f, "{}", op)
+            }
+        }
+    }
+}
+
+/// Split an expression into tokens.
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let mut next_is = |expected: char| {
+            if chars.peek().map(|(_, c)| *c) == Some(expected) {
+                chars.next();
+                true
+            } else {
+                false
+            }
+        };
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '.' => Token::Dot,
+            '-' => Token::Minus,
+            '!' if next_is('=') => Token::Cmp(CmpOp::Ne),
+            '!' => Token::Not,
+            '=' if next_is('=') => Token::Cmp(CmpOp::Eq),
+            '<' if next_is('=') => Token::Cmp(CmpOp::Le),
+            '<' => Token::Cmp(CmpOp::Lt),
+            '>' if next_is('=') => Token::Cmp(CmpOp::Ge),
+            '>' => Token::Cmp(CmpOp::Gt),
+            '&' if next_is('&') => Token::And,
+            '|' if next_is('|') => Token::Or,
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => return Err(Error::msg("unterminated string")),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(Error::msg("unterminated string")),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.peek() {
+                    // A dot followed by a method name isn't part of the number
+                    let is_fraction =
+                        *c == '.' && source[j + 1..].starts_with(|c: char| c.is_ascii_digit());
+                    if c.is_ascii_digit() || is_fraction {
+                        end = j + c.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Num(source[i..end].parse()?)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || *c == '_' {
+                        end = j + c.len_utf8();
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(source[i..end].to_owned())
+            }
+            c => {
+                return Err(Error::msg(format!(
+                    "unexpected character {:?} at position {}",
+                    c, i
+                )))
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Either a condition or a value, until it is known which one is needed.
+enum Node {
+    Cond(Cond),
+    Value(Value, Type),
+}
+
+impl Node {
+    /// Use this node as a condition
+    fn into_cond(self) -> Result<Cond> {
+        match self {
+            Node::Cond(cond) => Ok(cond),
+            Node::Value(Value::Tag(tag), _) => Ok(Cond::HasTag(tag)),
+            Node::Value(_, ty) => Err(Error::msg(format!(
+                "expected a condition, found {} on its own",
+                ty
+            ))),
+        }
+    }
+
+    /// Use this node as a value
+    fn into_value(self) -> Result<(Value, Type)> {
+        match self {
+            Node::Value(value, ty) => Ok((value, ty)),
+            Node::Cond(_) => Err(Error::msg("expected a value, found a condition")),
+        }
+    }
+}
+
+/// A recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    /// Parse the whole expression into a condition
+    fn parse(&mut self) -> Result<Cond> {
+        let cond = self.parse_or()?.into_cond()?;
+        match self.tokens.get(self.next) {
+            Some(token) => Err(Error::msg(format!("unexpected `{}`", token))),
+            None => Ok(cond),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    /// Take the next token if it is `token`
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take the next token, failing unless it is `token`
+    fn expect(&mut self, token: &Token) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", token)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        match self.peek() {
+            Some(token) => Error::msg(format!("expected {}, found `{}`", expected, token)),
+            None => Error::msg(format!("expected {}, found the end", expected)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node> {
+        let mut node = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            let rhs = self.parse_and()?.into_cond()?;
+            node = Node::Cond(Cond::Or(Box::new(node.into_cond()?), Box::new(rhs)));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node> {
+        let mut node = self.parse_not()?;
+        while self.eat(&Token::And) {
+            let rhs = self.parse_not()?.into_cond()?;
+            node = Node::Cond(Cond::And(Box::new(node.into_cond()?), Box::new(rhs)));
+        }
+        Ok(node)
+    }
+
+    fn parse_not(&mut self) -> Result<Node> {
+        if self.eat(&Token::Not) {
+            let cond = self.parse_not()?.into_cond()?;
+            Ok(Node::Cond(Cond::Not(Box::new(cond))))
+        } else {
+            self.parse_cmp()
+        }
+    }
+
+    fn parse_cmp(&mut self) -> Result<Node> {
+        let lhs = self.parse_operand()?;
+        let op = match self.peek() {
+            Some(Token::Cmp(op)) => *op,
+            _ => return Ok(lhs),
+        };
+        self.next += 1;
+        let (lhs, lhs_type) = lhs.into_value()?;
+        let (rhs, rhs_type) = self.parse_operand()?.into_value()?;
+        if lhs_type != Type::Any && rhs_type != Type::Any && lhs_type != rhs_type {
+            return Err(Error::msg(format!(
+                "can't compare {} to {}",
+                lhs_type, rhs_type
+            )));
+        }
+        let is_order = !matches!(op, CmpOp::Eq | CmpOp::Ne);
+        if is_order && (lhs_type == Type::Str || rhs_type == Type::Str) {
+            return Err(Error::msg("strings can only be compared with == and !="));
+        }
+        Ok(Node::Cond(Cond::Compare(lhs, op, rhs)))
+    }
+
+    fn parse_operand(&mut self) -> Result<Node> {
+        let mut node = self.parse_atom()?;
+        // Methods on numbers
+        while self.eat(&Token::Dot) {
+            match self.peek() {
+                Some(Token::Ident(name)) if name == "abs" => self.next += 1,
+                _ => return Err(self.unexpected("`abs`")),
+            }
+            self.expect(&Token::LParen)?;
+            self.expect(&Token::RParen)?;
+            let (value, ty) = node.into_value()?;
+            node = Node::Value(Value::Abs(Box::new(value)), numeric(ty, "abs")?);
+        }
+        Ok(node)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.unexpected("a value or condition")),
+        };
+        self.next += 1;
+        match token {
+            Token::Num(n) => Ok(Node::Value(Value::Num(n), Type::Num)),
+            Token::Str(s) => Ok(Node::Value(Value::Str(s.into_bytes()), Type::Str)),
+            Token::Minus => {
+                let (value, ty) = self.parse_operand()?.into_value()?;
+                Ok(Node::Value(Value::Neg(Box::new(value)), numeric(ty, "-")?))
+            }
+            Token::LParen => {
+                let node = self.parse_or()?;
+                self.expect(&Token::RParen)?;
+                Ok(node)
+            }
+            Token::Ident(name) if name == "tag" => {
+                self.expect(&Token::LParen)?;
+                let tag = match self.peek() {
+                    Some(Token::Ident(tag)) | Some(Token::Str(tag)) if tag.len() == 2 => {
+                        [tag.as_bytes()[0], tag.as_bytes()[1]]
+                    }
+                    _ => return Err(self.unexpected("a two character tag name")),
+                };
+                self.next += 1;
+                self.expect(&Token::RParen)?;
+                Ok(Node::Value(Value::Tag(tag), Type::Any))
+            }
+            Token::Ident(name) => {
+                if let Some((_, bits)) = FLAGS.iter().find(|(flag, _)| *flag == name) {
+                    Ok(Node::Cond(Cond::Flag(*bits)))
+                } else if let Some((_, field, ty)) =
+                    Field::ALL.iter().find(|(field, _, _)| *field == name)
+                {
+                    Ok(Node::Value(Value::Field(*field), *ty))
+                } else {
+                    Err(Error::msg(format!("unknown name `{}`", name)))
+                }
+            }
+            token => Err(Error::msg(format!(
+                "expected a value or condition, found `{}`",
+                token
+            ))),
+        }
+    }
+}
+
+/// Check that `op` is applied to a number, returning the type of the result
+fn numeric(ty: Type, op: &str) -> Result<Type> {
+    match ty {
+        Type::Str => Err(Error::msg(format!("`{}` needs a number, not a string", op))),
+        Type::Num | Type::Any => Ok(Type::Num),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::bam::{self, HeaderView};
+
+    fn header() -> HeaderView {
+        let mut header = bam::header::Header::new();
+        let mut record = bam::header::HeaderRecord::new(b"SQ");
+        record.push_tag(b"SN", &"chr1");
+        record.push_tag(b"LN", &1000);
+        header.push_record(&record);
+        HeaderView::from_header(&header)
+    }
+
+    fn record(sam: &str) -> Record {
+        Record::from_sam(&header(), sam.as_bytes()).unwrap()
+    }
+
+    fn passes(expr: &str, read: &Record) -> bool {
+        ExprReadFilter::new(expr).unwrap().filter_read(read)
+    }
+
+    #[test]
+    fn evaluates() {
+        let read = record(
+            "read\t1123\tchr1\t11\t30\t5M2D5M\tchr1\t101\t-300\tAAAAAAAAAA\t##########\tNM:i:2\tRG:Z:L1\tXF:f:0.5",
+        );
+        let expr = "mapq >= 20 && !duplicate && tag(NM) <= 4 && tlen.abs() < 1000 && rg == \"L1\"";
+        assert!(!passes(expr, &read));
+        assert!(passes(&expr.replace("!duplicate", "duplicate"), &read));
+
+        assert!(passes(
+            "paired && proper_pair && mate_reverse && read1",
+            &read
+        ));
+        assert!(passes(
+            "!reverse && !read2 && !secondary && !supplementary",
+            &read
+        ));
+        assert!(passes("pos == 10 && end == 22 && mpos == 100", &read));
+        assert!(passes("len == 10 && flag == 1123 && tlen == -300", &read));
+        assert!(passes("-tlen == 300 && tlen < -299.5", &read));
+        assert!(passes("qname == \"read\" && rg != \"L2\"", &read));
+        assert!(passes("tag(XF) > 0.25 && tag(XF) < 0.75", &read));
+        assert!(passes("tag(\"NM\") == 2 && tag(RG) == \"L1\"", &read));
+        // The tag alone is a condition that it is present
+        assert!(passes("tag(NM) && !tag(XX)", &read));
+        // Comparisons with missing tags, or tags of the other type, are false
+        assert!(!passes("tag(XX) == 1 || tag(XX) != 1", &read));
+        assert!(!passes("tag(RG) == 1 || tag(NM) == \"2\"", &read));
+        // && binds tighter than ||
+        assert!(passes("unmapped && qcfail || mapq == 30", &read));
+        assert!(!passes("unmapped && (qcfail || mapq == 30)", &read));
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        for expr in [
+            "",
+            "mapq",
+            "mapq >=",
+            "mapq > 20 &&",
+            "mapq = 20",
+            "mapq == \"20\"",
+            "rg < \"L1\"",
+            "rg.abs() == 1",
+            "-qname == 1",
+            "unknown == 1",
+            "tag(NMX) == 1",
+            "tag(NM",
+            "(mapq > 1",
+            "mapq > 1)",
+            "duplicate == 1",
+            "rg == \"L1",
+            "mapq > 1 # 2",
+            "tlen.sqrt() > 1",
+        ]
+        .iter()
+        {
+            assert!(ExprReadFilter::new(expr).is_err(), "{} parsed", expr);
+        }
+    }
+}
@@ -1,16 +1,74 @@
 //! # ParGranges
 //!
 //! Iterates over chunked genomic regions in parallel.
-use anyhow::Result;
-use bio::io::bed;
-use crossbeam::channel::{unbounded, Receiver};
+use crate::read_density::ReadDensity;
+use crate::regions::{self, ContigMatcher, MissingContig};
+use crate::sites::{self, Site, SitesReader, VcfSites};
+use anyhow::{Context, Error, Result};
+use crossbeam::channel::{bounded, Receiver, Sender};
 use log::*;
 use num_cpus;
 use rayon::prelude::*;
-use rust_htslib::bam::{HeaderView, IndexedReader, Read};
+use rust_htslib::{
+    bam::{HeaderView, IndexedReader, Read},
+    bcf::{self, Read as BcfRead},
+};
 use rust_lapper::{Interval, Lapper};
 use serde::Serialize;
-use std::{path::PathBuf, thread};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// The default number of bytes of results that may be held in memory waiting to be sent, see
+/// [`ParGranges::with_buffer_size`].
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024 * 1024;
+
+thread_local! {
+    /// The readers opened by [`with_reader`] on this thread, by input and reference
+    static READERS: RefCell<HashMap<(PathBuf, Option<PathBuf>), IndexedReader>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Run `f` with an indexed reader on `reads`, reusing the reader this thread opened on the same
+/// file and reference before instead of opening a new one.
+///
+/// Opening a reader parses the header and loads the index, which adds up for CRAM and for many
+/// small regions. Readers are kept per thread, so when called from [`RegionProcessor::process_region`]
+/// each worker of a [`ParGranges`] pool opens each file once, and the readers are closed along
+/// with the pool. The reader is set aside while `f` runs, so `f` may borrow a reader on another
+/// file as well. If `f` fails the reader is dropped rather than reused, in case the error left it
+/// in a bad state.
+pub fn with_reader<T>(
+    reads: &Path,
+    ref_fasta: Option<&Path>,
+    f: impl FnOnce(&mut IndexedReader) -> Result<T>,
+) -> Result<T> {
+    let key = (reads.to_path_buf(), ref_fasta.map(Path::to_path_buf));
+    let mut reader = match READERS.with(|readers| readers.borrow_mut().remove(&key)) {
+        Some(reader) => reader,
+        None => {
+            let mut reader = IndexedReader::from_path(reads)
+                .with_context(|| format!("Failed to open indexed BAM/CRAM {:?}", reads))?;
+            if let Some(ref_fasta) = ref_fasta {
+                reader
+                    .set_reference(ref_fasta)
+                    .with_context(|| format!("Failed to set reference {:?}", ref_fasta))?;
+            }
+            reader
+        }
+    };
+    let result = f(&mut reader)?;
+    READERS.with(|readers| readers.borrow_mut().insert(key, reader));
+    Ok(result)
+}
 
 /// RegionProcessor defines the methods that must be implemented to process a region
 pub trait RegionProcessor {
@@ -23,29 +81,63 @@ pub trait RegionProcessor {
     /// A function that takes the tid, start, and stop and returns something serializable.
     /// Note, a common use of this function will be a `fetch` -> `pileup`. The pileup must
     /// be bounds checked.
-    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<Self::P>;
+    ///
+    /// An error stops all processing and is passed on to the receiver of the results.
+    fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>>;
+
+    /// A function that takes the tid and name of a contig and a sorted list of 0-based positions on
+    /// it, and returns the values at each position, in the same order as `positions`. The positions
+    /// are close enough together to be fetched as a single region.
+    ///
+    /// This is used by [`ParGranges::process_sites`]. The default implementation calls
+    /// [`process_region`] once per position, implementors should override it to process all of
+    /// the positions with a single fetch.
+    ///
+    /// [`process_region`]: #method.process_region
+    fn process_sites(
+        &self,
+        tid: u32,
+        ref_seq: &str,
+        positions: &[u64],
+    ) -> Result<Vec<Vec<Self::P>>> {
+        let _ = ref_seq;
+        positions
+            .iter()
+            .map(|&pos| self.process_region(tid, pos, pos + 1))
+            .collect()
+    }
 }
 
+/// A site along with the values at its position, as sent by [`ParGranges::process_sites`].
+pub type SiteValues<P> = (Site, Vec<P>);
+
 /// ParGranges holds all the information and configuration needed to launch the
 /// [`ParGranges::process`].
 ///
 /// [`ParGranges::process`]: #method.process
 #[derive(Debug)]
 pub struct ParGranges<R: 'static + RegionProcessor + Send + Sync> {
-    /// Path to an indexed BAM / CRAM file
-    reads: PathBuf,
+    /// Paths to indexed BAM / CRAM files, all sharing the same contigs
+    reads: Vec<PathBuf>,
     /// Optional reference file for CRAM
     ref_fasta: Option<PathBuf>,
     /// Optional path to a BED file to restrict the regions iterated over
     regions_bed: Option<PathBuf>,
     /// Number of threads this is allowed to use, uses all if None
     threads: usize,
-    /// The ideal number of basepairs each worker will receive. Total bp in memory at one time = `threads` * `chunksize`
+    /// The ideal number of basepairs each worker will receive
     chunksize: usize,
     /// The rayon threadpool to operate in
     pool: rayon::ThreadPool,
-    /// The implementation of [RegionProcessor] that will be used to process regions
-    processor: R,
+    /// The implementation of [RegionProcessor] that will be used to process regions, shared with
+    /// the tasks spawned on the pool
+    processor: Arc<R>,
+    /// What to do with regions or sites on contigs that are not in the BAM/CRAM header
+    missing_contig: MissingContig,
+    /// The approximate number of bytes of results that may wait in memory to be sent
+    buffer_size: usize,
+    /// Whether to size regions by the read density estimated from the index
+    adaptive_chunks: bool,
 }
 
 impl<R: RegionProcessor + Send + Sync> ParGranges<R> {
@@ -55,10 +147,11 @@ impl<R: RegionProcessor + Send + Sync> ParGranges<R> {
     ///
     /// * `reads`- path to an indexed BAM/CRAM
     /// * `ref_fasta`- path to an indexed reference file for CRAM
-    /// * `regions_bed`- Optional BED file path restricting the regions to be examined
+    /// * `regions_bed`- Optional BED file path restricting the regions to be examined. A VCF/BCF file,
+    ///   recognized by a `.vcf`, `.vcf.gz`, or `.bcf` extension, may be used instead
     /// * `threads`- Optional threads to restrict the number of threads this process will use, defaults to all
     /// * `chunksize`- optional argument to change the default chunksize of 1_000_000. `chunksize` determines the number of bases
-    ///                each worker will get to work on at one time.
+    ///   each worker will get to work on at one time.
     /// * `processor`- Something that implements [`RegionProcessor`](RegionProcessor)
     pub fn new(
         reads: PathBuf,
@@ -68,24 +161,42 @@ impl<R: RegionProcessor + Send + Sync> ParGranges<R> {
         chunksize: Option<usize>,
         processor: R,
     ) -> Self {
-        let threads = if let Some(threads) = threads {
-            threads
-        } else {
-            num_cpus::get()
-        };
+        Self::with_multiple_reads(
+            vec![reads],
+            ref_fasta,
+            regions_bed,
+            threads,
+            chunksize,
+            processor,
+        )
+    }
+
+    /// Create a ParIO object that iterates over the regions shared by several BAM/CRAM files.
+    ///
+    /// The contigs of all inputs must agree. Each header must either match the others exactly, or
+    /// be a prefix of the longest header, with the same names and lengths in the same order, so that
+    /// a tid refers to the same contig in every file. Regions are driven by the longest header, so the
+    /// [`RegionProcessor`] must handle a tid that is missing from some of the inputs.
+    ///
+    /// See [`ParGranges::new`] for the other arguments.
+    pub fn with_multiple_reads(
+        reads: Vec<PathBuf>,
+        ref_fasta: Option<PathBuf>,
+        regions_bed: Option<PathBuf>,
+        threads: Option<usize>,
+        chunksize: Option<usize>,
+        processor: R,
+    ) -> Self {
+        let threads = threads.unwrap_or_else(num_cpus::get);
 
         // Keep two around for main thread and thread running the pool
-        let threads = std::cmp::max(threads.checked_sub(2).unwrap_or(0), 1);
+        let threads = std::cmp::max(threads.saturating_sub(2), 1);
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads)
             .build()
             .unwrap();
 
-        let chunksize = if let Some(chunksize) = chunksize {
-            chunksize
-        } else {
-            1_000_000
-        };
+        let chunksize = chunksize.unwrap_or(1_000_000);
         info!("Using {} worker threads.", threads);
         info!("Using chunksize of {}.", chunksize);
         Self {
@@ -95,99 +206,477 @@ impl<R: RegionProcessor + Send + Sync> ParGranges<R> {
             threads,
             chunksize,
             pool,
-            processor,
+            processor: Arc::new(processor),
+            missing_contig: MissingContig::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            adaptive_chunks: false,
         }
     }
 
+    /// Size regions by the density of reads estimated from the BAI index of the inputs, instead of
+    /// only by `chunksize`, defaults to false.
+    ///
+    /// Each region is limited to about the bytes of reads that an average `chunksize` window of
+    /// the genome holds, so dense regions are split into smaller pieces that spread the work more
+    /// evenly over the workers. Regions are still never longer than `chunksize`. If any input
+    /// doesn't have a BAI index, such as a CRAM or a BAM with a CSI index, regions are split only
+    /// by `chunksize`. See [`ReadDensity`].
+    pub fn with_adaptive_chunks(mut self, adaptive_chunks: bool) -> Self {
+        self.adaptive_chunks = adaptive_chunks;
+        self
+    }
+
+    /// Set the approximate number of bytes of results that may be held in memory while waiting to
+    /// be sent, defaults to [`DEFAULT_BUFFER_SIZE`].
+    ///
+    /// Half of the budget goes to the results of regions that finished out of order and are waiting
+    /// on an earlier region, and half to the channel of results returned by [`ParGranges::process`]
+    /// and [`ParGranges::process_sites`]. Sizes are estimated from `size_of::<R::P>()`, so heap
+    /// memory owned by the values is not counted. Every worker is given a region of up to
+    /// chunksize bases whether or not the buffer is full, so the peak is this plus the results of
+    /// one region for each worker. A region queued up past that is counted as one value per base
+    /// until it finishes, and is only started if it fits in the buffer.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Set what to do with regions or sites on contigs that are not in the BAM/CRAM header,
+    /// defaults to [`MissingContig::Error`].
+    ///
+    /// Contig names are matched with [`ContigMatcher`], so `chr1` and `1`, or `chrM` and `MT`,
+    /// are treated as the same contig before a name is considered missing.
+    pub fn with_missing_contig(mut self, missing_contig: MissingContig) -> Self {
+        self.missing_contig = missing_contig;
+        self
+    }
+
     /// Process each region.
     ///
-    /// This method splits the sequences in the BAM/CRAM header into `chunksize` * `self.threads` regions (aka 'super chunks').
-    /// It then queries that 'super chunk' against the intervals (either the BED file, or the whole genome broken up into `chunksize`
-    /// regions). The results of that query are then processed by a pool of workers that apply `process_region` to reach interval to
-    /// do perbase analysis on. The collected result for each region is then sent back over the returned `Receiver<R::P>` channel
-    /// for the caller to use. The results will be returned in order according to the order of the intervals used to drive this method.
+    /// The intervals to process (either the BED file, or the whole genome) are split into regions of at most `chunksize` bases,
+    /// which are handed out in order to a pool of workers that apply `process_region` to each of them. The results of each region
+    /// are sent back over the returned `Receiver<Result<R::P>>` channel as soon as every earlier region has been sent, so the
+    /// results are returned in the order of the intervals used to drive this method.
+    ///
+    /// Memory is bounded by [`ParGranges::with_buffer_size`]. Results that finish out of order wait in a reorder buffer, and past
+    /// one region per worker, no new region is started unless its results would fit in that buffer along with those already
+    /// waiting. The returned channel is bounded as well, so a slow receiver holds back the workers rather than letting results
+    /// pile up.
     ///
-    /// While one 'super chunk' is being worked on by all workers, the last 'super chunks' results are being printed to either to
-    /// a file or to STDOUT, in order.
+    /// If anything fails, such as reading the regions or processing a region, the error is sent as the last item on the channel,
+    /// with context naming the region that failed, and no further results are sent. Callers should stop at the first error.
     ///
     /// Note, a common use case of this will be to fetch a region and do a pileup. The bounds of bases being looked at should still be
     /// checked since a fetch will pull all reads that overlap the region in question.
-    pub fn process(self) -> Result<Receiver<R::P>> {
-        // let mut writer = self.get_writer()?;
+    pub fn process(self) -> Result<Receiver<Result<R::P>>> {
+        let union_index = self.validate_headers()?;
 
-        let (snd, rxv) = unbounded();
+        let (snd, rxv) = bounded(self.channel_capacity::<R::P>());
+        // Regions are spawned onto the pool, so this thread is free to wait on their results
         thread::spawn(move || {
-            self.pool.install(|| {
-                info!("Reading from {:?}", self.reads);
-                let mut reader = IndexedReader::from_path(&self.reads).expect("Indexed BAM/CRAM");
-                // If passed add ref_fasta
-                if let Some(ref_fasta) = &self.ref_fasta {
-                    reader.set_reference(ref_fasta).expect("Set ref");
-                }
-                // Get a copy of the header
-                let header = reader.header().to_owned();
+            if let Err(err) = self.send_regions(union_index, &snd) {
+                // The receiver may already be gone, in which case there is no one to tell
+                let _ = snd.send(Err(err));
+            }
+        });
+        Ok(rxv)
+    }
 
-                let intervals = if let Some(regions_bed) = &self.regions_bed {
-                    Self::bed_to_intervals(&header, regions_bed).expect("Parsed BED to intervals")
-                } else {
-                    Self::header_to_intervals(&header, self.chunksize)
-                        .expect("Parsed BAM/CRAM header to intervals")
+    /// The number of values of type `T` that fit in the half of the buffer used by the returned
+    /// channel.
+    fn channel_capacity<T>(&self) -> usize {
+        std::cmp::max(
+            self.buffer_size / 2 / std::cmp::max(mem::size_of::<T>(), 1),
+            1,
+        )
+    }
+
+    /// The intervals [`ParGranges::process`] splits into regions, by tid: those of the BED or
+    /// VCF/BCF file with any overlaps merged, or else every contig in full.
+    ///
+    /// Contigs missing from the BAM/CRAM header are skipped quietly, it is up to processing to
+    /// report them.
+    pub fn intervals(&self) -> Result<Vec<Lapper<u64, ()>>> {
+        let union_index = self.validate_headers()?;
+        let reads = &self.reads[union_index];
+        let reader = IndexedReader::from_path(reads)
+            .with_context(|| format!("Failed to open indexed BAM/CRAM {:?}", reads))?;
+        self.read_intervals(reader.header(), MissingContig::Skip)
+    }
+
+    /// Read the intervals to process, by tid, see [`ParGranges::intervals`].
+    fn read_intervals(
+        &self,
+        header: &HeaderView,
+        missing_contig: MissingContig,
+    ) -> Result<Vec<Lapper<u64, ()>>> {
+        if let Some(regions_bed) = &self.regions_bed {
+            if Self::is_vcf(regions_bed) {
+                Self::vcf_to_intervals(header, regions_bed, missing_contig)
+            } else {
+                Self::bed_to_intervals(header, regions_bed, missing_contig)
+            }
+            .with_context(|| format!("Failed to read regions from {:?}", regions_bed))
+        } else {
+            Self::header_to_intervals(header, self.chunksize)
+        }
+    }
+
+    /// Process every region and send the results, in order, see [`ParGranges::process`].
+    fn send_regions(&self, union_index: usize, snd: &Sender<Result<R::P>>) -> Result<()> {
+        let reads = &self.reads[union_index];
+        info!("Reading from {:?}", reads);
+        let mut reader = IndexedReader::from_path(reads)
+            .with_context(|| format!("Failed to open indexed BAM/CRAM {:?}", reads))?;
+        // If passed add ref_fasta
+        if let Some(ref_fasta) = &self.ref_fasta {
+            reader
+                .set_reference(ref_fasta)
+                .with_context(|| format!("Failed to set reference {:?}", ref_fasta))?;
+        }
+        // Get a copy of the header
+        let header = reader.header().to_owned();
+        let names = Self::contig_names(&header);
+
+        let intervals = self.read_intervals(&header, self.missing_contig)?;
+
+        let tid_lens: Vec<u64> = (0..header.target_count())
+            .map(|tid| header.target_len(tid).unwrap())
+            .collect();
+        let density = if self.adaptive_chunks {
+            let density = ReadDensity::for_reads(&self.reads)?;
+            if density.is_none() {
+                info!("No BAI index found for every input, splitting regions by chunksize only.");
+            }
+            density
+        } else {
+            None
+        };
+        // Aim for the bytes of reads an average chunksize window of the genome holds
+        let target_bytes = density.as_ref().map(|density| {
+            let genome_len: u64 = tid_lens.iter().sum();
+            density.total_bytes() as f64 * self.chunksize as f64
+                / std::cmp::max(genome_len, 1) as f64
+        });
+
+        // Split the intervals into regions of at most chunksize, clipped to the end of the contig
+        let chunksize = self.chunksize as u64;
+        let density = &density;
+        let regions = intervals.into_iter().zip(tid_lens).enumerate().flat_map(
+            |(tid, (intervals, tid_end))| {
+                let tid = tid as u32;
+                intervals.intervals.into_iter().flat_map(move |iv| {
+                    let stop = std::cmp::min(iv.stop, tid_end);
+                    let pieces: Box<dyn Iterator<Item = (u64, u64)>> = match (density, target_bytes)
+                    {
+                        (Some(density), Some(target_bytes)) if target_bytes > 0.0 => {
+                            Box::new(density.split(tid, iv.start, stop, chunksize, target_bytes))
+                        }
+                        _ => Box::new(
+                            (iv.start..stop)
+                                .step_by(chunksize as usize)
+                                .map(move |start| (start, std::cmp::min(start + chunksize, stop))),
+                        ),
+                    };
+                    pieces.map(move |(start, stop)| (tid, start, stop))
+                })
+            },
+        );
+
+        // Keep every worker busy with one region while it has another waiting
+        let max_in_flight = self.threads * 2;
+        let buffer_size = self.buffer_size / 2;
+        let bytes = |len: usize| len * mem::size_of::<R::P>();
+        // The size of the results that are finished but not yet sent, along with an estimate of
+        // one value per base for the queued up regions that are still running
+        let buffered = Arc::new(AtomicUsize::new(0));
+        // The regions that have been started, in order, with a channel for each one's results
+        let mut in_flight = VecDeque::new();
+        let mut regions = regions.peekable();
+        loop {
+            while in_flight.len() < max_in_flight {
+                // Every worker gets a region, only the regions queued up past that are held back
+                // by the buffer
+                let estimate = match regions.peek() {
+                    Some(_) if in_flight.len() < self.threads => 0,
+                    Some((_, start, stop)) => bytes((stop - start) as usize),
+                    None => break,
                 };
+                if estimate > 0 && buffered.load(Ordering::SeqCst) + estimate > buffer_size {
+                    break;
+                }
+                let (tid, start, stop) = regions.next().unwrap();
+                buffered.fetch_add(estimate, Ordering::SeqCst);
+                let (region_snd, region_rxv) = bounded(1);
+                let processor = Arc::clone(&self.processor);
+                let buffered = Arc::clone(&buffered);
+                self.pool.spawn(move || {
+                    info!("Processing {}:{}-{}", tid, start, stop);
+                    let result = processor.process_region(tid, start, stop);
+                    // Swap the estimate for the actual size of the results
+                    let actual = result.as_ref().map_or(0, |values| bytes(values.len()));
+                    buffered.fetch_add(actual, Ordering::SeqCst);
+                    buffered.fetch_sub(estimate, Ordering::SeqCst);
+                    // The results are no longer wanted if an earlier region failed
+                    let _ = region_snd.send(result);
+                });
+                in_flight.push_back((tid, start, stop, region_rxv));
+            }
 
-                // The number positions to try to process in one batch
-                let serial_step_size = self
-                    .chunksize
-                    .checked_mul(self.threads)
-                    .unwrap_or(usize::MAX); // aka superchunk
-                for (tid, intervals) in intervals.into_iter().enumerate() {
-                    let tid: u32 = tid as u32;
-                    let tid_end = header.target_len(tid).unwrap() as u64;
-                    // Result holds the processed positions to be sent to writer
-                    let mut result = vec![];
-                    for chunk_start in (0..tid_end).step_by(serial_step_size) {
-                        let chunk_end =
-                            std::cmp::min(chunk_start + serial_step_size as u64, tid_end);
-                        info!("Batch Processing {}:{}-{}", tid, chunk_start, chunk_end);
-                        let (r, _) = rayon::join(
-                            || {
-                                // Must be a vec so that par_iter works and results stay in order
-                                let ivs: Vec<Interval<u64, ()>> = intervals
-                                    .find(chunk_start, chunk_end)
-                                    // Truncate intervals that extend forward or backward of chunk in question
-                                    .map(|iv| Interval {
-                                        start: std::cmp::max(iv.start, chunk_start),
-                                        stop: std::cmp::min(iv.stop, chunk_end),
-                                        val: (),
-                                    })
-                                    .collect();
-                                ivs.into_par_iter()
-                                    .flat_map(|iv| {
-                                        info!("Processing {}:{}-{}", tid, iv.start, iv.stop);
-                                        self.processor.process_region(tid, iv.start, iv.stop)
-                                    })
-                                    .collect()
-                            },
-                            || {
-                                result.into_iter().for_each(|p| {
-                                    snd.send(p).expect("Sent a serializable to writer")
-                                })
-                            },
-                        );
-                        result = r;
-                    }
-                    // Send final set of results
-                    result
-                        .into_iter()
-                        .for_each(|p| snd.send(p).expect("Sent a serializable to writer"));
+            let (tid, start, stop, region_rxv) = match in_flight.pop_front() {
+                Some(region) => region,
+                None => break,
+            };
+            let values = region_rxv
+                .recv()
+                .unwrap_or_else(|_| Err(Error::msg("Worker stopped without a result")))
+                .with_context(|| {
+                    format!(
+                        "Failed to process region {}:{}-{}",
+                        names[tid as usize], start, stop
+                    )
+                })?;
+            let sent = bytes(values.len());
+            Self::send_all(snd, values)?;
+            buffered.fetch_sub(sent, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Send values to the receiver, failing if the receiver has been dropped.
+    fn send_all<T>(snd: &Sender<Result<T>>, values: Vec<T>) -> Result<()> {
+        for value in values {
+            snd.send(Ok(value))
+                .map_err(|_| Error::msg("Receiver of results was dropped"))?;
+        }
+        Ok(())
+    }
+
+    /// The name of each contig in a header, indexed by tid.
+    fn contig_names(header: &HeaderView) -> Vec<String> {
+        header
+            .target_names()
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect()
+    }
+
+    /// Process only the positions listed in a sites file, see [`SitesReader`].
+    ///
+    /// The sites are read `chunksize` at a time. The sites in each batch are grouped by contig into
+    /// regions of nearby sites, which are processed in parallel by
+    /// [`RegionProcessor::process_sites`]. Each site is then sent back over the returned channel
+    /// along with its values, in the order of the sites file. A site listed more than once is sent
+    /// each time it is listed.
+    ///
+    /// As with [`ParGranges::process`], errors are sent as the last item on the channel. Sites on
+    /// contigs that are not in the BAM/CRAM header are handled according to
+    /// [`ParGranges::with_missing_contig`]. Skipped sites are not sent. Since sites are streamed,
+    /// an error only lists the unmatched contigs of the batch it was found in, while a warning
+    /// lists all of them once every site has been read.
+    pub fn process_sites(self, sites_file: PathBuf) -> Result<Receiver<Result<SiteValues<R::P>>>>
+    where
+        R::P: Clone,
+    {
+        let sites_reader = SitesReader::from_path(&sites_file)?;
+        self.process_site_list(sites_reader, sites_file, "line")
+    }
+
+    /// Process only the positions of the records of a VCF/BCF file, see [`VcfSites`].
+    ///
+    /// This works like [`ParGranges::process_sites`], with the sites sent in the order of the
+    /// VCF/BCF records. The `line` of each site is its 1-based record number, so that records
+    /// whose sites were skipped can be told apart.
+    pub fn process_vcf_sites(self, vcf_file: PathBuf) -> Result<Receiver<Result<SiteValues<R::P>>>>
+    where
+        R::P: Clone,
+    {
+        let sites = VcfSites::from_path(&vcf_file)?;
+        self.process_site_list(sites, vcf_file, "record")
+    }
+
+    /// Process a stream of sites on a separate thread, see [`ParGranges::process_sites`]. `unit`
+    /// names what the `line` of a site counts, for reporting missing contigs.
+    fn process_site_list<I>(
+        self,
+        sites: I,
+        sites_file: PathBuf,
+        unit: &'static str,
+    ) -> Result<Receiver<Result<SiteValues<R::P>>>>
+    where
+        I: Iterator<Item = Result<Site>> + Send + 'static,
+        R::P: Clone,
+    {
+        let union_index = self.validate_headers()?;
+
+        let (snd, rxv) = bounded(self.channel_capacity::<SiteValues<R::P>>());
+        thread::spawn(move || {
+            self.pool.install(|| {
+                if let Err(err) = self.send_sites(union_index, &sites_file, unit, sites, &snd) {
+                    // The receiver may already be gone, in which case there is no one to tell
+                    let _ = snd.send(Err(err));
                 }
             });
         });
-        // rxv.into_iter()
-        //     .for_each(|pos| writer.serialize(pos).unwrap());
-        // writer.flush()?;
         Ok(rxv)
     }
 
+    /// Process the sites in batches and send the results, in order, see [`ParGranges::process_sites`].
+    fn send_sites<I: Iterator<Item = Result<Site>>>(
+        &self,
+        union_index: usize,
+        sites_file: &Path,
+        unit: &'static str,
+        mut sites: I,
+        snd: &Sender<Result<SiteValues<R::P>>>,
+    ) -> Result<()>
+    where
+        R::P: Clone,
+    {
+        let reads = &self.reads[union_index];
+        info!("Reading from {:?}", reads);
+        let reader = IndexedReader::from_path(reads)
+            .with_context(|| format!("Failed to open indexed BAM/CRAM {:?}", reads))?;
+        let header = reader.header().to_owned();
+        let names = Self::contig_names(&header);
+        let mut matcher = ContigMatcher::new(&header, unit);
+        loop {
+            let batch = sites
+                .by_ref()
+                .take(self.chunksize)
+                .collect::<Result<Vec<_>>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            info!("Batch processing {} sites", batch.len());
+
+            let batch: Vec<(Site, u32)> = batch
+                .into_iter()
+                .filter_map(|site| {
+                    let tid = matcher.tid(&site.ref_seq, site.line)?;
+                    Some((site, tid))
+                })
+                .collect();
+            if self.missing_contig == MissingContig::Error {
+                matcher.check(self.missing_contig, sites_file)?;
+            }
+            for (site, tid) in batch.iter() {
+                let len = header.target_len(*tid).unwrap_or(0);
+                if site.pos >= len {
+                    return Err(Error::msg(format!(
+                        "Site {}:{} on {} {} of {:?} is past the end of {}, which is {}bp long",
+                        site.ref_seq,
+                        site.pos + 1,
+                        unit,
+                        site.line,
+                        sites_file,
+                        names[*tid as usize],
+                        len
+                    )));
+                }
+            }
+
+            // Sorted, unique positions of the batch on each contig
+            let mut by_tid: HashMap<u32, Vec<u64>> = HashMap::new();
+            for (site, tid) in batch.iter() {
+                by_tid.entry(*tid).or_default().push(site.pos);
+            }
+            let mut regions = vec![];
+            for (tid, mut positions) in by_tid.into_iter() {
+                positions.sort_unstable();
+                positions.dedup();
+                for group in
+                    sites::group_sites(&positions, sites::MAX_SITE_GAP, self.chunksize as u64)
+                {
+                    regions.push((tid, positions[group].to_vec()));
+                }
+            }
+
+            let values = regions
+                .into_par_iter()
+                .map(|(tid, positions)| {
+                    let ref_seq = &names[tid as usize];
+                    let values = self
+                        .processor
+                        .process_sites(tid, ref_seq, &positions)
+                        .with_context(|| {
+                            format!(
+                                "Failed to process sites in {}:{}-{}",
+                                ref_seq,
+                                positions[0],
+                                positions[positions.len() - 1] + 1
+                            )
+                        })?;
+                    Ok(positions
+                        .into_iter()
+                        .zip(values)
+                        .map(|(pos, values)| ((tid, pos), values))
+                        .collect::<Vec<_>>())
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect::<HashMap<(u32, u64), Vec<R::P>>>();
+
+            let results = batch
+                .into_iter()
+                .map(|(site, tid)| {
+                    let values = values[&(tid, site.pos)].clone();
+                    (site, values)
+                })
+                .collect();
+            Self::send_all(snd, results)?;
+        }
+        matcher.check(self.missing_contig, sites_file)
+    }
+
+    /// Check that the contigs of all inputs agree and return the index of the input whose header is
+    /// the union of all headers.
+    fn validate_headers(&self) -> Result<usize> {
+        let contigs = self
+            .reads
+            .iter()
+            .map(|reads| {
+                let reader = IndexedReader::from_path(reads)
+                    .with_context(|| format!("Failed to open indexed BAM/CRAM {:?}", reads))?;
+                let header = reader.header();
+                Ok((0..header.target_count())
+                    .map(|tid| {
+                        (
+                            header.tid2name(tid).to_owned(),
+                            header.target_len(tid).unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (union_index, union) = contigs
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, contigs)| (contigs.len(), std::cmp::Reverse(*i)))
+            .context("No BAM/CRAM files given")?;
+        for (i, contigs) in contigs.iter().enumerate() {
+            if let Some((tid, (name, len))) = contigs
+                .iter()
+                .enumerate()
+                .find(|(tid, contig)| *contig != &union[*tid])
+            {
+                return Err(Error::msg(format!(
+                    "Contigs of {:?} do not agree with {:?}: tid {} is {}:{} instead of {}:{}",
+                    self.reads[i],
+                    self.reads[union_index],
+                    tid,
+                    String::from_utf8_lossy(name),
+                    len,
+                    String::from_utf8_lossy(&union[tid].0),
+                    union[tid].1
+                )));
+            }
+        }
+        Ok(union_index)
+    }
+
     // Convert the header into intervals of equally sized chunks. The last interval may be short.
     fn header_to_intervals(header: &HeaderView, chunksize: usize) -> Result<Vec<Lapper<u64, ()>>> {
         let mut intervals = vec![vec![]; header.target_count() as usize];
@@ -196,40 +685,84 @@ impl<R: RegionProcessor + Send + Sync> ParGranges<R> {
             for start in (0..tid_len).step_by(chunksize) {
                 let stop = std::cmp::min(start + chunksize as u64, tid_len);
                 intervals[tid as usize].push(Interval {
-                    start: start,
-                    stop: stop,
+                    start,
+                    stop,
                     val: (),
                 });
             }
         }
-        Ok(intervals.into_iter().map(|ivs| Lapper::new(ivs)).collect())
+        Ok(intervals.into_iter().map(Lapper::new).collect())
+    }
+
+    /// Check if a regions file is a VCF/BCF rather than a BED file, based on its extension
+    fn is_vcf(regions: &Path) -> bool {
+        let name = regions.to_string_lossy();
+        name.ends_with(".vcf") || name.ends_with(".vcf.gz") || name.ends_with(".bcf")
     }
 
-    /// Read a bed file into a vector of lappers with the index representing the TID
-    // TODO add a proper error message
-    fn bed_to_intervals(header: &HeaderView, bed_file: &PathBuf) -> Result<Vec<Lapper<u64, ()>>> {
-        let mut bed_reader = bed::Reader::from_file(bed_file)?;
+    /// Read a VCF/BCF file into a vector of lappers with the index representing the TID.
+    ///
+    /// Each record covers the bases of its REF allele. Records on contigs that are not in the header
+    /// are handled according to `missing_contig`, and reported by their record number.
+    fn vcf_to_intervals(
+        header: &HeaderView,
+        vcf_file: &Path,
+        missing_contig: MissingContig,
+    ) -> Result<Vec<Lapper<u64, ()>>> {
+        let mut vcf_reader = bcf::Reader::from_path(vcf_file)?;
+        let vcf_header = vcf_reader.header().clone();
+        let mut matcher = ContigMatcher::new(header, "record");
         let mut intervals = vec![vec![]; header.target_count() as usize];
-        for record in bed_reader.records() {
+        for (index, record) in vcf_reader.records().enumerate() {
             let record = record?;
-            let tid = header
-                .tid(record.chrom().as_bytes())
-                .expect("Chromosome not found in BAM/CRAM header");
+            let rid = record.rid().context("VCF record without a contig")?;
+            let contig = String::from_utf8_lossy(vcf_header.rid2name(rid)?).into_owned();
+            let tid = match matcher.tid(&contig, index + 1) {
+                Some(tid) => tid,
+                None => continue,
+            };
+            let start = record.pos() as u64;
+            let ref_len = record.alleles().first().map_or(1, |r| r.len()) as u64;
             intervals[tid as usize].push(Interval {
-                start: record.start(),
-                stop: record.end(),
+                start,
+                stop: start + std::cmp::max(ref_len, 1),
                 val: (),
             });
         }
+        matcher.check(missing_contig, vcf_file)?;
+        Ok(intervals.into_iter().map(Self::merged_lapper).collect())
+    }
 
-        Ok(intervals
-            .into_iter()
-            .map(|ivs| {
-                let mut lapper = Lapper::new(ivs);
-                lapper.merge_overlaps();
-                lapper
-            })
-            .collect())
+    /// Read a bed file into a vector of lappers with the index representing the TID.
+    ///
+    /// Intervals on contigs that are not in the header are handled according to `missing_contig`.
+    fn bed_to_intervals(
+        header: &HeaderView,
+        bed_file: &Path,
+        missing_contig: MissingContig,
+    ) -> Result<Vec<Lapper<u64, ()>>> {
+        let mut matcher = ContigMatcher::new(header, "line");
+        let records = regions::read_bed(bed_file, &mut matcher)?;
+        matcher.check(missing_contig, bed_file)?;
+        let mut intervals = vec![vec![]; header.target_count() as usize];
+        for record in records {
+            intervals[record.tid as usize].push(Interval {
+                start: record.start,
+                stop: record.stop,
+                val: (),
+            });
+        }
+
+        Ok(intervals.into_iter().map(Self::merged_lapper).collect())
+    }
+
+    /// Create a lapper from intervals, merging any that overlap
+    fn merged_lapper(ivs: Vec<Interval<u64, ()>>) -> Lapper<u64, ()> {
+        let mut lapper = Lapper::new(ivs);
+        lapper.merge_overlaps();
+        // NB: `merge_overlaps` does not update the max interval length used by `find`,
+        // so rebuild the lapper to avoid missing merged intervals that are now longer.
+        Lapper::new(lapper.intervals)
     }
 }
 
@@ -261,7 +794,7 @@ mod test {
     fn arb_ivs(
         max_iv: u64,    // max iv size
         max_ivs: usize, // max number of intervals
-    ) -> impl Strategy<Value = (Vec<Interval<u64, ()>>, u64, u64)> {
+    ) -> impl Strategy<Value = ArbChr> {
         prop::collection::vec(arb_iv(max_iv), 0..max_ivs).prop_map(|vec| {
             let mut furthest_right = 0;
             let lapper = Lapper::new(vec.clone());
@@ -274,12 +807,14 @@ mod test {
             (vec, expected, furthest_right)
         })
     }
+    // Intervals for one contig, the number of positions they cover, and the furthest right position
+    type ArbChr = (Vec<Interval<u64, ()>>, u64, u64);
     // Create arbitrary number of contigs with arbitrary intervals each
     fn arb_chrs(
         max_chr: usize, // number of chromosomes to use
         max_iv: u64,    // max interval size
         max_ivs: usize, // max number of intervals
-    ) -> impl Strategy<Value = Vec<(Vec<Interval<u64, ()>>, u64, u64)>> {
+    ) -> impl Strategy<Value = Vec<ArbChr>> {
         prop::collection::vec(arb_ivs(max_iv, max_ivs), 0..max_chr)
     }
     // An empty BAM with correct header
@@ -331,7 +866,8 @@ mod test {
             );
             let receiver = par_granges_runner.process().expect("Launch ParGranges Process");
             let mut chrom_counts = HashMap::new();
-            receiver.into_iter().for_each(|p: PileupPosition| {
+            receiver.into_iter().for_each(|p| {
+                let p: PileupPosition = p.expect("Processed a region");
                 let positions = chrom_counts.entry(p.ref_seq.parse::<usize>().expect("parsed chr")).or_insert(0u64);
                 *positions += 1
             });
@@ -356,14 +892,239 @@ mod test {
     impl RegionProcessor for TestProcessor {
         type P = PileupPosition;
 
-        fn process_region(&self, tid: u32, start: u64, stop: u64) -> Vec<Self::P> {
+        fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>> {
             let mut results = vec![];
             for i in start..stop {
                 let chr = SmartString::from(&tid.to_string());
                 let pos = PileupPosition::new(chr, i as usize);
                 results.push(pos);
             }
-            results
+            Ok(results)
+        }
+    }
+
+    /// Fails on any region that includes position 150
+    struct FailingProcessor {}
+    impl RegionProcessor for FailingProcessor {
+        type P = PileupPosition;
+
+        fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>> {
+            if start <= 150 && 150 < stop {
+                return Err(Error::msg("bad region"));
+            }
+            TestProcessor {}.process_region(tid, start, stop)
+        }
+    }
+
+    /// Write an empty, indexed BAM with a single contig, `chr1`, of 1000bp
+    fn empty_bam(dir: &Path) -> PathBuf {
+        let bam_path = dir.join("test.bam");
+        let mut header = bam::header::Header::new();
+        let mut chr_rec = bam::header::HeaderRecord::new(b"SQ");
+        chr_rec.push_tag(b"SN", &"chr1");
+        chr_rec.push_tag(b"LN", &1000);
+        header.push_record(&chr_rec);
+        let writer = bam::Writer::from_path(&bam_path, &header, bam::Format::BAM).unwrap();
+        drop(writer);
+        bam::index::build(&bam_path, None, bam::index::Type::BAI, 1).unwrap();
+        bam_path
+    }
+
+    #[test]
+    fn region_errors_are_sent() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let par_granges_runner = ParGranges::new(
+            bam_path,
+            None,
+            None,
+            Some(1),
+            Some(100),
+            FailingProcessor {},
+        );
+        let results: Vec<Result<PileupPosition>> =
+            par_granges_runner.process().unwrap().into_iter().collect();
+
+        // Everything before the failed region is sent, then the error, then nothing else
+        let (last, positions) = results.split_last().unwrap();
+        assert!(positions.iter().all(|p| p.is_ok()));
+        assert_eq!(positions.len(), 100);
+        let err = format!("{:#}", last.as_ref().unwrap_err());
+        assert!(err.contains("chr1:100-200"), "{}", err);
+        assert!(err.contains("bad region"), "{}", err);
+    }
+
+    #[test]
+    fn bed_errors_are_sent() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let bed_path = tempdir.path().join("test.bed");
+        std::fs::write(&bed_path, "chr1\t0\t10\ta\nchr2\t0\t10\tb\n").unwrap();
+        let par_granges_runner = ParGranges::new(
+            bam_path,
+            None,
+            Some(bed_path),
+            Some(1),
+            None,
+            TestProcessor {},
+        );
+        let results: Vec<Result<PileupPosition>> =
+            par_granges_runner.process().unwrap().into_iter().collect();
+        assert_eq!(results.len(), 1);
+        let err = format!("{:#}", results[0].as_ref().unwrap_err());
+        assert!(err.contains("test.bed"), "{}", err);
+        assert!(
+            err.contains("not found in BAM/CRAM header: chr2 (line 2)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn missing_contigs_can_be_skipped() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let bed_path = tempdir.path().join("test.bed");
+        // `1` is an alias of chr1, chr2 is missing
+        std::fs::write(&bed_path, "1\t0\t10\nchr2\t0\t10\nchr1\t20\t25\n").unwrap();
+        let par_granges_runner = ParGranges::new(
+            bam_path,
+            None,
+            Some(bed_path),
+            Some(1),
+            None,
+            TestProcessor {},
+        )
+        .with_missing_contig(MissingContig::Skip);
+        let positions: Vec<usize> = par_granges_runner
+            .process()
+            .unwrap()
+            .into_iter()
+            .map(|p| p.unwrap().pos)
+            .collect();
+        assert_eq!(positions, (0..10).chain(20..25).collect::<Vec<_>>());
+    }
+
+    /// Counts the regions it has been asked to process
+    struct CountingProcessor {
+        calls: Arc<AtomicUsize>,
+    }
+    impl RegionProcessor for CountingProcessor {
+        type P = PileupPosition;
+
+        fn process_region(&self, tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            TestProcessor {}.process_region(tid, start, stop)
         }
     }
+
+    #[test]
+    fn small_buffer_applies_backpressure() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let par_granges_runner = ParGranges::new(
+            bam_path,
+            None,
+            None,
+            Some(1),
+            Some(10),
+            CountingProcessor {
+                calls: Arc::clone(&calls),
+            },
+        )
+        .with_buffer_size(1);
+        let receiver = par_granges_runner.process().unwrap();
+
+        // Without a receiver reading, only a few of the 100 regions can be started
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let started = calls.load(Ordering::SeqCst);
+        assert!(started <= 3, "{} regions started", started);
+
+        let positions: Vec<usize> = receiver.into_iter().map(|p| p.unwrap().pos).collect();
+        assert_eq!(positions, (0..1000).collect::<Vec<_>>());
+        assert_eq!(calls.load(Ordering::SeqCst), 100);
+    }
+
+    /// Tracks the number of its values that are alive, from the start of the region they are in
+    /// until the receiver takes them
+    struct TrackingProcessor {
+        live: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+        calls: Arc<AtomicUsize>,
+    }
+    impl RegionProcessor for TrackingProcessor {
+        type P = [u64; 8];
+
+        fn process_region(&self, _tid: u32, start: u64, stop: u64) -> Result<Vec<Self::P>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let len = (stop - start) as usize;
+            let live = self.live.fetch_add(len, Ordering::SeqCst) + len;
+            self.peak.fetch_max(live, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            Ok((start..stop).map(|pos| [pos; 8]).collect())
+        }
+    }
+
+    #[test]
+    fn buffer_size_bounds_values_in_flight() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let live = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let value_size = mem::size_of::<[u64; 8]>();
+        let par_granges_runner = ParGranges::new(
+            bam_path,
+            None,
+            None,
+            Some(4),
+            Some(100),
+            TrackingProcessor {
+                live: Arc::clone(&live),
+                peak: Arc::clone(&peak),
+                calls: Arc::clone(&calls),
+            },
+        )
+        .with_buffer_size(200 * value_size);
+
+        // A slow receiver lets the workers run ahead as far as the buffer allows
+        let mut positions = vec![];
+        for value in par_granges_runner.process().unwrap() {
+            positions.push(value.unwrap()[0]);
+            live.fetch_sub(1, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_micros(200));
+        }
+        assert_eq!(positions, (0..1000).collect::<Vec<_>>());
+        // The regions are not made shorter than the chunksize
+        assert_eq!(calls.load(Ordering::SeqCst), 10);
+        // At most a region per worker on top of the buffer, rather than the two regions per
+        // worker that could be in flight without it
+        let peak = peak.load(Ordering::SeqCst);
+        assert!(peak <= 4 * 100 + 200, "{} values in flight", peak);
+    }
+
+    #[test]
+    fn readers_are_reused() {
+        let tempdir = tempdir().unwrap();
+        let bam_path = empty_bam(tempdir.path());
+        let target_count = |reader: &mut IndexedReader| Ok(reader.header().target_count());
+        assert_eq!(with_reader(&bam_path, None, target_count).unwrap(), 1);
+
+        // With the files gone, only the reader this thread already has can be used
+        std::fs::remove_file(&bam_path).unwrap();
+        std::fs::remove_file(bam_path.with_extension("bam.bai")).unwrap();
+        assert_eq!(with_reader(&bam_path, None, target_count).unwrap(), 1);
+        // Another thread has to open its own
+        let other_path = bam_path.clone();
+        let other = std::thread::spawn(move || with_reader(&other_path, None, target_count));
+        assert!(other.join().unwrap().is_err());
+
+        // A failure drops the reader
+        let err = with_reader(&bam_path, None, |_| -> Result<()> {
+            Err(Error::msg("failed"))
+        });
+        assert!(err.is_err());
+        assert!(with_reader(&bam_path, None, target_count).is_err());
+    }
 }
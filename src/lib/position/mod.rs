@@ -2,7 +2,6 @@
 pub mod pileup_position;
 pub mod range_positions;
 
-
 use serde::Serialize;
 use smartstring::alias::String;
 /// A serializable object meant to hold all information about a position.
@@ -10,4 +9,3 @@ pub trait Position: Default + Serialize {
     /// Create a new position with all other values zeroed
     fn new(ref_seq: String, pos: usize) -> Self;
 }
-
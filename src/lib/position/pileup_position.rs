@@ -1,30 +1,41 @@
 //! An implementation of `Position` for dealing with pileups.
 use crate::position::Position;
-use crate::read_filter::ReadFilter;
+use crate::read_filter::{tag_bytes, FailReason, ReadFilter};
+use crate::read_groups::ReadGroups;
 use itertools::Itertools;
 use rust_htslib::bam::{
     self,
+    ext::BamRecordExtensions,
     pileup::{Alignment, Pileup},
     record::Record,
 };
-use serde::Serialize;
+use serde::{
+    ser::{SerializeStruct, Serializer},
+    Serialize,
+};
 use smartstring::alias::String;
-use std::{cmp::Ordering, default};
+use std::{cmp::Ordering, collections::BTreeMap, default};
+
+/// The tag value reads without the tag are reported under when splitting by a tag.
+pub const UNKNOWN_TAG_VALUE: &str = "UNKNOWN";
 
 /// Hold all information about a position.
-#[derive(Debug, Serialize, Default)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+///
+/// The optional groups of columns are boxed and only allocated when asked for with
+/// [`OptionalColumns`], so that a position without them stays small.
+#[derive(Debug, Clone, Default)]
 pub struct PileupPosition {
     /// Reference sequence name.
-    #[serde(rename = "REF")]
     pub ref_seq: String,
     /// 1-based position in the sequence.
     pub pos: usize,
     /// The reference base at this position.
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub ref_base: Option<char>,
     /// Total depth at this position.
     pub depth: usize,
+    /// Depth at this position before collapsing reads from the same molecule, only set when
+    /// collapsing by UMI.
+    pub raw_depth: Option<usize>,
     /// Number of A bases at this position.
     pub a: usize,
     /// Number of C bases at this position.
@@ -44,6 +55,235 @@ pub struct PileupPosition {
     pub ref_skip: usize,
     /// Number of reads failing filters at this position.
     pub fail: usize,
+    /// Number of bases below the minimum base quality at this position, only set when a minimum
+    /// base quality is given. Does not count toward depth.
+    pub low_qual: Option<u32>,
+    /// The labels of the site, sample, or group of reads these counts came from, if any.
+    labels: Option<Box<Labels>>,
+    /// Counts split by strand, if tracked.
+    strand: Option<Box<StrandCounts>>,
+    /// Running sums of the base qualities for A, C, G, T, and N, in that order, if tracked.
+    qual_sums: Option<Box<[u64; 5]>>,
+    /// Number of reads failing filters at this position, by [`FailReason::index`], if tracked.
+    fail_reasons: Option<Box<[usize; 5]>>,
+}
+
+/// The optional groups of columns a [`PileupPosition`] can keep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptionalColumns {
+    /// Count the nucleotides, insertions, and deletions seen on each strand, see [`StrandCounts`].
+    pub strand: bool,
+    /// Report the mean base quality of each nucleotide.
+    pub mean_quals: bool,
+    /// Break the reads failing filters down by the reason they failed, see [`FailReason`].
+    pub fail_reasons: bool,
+}
+
+/// Labels for what a [`PileupPosition`] was counted from, each only set by some modes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels {
+    /// The ID of the site this position was requested as, only set when reporting on a list of sites.
+    pub id: Option<String>,
+    /// The sample these counts came from, only set when counting several samples at once.
+    pub sample: Option<String>,
+    /// The read group these counts came from, only set when splitting counts by read group.
+    pub read_group: Option<String>,
+    /// The value of the aux tag these counts came from, only set when splitting counts by a tag.
+    pub tag_value: Option<String>,
+}
+
+/// The labels of a position without any.
+static NO_LABELS: Labels = Labels {
+    id: None,
+    sample: None,
+    read_group: None,
+    tag_value: None,
+};
+
+/// Nucleotide, insertion, and deletion counts split by the strand of the read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StrandCounts {
+    /// Number of A bases from forward strand reads at this position.
+    pub a_fwd: usize,
+    /// Number of A bases from reverse strand reads at this position.
+    pub a_rev: usize,
+    /// Number of C bases from forward strand reads at this position.
+    pub c_fwd: usize,
+    /// Number of C bases from reverse strand reads at this position.
+    pub c_rev: usize,
+    /// Number of G bases from forward strand reads at this position.
+    pub g_fwd: usize,
+    /// Number of G bases from reverse strand reads at this position.
+    pub g_rev: usize,
+    /// Number of T bases from forward strand reads at this position.
+    pub t_fwd: usize,
+    /// Number of T bases from reverse strand reads at this position.
+    pub t_rev: usize,
+    /// Number of N bases from forward strand reads at this position.
+    pub n_fwd: usize,
+    /// Number of N bases from reverse strand reads at this position.
+    pub n_rev: usize,
+    /// Number of insertions from forward strand reads at this position.
+    pub ins_fwd: usize,
+    /// Number of insertions from reverse strand reads at this position.
+    pub ins_rev: usize,
+    /// Number of deletions from forward strand reads at this position.
+    pub del_fwd: usize,
+    /// Number of deletions from reverse strand reads at this position.
+    pub del_rev: usize,
+}
+
+impl StrandCounts {
+    /// Each count with its column name, in column order.
+    fn columns(&self) -> [(&'static str, usize); 14] {
+        [
+            ("A_FWD", self.a_fwd),
+            ("A_REV", self.a_rev),
+            ("C_FWD", self.c_fwd),
+            ("C_REV", self.c_rev),
+            ("G_FWD", self.g_fwd),
+            ("G_REV", self.g_rev),
+            ("T_FWD", self.t_fwd),
+            ("T_REV", self.t_rev),
+            ("N_FWD", self.n_fwd),
+            ("N_REV", self.n_rev),
+            ("INS_FWD", self.ins_fwd),
+            ("INS_REV", self.ins_rev),
+            ("DEL_FWD", self.del_fwd),
+            ("DEL_REV", self.del_rev),
+        ]
+    }
+}
+
+/// Serialize the columns in a fixed order, leaving out the optional columns that aren't set.
+impl Serialize for PileupPosition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let labels = self.labels();
+        let optional = [
+            labels.id.is_some(),
+            self.ref_base.is_some(),
+            labels.sample.is_some(),
+            labels.read_group.is_some(),
+            labels.tag_value.is_some(),
+            self.raw_depth.is_some(),
+            self.low_qual.is_some(),
+        ];
+        let len = 12
+            + optional.iter().filter(|set| **set).count()
+            + self.strand.as_ref().map_or(0, |_| 14)
+            + self.qual_sums.as_ref().map_or(0, |_| 5)
+            + self.fail_reasons.as_ref().map_or(0, |_| 5);
+        let mut state = serializer.serialize_struct("PileupPosition", len)?;
+        state.serialize_field("REF", &self.ref_seq)?;
+        state.serialize_field("POS", &self.pos)?;
+        serialize_option(&mut state, "ID", &labels.id)?;
+        serialize_option(&mut state, "REF_BASE", &self.ref_base)?;
+        serialize_option(&mut state, "SAMPLE", &labels.sample)?;
+        serialize_option(&mut state, "READ_GROUP", &labels.read_group)?;
+        serialize_option(&mut state, "TAG_VALUE", &labels.tag_value)?;
+        state.serialize_field("DEPTH", &self.depth)?;
+        serialize_option(&mut state, "RAW_DEPTH", &self.raw_depth)?;
+        state.serialize_field("A", &self.a)?;
+        state.serialize_field("C", &self.c)?;
+        state.serialize_field("G", &self.g)?;
+        state.serialize_field("T", &self.t)?;
+        state.serialize_field("N", &self.n)?;
+        state.serialize_field("INS", &self.ins)?;
+        state.serialize_field("DEL", &self.del)?;
+        state.serialize_field("REF_SKIP", &self.ref_skip)?;
+        state.serialize_field("FAIL", &self.fail)?;
+        serialize_option(&mut state, "LOW_QUAL", &self.low_qual)?;
+        if let Some(strand) = self.strand() {
+            for (name, count) in strand.columns().iter() {
+                state.serialize_field(name, count)?;
+            }
+        }
+        if let Some(mean_quals) = self.mean_quals() {
+            let names = [
+                "MEAN_QUAL_A",
+                "MEAN_QUAL_C",
+                "MEAN_QUAL_G",
+                "MEAN_QUAL_T",
+                "MEAN_QUAL_N",
+            ];
+            for (name, mean) in names.iter().zip(mean_quals.iter()) {
+                state.serialize_field(name, mean)?;
+            }
+        }
+        if let Some(fail_reasons) = self.fail_reasons() {
+            let names = [
+                "FAIL_DUP",
+                "FAIL_QC",
+                "FAIL_FLAG",
+                "FAIL_MAPQ",
+                "FAIL_OTHER",
+            ];
+            for reason in FailReason::ALL.iter() {
+                state.serialize_field(names[reason.index()], &fail_reasons[reason.index()])?;
+            }
+        }
+        state.end()
+    }
+}
+
+/// Serialize an optional column, or skip it if it isn't set.
+fn serialize_option<S: SerializeStruct, T: Serialize>(
+    state: &mut S,
+    name: &'static str,
+    value: &Option<T>,
+) -> Result<(), S::Error> {
+    match value {
+        Some(value) => state.serialize_field(name, value),
+        None => state.skip_field(name),
+    }
+}
+
+/// The molecule a read came from, for counting each molecule once when collapsing by UMI.
+///
+/// Reads with the same UMI whose fragments start at the same position on the same strand are
+/// taken to be copies of one molecule. The fragment of a pair whose mate is mapped to the same
+/// contig starts at the leftmost of the two mates, and is on the strand of the first mate, so
+/// that both mates of a pair share a molecule. The fragment of any other read starts at its 5'
+/// end, and is on its strand. Reads without a UMI are each their own molecule, with their mate.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Molecule {
+    /// A read with a UMI
+    Umi {
+        umi: Vec<u8>,
+        start: i64,
+        reverse: bool,
+    },
+    /// A read without a UMI, by read name
+    Read(Vec<u8>),
+}
+
+impl Molecule {
+    /// The molecule `record` came from, with its UMI in `umi_tag`.
+    fn of(record: &Record, umi_tag: &[u8; 2]) -> Self {
+        let umi = match tag_bytes(record, umi_tag) {
+            Some(umi) => umi.into_owned(),
+            None => return Molecule::Read(record.qname().to_owned()),
+        };
+        let paired =
+            record.is_paired() && !record.is_mate_unmapped() && record.tid() == record.mtid();
+        let (start, reverse) = if paired {
+            let reverse = if record.is_last_in_template() {
+                record.is_mate_reverse()
+            } else {
+                record.is_reverse()
+            };
+            (std::cmp::min(record.pos(), record.mpos()), reverse)
+        } else if record.is_reverse() {
+            (record.reference_end(), true)
+        } else {
+            (record.pos(), false)
+        };
+        Molecule::Umi {
+            umi,
+            start,
+            reverse,
+        }
+    }
 }
 
 impl Position for PileupPosition {
@@ -59,12 +299,22 @@ impl Position for PileupPosition {
 
 impl PileupPosition {
     /// Given a record, update the counts at this position
-    fn update<F: ReadFilter>(&mut self, alignment: &Alignment, record: Record, read_filter: &F) {
-        if !read_filter.filter_read(&record) {
+    fn update<F: ReadFilter>(
+        &mut self,
+        alignment: &Alignment,
+        record: Record,
+        read_filter: &F,
+        base_filter: Option<u8>,
+    ) {
+        if let Some(reason) = read_filter.fail_reason(&record) {
             self.depth -= 1;
             self.fail += 1;
+            if let Some(fail_reasons) = self.fail_reasons.as_deref_mut() {
+                fail_reasons[reason.index()] += 1;
+            }
             return;
         }
+        let reverse = record.is_reverse();
         // NB: Order matters here, a refskip is true for both is_del and is_refskip
         // while a true del is only true for is_del
         if alignment.is_refskip() {
@@ -72,22 +322,183 @@ impl PileupPosition {
             self.depth -= 1;
         } else if alignment.is_del() {
             self.del += 1;
+            self.incr_strand(reverse, |s| (&mut s.del_fwd, &mut s.del_rev));
         } else {
             // We have an actual base!
-            match (record.seq()[alignment.qpos().unwrap()] as char).to_ascii_uppercase() {
-                'A' => self.a += 1,
-                'C' => self.c += 1,
-                'T' => self.t += 1,
-                'G' => self.g += 1,
-                _ => self.n += 1,
+            // Check for insertions first, an insertion is counted even if the base before it is low quality
+            if let bam::pileup::Indel::Ins(_len) = alignment.indel() {
+                self.ins += 1;
+                self.incr_strand(reverse, |s| (&mut s.ins_fwd, &mut s.ins_rev));
             }
-            // Check for insertions
-            match alignment.indel() {
-                bam::pileup::Indel::Ins(_len) => {
-                    self.ins += 1;
+            let qpos = alignment.qpos().unwrap();
+            let qual = record.qual()[qpos];
+            // Bases below the quality cutoff are set aside and don't count toward depth
+            if let Some(min_qual) = base_filter {
+                if qual < min_qual {
+                    *self.low_qual.get_or_insert(0) += 1;
+                    self.depth -= 1;
+                    return;
                 }
-                _ => (),
             }
+            let base_index = match (record.seq()[qpos] as char).to_ascii_uppercase() {
+                'A' => {
+                    self.a += 1;
+                    self.incr_strand(reverse, |s| (&mut s.a_fwd, &mut s.a_rev));
+                    0
+                }
+                'C' => {
+                    self.c += 1;
+                    self.incr_strand(reverse, |s| (&mut s.c_fwd, &mut s.c_rev));
+                    1
+                }
+                'G' => {
+                    self.g += 1;
+                    self.incr_strand(reverse, |s| (&mut s.g_fwd, &mut s.g_rev));
+                    2
+                }
+                'T' => {
+                    self.t += 1;
+                    self.incr_strand(reverse, |s| (&mut s.t_fwd, &mut s.t_rev));
+                    3
+                }
+                _ => {
+                    self.n += 1;
+                    self.incr_strand(reverse, |s| (&mut s.n_fwd, &mut s.n_rev));
+                    4
+                }
+            };
+            if let Some(qual_sums) = self.qual_sums.as_deref_mut() {
+                qual_sums[base_index] += u64::from(qual);
+            }
+        }
+    }
+
+    /// Increment the forward or reverse count picked out by `counts`, if strand resolved counts are
+    /// being tracked.
+    #[inline]
+    fn incr_strand(
+        &mut self,
+        reverse: bool,
+        counts: impl FnOnce(&mut StrandCounts) -> (&mut usize, &mut usize),
+    ) {
+        if let Some(strand) = self.strand.as_deref_mut() {
+            let (fwd, rev) = counts(strand);
+            *if reverse { rev } else { fwd } += 1;
+        }
+    }
+
+    /// Create a new position for the given ref_seq name, keeping the given optional columns.
+    pub fn with_columns(ref_seq: String, pos: usize, columns: OptionalColumns) -> Self {
+        let mut position = Self::new(ref_seq, pos);
+        if columns.strand {
+            position.strand = Some(Box::default());
+        }
+        if columns.mean_quals {
+            position.qual_sums = Some(Box::default());
+        }
+        if columns.fail_reasons {
+            position.fail_reasons = Some(Box::default());
+        }
+        position
+    }
+
+    /// Create an empty position at the location of a pileup.
+    fn at_pileup(pileup: &Pileup, header: &bam::HeaderView, columns: OptionalColumns) -> Self {
+        let name = std::str::from_utf8(header.tid2name(pileup.tid())).unwrap();
+        // make output 1-based
+        let mut pos = Self::with_columns(String::from(name), (pileup.pos()) as usize, columns);
+        pos.depth = pileup.depth() as usize;
+        pos
+    }
+
+    /// Count each of the alignments.
+    fn count<'a, F: ReadFilter>(
+        &mut self,
+        alignments: impl Iterator<Item = (Alignment<'a>, Record)>,
+        read_filter: &F,
+        base_filter: Option<u8>,
+    ) {
+        self.track_low_qual(base_filter);
+        for (alignment, record) in alignments {
+            self.update(&alignment, record, read_filter, base_filter);
+        }
+    }
+
+    /// Count each of the alignments, only counting one read of each overlapping pair of mates.
+    fn count_mate_aware<'a, F: ReadFilter>(
+        &mut self,
+        alignments: impl Iterator<Item = (Alignment<'a>, Record)>,
+        read_filter: &F,
+        base_filter: Option<u8>,
+    ) {
+        // TODO: I'm not sure there is a good way to remove this allocation
+        self.count_grouped(alignments, read_filter, base_filter, |record| {
+            record.qname().to_owned()
+        });
+    }
+
+    /// Count each of the alignments, only counting one read of each molecule, see [`Molecule`].
+    fn count_umi_aware<'a, F: ReadFilter>(
+        &mut self,
+        alignments: impl Iterator<Item = (Alignment<'a>, Record)>,
+        read_filter: &F,
+        base_filter: Option<u8>,
+        umi_tag: &[u8; 2],
+    ) {
+        self.count_grouped(alignments, read_filter, base_filter, |record| {
+            Molecule::of(record, umi_tag)
+        });
+    }
+
+    /// Count one read of each group of alignments that share a key.
+    fn count_grouped<'a, F: ReadFilter, K: Ord + Clone>(
+        &mut self,
+        alignments: impl Iterator<Item = (Alignment<'a>, Record)>,
+        read_filter: &F,
+        base_filter: Option<u8>,
+        key: impl Fn(&Record) -> K,
+    ) {
+        self.track_low_qual(base_filter);
+        // Group records by key
+        let grouped = alignments
+            .map(|a| (key(&a.1), a))
+            .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
+            .group_by(|a| a.0.clone());
+
+        for (_key, reads) in grouped.into_iter() {
+            // Choose the best of the reads based on mapq, if tied, check which is first and passes filters
+            let mut total_reads = 0; // count how many reads there were
+            let (alignment, record) = reads
+                .into_iter()
+                .map(|(_key, read)| read)
+                .inspect(|_| total_reads += 1)
+                .max_by(|a, b| match a.1.mapq().cmp(&b.1.mapq()) {
+                    Ordering::Greater => Ordering::Greater,
+                    Ordering::Less => Ordering::Less,
+                    Ordering::Equal => {
+                        // Check if a is first in pair
+                        if a.1.flags() & 64 == 0 && read_filter.filter_read(&a.1) {
+                            Ordering::Greater
+                        } else if b.1.flags() & 64 == 0 && read_filter.filter_read(&b.1) {
+                            Ordering::Less
+                        } else {
+                            // Default to `a` in the event that there is no first in pair for some reason
+                            Ordering::Greater
+                        }
+                    }
+                })
+                .unwrap();
+            // decrement depth for each read not used
+            self.depth -= total_reads - 1;
+            self.update(&alignment, record, read_filter, base_filter);
+        }
+    }
+
+    /// Report the `LOW_QUAL` column if there is a minimum base quality, even at a position without
+    /// any low quality bases.
+    fn track_low_qual(&mut self, base_filter: Option<u8>) {
+        if base_filter.is_some() {
+            self.low_qual.get_or_insert(0);
         }
     }
 
@@ -101,20 +512,21 @@ impl PileupPosition {
     /// * `pileup` - a pileup at a genomic position
     /// * `header` - a headerview for the bam file being read, to get the sequence name
     /// * `read_filter` - a function to filter out reads, returning false will cause a read to be filtered
+    /// * `base_filter` - an optional minimum base quality, bases below it are counted as `low_qual` instead of toward depth
+    /// * `columns` - the optional groups of columns to keep, such as the counts on each strand
     pub fn from_pileup<F: ReadFilter>(
         pileup: Pileup,
         header: &bam::HeaderView,
         read_filter: &F,
+        base_filter: Option<u8>,
+        columns: OptionalColumns,
     ) -> Self {
-        let name = std::str::from_utf8(header.tid2name(pileup.tid())).unwrap();
-        // make output 1-based
-        let mut pos = Self::new(String::from(name), (pileup.pos()) as usize);
-        pos.depth = pileup.depth() as usize;
-
-        for alignment in pileup.alignments() {
-            let record = alignment.record();
-            &pos.update(&alignment, record, read_filter);
-        }
+        let mut pos = Self::at_pileup(&pileup, header, columns);
+        let alignments = pileup.alignments().map(|aln| {
+            let record = aln.record();
+            (aln, record)
+        });
+        pos.count(alignments, read_filter, base_filter);
         pos
     }
 
@@ -133,56 +545,270 @@ impl PileupPosition {
     /// * `pileup` - a pileup at a genomic position
     /// * `header` - a headerview for the bam file being read, to get the sequence name
     /// * `read_filter` - a function to filter out reads, returning false will cause a read to be filtered
+    /// * `base_filter` - an optional minimum base quality, bases below it are counted as `low_qual` instead of toward depth
+    /// * `columns` - the optional groups of columns to keep, such as the counts on each strand
     pub fn from_pileup_mate_aware<F: ReadFilter>(
         pileup: Pileup,
         header: &bam::HeaderView,
         read_filter: &F,
+        base_filter: Option<u8>,
+        columns: OptionalColumns,
     ) -> Self {
-        let name = std::str::from_utf8(header.tid2name(pileup.tid())).unwrap();
-        // make output 1-based
-        let mut pos = Self::new(String::from(name), (pileup.pos()) as usize);
-        pos.depth = pileup.depth() as usize;
+        let mut pos = Self::at_pileup(&pileup, header, columns);
+        let alignments = pileup.alignments().map(|aln| {
+            let record = aln.record();
+            (aln, record)
+        });
+        pos.count_mate_aware(alignments, read_filter, base_filter);
+        pos
+    }
 
-        // Group records by qname
-        let grouped_by_qname = pileup
-            .alignments()
-            .map(|aln| {
+    /// Convert a pileup into a `Position`, counting each molecule once.
+    ///
+    /// This is for UMI libraries where the duplicate flags can't be trusted. Reads are grouped into
+    /// molecules by their UMI, given by `umi_tag`, plus the start and strand of their fragment, see
+    /// [`Molecule`], and one read of each molecule is chosen the same way one mate is chosen in
+    /// [`PileupPosition::from_pileup_mate_aware`]. Since both mates of a pair belong to the same
+    /// molecule, overlapping mates are only counted once as well.
+    ///
+    /// The collapsed depth is reported as `depth`, and the depth without collapsing as `raw_depth`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pileup` - a pileup at a genomic position
+    /// * `header` - a headerview for the bam file being read, to get the sequence name
+    /// * `read_filter` - a function to filter out reads, returning false will cause a read to be filtered
+    /// * `base_filter` - an optional minimum base quality, bases below it are counted as `low_qual` instead of toward depth
+    /// * `columns` - the optional groups of columns to keep, such as the counts on each strand
+    /// * `umi_tag` - the aux tag holding the UMI of each read, such as `RX`
+    pub fn from_pileup_umi_aware<F: ReadFilter>(
+        pileup: Pileup,
+        header: &bam::HeaderView,
+        read_filter: &F,
+        base_filter: Option<u8>,
+        columns: OptionalColumns,
+        umi_tag: &[u8; 2],
+    ) -> Self {
+        let alignments = || {
+            pileup.alignments().map(|aln| {
                 let record = aln.record();
                 (aln, record)
             })
-            .sorted_by(|a, b| Ord::cmp(a.1.qname(), b.1.qname()))
-            // TODO: I'm not sure there is a good way to remove this allocation
-            .group_by(|a| a.1.qname().to_owned());
+        };
+        let mut raw = Self::at_pileup(&pileup, header, OptionalColumns::default());
+        raw.count(alignments(), read_filter, base_filter);
 
-        for (_qname, reads) in grouped_by_qname.into_iter() {
-            // Choose the best of the reads based on mapq, if tied, check which is first and passes filters
-            let mut total_reads = 0; // count how many reads there were
-            let (alignment, record) = reads
-                .into_iter()
-                .map(|x| {
-                    total_reads += 1;
-                    x
-                })
-                .max_by(|a, b| match a.1.mapq().cmp(&b.1.mapq()) {
-                    Ordering::Greater => Ordering::Greater,
-                    Ordering::Less => Ordering::Less,
-                    Ordering::Equal => {
-                        // Check if a is first in pair
-                        if a.1.flags() & 64 == 0 && read_filter.filter_read(&a.1) {
-                            Ordering::Greater
-                        } else if b.1.flags() & 64 == 0 && read_filter.filter_read(&b.1) {
-                            Ordering::Less
-                        } else {
-                            // Default to `a` in the event that there is no first in pair for some reason
-                            Ordering::Greater
-                        }
-                    }
-                })
-                .unwrap();
-            // decrement depth for each read not used
-            pos.depth -= total_reads - 1;
-            pos.update(&alignment, record, read_filter);
-        }
+        let mut pos = Self::at_pileup(&pileup, header, columns);
+        pos.count_umi_aware(alignments(), read_filter, base_filter, umi_tag);
+        pos.raw_depth = Some(raw.depth);
         pos
     }
+
+    /// Convert a pileup into one `Position` for each read group that has reads at this position.
+    ///
+    /// Each read is counted toward the group given by [`ReadGroups::index`], and the name of the group
+    /// is set as the `read_group` of its position. Positions are returned in group order.
+    ///
+    /// # Arguments
+    ///
+    /// * `pileup` - a pileup at a genomic position
+    /// * `header` - a headerview for the bam file being read, to get the sequence name
+    /// * `read_filter` - a function to filter out reads, returning false will cause a read to be filtered
+    /// * `base_filter` - an optional minimum base quality, bases below it are counted as `low_qual` instead of toward depth
+    /// * `columns` - the optional groups of columns to keep, such as the counts on each strand
+    /// * `mate_aware` - if true, count overlapping mates as in [`PileupPosition::from_pileup_mate_aware`]
+    /// * `read_groups` - the read groups to split the reads into
+    pub fn from_pileup_by_read_group<F: ReadFilter>(
+        pileup: Pileup,
+        header: &bam::HeaderView,
+        read_filter: &F,
+        base_filter: Option<u8>,
+        columns: OptionalColumns,
+        mate_aware: bool,
+        read_groups: &ReadGroups,
+    ) -> Vec<Self> {
+        let mut groups: Vec<Vec<(Alignment, Record)>> =
+            (0..read_groups.len()).map(|_| vec![]).collect();
+        for alignment in pileup.alignments() {
+            let record = alignment.record();
+            groups[read_groups.index(&record)].push((alignment, record));
+        }
+
+        groups
+            .into_iter()
+            .enumerate()
+            .filter(|(_, alignments)| !alignments.is_empty())
+            .map(|(index, alignments)| {
+                let mut pos = Self::at_pileup(&pileup, header, columns);
+                pos.depth = alignments.len();
+                pos.labels_mut().read_group = Some(read_groups.name(index).clone());
+                if mate_aware {
+                    pos.count_mate_aware(alignments.into_iter(), read_filter, base_filter);
+                } else {
+                    pos.count(alignments.into_iter(), read_filter, base_filter);
+                }
+                pos
+            })
+            .collect()
+    }
+
+    /// Convert a pileup into one `Position` for each value of an aux tag, such as the `CB` cell
+    /// barcode, that has reads at this position.
+    ///
+    /// Only the values seen at this position get a `Position`, so positions stay sparse no matter
+    /// how many values there are overall. The value is set as the `tag_value` of its position, and
+    /// positions are returned sorted by value, with reads that don't have the tag counted last under
+    /// [`UNKNOWN_TAG_VALUE`].
+    ///
+    /// # Arguments
+    ///
+    /// * `pileup` - a pileup at a genomic position
+    /// * `header` - a headerview for the bam file being read, to get the sequence name
+    /// * `read_filter` - a function to filter out reads, returning false will cause a read to be filtered
+    /// * `base_filter` - an optional minimum base quality, bases below it are counted as `low_qual` instead of toward depth
+    /// * `columns` - the optional groups of columns to keep, such as the counts on each strand
+    /// * `mate_aware` - if true, count overlapping mates as in [`PileupPosition::from_pileup_mate_aware`]
+    /// * `tag` - the aux tag to split the reads by
+    pub fn from_pileup_by_tag<F: ReadFilter>(
+        pileup: Pileup,
+        header: &bam::HeaderView,
+        read_filter: &F,
+        base_filter: Option<u8>,
+        columns: OptionalColumns,
+        mate_aware: bool,
+        tag: &[u8; 2],
+    ) -> Vec<Self> {
+        let mut groups: BTreeMap<Vec<u8>, Vec<(Alignment, Record)>> = BTreeMap::new();
+        let mut untagged = vec![];
+        for alignment in pileup.alignments() {
+            let record = alignment.record();
+            match tag_bytes(&record, tag) {
+                Some(value) => groups
+                    .entry(value.into_owned())
+                    .or_default()
+                    .push((alignment, record)),
+                None => untagged.push((alignment, record)),
+            }
+        }
+
+        let unknown = (UNKNOWN_TAG_VALUE.as_bytes().to_vec(), untagged);
+        groups
+            .into_iter()
+            .chain(std::iter::once(unknown))
+            .filter(|(_, alignments)| !alignments.is_empty())
+            .map(|(value, alignments)| {
+                let mut pos = Self::at_pileup(&pileup, header, columns);
+                pos.depth = alignments.len();
+                pos.labels_mut().tag_value =
+                    Some(String::from(std::string::String::from_utf8_lossy(&value)));
+                if mate_aware {
+                    pos.count_mate_aware(alignments.into_iter(), read_filter, base_filter);
+                } else {
+                    pos.count(alignments.into_iter(), read_filter, base_filter);
+                }
+                pos
+            })
+            .collect()
+    }
+
+    /// The labels of the site, sample, or group of reads these counts came from.
+    pub fn labels(&self) -> &Labels {
+        self.labels.as_deref().unwrap_or(&NO_LABELS)
+    }
+
+    /// The labels of this position, to set them.
+    pub fn labels_mut(&mut self) -> &mut Labels {
+        self.labels.get_or_insert_with(Box::default)
+    }
+
+    /// The counts split by strand, if they were tracked.
+    pub fn strand(&self) -> Option<&StrandCounts> {
+        self.strand.as_deref()
+    }
+
+    /// The mean base quality of A, C, G, T, and N, in that order, if base qualities were tracked.
+    ///
+    /// Nucleotides that were not observed at this position get a mean quality of 0.
+    pub fn mean_quals(&self) -> Option<[f64; 5]> {
+        let sums = self.qual_sums.as_deref()?;
+        let counts = [self.a, self.c, self.g, self.t, self.n];
+        let mut means = [0.0; 5];
+        for ((mean, sum), count) in means.iter_mut().zip(sums.iter()).zip(counts.iter()) {
+            if *count > 0 {
+                *mean = *sum as f64 / *count as f64;
+            }
+        }
+        Some(means)
+    }
+
+    /// The number of reads failing filters at this position by [`FailReason::index`], if the
+    /// reasons were tracked.
+    pub fn fail_reasons(&self) -> Option<&[usize; 5]> {
+        self.fail_reasons.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The header and row a position is written as
+    fn write(pos: &PileupPosition) -> Vec<Vec<std::string::String>> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+        writer.serialize(pos).unwrap();
+        let output = std::string::String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        output
+            .lines()
+            .map(|line| line.split('\t').map(|s| s.to_owned()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn default_position_is_small() {
+        // Optional columns are boxed, so only the base columns take space in every position
+        assert!(std::mem::size_of::<PileupPosition>() <= 176);
+    }
+
+    #[test]
+    fn default_columns() {
+        let pos = PileupPosition::new(String::from("chr1"), 5);
+        let written = write(&pos);
+        assert_eq!(
+            written[0],
+            vec!["REF", "POS", "DEPTH", "A", "C", "G", "T", "N", "INS", "DEL", "REF_SKIP", "FAIL"]
+        );
+        assert_eq!(written[1][..2], ["chr1", "5"]);
+    }
+
+    #[test]
+    fn optional_columns() {
+        let columns = OptionalColumns {
+            strand: true,
+            mean_quals: true,
+            fail_reasons: true,
+        };
+        let mut pos = PileupPosition::with_columns(String::from("chr1"), 5, columns);
+        pos.ref_base = Some('A');
+        pos.raw_depth = Some(0);
+        pos.low_qual = Some(0);
+        *pos.labels_mut() = Labels {
+            id: Some(String::from("rs1")),
+            sample: Some(String::from("tumor")),
+            read_group: Some(String::from("rg1")),
+            tag_value: Some(String::from("AAAA-1")),
+        };
+        let written = write(&pos);
+        let expected = "REF POS ID REF_BASE SAMPLE READ_GROUP TAG_VALUE DEPTH RAW_DEPTH A C G T N INS DEL \
+            REF_SKIP FAIL LOW_QUAL A_FWD A_REV C_FWD C_REV G_FWD G_REV T_FWD T_REV N_FWD N_REV INS_FWD \
+            INS_REV DEL_FWD DEL_REV MEAN_QUAL_A MEAN_QUAL_C MEAN_QUAL_G MEAN_QUAL_T MEAN_QUAL_N FAIL_DUP \
+            FAIL_QC FAIL_FLAG FAIL_MAPQ FAIL_OTHER";
+        assert_eq!(written[0], expected.split(' ').collect::<Vec<_>>());
+        assert_eq!(
+            written[1][..7],
+            ["chr1", "5", "rs1", "A", "tumor", "rg1", "AAAA-1"]
+        );
+        assert_eq!(written[1][33], "0.0");
+    }
 }
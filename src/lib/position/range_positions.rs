@@ -4,7 +4,7 @@
 use crate::position::Position;
 use serde::Serialize;
 use smartstring::alias::String;
-use std::{default};
+use std::default;
 
 /// Hold all information about a range of positions.
 #[derive(Debug, Serialize, Default)]
@@ -17,6 +17,9 @@ pub struct RangePositions {
     pub pos: usize,
     /// The point at which this depth ends, non-inclusive
     pub end: usize,
+    /// The read group of this depth, only set when splitting depths by read group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_group: Option<String>,
     /// Total depth at this position.
     pub depth: usize,
 }
@@ -30,4 +33,4 @@ impl Position for RangePositions {
             ..default::Default::default()
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,328 @@
+//! Estimating how densely reads are packed along each contig from the bins of a BAI index, so
+//! that regions can be sized by how much data they hold instead of only by their length.
+//!
+//! Each read in a BAM is put in the smallest bin of the index that fully contains it, and the bin
+//! records the chunks of the file its reads are stored in. The size of those chunks is roughly
+//! the number of bytes of reads in the bin, which is a good proxy for the work of processing it.
+use anyhow::{Context, Error, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The width of the smallest bins of a BAI index, and of the windows densities are kept for.
+pub const WINDOW_SIZE: u64 = 1 << 14;
+
+/// The bin number of the BAI pseudo-bin, which holds summary stats rather than reads.
+const PSEUDO_BIN: u32 = 37450;
+
+/// The first bin number of each level of the binning scheme, from the largest bins to the smallest.
+const LEVEL_STARTS: [u32; 6] = [0, 1, 9, 73, 585, 4681];
+
+/// The approximate ratio of uncompressed to compressed BAM bytes, used to measure chunks within
+/// a BGZF block on the same scale as chunks spanning blocks.
+const COMPRESSION_RATIO: i64 = 3;
+
+/// The estimated number of bytes of reads in each window of each contig.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadDensity {
+    /// The estimated bytes in each window, indexed by tid and then window
+    windows: Vec<Vec<u64>>,
+}
+
+impl ReadDensity {
+    /// Estimate the density of reads in all of the given BAM files, summed together.
+    ///
+    /// Returns `None` if any of them doesn't have a BAI index next to it, such as a CRAM or a BAM
+    /// with a CSI index, since only BAI indexes are read.
+    pub fn for_reads(reads: &[PathBuf]) -> Result<Option<Self>> {
+        let mut density: Option<Self> = None;
+        for reads in reads {
+            let bai = match Self::find_bai(reads) {
+                Some(bai) => bai,
+                None => return Ok(None),
+            };
+            let other = Self::from_bai(&bai)?;
+            density = Some(match density {
+                Some(density) => density.add(other),
+                None => other,
+            });
+        }
+        Ok(density)
+    }
+
+    /// Find the BAI index of a BAM file, either `<name>.bam.bai` or `<name>.bai`.
+    fn find_bai(reads: &Path) -> Option<PathBuf> {
+        let mut appended = reads.as_os_str().to_owned();
+        appended.push(".bai");
+        [PathBuf::from(appended), reads.with_extension("bai")]
+            .iter()
+            .find(|path| path.is_file())
+            .cloned()
+    }
+
+    /// Read the bins of a BAI file.
+    pub fn from_bai(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read BAI {:?}", path))?;
+        Self::parse_bai(&bytes).with_context(|| format!("Failed to parse BAI {:?}", path))
+    }
+
+    /// Parse the contents of a BAI file, see section 5.2 of the SAM spec for the layout.
+    fn parse_bai(bytes: &[u8]) -> Result<Self> {
+        let mut reader = LeReader { bytes, pos: 0 };
+        if reader.take(4)? != b"BAI\x01" {
+            return Err(Error::msg("Not a BAI file"));
+        }
+        let n_ref = reader.u32()?;
+        let mut windows = Vec::with_capacity(n_ref as usize);
+        for _ in 0..n_ref {
+            // The bytes of reads in each bin, as (first window, last window + 1, bytes)
+            let mut bins = vec![];
+            for _ in 0..reader.u32()? {
+                let bin = reader.u32()?;
+                let mut bytes = 0;
+                for _ in 0..reader.u32()? {
+                    let (beg, end) = (reader.u64()?, reader.u64()?);
+                    bytes += Self::chunk_bytes(beg, end);
+                }
+                if bin != PSEUDO_BIN {
+                    let (start, end) = Self::bin_windows(bin)?;
+                    bins.push((start, end, bytes));
+                }
+            }
+            // The linear index has an entry for each window up to the last one with reads
+            let n_intv = reader.u32()? as u64;
+            reader.take(8 * n_intv as usize)?;
+
+            let mut contig = vec![0; n_intv as usize];
+            for (start, end, bytes) in bins {
+                // Spread the reads of large bins evenly over the windows that have reads
+                let end = std::cmp::max(std::cmp::min(end, n_intv), start + 1);
+                if end as usize > contig.len() {
+                    contig.resize(end as usize, 0);
+                }
+                let per_window = bytes / (end - start);
+                for window in &mut contig[start as usize..end as usize] {
+                    *window += per_window;
+                }
+            }
+            windows.push(contig);
+        }
+        Ok(Self { windows })
+    }
+
+    /// The windows covered by a bin, as (first window, last window + 1).
+    fn bin_windows(bin: u32) -> Result<(u64, u64)> {
+        let level = LEVEL_STARTS
+            .iter()
+            .rposition(|&start| bin >= start)
+            .unwrap();
+        let offset = (bin - LEVEL_STARTS[level]) as u64;
+        if level == LEVEL_STARTS.len() - 1 && offset >= (1 << 15) {
+            return Err(Error::msg(format!("Invalid bin {}", bin)));
+        }
+        // Each level's bins are 8 times smaller than the level above, down to a single window
+        let windows_per_bin = 1 << (3 * (LEVEL_STARTS.len() - 1 - level));
+        Ok((offset * windows_per_bin, (offset + 1) * windows_per_bin))
+    }
+
+    /// Estimate the bytes of reads between two virtual file offsets, on the compressed scale.
+    ///
+    /// How much of a BGZF block a chunk starts or ends partway through is not known, so the
+    /// estimate is only good to about a block.
+    fn chunk_bytes(beg: u64, end: u64) -> u64 {
+        // The upper 48 bits are the file offset of the block, so this is already in compressed bytes
+        let compressed = (end >> 16) as i64 - (beg >> 16) as i64;
+        // The lower 16 bits are the offset into the uncompressed block, so scale it down to match
+        let within = (end & 0xffff) as i64 - (beg & 0xffff) as i64;
+        std::cmp::max(compressed + within / COMPRESSION_RATIO, 0) as u64
+    }
+
+    /// Add the density of another file with the same contigs to this one.
+    fn add(mut self, other: Self) -> Self {
+        if other.windows.len() > self.windows.len() {
+            self.windows.resize(other.windows.len(), vec![]);
+        }
+        for (windows, other) in self.windows.iter_mut().zip(other.windows) {
+            if other.len() > windows.len() {
+                windows.resize(other.len(), 0);
+            }
+            for (bytes, other) in windows.iter_mut().zip(other) {
+                *bytes += other;
+            }
+        }
+        self
+    }
+
+    /// The estimated bytes of reads in every contig.
+    pub fn total_bytes(&self) -> u64 {
+        self.windows.iter().flatten().sum()
+    }
+
+    /// The estimated bytes of reads per base in a window.
+    fn density(&self, tid: u32, window: u64) -> f64 {
+        let bytes = self
+            .windows
+            .get(tid as usize)
+            .and_then(|windows| windows.get(window as usize))
+            .copied()
+            .unwrap_or(0);
+        bytes as f64 / WINDOW_SIZE as f64
+    }
+
+    /// Split `start..stop` on a contig into regions that each hold about `target_bytes` of reads,
+    /// and are never longer than `max_len`.
+    ///
+    /// Reads are assumed to be spread evenly within each window, so a window with many times the
+    /// target is split into that many equal pieces.
+    pub fn split(
+        &self,
+        tid: u32,
+        start: u64,
+        stop: u64,
+        max_len: u64,
+        target_bytes: f64,
+    ) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let mut region_start = start;
+        std::iter::from_fn(move || {
+            if region_start >= stop {
+                return None;
+            }
+            let mut pos = region_start;
+            let mut bytes = 0.0;
+            while pos < stop && pos - region_start < max_len && bytes < target_bytes {
+                let window = pos / WINDOW_SIZE;
+                let window_end = std::cmp::min((window + 1) * WINDOW_SIZE, stop);
+                let density = self.density(tid, window);
+                let mut step = std::cmp::min(window_end - pos, max_len - (pos - region_start));
+                if density > 0.0 {
+                    let to_target = ((target_bytes - bytes) / density).ceil() as u64;
+                    step = std::cmp::max(std::cmp::min(step, to_target), 1);
+                }
+                bytes += density * step as f64;
+                pos += step;
+            }
+            let region = (region_start, pos);
+            region_start = pos;
+            Some(region)
+        })
+    }
+}
+
+/// Reads little endian integers from a byte slice.
+struct LeReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LeReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .context("Unexpected end of file")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::bam;
+    use tempfile::tempdir;
+
+    #[test]
+    fn splits_dense_windows() {
+        let density = ReadDensity {
+            windows: vec![vec![0, 1600, 0, 16], vec![]],
+        };
+        let w = WINDOW_SIZE;
+        // The dense second window is split in 4, the sparse ones are only split by max_len
+        let regions: Vec<_> = density.split(0, 0, 4 * w, 2 * w, 400.0).collect();
+        assert_eq!(
+            regions,
+            vec![
+                (0, w + w / 4),
+                (w + w / 4, w + w / 2),
+                (w + w / 2, w + 3 * w / 4),
+                (w + 3 * w / 4, 2 * w),
+                (2 * w, 4 * w),
+            ]
+        );
+        // Contigs without reads are split only by max_len
+        let regions: Vec<_> = density.split(1, 0, 5 * w, 2 * w, 400.0).collect();
+        assert_eq!(regions, vec![(0, 2 * w), (2 * w, 4 * w), (4 * w, 5 * w)]);
+        // Regions cover the interval asked for
+        let regions: Vec<_> = density.split(0, 100, 200, 2 * w, 400.0).collect();
+        assert_eq!(regions, vec![(100, 200)]);
+    }
+
+    #[test]
+    fn reads_bai() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.bam");
+        let mut header = bam::header::Header::new();
+        for contig in &["chr1", "chr2"] {
+            let mut record = bam::header::HeaderRecord::new(b"SQ");
+            record.push_tag(b"SN", contig);
+            record.push_tag(b"LN", &100_000);
+            header.push_record(&record);
+        }
+        let view = bam::HeaderView::from_header(&header);
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::BAM).unwrap();
+        // A hotspot in the second window of chr1, and a few reads in the fourth
+        let starts = (0..210).map(|i| if i < 200 { 20_000 } else { 50_000 });
+        // Pseudo-random bases and qualities, so the file compresses about as well as real data
+        let mut state: u32 = 1;
+        let mut random = |choices: &[u8]| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            choices[(state >> 16) as usize % choices.len()] as char
+        };
+        for (i, start) in starts.enumerate() {
+            let seq: String = (0..100).map(|_| random(b"ACGT")).collect();
+            let qual: String = (0..100).map(|_| random(b"#+5?AFIJ")).collect();
+            let sam = format!(
+                "R{}\t0\tchr1\t{}\t40\t100M\t*\t0\t0\t{}\t{}",
+                i, start, seq, qual
+            );
+            writer
+                .write(&bam::Record::from_sam(&view, sam.as_bytes()).unwrap())
+                .unwrap();
+        }
+        drop(writer);
+        bam::index::build(&path, None, bam::index::Type::BAI, 1).unwrap();
+
+        let density = ReadDensity::for_reads(std::slice::from_ref(&path))
+            .unwrap()
+            .unwrap();
+        let chr1 = &density.windows[0];
+        // Estimates are only good to about a BGZF block, so only compare them loosely
+        assert!(chr1[1] > 2 * chr1[3], "{:?}", chr1);
+        assert!(chr1[3] > 0, "{:?}", chr1);
+        assert_eq!((chr1[0], chr1[2]), (0, 0), "{:?}", chr1);
+        assert!(density.windows[1].iter().all(|&bytes| bytes == 0));
+
+        // Two copies of the same file have twice the density
+        let doubled = ReadDensity::for_reads(&[path.clone(), path])
+            .unwrap()
+            .unwrap();
+        assert_eq!(doubled.total_bytes(), 2 * density.total_bytes());
+
+        // Without a BAI there is no density
+        let no_index = dir.path().join("other.bam");
+        assert_eq!(ReadDensity::for_reads(&[no_index]).unwrap(), None);
+    }
+}
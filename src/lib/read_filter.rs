@@ -1,19 +1,151 @@
 //! A trait and default implementation of a read filter.
-use rust_htslib::bam::{
-    record::Record,
+//!
+//! Filters can be combined with [`ReadFilter::and`], [`ReadFilter::or`] and [`ReadFilter::not`],
+//! and this module has ready-made filters for the most common read properties, so that a filter
+//! like "primary, properly paired reads with at most 4 mismatches" doesn't have to be written by
+//! hand:
+//!
+//! ```
+//! use perbase_lib::read_filter::{DefaultReadFilter, MaxMismatches, Primary, ProperPair, ReadFilter};
+//!
+//! let read_filter = DefaultReadFilter::new(0, 1536, 20)
+//!     .and(Primary)
+//!     .and(ProperPair)
+//!     .and(MaxMismatches::new(4));
+//! ```
+use crate::filter_expr::ExprReadFilter;
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use rust_htslib::bam::record::{Aux, Record};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    ops::{Bound, RangeBounds},
+    path::Path,
 };
 
+/// The rule a read failed, for breaking down filtered reads by why they were filtered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailReason {
+    /// Flagged as a duplicate, and duplicates are excluded
+    Duplicate,
+    /// Flagged as failing QC, and QC failures are excluded
+    QcFail,
+    /// Has an excluded flag, or is missing an included flag, other than the above
+    Flag,
+    /// Below the minimum MAPQ
+    Mapq,
+    /// Failed any other filter, such as a filter expression
+    Other,
+}
+
+impl FailReason {
+    /// Every reason, in the order of [`FailReason::index`]
+    pub const ALL: [FailReason; 5] = [
+        FailReason::Duplicate,
+        FailReason::QcFail,
+        FailReason::Flag,
+        FailReason::Mapq,
+        FailReason::Other,
+    ];
+
+    /// The index of this reason in [`FailReason::ALL`], for keeping counts per reason in an array
+    #[inline]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// A short name for the reason, as used in column names
+    pub fn name(self) -> &'static str {
+        match self {
+            FailReason::Duplicate => "dup",
+            FailReason::QcFail => "qc",
+            FailReason::Flag => "flag",
+            FailReason::Mapq => "mapq",
+            FailReason::Other => "other",
+        }
+    }
+}
+
 /// Anything that implements ReadFilter can apply a filter set to read.
 pub trait ReadFilter {
     /// filters a read, true is pass, false if fail
     fn filter_read(&self, read: &Record) -> bool;
+
+    /// Why a read fails this filter, or `None` if it passes. Filters that can tell their rules
+    /// apart override this, otherwise every failure is [`FailReason::Other`].
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        if self.filter_read(read) {
+            None
+        } else {
+            Some(FailReason::Other)
+        }
+    }
+
+    /// Pass reads that pass both this filter and `other`
+    fn and<G: ReadFilter>(self, other: G) -> And<Self, G>
+    where
+        Self: Sized,
+    {
+        And(self, other)
+    }
+
+    /// Pass reads that pass either this filter or `other`
+    fn or<G: ReadFilter>(self, other: G) -> Or<Self, G>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+
+    /// Pass reads that fail this filter
+    fn not(self) -> Not<Self>
+    where
+        Self: Sized,
+    {
+        Not(self)
+    }
+}
+
+impl<F: ReadFilter + ?Sized> ReadFilter for &F {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        (**self).filter_read(read)
+    }
+
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        (**self).fail_reason(read)
+    }
 }
 
+impl<F: ReadFilter + ?Sized> ReadFilter for Box<F> {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        (**self).filter_read(read)
+    }
+
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        (**self).fail_reason(read)
+    }
+}
+
+/// The SAM flag of duplicate reads.
+const DUPLICATE: u16 = 0x400;
+/// The SAM flag of reads failing QC.
+const QC_FAIL: u16 = 0x200;
+
 /// A straightforward read filter.
 pub struct DefaultReadFilter {
     include_flags: u16,
     exclude_flags: u16,
     min_mapq: u8,
+    expression: Option<ExprReadFilter>,
+    read_lists: Vec<ReadList>,
 }
 
 impl DefaultReadFilter {
@@ -23,18 +155,614 @@ impl DefaultReadFilter {
             include_flags,
             exclude_flags,
             min_mapq,
+            expression: None,
+            read_lists: vec![],
         }
     }
+
+    /// Also require reads to pass a filter expression, see [`crate::filter_expr`]
+    pub fn with_expression(mut self, expression: Option<ExprReadFilter>) -> Self {
+        self.expression = expression;
+        self
+    }
+
+    /// Also require reads to pass a whitelist or blacklist, if one is given
+    pub fn with_read_list(mut self, read_list: Option<ReadList>) -> Self {
+        self.read_lists.extend(read_list);
+        self
+    }
 }
 
-impl ReadFilter for DefaultReadFilter{
-    /// Filter reads based SAM flags and mapping quality
+impl ReadFilter for DefaultReadFilter {
+    /// Filter reads based SAM flags, mapping quality, the filter expression if there is one, and
+    /// the read lists
     #[inline]
     fn filter_read(&self, read: &Record) -> bool {
         let flags = read.flags();
-        (!flags) & &self.include_flags == 0
-            && flags & &self.exclude_flags == 0
-            && &read.mapq() >= &self.min_mapq
+        (!flags) & self.include_flags == 0
+            && flags & self.exclude_flags == 0
+            && read.mapq() >= self.min_mapq
+            && self
+                .expression
+                .as_ref()
+                .is_none_or(|expression| expression.filter_read(read))
+            && self.read_lists.iter().all(|list| list.filter_read(read))
+    }
+
+    /// Check the rules in order, so a read is failed for duplicates, then QC failures, then other
+    /// flags, then MAPQ, then the filter expression and read lists
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        let flags = read.flags();
+        let excluded = flags & self.exclude_flags;
+        if excluded & DUPLICATE != 0 {
+            Some(FailReason::Duplicate)
+        } else if excluded & QC_FAIL != 0 {
+            Some(FailReason::QcFail)
+        } else if excluded != 0 || (!flags) & self.include_flags != 0 {
+            Some(FailReason::Flag)
+        } else if read.mapq() < self.min_mapq {
+            Some(FailReason::Mapq)
+        } else if self
+            .expression
+            .as_ref()
+            .is_some_and(|expression| !expression.filter_read(read))
+            || !self.read_lists.iter().all(|list| list.filter_read(read))
+        {
+            Some(FailReason::Other)
+        } else {
+            None
+        }
+    }
+}
+
+/// Passes reads that pass both filters, see [`ReadFilter::and`].
+#[derive(Debug, Clone)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: ReadFilter, B: ReadFilter> ReadFilter for And<A, B> {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        self.0.filter_read(read) && self.1.filter_read(read)
+    }
+
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        self.0
+            .fail_reason(read)
+            .or_else(|| self.1.fail_reason(read))
+    }
+}
+
+/// Passes reads that pass either filter, see [`ReadFilter::or`].
+#[derive(Debug, Clone)]
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: ReadFilter, B: ReadFilter> ReadFilter for Or<A, B> {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        self.0.filter_read(read) || self.1.filter_read(read)
+    }
+
+    /// The reason the second filter failed, if both failed
+    #[inline]
+    fn fail_reason(&self, read: &Record) -> Option<FailReason> {
+        self.0
+            .fail_reason(read)
+            .and_then(|_| self.1.fail_reason(read))
+    }
+}
+
+/// Passes reads that fail a filter, see [`ReadFilter::not`].
+#[derive(Debug, Clone)]
+pub struct Not<A>(pub A);
+
+impl<A: ReadFilter> ReadFilter for Not<A> {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        !self.0.filter_read(read)
+    }
+}
+
+/// The value of an aux tag to match with [`TagEquals`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    /// An integer, of any of the integer types
+    Integer(i64),
+    /// A string, `Z` or `H` type
+    String(Vec<u8>),
+    /// A single character, `A` type
+    Char(u8),
+}
+
+/// Passes reads that have an aux tag with the given value.
+#[derive(Debug, Clone)]
+pub struct TagEquals {
+    tag: [u8; 2],
+    value: TagValue,
+}
+
+impl TagEquals {
+    /// Create a filter for reads where `tag` is `value`
+    pub fn new(tag: [u8; 2], value: TagValue) -> Self {
+        Self { tag, value }
+    }
+
+    /// Create a filter for reads where `tag` is the string `value`, such as `RG` or `CB`
+    pub fn string(tag: [u8; 2], value: &str) -> Self {
+        Self::new(tag, TagValue::String(value.as_bytes().to_vec()))
+    }
+}
+
+impl ReadFilter for TagEquals {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        match (read.aux(&self.tag), &self.value) {
+            (Some(Aux::Integer(n)), TagValue::Integer(value)) => n == *value,
+            (Some(Aux::String(s)), TagValue::String(value)) => s == value.as_slice(),
+            (Some(Aux::Char(c)), TagValue::Char(value)) => c == *value,
+            _ => false,
+        }
+    }
+}
+
+/// Passes reads that have a numeric aux tag within a range. Reads without the tag, or with a
+/// string in it, fail.
+#[derive(Debug, Clone)]
+pub struct TagRange {
+    tag: [u8; 2],
+    start: Bound<f64>,
+    end: Bound<f64>,
+}
+
+impl TagRange {
+    /// Create a filter for reads where `tag` is in `range`, such as `TagRange::new(*b"AS", 100.0..)`
+    pub fn new<R: RangeBounds<f64>>(tag: [u8; 2], range: R) -> Self {
+        Self {
+            tag,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+impl ReadFilter for TagRange {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        let value = match read.aux(&self.tag) {
+            Some(Aux::Integer(n)) => n as f64,
+            Some(Aux::Float(n)) => n,
+            _ => return false,
+        };
+        (self.start, self.end).contains(&value)
+    }
+}
+
+/// Passes reads with at most `max` mismatches to the reference, going by the `NM` tag. Reads
+/// without an `NM` tag fail.
+#[derive(Debug, Clone)]
+pub struct MaxMismatches {
+    max: i64,
+}
+
+impl MaxMismatches {
+    /// Create a filter for reads with an edit distance of at most `max`
+    pub fn new(max: i64) -> Self {
+        Self { max }
+    }
+}
+
+impl ReadFilter for MaxMismatches {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        matches!(read.aux(b"NM"), Some(Aux::Integer(nm)) if nm <= self.max)
+    }
+}
+
+/// Passes reads where at most a fraction of the read is soft clipped.
+#[derive(Debug, Clone)]
+pub struct SoftClipFraction {
+    max: f64,
+}
+
+impl SoftClipFraction {
+    /// Create a filter for reads with at most `max` of their bases soft clipped, between 0 and 1
+    pub fn new(max: f64) -> Self {
+        Self { max }
+    }
+}
+
+impl ReadFilter for SoftClipFraction {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        let (clipped, length) = clipped_and_query_length(read);
+        length == 0 || clipped as f64 / length as f64 <= self.max
+    }
+}
+
+/// Passes reads with a length within a range. The length is that of the read sequence, or the
+/// query length from the CIGAR if the sequence isn't stored, not counting hard clips.
+#[derive(Debug, Clone)]
+pub struct ReadLength {
+    start: Bound<usize>,
+    end: Bound<usize>,
+}
+
+impl ReadLength {
+    /// Create a filter for reads with a length in `range`, such as `ReadLength::new(50..)`
+    pub fn new<R: RangeBounds<usize>>(range: R) -> Self {
+        Self {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+impl ReadFilter for ReadLength {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        let length = match read.seq_len() {
+            0 => clipped_and_query_length(read).1,
+            length => length,
+        };
+        (self.start, self.end).contains(&length)
+    }
+}
+
+/// Passes reads whose absolute insert size (`TLEN`) is within a range. Reads without a mate on
+/// the same contig have an insert size of 0.
+#[derive(Debug, Clone)]
+pub struct InsertSize {
+    start: Bound<u64>,
+    end: Bound<u64>,
+}
+
+impl InsertSize {
+    /// Create a filter for reads with an absolute insert size in `range`, such as `InsertSize::new(1..=1000)`
+    pub fn new<R: RangeBounds<u64>>(range: R) -> Self {
+        Self {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
     }
 }
 
+impl ReadFilter for InsertSize {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        (self.start, self.end).contains(&read.insert_size().unsigned_abs())
+    }
+}
+
+/// Passes reads that are mapped in a proper pair.
+#[derive(Debug, Clone, Copy)]
+pub struct ProperPair;
+
+impl ReadFilter for ProperPair {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        read.is_proper_pair()
+    }
+}
+
+/// Passes primary alignments, dropping both secondary and supplementary alignments.
+#[derive(Debug, Clone, Copy)]
+pub struct Primary;
+
+impl ReadFilter for Primary {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        !read.is_secondary() && !read.is_supplementary()
+    }
+}
+
+/// Passes all but secondary alignments, keeping supplementary alignments.
+#[derive(Debug, Clone, Copy)]
+pub struct NotSecondary;
+
+impl ReadFilter for NotSecondary {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        !read.is_secondary()
+    }
+}
+
+/// Passes all but supplementary alignments, keeping secondary alignments.
+#[derive(Debug, Clone, Copy)]
+pub struct NotSupplementary;
+
+impl ReadFilter for NotSupplementary {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        !read.is_supplementary()
+    }
+}
+
+/// Passes reads whose name, or value of an aux tag such as `CB` or `UB`, is in a list. With
+/// [`ReadList::exclude`] it is a blacklist instead, passing reads that are not in the list.
+///
+/// A whitelist fails reads without the tag, and a blacklist passes them.
+#[derive(Debug, Clone)]
+pub struct ReadList {
+    tag: Option<[u8; 2]>,
+    values: HashSet<Vec<u8>>,
+    exclude: bool,
+}
+
+impl ReadList {
+    /// Create a whitelist of the values of `tag`, or of read names if there is no tag
+    pub fn new<I: IntoIterator<Item = Vec<u8>>>(tag: Option<[u8; 2]>, values: I) -> Self {
+        Self {
+            tag,
+            values: values.into_iter().collect(),
+            exclude: false,
+        }
+    }
+
+    /// Load a whitelist from a file with one value per line, which may be gzipped. Only the first
+    /// tab separated column is used, so a 10x `barcodes.tsv.gz` can be used as is, and blank
+    /// lines are skipped.
+    pub fn from_path<P: AsRef<Path>>(tag: Option<[u8; 2]>, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let mut values = HashSet::new();
+        for line in BufReader::new(reader).split(b'\n') {
+            let line = line.with_context(|| format!("Failed to read {:?}", path))?;
+            let value = line.split(|&b| b == b'\t').next().unwrap_or(&[]);
+            let value = value.strip_suffix(b"\r").unwrap_or(value);
+            if !value.is_empty() {
+                values.insert(value.to_vec());
+            }
+        }
+        Ok(Self::new(tag, values))
+    }
+
+    /// Make this a blacklist, passing only the reads that are not in the list
+    pub fn exclude(mut self) -> Self {
+        self.exclude = true;
+        self
+    }
+
+    /// The number of distinct values in the list
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether or not the list has no values
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl ReadFilter for ReadList {
+    #[inline]
+    fn filter_read(&self, read: &Record) -> bool {
+        let value = match &self.tag {
+            Some(tag) => tag_bytes(read, tag),
+            None => Some(Cow::Borrowed(read.qname())),
+        };
+        match value {
+            Some(value) => self.values.contains(value.as_ref()) != self.exclude,
+            None => self.exclude,
+        }
+    }
+}
+
+/// The value of an aux tag of a read as bytes, with numbers formatted as text, so that string
+/// tags like `CB` don't need to be copied.
+#[inline]
+pub fn tag_bytes<'a>(read: &'a Record, tag: &[u8; 2]) -> Option<Cow<'a, [u8]>> {
+    match read.aux(tag)? {
+        Aux::String(s) => Some(Cow::Borrowed(s)),
+        Aux::Char(c) => Some(Cow::Owned(vec![c])),
+        Aux::Integer(n) => Some(Cow::Owned(n.to_string().into_bytes())),
+        Aux::Float(f) => Some(Cow::Owned(f.to_string().into_bytes())),
+    }
+}
+
+/// The number of soft clipped bases and the query length of a read, from its raw CIGAR so that
+/// the CIGAR isn't copied for every read.
+#[inline]
+fn clipped_and_query_length(read: &Record) -> (usize, usize) {
+    // BAM CIGAR op codes for M, I, S, =, and X, the ops that consume the query
+    const SOFT_CLIP: u32 = 4;
+    const QUERY_OPS: [u32; 5] = [0, 1, SOFT_CLIP, 7, 8];
+    read.raw_cigar()
+        .iter()
+        .fold((0, 0), |(clipped, length), op| {
+            let (code, len) = (op & 0xf, (op >> 4) as usize);
+            match code {
+                SOFT_CLIP => (clipped + len, length + len),
+                code if QUERY_OPS.contains(&code) => (clipped, length + len),
+                _ => (clipped, length),
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::bam::{self, HeaderView};
+
+    fn header() -> HeaderView {
+        let mut header = bam::header::Header::new();
+        let mut record = bam::header::HeaderRecord::new(b"SQ");
+        record.push_tag(b"SN", &"chr1");
+        record.push_tag(b"LN", &1000);
+        header.push_record(&record);
+        HeaderView::from_header(&header)
+    }
+
+    fn record(flags: u16, cigar: &str, seq: &str, tlen: i64, tags: &str) -> Record {
+        let qual = if seq == "*" {
+            "*".to_owned()
+        } else {
+            "#".repeat(seq.len())
+        };
+        let sam = format!(
+            "read\t{}\tchr1\t11\t30\t{}\tchr1\t101\t{}\t{}\t{}{}",
+            flags, cigar, tlen, seq, qual, tags
+        );
+        Record::from_sam(&header(), sam.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn combinators() {
+        let read = record(99, "10M", "AAAAAAAAAA", 300, "");
+        let pass = DefaultReadFilter::new(0, 0, 0);
+        let fail = DefaultReadFilter::new(0, 0, 40);
+        assert!(pass.filter_read(&read) && !fail.filter_read(&read));
+        assert!(DefaultReadFilter::new(0, 0, 0)
+            .and(ProperPair)
+            .filter_read(&read));
+        assert!(!(&pass).and(&fail).filter_read(&read));
+        assert!((&pass).or(&fail).filter_read(&read));
+        assert!((&fail).or(&pass).filter_read(&read));
+        assert!(!(&fail).or(&fail).filter_read(&read));
+        assert!((&fail).not().filter_read(&read));
+        assert!(!(&pass).not().filter_read(&read));
+        let boxed: Box<dyn ReadFilter> = Box::new(Primary);
+        assert!(boxed.and(pass).filter_read(&read));
+    }
+
+    #[test]
+    fn fail_reasons() {
+        let read_filter = DefaultReadFilter::new(1, 1536, 20)
+            .with_expression(Some("tag(NM) <= 2".parse().unwrap()));
+        for (flags, mapq, reason) in [
+            (1024 + 512 + 1, 0, Some(FailReason::Duplicate)),
+            (512 + 1, 0, Some(FailReason::QcFail)),
+            (0, 0, Some(FailReason::Flag)),
+            (1, 19, Some(FailReason::Mapq)),
+            (1, 20, Some(FailReason::Other)),
+        ] {
+            let mut read = record(flags, "10M", "AAAAAAAAAA", 0, "\tNM:i:3");
+            read.set_mapq(mapq);
+            assert_eq!(read_filter.fail_reason(&read), reason);
+            assert!(!read_filter.filter_read(&read));
+        }
+        let read = record(1, "10M", "AAAAAAAAAA", 0, "\tNM:i:2");
+        assert_eq!(read_filter.fail_reason(&read), None);
+        assert!(read_filter.filter_read(&read));
+        // Duplicates that aren't excluded are fine
+        let read = record(1024 + 1, "10M", "AAAAAAAAAA", 0, "\tNM:i:2");
+        assert_eq!(DefaultReadFilter::new(0, 0, 0).fail_reason(&read), None);
+
+        // Combinators keep the reason of the filter that failed
+        let mapq = DefaultReadFilter::new(0, 0, 40);
+        assert_eq!(
+            ProperPair.and(&mapq).fail_reason(&read),
+            Some(FailReason::Other)
+        );
+        assert_eq!(
+            Primary.and(&mapq).fail_reason(&read),
+            Some(FailReason::Mapq)
+        );
+        assert_eq!(Primary.or(&mapq).fail_reason(&read), None);
+        assert_eq!(
+            ProperPair.or(&mapq).fail_reason(&read),
+            Some(FailReason::Mapq)
+        );
+    }
+
+    #[test]
+    fn tags() {
+        let read = record(
+            99,
+            "10M",
+            "AAAAAAAAAA",
+            300,
+            "\tNM:i:3\tRG:Z:L1\tXA:A:x\tAS:f:95.5",
+        );
+        assert!(TagEquals::string(*b"RG", "L1").filter_read(&read));
+        assert!(!TagEquals::string(*b"RG", "L2").filter_read(&read));
+        assert!(TagEquals::new(*b"NM", TagValue::Integer(3)).filter_read(&read));
+        assert!(TagEquals::new(*b"XA", TagValue::Char(b'x')).filter_read(&read));
+        // A tag of another type doesn't match
+        assert!(!TagEquals::string(*b"NM", "3").filter_read(&read));
+        assert!(!TagEquals::string(*b"XX", "L1").filter_read(&read));
+
+        assert!(TagRange::new(*b"NM", 0.0..=3.0).filter_read(&read));
+        assert!(!TagRange::new(*b"NM", 0.0..3.0).filter_read(&read));
+        assert!(TagRange::new(*b"AS", 90.0..).filter_read(&read));
+        assert!(!TagRange::new(*b"AS", ..95.0).filter_read(&read));
+        assert!(!TagRange::new(*b"RG", ..).filter_read(&read));
+        assert!(!TagRange::new(*b"XX", ..).filter_read(&read));
+
+        assert!(MaxMismatches::new(3).filter_read(&read));
+        assert!(!MaxMismatches::new(2).filter_read(&read));
+        assert!(!MaxMismatches::new(10).filter_read(&record(99, "10M", "*", 300, "")));
+    }
+
+    #[test]
+    fn read_properties() {
+        let clipped = record(99, "2S6M2S", "AAAAAAAAAA", -300, "");
+        assert!(SoftClipFraction::new(0.4).filter_read(&clipped));
+        assert!(!SoftClipFraction::new(0.3).filter_read(&clipped));
+        assert!(SoftClipFraction::new(0.0).filter_read(&record(99, "5H10M", "AAAAAAAAAA", 0, "")));
+
+        assert!(ReadLength::new(10..).filter_read(&clipped));
+        assert!(!ReadLength::new(..10).filter_read(&clipped));
+        // Without a stored sequence the length comes from the CIGAR, without hard clips
+        let no_seq = record(99, "5H4S8M3I", "*", 0, "");
+        assert!(ReadLength::new(15..=15).filter_read(&no_seq));
+
+        assert!(InsertSize::new(1..=300).filter_read(&clipped));
+        assert!(!InsertSize::new(..300).filter_read(&clipped));
+        assert!(!InsertSize::new(1..).filter_read(&no_seq));
+
+        let secondary = record(256 + 65, "10M", "AAAAAAAAAA", 0, "");
+        let supplementary = record(2048 + 65, "10M", "AAAAAAAAAA", 0, "");
+        assert!(Primary.filter_read(&clipped));
+        assert!(!Primary.filter_read(&secondary) && !Primary.filter_read(&supplementary));
+        assert!(!NotSecondary.filter_read(&secondary) && NotSecondary.filter_read(&supplementary));
+        assert!(NotSupplementary.filter_read(&secondary));
+        assert!(!NotSupplementary.filter_read(&supplementary));
+        assert!(ProperPair.filter_read(&clipped) && !ProperPair.filter_read(&secondary));
+    }
+
+    #[test]
+    fn read_lists() {
+        let cell = record(99, "10M", "AAAAAAAAAA", 0, "\tCB:Z:ACGT-1\tUB:i:7");
+        let other = record(99, "10M", "AAAAAAAAAA", 0, "\tCB:Z:TTTT-1");
+        let untagged = record(99, "10M", "AAAAAAAAAA", 0, "");
+
+        let whitelist = ReadList::new(Some(*b"CB"), vec![b"ACGT-1".to_vec()]);
+        assert!(whitelist.filter_read(&cell));
+        assert!(!whitelist.filter_read(&other) && !whitelist.filter_read(&untagged));
+        let blacklist = whitelist.exclude();
+        assert!(!blacklist.filter_read(&cell));
+        assert!(blacklist.filter_read(&other) && blacklist.filter_read(&untagged));
+        assert!(ReadList::new(Some(*b"UB"), vec![b"7".to_vec()]).filter_read(&cell));
+        assert!(ReadList::new(None, vec![b"read".to_vec()]).filter_read(&untagged));
+        assert!(!ReadList::new(None, vec![b"other".to_vec()]).filter_read(&untagged));
+
+        // Lists are read from the first column of plain or gzipped files
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("barcodes.tsv");
+        std::fs::write(&plain, "ACGT-1\tcell\r\n\nGGGG-1\n").unwrap();
+        let gzipped = dir.path().join("barcodes.tsv.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&gzipped).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::Write::write_all(&mut encoder, b"ACGT-1\nGGGG-1\n").unwrap();
+        encoder.finish().unwrap();
+        for path in &[plain, gzipped] {
+            let list = ReadList::from_path(Some(*b"CB"), path).unwrap();
+            assert_eq!(list.len(), 2);
+            assert!(list.filter_read(&cell) && !list.filter_read(&other));
+        }
+        assert!(ReadList::from_path(None, dir.path().join("missing.txt")).is_err());
+
+        let read_filter = DefaultReadFilter::new(0, 0, 0)
+            .with_read_list(Some(ReadList::new(Some(*b"CB"), vec![b"ACGT-1".to_vec()])));
+        assert!(read_filter.filter_read(&cell));
+        assert_eq!(read_filter.fail_reason(&other), Some(FailReason::Other));
+    }
+}
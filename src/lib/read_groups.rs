@@ -0,0 +1,150 @@
+//! Grouping reads by their read group, as given by the `RG` aux tag.
+use anyhow::{Context, Result};
+use rust_htslib::bam::{self, record::Aux, HeaderView, Read};
+use smartstring::alias::String;
+use std::{collections::HashMap, path::Path};
+
+/// The group reads without an `RG` tag, or with one not listed in the header, are reported under.
+pub const UNKNOWN_READ_GROUP: &str = "UNKNOWN";
+
+/// Maps the `RG` tag of each read to the group it is counted under.
+///
+/// Groups are numbered in the order their `@RG` lines appear in the header, with the group for
+/// reads that have an unknown read group last.
+#[derive(Debug, Clone)]
+pub struct ReadGroups {
+    /// The group index of each read group ID
+    ids: HashMap<Vec<u8>, usize>,
+    /// The name reported for each group
+    names: Vec<String>,
+}
+
+impl ReadGroups {
+    /// Create the groups from the `@RG` lines of a header.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - the header of the BAM/CRAM being read
+    /// * `by_sample` - if true, group read groups by their `SM` tag instead of their `ID`. Read groups
+    ///   without an `SM` tag are kept under their `ID`.
+    pub fn from_header(header: &HeaderView, by_sample: bool) -> Self {
+        let text = std::string::String::from_utf8_lossy(header.as_bytes()).into_owned();
+        let mut ids = HashMap::new();
+        let mut names: Vec<String> = vec![];
+        for line in text.lines().filter(|line| line.starts_with("@RG\t")) {
+            let tag = |tag: &str| line.split('\t').find_map(|field| field.strip_prefix(tag));
+            let id = match tag("ID:") {
+                Some(id) => id,
+                None => continue,
+            };
+            let name = if by_sample {
+                tag("SM:").unwrap_or(id)
+            } else {
+                id
+            };
+            let index = match names.iter().position(|n| n == name) {
+                Some(index) => index,
+                None => {
+                    names.push(String::from(name));
+                    names.len() - 1
+                }
+            };
+            ids.insert(id.as_bytes().to_vec(), index);
+        }
+        names.push(String::from(UNKNOWN_READ_GROUP));
+        Self { ids, names }
+    }
+
+    /// Create the groups from the header of a BAM/CRAM file, see [`ReadGroups::from_header`].
+    pub fn from_path<P: AsRef<Path>>(reads: P, by_sample: bool) -> Result<Self> {
+        let reader = bam::Reader::from_path(reads.as_ref())
+            .with_context(|| format!("Failed to open BAM/CRAM {:?}", reads.as_ref()))?;
+        Ok(Self::from_header(reader.header(), by_sample))
+    }
+
+    /// The number of groups, including the group for unknown read groups.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// There is always at least the group for unknown read groups.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The names of the groups found in the header, not including the group for unknown read groups.
+    pub fn header_names(&self) -> &[String] {
+        &self.names[..self.names.len() - 1]
+    }
+
+    /// The name of the group at `index`.
+    pub fn name(&self, index: usize) -> &String {
+        &self.names[index]
+    }
+
+    /// The index of the group a read belongs to.
+    #[inline]
+    pub fn index(&self, record: &bam::Record) -> usize {
+        match record.aux(b"RG") {
+            Some(Aux::String(id)) => self.ids.get(id).copied(),
+            _ => None,
+        }
+        .unwrap_or(self.names.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::bam::{header::HeaderRecord, Header};
+
+    fn header() -> HeaderView {
+        let mut header = Header::new();
+        let mut chr1 = HeaderRecord::new(b"SQ");
+        chr1.push_tag(b"SN", &"chr1");
+        chr1.push_tag(b"LN", &100);
+        header.push_record(&chr1);
+        for (id, sample) in &[("rg1", "alice"), ("rg2", "bob"), ("rg3", "alice")] {
+            let mut rg = HeaderRecord::new(b"RG");
+            rg.push_tag(b"ID", id);
+            rg.push_tag(b"SM", sample);
+            header.push_record(&rg);
+        }
+        HeaderView::from_header(&header)
+    }
+
+    fn record(header: &HeaderView, rg: Option<&str>) -> bam::Record {
+        let mut sam = b"read\t0\tchr1\t1\t40\t4M\t*\t0\t0\tAAAA\t####".to_vec();
+        if let Some(rg) = rg {
+            sam.extend(format!("\tRG:Z:{}", rg).as_bytes());
+        }
+        bam::Record::from_sam(header, &sam).unwrap()
+    }
+
+    #[test]
+    fn by_id() {
+        let header = header();
+        let groups = ReadGroups::from_header(&header, false);
+        assert_eq!(groups.len(), 4);
+        let names: Vec<&str> = groups.header_names().iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["rg1", "rg2", "rg3"]);
+        assert_eq!(groups.index(&record(&header, Some("rg2"))), 1);
+        assert_eq!(groups.index(&record(&header, Some("rg3"))), 2);
+        assert_eq!(groups.index(&record(&header, Some("rg4"))), 3);
+        assert_eq!(groups.index(&record(&header, None)), 3);
+        assert_eq!(groups.name(3), UNKNOWN_READ_GROUP);
+    }
+
+    #[test]
+    fn by_sample() {
+        let header = header();
+        let groups = ReadGroups::from_header(&header, true);
+        assert_eq!(groups.len(), 3);
+        let names: Vec<&str> = groups.header_names().iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert_eq!(groups.index(&record(&header, Some("rg1"))), 0);
+        assert_eq!(groups.index(&record(&header, Some("rg2"))), 1);
+        assert_eq!(groups.index(&record(&header, Some("rg3"))), 0);
+        assert_eq!(groups.index(&record(&header, None)), 2);
+    }
+}
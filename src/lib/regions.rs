@@ -0,0 +1,382 @@
+//! Reading regions files, and matching the contig names they use to the contigs of a BAM/CRAM
+//! header.
+//!
+//! Regions files don't always name contigs the same way as the reads, most commonly `chr1` vs `1`
+//! and `chrM` vs `MT`. [`ContigMatcher`] falls back on these aliases when a name isn't in the
+//! header, and records the names that still can't be matched so that they can all be reported
+//! together, according to a [`MissingContig`] policy.
+use anyhow::{Context, Error, Result};
+use log::*;
+use rust_htslib::bam::HeaderView;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+/// The names mitochondrial contigs go by, which are all aliases of each other.
+const MITO_NAMES: [&str; 4] = ["chrM", "chrMT", "MT", "M"];
+
+/// What to do with regions on contigs that are not in the BAM/CRAM header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingContig {
+    /// Fail, listing every unmatched contig
+    #[default]
+    Error,
+    /// Skip the regions, logging a warning listing every unmatched contig
+    Warn,
+    /// Skip the regions silently
+    Skip,
+}
+
+impl FromStr for MissingContig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(MissingContig::Error),
+            "warn" => Ok(MissingContig::Warn),
+            "skip" => Ok(MissingContig::Skip),
+            other => Err(Error::msg(format!(
+                "Unknown missing contig policy {:?}, expected one of error, warn, skip",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for MissingContig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MissingContig::Error => "error",
+            MissingContig::Warn => "warn",
+            MissingContig::Skip => "skip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The other names a contig may go by: with or without a `chr` prefix, and any of the common names
+/// for the mitochondrial genome.
+fn aliases(name: &str) -> Vec<String> {
+    if MITO_NAMES.contains(&name) {
+        return MITO_NAMES
+            .iter()
+            .filter(|alias| **alias != name)
+            .map(|alias| alias.to_string())
+            .collect();
+    }
+    match name.strip_prefix("chr") {
+        Some(stripped) if !stripped.is_empty() => vec![stripped.to_string()],
+        Some(_) => vec![],
+        None => vec![format!("chr{}", name)],
+    }
+}
+
+/// Looks up contig names from a regions file in a BAM/CRAM header, trying aliases for
+/// names that aren't in the header, and keeping track of the names that don't match at all.
+#[derive(Debug)]
+pub struct ContigMatcher {
+    /// The name of each contig in the header, indexed by tid
+    names: Vec<String>,
+    /// The tid of each contig in the header, by name
+    tids: HashMap<String, u32>,
+    /// The tid that each name looked up so far resolved to
+    resolved: HashMap<String, Option<u32>>,
+    /// The names that did not match a contig, along with how many times each was seen and the
+    /// location it was first seen at
+    missing: HashMap<String, (usize, usize)>,
+    /// What the locations passed to [`ContigMatcher::tid`] count, i.e. `line` or `record`
+    unit: &'static str,
+}
+
+impl ContigMatcher {
+    /// Create a matcher for the contigs of a header. `unit` names what the locations given to
+    /// [`ContigMatcher::tid`] count, and is used when reporting unmatched contigs.
+    pub fn new(header: &HeaderView, unit: &'static str) -> Self {
+        let names: Vec<String> = header
+            .target_names()
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        let tids = names
+            .iter()
+            .enumerate()
+            .map(|(tid, name)| (name.clone(), tid as u32))
+            .collect();
+        Self {
+            names,
+            tids,
+            resolved: HashMap::new(),
+            missing: HashMap::new(),
+            unit,
+        }
+    }
+
+    /// Get the tid of a contig, falling back on its aliases if the name itself isn't in the
+    /// header. A name that can't be matched is recorded along with `location`, the 1-based line or
+    /// record number it was seen at.
+    pub fn tid(&mut self, name: &str, location: usize) -> Option<u32> {
+        let tid = match self.resolved.get(name) {
+            Some(tid) => *tid,
+            None => {
+                let tid = self.lookup(name);
+                self.resolved.insert(name.to_string(), tid);
+                tid
+            }
+        };
+        if tid.is_none() {
+            self.missing
+                .entry(name.to_string())
+                .or_insert((0, location))
+                .0 += 1;
+        }
+        tid
+    }
+
+    /// Find the tid of a name, or of the first of its aliases that is in the header.
+    fn lookup(&self, name: &str) -> Option<u32> {
+        if let Some(tid) = self.tids.get(name) {
+            return Some(*tid);
+        }
+        let alias = aliases(name)
+            .into_iter()
+            .find(|alias| self.tids.contains_key(alias))?;
+        info!(
+            "Matching contig {} to {} in the BAM/CRAM header",
+            name, alias
+        );
+        Some(self.tids[&alias])
+    }
+
+    /// The name of a contig in the header.
+    pub fn name(&self, tid: u32) -> &str {
+        &self.names[tid as usize]
+    }
+
+    /// The names that did not match a contig so far, in the order they were first seen, with how
+    /// many times each was seen and the location it was first seen at.
+    pub fn missing(&self) -> Vec<(&str, usize, usize)> {
+        let mut missing: Vec<(&str, usize, usize)> = self
+            .missing
+            .iter()
+            .map(|(name, (count, first))| (name.as_str(), *count, *first))
+            .collect();
+        missing.sort_by_key(|(_, _, first)| *first);
+        missing
+    }
+
+    /// Apply a [`MissingContig`] policy to the names that did not match a contig so far. `source`
+    /// is the file the names were read from.
+    pub fn check(&self, policy: MissingContig, source: &Path) -> Result<()> {
+        if self.missing.is_empty() {
+            return Ok(());
+        }
+        match policy {
+            MissingContig::Error => Err(Error::msg(self.report(source))),
+            MissingContig::Warn => {
+                warn!("{}, skipping them", self.report(source));
+                Ok(())
+            }
+            MissingContig::Skip => {
+                debug!("{}, skipping them", self.report(source));
+                Ok(())
+            }
+        }
+    }
+
+    /// Describe the names that did not match a contig, and where they were seen.
+    fn report(&self, source: &Path) -> String {
+        let contigs: Vec<String> = self
+            .missing()
+            .into_iter()
+            .map(|(name, count, first)| {
+                if count == 1 {
+                    format!("{} ({} {})", name, self.unit, first)
+                } else {
+                    format!(
+                        "{} ({} {}s, first on {} {})",
+                        name, count, self.unit, self.unit, first
+                    )
+                }
+            })
+            .collect();
+        format!(
+            "{} contig{} in {:?} not found in BAM/CRAM header: {}",
+            contigs.len(),
+            if contigs.len() == 1 { "" } else { "s" },
+            source,
+            contigs.join(", ")
+        )
+    }
+}
+
+/// A single interval of a BED file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BedRecord {
+    /// The tid of the contig in the BAM/CRAM header
+    pub tid: u32,
+    /// 0-based start
+    pub start: u64,
+    /// 0-based, exclusive stop
+    pub stop: u64,
+    /// The name column, if there is one
+    pub name: Option<String>,
+    /// The 1-based line number the interval was on
+    pub line: usize,
+}
+
+/// Read the intervals of a BED file, in file order, matching their contigs with `matcher`.
+///
+/// Only the first three columns are required, and a fourth is used as the name. Blank lines, and
+/// lines starting with `#`, `track`, or `browser` are skipped. Intervals on contigs that can't be
+/// matched are left out, and recorded by the matcher to be checked by the caller.
+pub fn read_bed(bed_file: &Path, matcher: &mut ContigMatcher) -> Result<Vec<BedRecord>> {
+    let file =
+        File::open(bed_file).with_context(|| format!("Failed to open BED file {:?}", bed_file))?;
+    let mut records = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line_number = index + 1;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(Error::msg(format!(
+                "Expected at least 3 columns on line {}, found {}",
+                line_number,
+                fields.len()
+            )));
+        }
+        let number = |i: usize, name: &str| -> Result<u64> {
+            fields[i].parse().with_context(|| {
+                format!("Invalid {} {:?} on line {}", name, fields[i], line_number)
+            })
+        };
+        let start = number(1, "start")?;
+        let stop = number(2, "end")?;
+        if let Some(tid) = matcher.tid(fields[0], line_number) {
+            records.push(BedRecord {
+                tid,
+                start,
+                stop,
+                name: fields
+                    .get(3)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_string()),
+                line: line_number,
+            });
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_htslib::bam;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn header(contigs: &[&str]) -> HeaderView {
+        let mut header = bam::header::Header::new();
+        for contig in contigs {
+            let mut record = bam::header::HeaderRecord::new(b"SQ");
+            record.push_tag(b"SN", contig);
+            record.push_tag(b"LN", &1000);
+            header.push_record(&record);
+        }
+        HeaderView::from_header(&header)
+    }
+
+    #[test]
+    fn matches_aliases() {
+        let mut matcher = ContigMatcher::new(&header(&["chr1", "chrM", "2", "MT"]), "line");
+        assert_eq!(matcher.tid("chr1", 1), Some(0));
+        assert_eq!(matcher.tid("1", 2), Some(0));
+        assert_eq!(matcher.tid("M", 3), Some(1));
+        assert_eq!(matcher.tid("chrMT", 4), Some(1));
+        assert_eq!(matcher.tid("chr2", 5), Some(2));
+        // An exact match wins over an alias
+        assert_eq!(matcher.tid("MT", 6), Some(3));
+        assert!(matcher.missing().is_empty());
+    }
+
+    #[test]
+    fn reports_missing() {
+        let mut matcher = ContigMatcher::new(&header(&["chr1"]), "line");
+        assert_eq!(matcher.tid("chrZ", 2), None);
+        assert_eq!(matcher.tid("chr1", 3), Some(0));
+        for line in 4..11 {
+            matcher.tid("chrY_random", line);
+        }
+        matcher.tid("chrZ", 11);
+        assert_eq!(
+            matcher.missing(),
+            vec![("chrZ", 2, 2), ("chrY_random", 7, 4)]
+        );
+        let source = Path::new("regions.bed");
+        assert!(matcher.check(MissingContig::Warn, source).is_ok());
+        assert!(matcher.check(MissingContig::Skip, source).is_ok());
+        let err = matcher
+            .check(MissingContig::Error, source)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "2 contigs in \"regions.bed\" not found in BAM/CRAM header: chrZ (2 lines, first on \
+             line 2), chrY_random (7 lines, first on line 4)"
+        );
+    }
+
+    #[test]
+    fn reads_bed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("regions.bed");
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "track name=test\n1\t10\t20\n\nchrZ\t0\t5\tmissing\nchr2\t30\t40\tgene\n"
+        )
+        .unwrap();
+        drop(file);
+
+        let mut matcher = ContigMatcher::new(&header(&["chr1", "chr2"]), "line");
+        let records = read_bed(&path, &mut matcher).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                BedRecord {
+                    tid: 0,
+                    start: 10,
+                    stop: 20,
+                    name: None,
+                    line: 2
+                },
+                BedRecord {
+                    tid: 1,
+                    start: 30,
+                    stop: 40,
+                    name: Some("gene".to_string()),
+                    line: 5
+                },
+            ]
+        );
+        assert_eq!(matcher.missing(), vec![("chrZ", 1, 4)]);
+
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "chr1\t10\tx").unwrap();
+        drop(file);
+        let err = read_bed(&path, &mut matcher).unwrap_err().to_string();
+        assert!(err.contains("line 1"), "{}", err);
+    }
+}
@@ -0,0 +1,347 @@
+//! Reading lists of single positions ("sites") to report on, and grouping them into regions that
+//! can be fetched together.
+use anyhow::{Context, Error, Result};
+use rust_htslib::bcf::{self, Read};
+use smartstring::alias::String;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    path::Path,
+};
+
+/// The largest gap between two sites that are still fetched as part of the same region.
+pub const MAX_SITE_GAP: u64 = 1_000;
+
+/// A single position to report on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Site {
+    /// Reference sequence name
+    pub ref_seq: String,
+    /// 0-based position on the reference sequence
+    pub pos: u64,
+    /// The ID of the site, if one was given
+    pub id: Option<String>,
+    /// The 1-based line of the sites file the site was listed on, or the 1-based record number
+    /// for sites read from a VCF/BCF
+    pub line: usize,
+}
+
+/// The layout of a sites file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SitesFormat {
+    /// `<chrom>\t<start>\t<end>[\t<name>]` with 0-based, half-open coordinates. Every base of each
+    /// interval is a site, named by the interval's name.
+    Bed,
+    /// `<chrom>\t<pos>[\t<id>]` with 1-based positions.
+    Tsv,
+}
+
+impl SitesFormat {
+    /// Guess the format of a sites file from its extension, `.bed` files are BED and anything
+    /// else is a TSV.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        if path.as_ref().to_string_lossy().ends_with(".bed") {
+            SitesFormat::Bed
+        } else {
+            SitesFormat::Tsv
+        }
+    }
+}
+
+/// Streams the sites of a sites file, in file order.
+///
+/// Blank lines, and lines starting with `#`, `track`, or `browser` are skipped, as is a first line
+/// of a TSV with a position that isn't a number, which is taken to be a header.
+pub struct SitesReader<R: BufRead> {
+    /// The remaining lines of the file
+    lines: Lines<R>,
+    /// The layout of the file
+    format: SitesFormat,
+    /// The 1-based number of the last line read
+    line_number: usize,
+    /// The rest of the BED interval being expanded into sites, as (chrom, next pos, end, name)
+    interval: Option<(String, u64, u64, Option<String>)>,
+}
+
+impl SitesReader<BufReader<File>> {
+    /// Open a sites file, guessing its format from its extension, see [`SitesFormat::from_path`].
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .with_context(|| format!("Failed to open sites file {:?}", path.as_ref()))?;
+        Ok(Self::new(
+            BufReader::new(file),
+            SitesFormat::from_path(path),
+        ))
+    }
+}
+
+impl<R: BufRead> SitesReader<R> {
+    /// Create a reader over sites in the given format.
+    pub fn new(reader: R, format: SitesFormat) -> Self {
+        Self {
+            lines: reader.lines(),
+            format,
+            line_number: 0,
+            interval: None,
+        }
+    }
+
+    /// Check if a line is a TSV header, with a position column that isn't a number.
+    fn is_header(&self, line: &str) -> bool {
+        self.format == SitesFormat::Tsv
+            && line
+                .split('\t')
+                .nth(1)
+                .is_some_and(|pos| pos.parse::<u64>().is_err())
+    }
+
+    /// Parse one line, returning the interval of sites it covers as (chrom, start, end, id).
+    fn parse_line(&self, line: &str) -> Result<(String, u64, u64, Option<String>)> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let field = |i: usize, name: &str| {
+            fields
+                .get(i)
+                .filter(|f| !f.is_empty())
+                .with_context(|| format!("Missing {} on line {}", name, self.line_number))
+        };
+        let number = |i: usize, name: &str| -> Result<u64> {
+            let value = field(i, name)?;
+            value.parse().with_context(|| {
+                format!("Invalid {} {:?} on line {}", name, value, self.line_number)
+            })
+        };
+        let chrom = String::from(*field(0, "chrom")?);
+        let (start, end, id) = match self.format {
+            SitesFormat::Bed => (number(1, "start")?, number(2, "end")?, fields.get(3)),
+            SitesFormat::Tsv => {
+                let pos = number(1, "position")?;
+                if pos == 0 {
+                    return Err(Error::msg(format!(
+                        "Position 0 on line {}, positions are 1-based",
+                        self.line_number
+                    )));
+                }
+                (pos - 1, pos, fields.get(2))
+            }
+        };
+        if end <= start {
+            return Err(Error::msg(format!(
+                "Empty interval {}-{} on line {}",
+                start, end, self.line_number
+            )));
+        }
+        let id = id.filter(|id| !id.is_empty()).map(|id| String::from(*id));
+        Ok((chrom, start, end, id))
+    }
+}
+
+impl<R: BufRead> Iterator for SitesReader<R> {
+    type Item = Result<Site>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref_seq, pos, end, id)) = &mut self.interval {
+                if *pos < *end {
+                    let site = Site {
+                        ref_seq: ref_seq.clone(),
+                        pos: *pos,
+                        id: id.clone(),
+                        line: self.line_number,
+                    };
+                    *pos += 1;
+                    return Some(Ok(site));
+                }
+                self.interval = None;
+            }
+
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line_number += 1;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            match self.parse_line(line) {
+                Ok(interval) => self.interval = Some(interval),
+                // Allow a header line without a leading `#`, like `chrom\tpos\tid`
+                Err(_) if self.line_number == 1 && self.is_header(line) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Streams the positions of the records of a VCF/BCF file as sites, in file order.
+///
+/// Each record is a single site at its position, regardless of the length of its REF allele, and
+/// the `line` of the site is the record number.
+pub struct VcfSites {
+    /// The VCF/BCF being read
+    reader: bcf::Reader,
+    /// The record being read into
+    record: bcf::Record,
+    /// The 1-based number of the last record read
+    record_number: usize,
+}
+
+impl VcfSites {
+    /// Open a VCF/BCF file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = bcf::Reader::from_path(path.as_ref())
+            .with_context(|| format!("Failed to open VCF/BCF {:?}", path.as_ref()))?;
+        let record = reader.empty_record();
+        Ok(Self {
+            reader,
+            record,
+            record_number: 0,
+        })
+    }
+
+    /// Convert the last record read to a site.
+    fn site(&self) -> Result<Site> {
+        let rid = self
+            .record
+            .rid()
+            .with_context(|| format!("VCF record {} without a contig", self.record_number))?;
+        let contig = self.record.header().rid2name(rid)?;
+        Ok(Site {
+            ref_seq: String::from(std::string::String::from_utf8_lossy(contig).as_ref()),
+            pos: self.record.pos() as u64,
+            id: None,
+            line: self.record_number,
+        })
+    }
+}
+
+impl Iterator for VcfSites {
+    type Item = Result<Site>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        self.record_number += 1;
+        Some(self.site())
+    }
+}
+
+/// Group sorted, deduplicated positions on one contig into regions that can be fetched together.
+///
+/// A new region is started whenever the gap to the next position is more than `max_gap`, or the
+/// region would span more than `max_len` bases. Returns the index range into `positions` of the
+/// sites in each region.
+pub fn group_sites(positions: &[u64], max_gap: u64, max_len: u64) -> Vec<std::ops::Range<usize>> {
+    let mut groups = vec![];
+    let mut start = 0;
+    for i in 1..positions.len() {
+        if positions[i] - positions[i - 1] > max_gap || positions[i] - positions[start] >= max_len {
+            groups.push(start..i);
+            start = i;
+        }
+    }
+    if !positions.is_empty() {
+        groups.push(start..positions.len());
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(text: &str, format: SitesFormat) -> Result<Vec<Site>> {
+        SitesReader::new(text.as_bytes(), format).collect()
+    }
+
+    fn site(ref_seq: &str, pos: u64, id: Option<&str>, line: usize) -> Site {
+        Site {
+            ref_seq: String::from(ref_seq),
+            pos,
+            id: id.map(String::from),
+            line,
+        }
+    }
+
+    #[test]
+    fn reads_tsv() {
+        let sites = read(
+            "#chrom\tpos\tid\nchr2\t10\trs1\nchr1\t5\n\nchr2\t10\trs2\n",
+            SitesFormat::Tsv,
+        )
+        .unwrap();
+        assert_eq!(
+            sites,
+            vec![
+                site("chr2", 9, Some("rs1"), 2),
+                site("chr1", 4, None, 3),
+                site("chr2", 9, Some("rs2"), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_bed() {
+        let sites = read(
+            "track name=sites\nchr1\t4\t5\tsnp\nchr1\t10\t12\n",
+            SitesFormat::Bed,
+        )
+        .unwrap();
+        assert_eq!(
+            sites,
+            vec![
+                site("chr1", 4, Some("snp"), 2),
+                site("chr1", 10, None, 3),
+                site("chr1", 11, None, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        let sites = read("chrom\tpos\nchr1\t5\n", SitesFormat::Tsv).unwrap();
+        assert_eq!(sites, vec![site("chr1", 4, None, 2)]);
+        let err = read("chr1\t5\nchr1\tfive\n", SitesFormat::Tsv).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        let err = read("chr1\t0\n", SitesFormat::Tsv).unwrap_err();
+        assert!(err.to_string().contains("1-based"), "{}", err);
+        let err = read("chr1\t5\t5\n", SitesFormat::Bed).unwrap_err();
+        assert!(err.to_string().contains("Empty interval"), "{}", err);
+    }
+
+    #[test]
+    fn reads_vcf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sites.vcf");
+        std::fs::write(
+            &path,
+            "##fileformat=VCFv4.2\n##contig=<ID=chr1,length=100>\n##contig=<ID=chr2,length=100>\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+             chr2\t10\trs1\tAT\tA\t.\t.\t.\nchr1\t5\t.\tA\tC\t.\t.\t.\n",
+        )
+        .unwrap();
+        let sites = VcfSites::from_path(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            sites,
+            vec![site("chr2", 9, None, 1), site("chr1", 4, None, 2)]
+        );
+    }
+
+    #[test]
+    fn groups_sites() {
+        let positions = [1, 2, 10, 2000, 2001, 2500, 2600];
+        assert_eq!(group_sites(&positions, 1000, 10_000), vec![0..3, 3..7]);
+        assert_eq!(group_sites(&positions, 1000, 500), vec![0..3, 3..5, 5..7]);
+        assert!(group_sites(&[], 1000, 500).is_empty());
+    }
+}
@@ -1,9 +1,11 @@
 //! General utility methods.
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
 use lazy_static::lazy_static;
 use log::*;
 use num_cpus;
 use rayon;
+use rust_htslib::htslib;
+use std::{ffi::CString, path::Path};
 
 /// Set rayon global thread pool size
 pub fn set_rayon_global_pools_size(size: usize) -> Result<()> {
@@ -16,7 +18,7 @@ pub fn set_rayon_global_pools_size(size: usize) -> Result<()> {
 
 /// Check that specified `desired` is valid
 pub fn determine_allowed_cpus(desired: usize) -> Result<usize> {
-    if desired <= 0 {
+    if desired == 0 {
         error!("Must select > 0 threads");
         Err(Error::msg("Too few threads selected. Min 4"))
     } else if desired > num_cpus::get() {
@@ -28,6 +30,53 @@ pub fn determine_allowed_cpus(desired: usize) -> Result<usize> {
     }
 }
 
+/// Count the mapped reads in an indexed BAM from the per-contig counts stored in its index, without
+/// reading the BAM. The counts include every mapped record, with no filtering by SAM flag or MAPQ.
+///
+/// CRAM indexes don't store these counts, so this returns an error for CRAM.
+pub fn mapped_reads(reads: &Path) -> Result<u64> {
+    let c_path = reads
+        .to_str()
+        .and_then(|p| CString::new(p).ok())
+        .with_context(|| format!("Invalid path {:?}", reads))?;
+    let mode = CString::new("r").unwrap();
+    unsafe {
+        let fp = htslib::hts_open(c_path.as_ptr(), mode.as_ptr());
+        if fp.is_null() {
+            return Err(Error::msg(format!("Failed to open {:?}", reads)));
+        }
+        let header = htslib::sam_hdr_read(fp);
+        let idx = if header.is_null() {
+            std::ptr::null_mut()
+        } else {
+            htslib::sam_index_load(fp, c_path.as_ptr())
+        };
+        let mut total = Some(0);
+        if !idx.is_null() {
+            for tid in 0..(*header).n_targets {
+                let (mut mapped, mut unmapped) = (0, 0);
+                if htslib::hts_idx_get_stat(idx, tid, &mut mapped, &mut unmapped) < 0 {
+                    total = None;
+                    break;
+                }
+                total = total.map(|total| total + mapped);
+            }
+            htslib::hts_idx_destroy(idx);
+        }
+        if !header.is_null() {
+            htslib::sam_hdr_destroy(header);
+        }
+        htslib::hts_close(fp);
+        match (idx.is_null(), total) {
+            (false, Some(total)) => Ok(total),
+            _ => Err(Error::msg(format!(
+                "No mapped read counts in the index of {:?}, a BAM with a BAI or CSI index is needed",
+                reads
+            ))),
+        }
+    }
+}
+
 lazy_static! {
     /// Return the number of cpus as an &str
     pub static ref NUM_CPU: String = num_cpus::get().to_string();
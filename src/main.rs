@@ -20,6 +20,8 @@ enum Subcommand {
     BaseDepth(base_depth::BaseDepth),
     OnlyDepth(only_depth::OnlyDepth),
     MergeAdjacent(merge_adjacent::MergeAdjacent),
+    RegionStats(region_stats::RegionStats),
+    VcfCounts(vcf_counts::VcfCounts),
 }
 
 impl Subcommand {
@@ -28,6 +30,8 @@ impl Subcommand {
             Subcommand::BaseDepth(x) => x.run()?,
             Subcommand::OnlyDepth(x) => x.run()?,
             Subcommand::MergeAdjacent(x) => x.run()?,
+            Subcommand::RegionStats(x) => x.run()?,
+            Subcommand::VcfCounts(x) => x.run()?,
         }
         Ok(())
     }
@@ -36,7 +40,7 @@ impl Subcommand {
 fn main() -> Result<()> {
     env_logger::from_env(Env::default().default_filter_or("info")).init();
     if let Err(err) = Args::from_args().subcommand.run() {
-        error!("{}", err);
+        error!("{:#}", err);
         std::process::exit(1);
     }
     Ok(())